@@ -0,0 +1,30 @@
+/*
+ * Entry point of the statically linked sqlite3-chromaprint extension.
+ *
+ * Build the static library without the extension feature:
+ *
+ *     cargo build --release --no-default-features --features decode
+ *
+ * and link target/release/libsqlite3_chromaprint.a into the app with SQLite.
+ * Registering the entry point adds the extension's functions and tables to
+ * every connection opened afterwards:
+ *
+ *     sqlite3_auto_extension((void (*)(void))sqlite3_chromaprint_init);
+ */
+#ifndef SQLITE3_CHROMAPRINT_H
+#define SQLITE3_CHROMAPRINT_H
+
+#include <sqlite3.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+int sqlite3_chromaprint_init(sqlite3 *db, char **pzErrMsg,
+                             const sqlite3_api_routines *pApi);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SQLITE3_CHROMAPRINT_H */
@@ -0,0 +1,169 @@
+//! Track identification with the AcoustID web service.
+//!
+//! With the `acoustid` feature, `acoustid_lookup(fingerprint, duration,
+//! api_key)` looks a fingerprint up in the AcoustID database and returns the
+//! service's JSON response, with the recordings each result belongs to:
+//!
+//! ```sql
+//! SELECT r.value ->> '$.score', r.value -> '$.recordings[0].id'
+//! FROM json_each(acoustid_lookup(fp, 215, :api_key), '$.results') r;
+//! ```
+//!
+//! `duration` is the length of the audio in seconds and `api_key` an
+//! application key registered at <https://acoustid.org>. Fingerprints in
+//! Chromaprint's compressed format are sent as they are. Others are sent
+//! compressed, marked as made with the `preset` option's preset. AcoustID
+//! only holds fingerprints made with `fpcalc`'s default, `'test2'`, so only
+//! those find matches.
+//!
+//! Requests from the whole process are spaced to stay within the service's
+//! limit of three per second, so a query looking up many fingerprints takes
+//! at least a third of a second per row.
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+
+use crate::panic::guard;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::{encoding, fingerprint_and_preset_from_value, json, limits};
+
+const LOOKUP_URL: &str = "https://api.acoustid.org/v2/lookup";
+
+/// Timeout of a whole request.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Minimum time between the starts of two requests.
+const MIN_INTERVAL: Duration = Duration::from_millis(334);
+
+/// When the last request was started.
+static LAST_REQUEST: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_scalar_function(
+        "acoustid_lookup",
+        3,
+        FunctionFlags::SQLITE_UTF8
+            | FunctionFlags::SQLITE_DIRECTONLY
+            | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+        guard(move |ctx| {
+            let preset = state.options().preset;
+            let compressed = compressed_from_value(0, "fingerprint", ctx.get_raw(0), preset)?;
+            let duration = ctx.get::<f64>(1)?;
+            let api_key = ctx.get::<String>(2)?;
+
+            let response = lookup(&compressed, duration, &api_key, "recordings")
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            limits::check_length(ctx, response.len())?;
+
+            Ok(json::result(response))
+        }),
+    )
+}
+
+/// A fingerprint argument in Chromaprint's compressed format, as sent to
+/// AcoustID. Raw fingerprints are taken to be made with `default`.
+pub(crate) fn compressed_from_value(
+    idx: usize,
+    name: &str,
+    value: ValueRef<'_>,
+    default: Preset,
+) -> rusqlite::Result<String> {
+    let (preset, fingerprint) = fingerprint_and_preset_from_value(idx, name, value)?;
+    Ok(encoding::compress(
+        &fingerprint,
+        &preset.unwrap_or(default).config(),
+    ))
+}
+
+/// Look up a compressed fingerprint, with the metadata listed in `meta`,
+/// returning the response body.
+pub(crate) fn lookup(compressed: &str, duration: f64, api_key: &str, meta: &str) -> Result<String> {
+    let form = form(compressed, duration, api_key, meta)?;
+    let form: Vec<(&str, &str)> = form.iter().map(|(k, v)| (*k, v.as_str())).collect();
+
+    wait_turn();
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    match agent.post(LOOKUP_URL).send_form(&form) {
+        Ok(response) => response
+            .into_string()
+            .context("Failed to read AcoustID response"),
+        Err(ureq::Error::Status(status, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            bail!(
+                "AcoustID lookup failed: {}",
+                error_message(&body).unwrap_or_else(|| format!("HTTP {status}"))
+            )
+        }
+        Err(e) => bail!("AcoustID lookup failed: {e}"),
+    }
+}
+
+/// The fields of a lookup request.
+fn form(
+    compressed: &str,
+    duration: f64,
+    api_key: &str,
+    meta: &str,
+) -> Result<Vec<(&'static str, String)>> {
+    if !(duration.is_finite() && duration >= 1.0) {
+        bail!("Invalid duration: {duration}");
+    }
+    Ok(vec![
+        ("format", "json".to_owned()),
+        ("client", api_key.to_owned()),
+        ("meta", meta.to_owned()),
+        ("duration", duration_secs(duration).to_string()),
+        ("fingerprint", compressed.to_owned()),
+    ])
+}
+
+/// A duration as AcoustID takes it, in whole seconds.
+pub(crate) fn duration_secs(duration: f64) -> i64 {
+    duration.round() as i64
+}
+
+/// The message of an error response, e.g. `{"status": "error", "error":
+/// {"code": 4, "message": "invalid API key"}}`.
+fn error_message(body: &str) -> Option<String> {
+    let body: serde_json::Value = serde_json::from_str(body).ok()?;
+    Some(body["error"]["message"].as_str()?.to_owned())
+}
+
+/// Wait until a request may be started, and record that it was.
+fn wait_turn() {
+    let mut last = LAST_REQUEST.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(last) = *last {
+        std::thread::sleep(MIN_INTERVAL.saturating_sub(last.elapsed()));
+    }
+    *last = Some(Instant::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_form() {
+        let fields = form("AQAAAQE", 215.4, "key", "recordings").unwrap();
+        assert!(fields.contains(&("duration", "215".to_owned())));
+        assert!(fields.contains(&("fingerprint", "AQAAAQE".to_owned())));
+        assert!(fields.contains(&("client", "key".to_owned())));
+        assert!(form("AQAAAQE", f64::NAN, "key", "recordings").is_err());
+        assert!(form("AQAAAQE", 0.0, "key", "recordings").is_err());
+
+        assert_eq!(
+            error_message(
+                r#"{"status": "error", "error": {"code": 4, "message": "invalid API key"}}"#
+            )
+            .as_deref(),
+            Some("invalid API key")
+        );
+        assert_eq!(error_message("<html>"), None);
+    }
+}
@@ -0,0 +1,204 @@
+//! Broadcast-monitoring reports.
+//!
+//! `airplay_report(station, track_id, start_secs, end_secs, confidence)`
+//! aggregates window matches (one row per matched window of a recorded
+//! stream) into play events. Consecutive windows on the same station that
+//! matched the same track are merged, bridging gaps of up to
+//! [`MAX_GAP_SECS`] left by windows that failed to match:
+//!
+//! ```sql
+//! SELECT e.value ->> 'station', e.value ->> 'track_id', e.value ->> 'start_secs'
+//! FROM json_each((
+//!   SELECT airplay_report(station, track_id, start_secs, end_secs, confidence)
+//!   FROM window_matches
+//! )) AS e;
+//! ```
+//!
+//! The result is a JSON array of events ordered by start time. Rows with a
+//! NULL track id (windows that matched nothing) are ignored.
+
+use std::panic::AssertUnwindSafe;
+
+use rusqlite::functions::{Aggregate, Context, SubType};
+use rusqlite::types::ValueRef;
+use serde_json::{json, Value};
+
+use crate::panic::catch_panic;
+use crate::{json, limits};
+
+/// Longest gap between two matches of a track that still counts as one play.
+const MAX_GAP_SECS: f64 = 30.0;
+
+pub(crate) struct AirplayReport;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Window {
+    station: Value,
+    track_id: Value,
+    start_secs: f64,
+    end_secs: f64,
+    confidence: f64,
+}
+
+#[derive(Debug)]
+struct Event {
+    window: Window,
+    windows: usize,
+    confidence_sum: f64,
+}
+
+/// Merge window matches into play events, ordered by start time.
+fn events(mut windows: Vec<Window>) -> Vec<Event> {
+    windows.sort_by(|a, b| {
+        a.station
+            .to_string()
+            .cmp(&b.station.to_string())
+            .then(a.start_secs.total_cmp(&b.start_secs))
+    });
+
+    let mut events: Vec<Event> = Vec::new();
+    for window in windows {
+        if let Some(event) = events.last_mut() {
+            if event.window.station == window.station
+                && event.window.track_id == window.track_id
+                && window.start_secs - event.window.end_secs <= MAX_GAP_SECS
+            {
+                event.window.end_secs = event.window.end_secs.max(window.end_secs);
+                event.windows += 1;
+                event.confidence_sum += window.confidence;
+                continue;
+            }
+        }
+        events.push(Event {
+            windows: 1,
+            confidence_sum: window.confidence,
+            window,
+        });
+    }
+
+    events.sort_by(|a, b| a.window.start_secs.total_cmp(&b.window.start_secs));
+    events
+}
+
+fn json_value(idx: usize, value: ValueRef<'_>) -> rusqlite::Result<Value> {
+    Ok(match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => json!(i),
+        ValueRef::Real(f) => json!(f),
+        ValueRef::Text(s) => json!(std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?),
+        v => {
+            return Err(rusqlite::Error::InvalidFunctionParameterType(
+                idx,
+                v.data_type(),
+            ))
+        }
+    })
+}
+
+impl Aggregate<AssertUnwindSafe<Vec<Window>>, (Option<String>, SubType)> for AirplayReport {
+    fn init(&self, _ctx: &mut Context<'_>) -> rusqlite::Result<AssertUnwindSafe<Vec<Window>>> {
+        Ok(AssertUnwindSafe(Vec::new()))
+    }
+
+    fn step(
+        &self,
+        ctx: &mut Context<'_>,
+        windows: &mut AssertUnwindSafe<Vec<Window>>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            if let ValueRef::Null = ctx.get_raw(1) {
+                return Ok(());
+            }
+            windows.push(Window {
+                station: json_value(0, ctx.get_raw(0))?,
+                track_id: json_value(1, ctx.get_raw(1))?,
+                start_secs: ctx.get(2)?,
+                end_secs: ctx.get(3)?,
+                confidence: ctx.get(4)?,
+            });
+            Ok(())
+        })
+    }
+
+    fn finalize(
+        &self,
+        ctx: &mut Context<'_>,
+        windows: Option<AssertUnwindSafe<Vec<Window>>>,
+    ) -> rusqlite::Result<(Option<String>, SubType)> {
+        catch_panic(|| {
+            let Some(AssertUnwindSafe(windows)) = windows else {
+                return Ok((None, None));
+            };
+
+            let report = Value::Array(
+                events(windows)
+                    .into_iter()
+                    .map(|e| {
+                        json!({
+                            "station": e.window.station,
+                            "track_id": e.window.track_id,
+                            "start_secs": e.window.start_secs,
+                            "end_secs": e.window.end_secs,
+                            "confidence": e.confidence_sum / e.windows as f64,
+                            "windows": e.windows,
+                        })
+                    })
+                    .collect(),
+            )
+            .to_string();
+            limits::check_length(ctx, report.len())?;
+            Ok(json::result(Some(report)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(station: &str, track_id: i64, start_secs: f64, confidence: f64) -> Window {
+        Window {
+            station: json!(station),
+            track_id: json!(track_id),
+            start_secs,
+            end_secs: start_secs + 10.0,
+            confidence,
+        }
+    }
+
+    #[test]
+    fn test_events_merge_adjacent_windows() {
+        let events = events(vec![
+            window("kexp", 1, 10.0, 0.8),
+            window("kexp", 1, 0.0, 0.6),
+            // A window that failed to match leaves a short gap.
+            window("kexp", 1, 40.0, 0.7),
+            window("wfmu", 1, 20.0, 0.9),
+            window("kexp", 2, 50.0, 0.9),
+            window("kexp", 1, 200.0, 0.9),
+        ]);
+
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| {
+                (
+                    e.window.station.as_str().unwrap(),
+                    e.window.track_id.as_i64().unwrap(),
+                    e.window.start_secs,
+                    e.window.end_secs,
+                    e.windows,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("kexp", 1, 0.0, 50.0, 3),
+                ("wfmu", 1, 20.0, 30.0, 1),
+                ("kexp", 2, 50.0, 60.0, 1),
+                ("kexp", 1, 200.0, 210.0, 1),
+            ]
+        );
+        assert!((events[0].confidence_sum / 3.0 - 0.7).abs() < 1e-9);
+    }
+}
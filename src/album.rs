@@ -0,0 +1,196 @@
+//! Continuous fingerprints spanning several files.
+//!
+//! `fingerprint_album_agg(path)` decodes each track in turn into a single
+//! fingerprinter, so a track-split release produces the same kind of
+//! fingerprint as a single-file rip of the whole album:
+//!
+//! ```sql
+//! SELECT fingerprint_album_agg(path ORDER BY track_no)
+//! FROM tracks WHERE album_id = 42;
+//! ```
+//!
+//! Tracks are decoded gaplessly where the format records encoder delay and
+//! padding. All tracks must share the sample rate and channel count of the
+//! first one.
+
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{bail, Context as _, Result};
+use rusqlite::functions::{Aggregate, Context};
+use rusqlite::types::ValueRef;
+use rusty_chromaprint::Fingerprinter;
+
+use crate::decode::AudioStream;
+use crate::limits;
+use crate::panic::catch_panic;
+use crate::path::path_from_value;
+use crate::state::State;
+use crate::timings::CallStats;
+
+pub(crate) struct AlbumFingerprint {
+    pub state: Arc<State>,
+}
+
+pub(crate) struct Album {
+    printer: Fingerprinter,
+    /// Sample rate and channel count of the first track.
+    format: Option<(u32, usize)>,
+}
+
+impl Album {
+    fn append(&mut self, path: &Path, stats: &mut CallStats) -> Result<()> {
+        let mut stream = AudioStream::open_file(path, true, stats)?;
+        let format = (stream.sample_rate, stream.channels);
+        match self.format {
+            None => {
+                self.printer
+                    .start(stream.sample_rate, stream.channels as u32)
+                    .context("Failed to start fingerprinter")?;
+                self.format = Some(format);
+            }
+            Some(expected) if expected != format => bail!(
+                "{}: {} Hz / {} channels does not match the first track ({} Hz / {} channels)",
+                path.display(),
+                format.0,
+                format.1,
+                expected.0,
+                expected.1
+            ),
+            Some(_) => {}
+        }
+
+        while let Some(samples) = stream.next_samples(stats)? {
+            let started = Instant::now();
+            self.printer.consume(samples);
+            stats.fingerprint += started.elapsed();
+        }
+        Ok(())
+    }
+}
+
+impl Aggregate<AssertUnwindSafe<Album>, Option<String>> for AlbumFingerprint {
+    fn init(&self, _ctx: &mut Context<'_>) -> rusqlite::Result<AssertUnwindSafe<Album>> {
+        catch_panic(|| {
+            Ok(AssertUnwindSafe(Album {
+                printer: Fingerprinter::new(&self.state.options().preset.config()),
+                format: None,
+            }))
+        })
+    }
+
+    fn step(
+        &self,
+        ctx: &mut Context<'_>,
+        album: &mut AssertUnwindSafe<Album>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            if let ValueRef::Null = ctx.get_raw(0) {
+                return Ok(());
+            }
+            let path = path_from_value(0, ctx.get_raw(0))?;
+
+            let mut stats = self.state.call_stats();
+            album
+                .append(&path, &mut stats)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            self.state
+                .record_timing("fingerprint_album_agg", &path.to_string_lossy(), stats);
+            Ok(())
+        })
+    }
+
+    fn finalize(
+        &self,
+        ctx: &mut Context<'_>,
+        album: Option<AssertUnwindSafe<Album>>,
+    ) -> rusqlite::Result<Option<String>> {
+        catch_panic(|| {
+            let Some(AssertUnwindSafe(mut album)) = album else {
+                return Ok(None);
+            };
+            if album.format.is_none() {
+                return Ok(None);
+            }
+
+            album.printer.finish();
+            let encoding = self.state.options().encoding;
+            let fingerprint = encoding.encode(album.printer.fingerprint());
+            limits::check_length(ctx, fingerprint.len())?;
+            Ok(Some(fingerprint))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(any(feature = "extension", feature = "blob-only")))]
+    #[test]
+    fn test_fingerprint_album_agg() {
+        use rusqlite::Connection;
+
+        use super::*;
+
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let testdata = Path::new(&manifest_dir).join("src/testdata");
+        let tracks = [testdata.join("XC444467.mp3"), testdata.join("XC444467.ogg")];
+
+        // The same audio decoded into one fingerprinter as a single stream.
+        let mut stats = CallStats::default();
+        let mut printer = Fingerprinter::new(&State::default().options().preset.config());
+        let mut started = false;
+        for track in &tracks {
+            let mut stream = AudioStream::open_file(track, true, &stats).unwrap();
+            if !started {
+                printer
+                    .start(stream.sample_rate, stream.channels as u32)
+                    .unwrap();
+                started = true;
+            }
+            while let Some(samples) = stream.next_samples(&mut stats).unwrap() {
+                printer.consume(samples);
+            }
+        }
+        printer.finish();
+        let expected = State::default()
+            .options()
+            .encoding
+            .encode(printer.fingerprint());
+
+        let db = Connection::open_in_memory().unwrap();
+        crate::register(&db).unwrap();
+        db.execute_batch("CREATE TABLE tracks(album, no, path)")
+            .unwrap();
+        for (no, track) in tracks.iter().enumerate() {
+            db.execute(
+                "INSERT INTO tracks VALUES (1, ?1, ?2)",
+                rusqlite::params![no, track.to_str()],
+            )
+            .unwrap();
+        }
+        db.execute_batch("INSERT INTO tracks VALUES (1, 5, NULL), (2, 0, NULL)")
+            .unwrap();
+
+        let album =
+            |sql: &str| -> Option<String> { db.query_row(sql, [], |row| row.get(0)).unwrap() };
+        // NULL paths are skipped.
+        assert_eq!(
+            album(
+                "SELECT fingerprint_album_agg(path) \
+             FROM (SELECT path FROM tracks WHERE album = 1 ORDER BY no)"
+            ),
+            Some(expected)
+        );
+        // A group of only NULL paths, and an empty one, have no fingerprint.
+        assert_eq!(
+            album("SELECT fingerprint_album_agg(path) FROM tracks WHERE album = 2"),
+            None
+        );
+        assert_eq!(
+            album("SELECT fingerprint_album_agg(path) FROM tracks WHERE album = 3"),
+            None
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Allocator shim that routes large allocations through `sqlite3_malloc64`.
+//!
+//! Decoded sample buffers and fingerprinter state can run to megabytes per
+//! call. With the `sqlite-malloc` feature enabled, every allocation of at
+//! least [`THRESHOLD`] bytes is served by SQLite's memory allocator, so
+//! `PRAGMA soft_heap_limit` and `sqlite3_memory_used()` reflect the
+//! extension's usage. Smaller allocations go straight to the system
+//! allocator.
+//!
+//! This is the `#[global_allocator]` of whatever the crate is linked into, so
+//! it serves the whole program, not just this library. That is the intent for
+//! the loadable extension, whose own copy of the Rust runtime only allocates
+//! for it. A Rust program using the crate as a library gets the same
+//! treatment for all its large allocations, and can't enable the feature if
+//! it already has a global allocator of its own.
+//!
+//! Each large block carries a small header recording which allocator served
+//! it, so blocks allocated before the extension was initialized (or after
+//! SQLite refused a request, e.g. because of a hard heap limit) are still
+//! released correctly.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rusqlite::ffi;
+
+/// Minimum allocation size served by SQLite.
+const THRESHOLD: usize = 64 * 1024;
+
+/// Size of the header in front of large blocks. SQLite guarantees 8 byte
+/// alignment, which a 16 byte header preserves.
+const HEADER: usize = 16;
+
+const FROM_SYSTEM: usize = 0;
+const FROM_SQLITE: usize = 1;
+
+/// Set once the SQLite API routines are available.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Start serving large allocations from SQLite.
+///
+/// Must only be called once the loadable extension API has been initialized.
+pub(crate) fn enable() {
+    READY.store(true, Ordering::Release);
+}
+
+pub(crate) struct SqliteAllocator;
+
+fn is_large(layout: &Layout) -> bool {
+    layout.size() >= THRESHOLD && layout.align() <= 8
+}
+
+fn system_layout(layout: &Layout) -> Layout {
+    // Cannot fail: the size was already valid and the alignment is a power of two.
+    Layout::from_size_align(layout.size() + HEADER, 8).unwrap()
+}
+
+unsafe impl GlobalAlloc for SqliteAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !is_large(&layout) {
+            return System.alloc(layout);
+        }
+
+        let mut source = FROM_SQLITE;
+        let mut base = std::ptr::null_mut::<u8>();
+        if READY.load(Ordering::Acquire) {
+            base = ffi::sqlite3_malloc64((layout.size() + HEADER) as u64).cast();
+        }
+        if base.is_null() {
+            source = FROM_SYSTEM;
+            base = System.alloc(system_layout(&layout));
+            if base.is_null() {
+                return base;
+            }
+        }
+
+        base.cast::<usize>().write(source);
+        base.add(HEADER)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if !is_large(&layout) {
+            return System.alloc_zeroed(layout);
+        }
+
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            ptr.write_bytes(0, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !is_large(&layout) {
+            return System.dealloc(ptr, layout);
+        }
+
+        let base = ptr.sub(HEADER);
+        match base.cast::<usize>().read() {
+            FROM_SQLITE => ffi::sqlite3_free(base.cast()),
+            _ => System.dealloc(base, system_layout(&layout)),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if !is_large(&layout) && !is_large(&new_layout) {
+            return System.realloc(ptr, layout, new_size);
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            new_ptr.copy_from_nonoverlapping(ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocations_before_enable() {
+        let allocator = SqliteAllocator;
+        unsafe {
+            for size in [16, THRESHOLD - 1, THRESHOLD, 4 * THRESHOLD] {
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                let ptr = allocator.alloc_zeroed(layout);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr.add(size - 1).read(), 0);
+                ptr.add(size - 1).write(0xAA);
+
+                // Grow across the threshold and make sure the contents survive.
+                let ptr = allocator.realloc(ptr, layout, size * 2);
+                assert_eq!(ptr.add(size - 1).read(), 0xAA);
+                allocator.dealloc(ptr, Layout::from_size_align(size * 2, 8).unwrap());
+            }
+        }
+    }
+}
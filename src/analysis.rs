@@ -0,0 +1,312 @@
+//! Frame-level features of decoded audio, shared by the `audio_*` functions.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use realfft::num_complex::Complex;
+use realfft::{RealFftPlanner, RealToComplex};
+
+use crate::decode::AudioStream;
+use crate::timings::CallStats;
+
+/// Level reported for digital silence.
+pub(crate) const MIN_DBFS: f64 = -120.0;
+
+/// Percentile of frame levels taken as the noise floor.
+pub(crate) const NOISE_FLOOR_PERCENTILE: f64 = 0.1;
+
+/// Frame length used to estimate the noise floor.
+const NOISE_FLOOR_FRAME_SECS: f64 = 0.05;
+
+/// Frame length used for the zero crossing rate; only affects buffering.
+const ZCR_FRAME_SECS: f64 = 0.05;
+
+/// Level below which audio counts as silence, unless another is given.
+pub(crate) const DEFAULT_SILENCE_DBFS: f64 = -60.0;
+
+/// Resolution of leading and trailing silence durations.
+const SILENCE_FRAME_SECS: f64 = 0.01;
+
+/// Fixed-length frames of a file's audio, downmixed to mono `f32` samples.
+pub(crate) struct MonoFrames {
+    stream: AudioStream,
+    frame: Vec<f32>,
+    /// Samples decoded but not yet returned in a frame.
+    pending: Vec<f32>,
+    /// Samples still to be dropped before the next frame starts.
+    skip: usize,
+    pub frame_len: usize,
+    pub hop_len: usize,
+    pub sample_rate: u32,
+}
+
+impl MonoFrames {
+    /// Open the file at `path`, returning consecutive frames of `frame_secs` each.
+    pub fn open(path: &Path, frame_secs: f64, stats: &CallStats) -> Result<Self> {
+        Self::open_with_hop(path, frame_secs, frame_secs, stats)
+    }
+
+    /// Open the file at `path`, returning frames of `frame_secs` that start
+    /// every `hop_secs`.
+    pub fn open_with_hop(
+        path: &Path,
+        frame_secs: f64,
+        hop_secs: f64,
+        stats: &CallStats,
+    ) -> Result<Self> {
+        let stream = AudioStream::open_file(path, false, stats)?;
+        let sample_rate = stream.sample_rate;
+        let frame_len = ((frame_secs * sample_rate as f64) as usize).max(1);
+        let hop_len = ((hop_secs * sample_rate as f64) as usize).max(1);
+        Ok(Self {
+            stream,
+            frame: Vec::with_capacity(frame_len),
+            pending: Vec::new(),
+            skip: 0,
+            frame_len,
+            hop_len,
+            sample_rate,
+        })
+    }
+
+    /// The next full frame, or `None` at the end of the stream. A trailing
+    /// partial frame is dropped.
+    pub fn next_frame(&mut self, stats: &mut CallStats) -> Result<Option<&[f32]>> {
+        loop {
+            let skipped = self.skip.min(self.pending.len());
+            self.pending.drain(..skipped);
+            self.skip -= skipped;
+            if self.skip == 0 && self.pending.len() >= self.frame_len {
+                break;
+            }
+
+            let channels = self.stream.channels.max(1);
+            let Some(samples) = self.stream.next_samples(stats)? else {
+                return Ok(None);
+            };
+            self.pending
+                .extend(samples.chunks_exact(channels).map(|frame| {
+                    frame.iter().map(|&s| s as f32).sum::<f32>() / (channels as f32 * 32768.0)
+                }));
+        }
+
+        self.frame.clear();
+        self.frame
+            .extend_from_slice(&self.pending[..self.frame_len]);
+        self.skip = self.hop_len;
+        Ok(Some(&self.frame))
+    }
+}
+
+/// Spectrum of windowed frames of a fixed length.
+pub(crate) struct Spectrum {
+    fft: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    input: Vec<f32>,
+    output: Vec<Complex<f32>>,
+    /// Width of each frequency bin in Hz.
+    pub bin_hz: f32,
+}
+
+impl Spectrum {
+    /// Spectrum of Hann-windowed frames.
+    pub fn new(frame_len: usize, sample_rate: u32) -> Self {
+        Self::with_window(frame_len, sample_rate, |x| x.sin().powi(2))
+    }
+
+    /// Spectrum of Hamming-windowed frames, as used by Chromaprint.
+    pub fn hamming(frame_len: usize, sample_rate: u32) -> Self {
+        Self::with_window(frame_len, sample_rate, |x| 0.54 - 0.46 * (2.0 * x).cos())
+    }
+
+    /// `window` maps the position in the frame, from 0 to pi, to a weight.
+    fn with_window(frame_len: usize, sample_rate: u32, window: impl Fn(f32) -> f32) -> Self {
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(frame_len);
+        let window = (0..frame_len)
+            .map(|i| window(std::f32::consts::PI * i as f32 / frame_len as f32))
+            .collect();
+        Self {
+            input: fft.make_input_vec(),
+            output: fft.make_output_vec(),
+            fft,
+            window,
+            bin_hz: sample_rate as f32 / frame_len as f32,
+        }
+    }
+
+    /// The `frame_len / 2 + 1` frequency bins of `frame`, from 0 Hz up.
+    pub fn of(&mut self, frame: &[f32]) -> Result<&[Complex<f32>]> {
+        for ((x, s), w) in self.input.iter_mut().zip(frame).zip(&self.window) {
+            *x = s * w;
+        }
+        self.fft
+            .process(&mut self.input, &mut self.output)
+            .map_err(|e| anyhow!("FFT failed: {e}"))?;
+        Ok(&self.output)
+    }
+}
+
+/// RMS level and zero crossing rate of a frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct FrameFeatures {
+    /// Root mean square of the samples, where full scale is 1.
+    pub rms: f32,
+    /// Fraction of consecutive samples that change sign.
+    pub zcr: f32,
+}
+
+impl FrameFeatures {
+    pub fn of(frame: &[f32]) -> Self {
+        if frame.is_empty() {
+            return Self::default();
+        }
+        let rms = (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt();
+        let crossings = frame
+            .windows(2)
+            .filter(|w| (w[0] >= 0.0) != (w[1] >= 0.0))
+            .count();
+        Self {
+            rms,
+            zcr: crossings as f32 / frame.len() as f32,
+        }
+    }
+}
+
+/// Features of each frame of the file at `path`.
+pub(crate) fn frame_features(
+    path: &Path,
+    frame_secs: f64,
+    stats: &mut CallStats,
+) -> Result<Vec<FrameFeatures>> {
+    let mut frames = MonoFrames::open(path, frame_secs, stats)?;
+    let mut features = Vec::new();
+    while let Some(frame) = frames.next_frame(stats)? {
+        features.push(FrameFeatures::of(frame));
+    }
+    Ok(features)
+}
+
+/// A linear RMS level in dB relative to full scale.
+pub(crate) fn to_dbfs(rms: f32) -> f64 {
+    if rms > 0.0 {
+        (20.0 * (rms as f64).log10()).max(MIN_DBFS)
+    } else {
+        MIN_DBFS
+    }
+}
+
+/// The level below which `fraction` of the frames fall, in dBFS.
+pub(crate) fn percentile_dbfs(frames: &[FrameFeatures], fraction: f64) -> Option<f64> {
+    let mut levels: Vec<f32> = frames.iter().map(|f| f.rms).collect();
+    if levels.is_empty() {
+        return None;
+    }
+    levels.sort_by(f32::total_cmp);
+    let i = ((levels.len() - 1) as f64 * fraction).round() as usize;
+    Some(to_dbfs(levels[i]))
+}
+
+/// The estimated noise floor of the file at `path` in dBFS: the level of its
+/// quietest frames, ignoring the quietest [`NOISE_FLOOR_PERCENTILE`] as
+/// dropouts or digital silence between tracks.
+pub(crate) fn audio_noise_floor(path: &Path, stats: &mut CallStats) -> Result<Option<f64>> {
+    let frames = frame_features(path, NOISE_FLOOR_FRAME_SECS, stats)?;
+    Ok(percentile_dbfs(&frames, NOISE_FLOOR_PERCENTILE))
+}
+
+/// The zero crossing rate of the file at `path`, in sign changes per second
+/// so files with different sample rates compare directly.
+pub(crate) fn audio_zcr(path: &Path, stats: &mut CallStats) -> Result<Option<f64>> {
+    let mut frames = MonoFrames::open(path, ZCR_FRAME_SECS, stats)?;
+    let sample_rate = frames.sample_rate as f64;
+
+    let mut previous: Option<f32> = None;
+    let (mut crossings, mut samples) = (0u64, 0u64);
+    while let Some(frame) = frames.next_frame(stats)? {
+        for &s in frame {
+            if previous.is_some_and(|p| (p >= 0.0) != (s >= 0.0)) {
+                crossings += 1;
+            }
+            previous = Some(s);
+        }
+        samples += frame.len() as u64;
+    }
+
+    Ok((samples > 0).then(|| crossings as f64 * sample_rate / samples as f64))
+}
+
+/// The number of frames at the start and at the end of `frames` quieter
+/// than `threshold_db`. If every frame is, both are all of them.
+fn silent_ends(frames: &[FrameFeatures], threshold_db: f64) -> (usize, usize) {
+    let silent = |f: &FrameFeatures| to_dbfs(f.rms) < threshold_db;
+    let lead = frames.iter().take_while(|f| silent(f)).count();
+    let trail = frames.iter().rev().take_while(|f| silent(f)).count();
+    (lead, trail)
+}
+
+/// The lead-in and fade-out silence of the file at `path` as JSON:
+/// `{"lead_secs": 0.42, "trail_secs": 3.1, "duration_secs": 215.8}`, where
+/// silence is audio quieter than `threshold_db` dBFS.
+pub(crate) fn audio_lead_trail_silence(
+    path: &Path,
+    threshold_db: f64,
+    stats: &mut CallStats,
+) -> Result<serde_json::Value> {
+    let mut frames = MonoFrames::open(path, SILENCE_FRAME_SECS, stats)?;
+    let mut features = Vec::new();
+    while let Some(frame) = frames.next_frame(stats)? {
+        features.push(FrameFeatures::of(frame));
+    }
+
+    let (lead, trail) = silent_ends(&features, threshold_db);
+    let secs = |n: usize| (n * frames.frame_len) as f64 / frames.sample_rate as f64;
+    Ok(serde_json::json!({
+        "lead_secs": secs(lead),
+        "trail_secs": secs(trail),
+        "duration_secs": secs(features.len()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_features() {
+        let square: Vec<f32> = (0..100)
+            .map(|i| if i % 2 == 0 { 0.5 } else { -0.5 })
+            .collect();
+        let features = FrameFeatures::of(&square);
+        assert_eq!(features.rms, 0.5);
+        assert_eq!(features.zcr, 0.99);
+
+        assert!((to_dbfs(0.5) - -6.0206).abs() < 1e-3);
+        assert_eq!(to_dbfs(0.0), MIN_DBFS);
+    }
+
+    #[test]
+    fn test_percentile_dbfs() {
+        let frames: Vec<_> = (1..=100)
+            .map(|i| FrameFeatures {
+                rms: i as f32 / 100.0,
+                zcr: 0.0,
+            })
+            .collect();
+        assert!((percentile_dbfs(&frames, 0.1).unwrap() - to_dbfs(0.11)).abs() < 1e-9);
+        assert_eq!(percentile_dbfs(&frames, 1.0), Some(0.0));
+        assert_eq!(percentile_dbfs(&[], 0.1), None);
+    }
+
+    #[test]
+    fn test_silent_ends() {
+        let frames: Vec<_> = [0.0, 0.0001, 0.5, 0.0, 0.5, 0.0]
+            .into_iter()
+            .map(|rms| FrameFeatures { rms, zcr: 0.0 })
+            .collect();
+        assert_eq!(silent_ends(&frames, -60.0), (2, 1));
+        assert_eq!(silent_ends(&frames, -90.0), (1, 1));
+        assert_eq!(silent_ends(&frames[..2], -60.0), (2, 2));
+        assert_eq!(silent_ends(&[], -60.0), (0, 0));
+    }
+}
@@ -0,0 +1,225 @@
+//! Beat tracking.
+//!
+//! `audio_beats(path)` returns the estimated beat times of a file, marking
+//! the first beat of each bar when the bar structure is clear:
+//!
+//! ```sql
+//! SELECT time_secs, downbeat FROM audio_beats('track.mp3');
+//! ```
+//!
+//! The tempo is the period, between [`MIN_BPM`] and [`MAX_BPM`], at which
+//! the onset strength (the spectral flux used by `audio_onsets`) best
+//! correlates with itself, favouring tempos near [`PREFERRED_BPM`] to
+//! avoid picking half or double the tempo. Beats are then placed by
+//! dynamic programming, which balances landing on strong onsets against
+//! keeping a steady period.
+//!
+//! Bars are assumed to have four beats. `downbeat` is 1 for the first beat
+//! of each bar and 0 for the others when one of the four positions has
+//! clearly stronger onsets than the rest, and NULL otherwise. Files without
+//! a detectable pulse have no rows.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rusqlite::vtab::Context;
+use rusqlite::Connection;
+
+use crate::onsets::{spectral_flux, MIN_FLUX};
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::tvf::{self, PathFunction};
+
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+const PREFERRED_BPM: f64 = 120.0;
+
+/// Width of the tempo preference, in octaves.
+const PREFERENCE_OCTAVES: f64 = 1.0;
+
+/// Penalty for beat intervals that differ from the period.
+const TIGHTNESS: f32 = 100.0;
+
+const BEATS_PER_BAR: usize = 4;
+
+/// How much stronger the downbeat position's onsets must be than the mean of
+/// the other positions.
+const DOWNBEAT_CONTRAST: f32 = 1.2;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    tvf::register_path_function::<Beats>(db, state)
+}
+
+/// The beat period in frames of `hop_secs`, or `None` if there is no pulse.
+fn beat_period(envelope: &[f32], hop_secs: f64) -> Option<usize> {
+    if envelope.iter().copied().fold(0.0, f32::max) < MIN_FLUX {
+        return None;
+    }
+    let mean = envelope.iter().sum::<f32>() / envelope.len() as f32;
+    let centred: Vec<f32> = envelope.iter().map(|e| e - mean).collect();
+
+    let min_lag = ((60.0 / MAX_BPM / hop_secs).round() as usize).max(1);
+    let max_lag = (60.0 / MIN_BPM / hop_secs).round() as usize;
+    (min_lag..=max_lag.min(centred.len() / 2))
+        .map(|lag| {
+            let correlation: f32 = centred
+                .iter()
+                .zip(&centred[lag..])
+                .map(|(a, b)| a * b)
+                .sum();
+            let octaves = (60.0 / (lag as f64 * hop_secs) / PREFERRED_BPM).log2();
+            let preference = (-0.5 * (octaves / PREFERENCE_OCTAVES).powi(2)).exp();
+            (lag, correlation * preference as f32)
+        })
+        .filter(|&(_, score)| score > 0.0)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(lag, _)| lag)
+}
+
+/// Frames of the beats, `period` frames apart give or take, that best
+/// follow the onsets in `envelope`.
+fn track_beats(envelope: &[f32], period: usize) -> Vec<usize> {
+    // score[t]: best total onset strength of a beat sequence ending at t,
+    // and the previous beat of that sequence.
+    let mut score: Vec<f32> = Vec::with_capacity(envelope.len());
+    let mut previous: Vec<Option<usize>> = Vec::with_capacity(envelope.len());
+    for (t, &e) in envelope.iter().enumerate() {
+        let best = (t.saturating_sub(2 * period)..=t.saturating_sub(period / 2))
+            .filter(|&p| p < t)
+            .map(|p| {
+                let ratio = (t - p) as f32 / period as f32;
+                (p, score[p] - TIGHTNESS * ratio.ln().powi(2))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        match best {
+            Some((p, s)) if s > 0.0 => {
+                score.push(e + s);
+                previous.push(Some(p));
+            }
+            _ => {
+                score.push(e);
+                previous.push(None);
+            }
+        }
+    }
+
+    // The sequence ends with the best score in the last period.
+    let Some(mut t) = (envelope.len().saturating_sub(period)..envelope.len())
+        .max_by(|&a, &b| score[a].total_cmp(&score[b]))
+    else {
+        return Vec::new();
+    };
+    let mut beats = vec![t];
+    while let Some(p) = previous[t] {
+        beats.push(p);
+        t = p;
+    }
+    beats.reverse();
+    beats
+}
+
+/// Position within the bar of the first beat of each bar, if one position
+/// stands out.
+fn downbeat_phase(envelope: &[f32], beats: &[usize]) -> Option<usize> {
+    if beats.len() < 2 * BEATS_PER_BAR {
+        return None;
+    }
+    let mut strength = [0.0; BEATS_PER_BAR];
+    let mut count = [0; BEATS_PER_BAR];
+    for (i, &b) in beats.iter().enumerate() {
+        strength[i % BEATS_PER_BAR] += envelope[b];
+        count[i % BEATS_PER_BAR] += 1;
+    }
+    let mean: Vec<f32> = strength
+        .iter()
+        .zip(count)
+        .map(|(s, n)| s / n as f32)
+        .collect();
+
+    let (phase, &best) = mean.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+    let others = (mean.iter().sum::<f32>() - best) / (BEATS_PER_BAR - 1) as f32;
+    (best > DOWNBEAT_CONTRAST * others).then_some(phase)
+}
+
+/// The beats of a file, found once it is decoded.
+struct Beats(std::vec::IntoIter<(f64, Option<bool>)>);
+
+impl PathFunction for Beats {
+    const NAME: &'static str = "audio_beats";
+
+    /// `(time_secs, downbeat)`.
+    type Row = (f64, Option<bool>);
+
+    fn columns() -> Vec<&'static str> {
+        vec!["time_secs", "downbeat"]
+    }
+
+    fn open(path: &Path, stats: &mut CallStats) -> Result<Self> {
+        let (hop_secs, frame_secs, envelope) = spectral_flux(path, stats)?;
+        let Some(period) = beat_period(&envelope, hop_secs) else {
+            return Ok(Beats(Vec::new().into_iter()));
+        };
+        let beats = track_beats(&envelope, period);
+        let phase = downbeat_phase(&envelope, &beats);
+        let rows: Vec<_> = beats
+            .into_iter()
+            .enumerate()
+            .map(|(i, b)| {
+                (
+                    b as f64 * hop_secs + frame_secs / 2.0,
+                    phase.map(|p| i % BEATS_PER_BAR == p),
+                )
+            })
+            .collect();
+        Ok(Beats(rows.into_iter()))
+    }
+
+    fn next_row(&mut self, _stats: &mut CallStats) -> Result<Option<Self::Row>> {
+        Ok(self.0.next())
+    }
+
+    fn column(row: &Self::Row, ctx: &mut Context, i: usize) -> rusqlite::Result<()> {
+        let (time_secs, downbeat) = row;
+        match i {
+            0 => ctx.set_result(time_secs),
+            _ => ctx.set_result(downbeat),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Onset strength of a click every `period` frames from `offset`, every
+    /// fourth one accented.
+    fn clicks(len: usize, period: usize, offset: usize) -> Vec<f32> {
+        let mut envelope = vec![0.01; len];
+        for (n, t) in (offset..len).step_by(period).enumerate() {
+            envelope[t] = if n % 4 == 0 { 2.0 } else { 1.0 };
+        }
+        envelope
+    }
+
+    #[test]
+    fn test_beats_of_clicks() {
+        // 125 BPM at 100 frames per second.
+        let envelope = clicks(2000, 48, 20);
+        assert_eq!(beat_period(&envelope, 0.01), Some(48));
+
+        let beats = track_beats(&envelope, 48);
+        let expected: Vec<usize> = (20..2000).step_by(48).collect();
+        assert_eq!(beats, expected);
+        assert_eq!(downbeat_phase(&envelope, &beats), Some(0));
+
+        assert_eq!(beat_period(&[0.0; 2000], 0.01), None);
+    }
+
+    #[test]
+    fn test_unaccented_bars() {
+        let envelope: Vec<f32> = clicks(2000, 48, 20).iter().map(|e| e.min(1.0)).collect();
+        let beats = track_beats(&envelope, 48);
+        assert_eq!(downbeat_phase(&envelope, &beats), None);
+    }
+}
@@ -0,0 +1,368 @@
+//! Throughput measurement.
+//!
+//! `chromaprint_benchmark(path, iterations, max_threads)` decodes and
+//! fingerprints a file `iterations` times on each of 1, 2, 4, ... up to
+//! `max_threads` threads at once, and reports how fast that went, to size
+//! hardware for large scans:
+//!
+//! ```sql
+//! SELECT threads, realtime, speedup
+//! FROM chromaprint_benchmark('track.flac', 5, 8);
+//! ```
+//!
+//! Each row is one thread count:
+//!
+//! * `runs`: the number of times the audio was fingerprinted,
+//!   `threads * iterations`.
+//! * `audio_secs`: the duration of the audio fingerprinted in all runs.
+//! * `decode_secs`, `fingerprint_secs`: the time spent in each stage,
+//!   summed over all threads.
+//! * `wall_secs`: the elapsed time.
+//! * `realtime`: `audio_secs / wall_secs`, i.e. how many times faster than
+//!   realtime audio was processed.
+//! * `speedup`: `realtime` relative to the single thread row.
+//!
+//! With a NULL path, the built-in reference signal of
+//! `chromaprint_selftest()` is fingerprinted instead, which measures the
+//! fingerprinting stage alone. The shared cache is bypassed.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{bail, Context as _, Result};
+use rusqlite::types::Type;
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+use rusty_chromaprint::Fingerprinter;
+
+use crate::decode::AudioStream;
+use crate::panic::catch_panic;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::{path, selftest, tvf};
+
+/// Upper limit of `max_threads`, against typos.
+const MAX_THREADS: i64 = 1024;
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 8;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "chromaprint_benchmark",
+        eponymous_only_module::<BenchmarkTab>(),
+        Some(state),
+    )
+}
+
+/// The audio to fingerprint.
+enum Input {
+    File(PathBuf),
+    /// Mono samples at [`selftest::SAMPLE_RATE`].
+    Signal(Vec<i16>),
+}
+
+impl Input {
+    /// Fingerprint the audio once with `preset`, returning its duration in
+    /// seconds.
+    fn run(&self, preset: Preset, stats: &mut CallStats) -> Result<f64> {
+        match self {
+            Input::File(path) => fingerprint_file(path, preset, stats),
+            Input::Signal(samples) => {
+                let config = preset.config();
+                let mut printer = Fingerprinter::new(&config);
+                let started = Instant::now();
+                printer
+                    .start(selftest::SAMPLE_RATE, 1)
+                    .context("Failed to start fingerprinter")?;
+                printer.consume(samples);
+                printer.finish();
+                stats.fingerprint += started.elapsed();
+                Ok(samples.len() as f64 / selftest::SAMPLE_RATE as f64)
+            }
+        }
+    }
+}
+
+fn fingerprint_file(path: &Path, preset: Preset, stats: &mut CallStats) -> Result<f64> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    let (sample_rate, channels) = (stream.sample_rate, stream.channels.max(1));
+
+    let config = preset.config();
+    let mut printer = Fingerprinter::new(&config);
+    printer
+        .start(sample_rate, channels as u32)
+        .context("Failed to start fingerprinter")?;
+
+    let mut samples = 0;
+    while let Some(packet) = stream.next_samples(stats)? {
+        samples += packet.len();
+        let started = Instant::now();
+        printer.consume(packet);
+        stats.fingerprint += started.elapsed();
+    }
+
+    let started = Instant::now();
+    printer.finish();
+    stats.fingerprint += started.elapsed();
+    Ok((samples / channels) as f64 / sample_rate as f64)
+}
+
+/// Measurements of one thread count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Row {
+    threads: usize,
+    runs: usize,
+    audio_secs: f64,
+    decode_secs: f64,
+    fingerprint_secs: f64,
+    wall_secs: f64,
+}
+
+impl Row {
+    fn realtime(&self) -> Option<f64> {
+        (self.wall_secs > 0.0).then(|| self.audio_secs / self.wall_secs)
+    }
+}
+
+/// The thread counts to measure: powers of two below `max`, then `max`.
+fn thread_counts(max: usize) -> Vec<usize> {
+    let mut counts: Vec<usize> = std::iter::successors(Some(1), |n| Some(n * 2))
+        .take_while(|&n| n < max)
+        .collect();
+    counts.push(max);
+    counts
+}
+
+/// Fingerprint `input` `iterations` times on each of `threads` threads.
+fn measure(input: &Input, iterations: usize, threads: usize, state: &State) -> Result<Row> {
+    let preset = state.options().preset;
+    let started = Instant::now();
+    let results: Vec<Result<(f64, CallStats)>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let mut stats = state.call_stats();
+                scope.spawn(move || {
+                    let mut audio_secs = 0.0;
+                    for _ in 0..iterations {
+                        audio_secs += input.run(preset, &mut stats)?;
+                    }
+                    Ok((audio_secs, stats))
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| bail!("Benchmark thread panicked"))
+            })
+            .collect()
+    });
+    let wall_secs = started.elapsed().as_secs_f64();
+
+    let mut row = Row {
+        threads,
+        runs: threads * iterations,
+        audio_secs: 0.0,
+        decode_secs: 0.0,
+        fingerprint_secs: 0.0,
+        wall_secs,
+    };
+    for result in results {
+        let (audio_secs, stats) = result?;
+        row.audio_secs += audio_secs;
+        row.decode_secs += stats.decode.as_secs_f64();
+        row.fingerprint_secs += stats.fingerprint.as_secs_f64();
+    }
+    Ok(row)
+}
+
+fn benchmark(
+    input: &Input,
+    iterations: usize,
+    max_threads: usize,
+    state: &State,
+) -> Result<Vec<Row>> {
+    thread_counts(max_threads)
+        .into_iter()
+        .map(|threads| measure(input, iterations, threads, state))
+        .collect()
+}
+
+#[repr(C)]
+struct BenchmarkTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for BenchmarkTab {
+    type Aux = Arc<State>;
+    type Cursor = BenchmarkCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("chromaprint_benchmark: missing state".to_owned())
+            })?;
+            tvf::reads_files(db)?;
+            Ok((
+                "CREATE TABLE x(threads, runs, audio_secs, decode_secs, fingerprint_secs, \
+                 wall_secs, realtime, speedup, \
+                 path HIDDEN, iterations HIDDEN, max_threads HIDDEN)"
+                    .to_owned(),
+                BenchmarkTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_arguments(
+                info,
+                "chromaprint_benchmark",
+                FIRST_ARGUMENT,
+                &["path", "iterations", "max_threads"],
+            )
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<BenchmarkCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(BenchmarkCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct BenchmarkCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    rows: Vec<Row>,
+    index: usize,
+    phantom: PhantomData<&'vtab BenchmarkTab>,
+}
+
+/// A count argument between 1 and `max`.
+fn count_argument(args: &Values<'_>, idx: usize, name: &str, max: i64) -> rusqlite::Result<usize> {
+    match args.get::<i64>(idx)? {
+        n @ 1.. if n <= max => Ok(n as usize),
+        _ => Err(rusqlite::Error::ModuleError(format!(
+            "chromaprint_benchmark: {name} must be between 1 and {max}"
+        ))),
+    }
+}
+
+unsafe impl VTabCursor for BenchmarkCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.rows = Vec::new();
+            self.index = 0;
+
+            let input = match args.iter().next() {
+                Some(value) if value.data_type() != Type::Null => {
+                    Input::File(path::path_from_value(0, value)?)
+                }
+                _ => Input::Signal(selftest::reference_signal()),
+            };
+            let iterations = count_argument(args, 1, "iterations", i64::from(u32::MAX))?;
+            let max_threads = count_argument(args, 2, "max_threads", MAX_THREADS)?;
+
+            self.rows = benchmark(&input, iterations, max_threads, &self.state)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let row = self.rows[self.index];
+            match i {
+                0 => ctx.set_result(&(row.threads as i64)),
+                1 => ctx.set_result(&(row.runs as i64)),
+                2 => ctx.set_result(&row.audio_secs),
+                3 => ctx.set_result(&row.decode_secs),
+                4 => ctx.set_result(&row.fingerprint_secs),
+                5 => ctx.set_result(&row.wall_secs),
+                6 => ctx.set_result(&row.realtime()),
+                7 => {
+                    let single = self.rows[0].realtime();
+                    let speedup = row.realtime().zip(single).map(|(r, s)| r / s);
+                    ctx.set_result(&speedup)
+                }
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+
+    #[test]
+    fn test_thread_counts() {
+        assert_eq!(thread_counts(1), [1]);
+        assert_eq!(thread_counts(4), [1, 2, 4]);
+        assert_eq!(thread_counts(6), [1, 2, 4, 6]);
+    }
+
+    #[test]
+    fn test_benchmark() {
+        let state = State::new(Options::default(), None, None, None);
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+
+        let rows = benchmark(&Input::File(path), 1, 2, &state).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[1].threads, rows[1].runs), (2, 2));
+        assert!((rows[1].audio_secs - 2.0 * rows[0].audio_secs).abs() < 1e-9);
+        assert!(rows[0].decode_secs > 0.0 && rows[0].fingerprint_secs > 0.0);
+
+        let rows = benchmark(&Input::Signal(selftest::reference_signal()), 2, 1, &state).unwrap();
+        assert_eq!(rows[0].audio_secs, 24.0);
+        assert_eq!(rows[0].decode_secs, 0.0);
+    }
+}
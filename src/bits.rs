@@ -0,0 +1,231 @@
+//! Bit statistics of fingerprints.
+//!
+//! `fingerprint_bits(fingerprint)` describes how the bits of a fingerprint
+//! are used, as JSON:
+//!
+//! ```json
+//! {"items": 1520, "density": 0.49, "toggle_rates": [0.31, ...], "entropy": 30.8}
+//! ```
+//!
+//! * `density`: the fraction of all bits that are set.
+//! * `toggle_rates`: for each of the 32 bits of an item, least significant
+//!   first, the fraction of consecutive items in which it changes.
+//! * `entropy`: the sum over the 32 bits of the binary entropy of how often
+//!   each is set, from 0 to 32 bits per item.
+//!
+//! Audio with little content, such as silence or a steady tone, gives
+//! degenerate fingerprints whose bits rarely toggle and whose entropy is
+//! low. They match each other and anything similarly empty, so they are
+//! worth excluding before comparing:
+//!
+//! ```sql
+//! SELECT path FROM tracks
+//! WHERE fingerprint_bits(fingerprint) ->> '$.entropy' < 8;
+//! ```
+//!
+//! An empty fingerprint has 0 items and NULL statistics.
+//!
+//! `bit_count(x)` counts the bits set in an INTEGER, as 64 bits, or a BLOB,
+//! and `hamming_distance(a, b)` the bits two INTEGERs or two BLOBs of the
+//! same length differ in, e.g. items from `fp_hashes` or raw fingerprints
+//! from `fingerprint_raw`, for coarse filters written in SQL:
+//!
+//! ```sql
+//! SELECT a.idx, b.idx FROM fp_hashes(?1) a, fp_hashes(?2) b
+//! WHERE hamming_distance(a.hash, b.hash) <= 2;
+//! ```
+//!
+//! Both return NULL if an argument is NULL.
+//!
+//! `fp_gross_similarity(a, b [, items])` is a cheap estimate of how alike two
+//! fingerprints are, without the segment matching `compare_fingerprints`
+//! does: the fraction of bits that agree between items at the same position
+//! in the two, over the first `items` or all the items both have. It is 1
+//! for identical fingerprints and about 0.5 for unrelated ones, and
+//! meaningful only for fingerprints that start at the same point of the
+//! audio, e.g. files of the same track. As a prefilter:
+//!
+//! ```sql
+//! SELECT id FROM tracks
+//! WHERE fp_gross_similarity(fingerprint, ?1, 120) > 0.75
+//!   AND compare_fingerprints(fingerprint, ?1) < 10;
+//! ```
+//!
+//! It returns NULL if an argument is NULL or the fingerprints share no
+//! position.
+
+use anyhow::{bail, Result};
+use rusqlite::types::ValueRef;
+use serde_json::{json, Value};
+
+pub(crate) fn fingerprint_bits(fingerprint: &[u32]) -> Value {
+    let n = fingerprint.len();
+    if n == 0 {
+        return json!({
+            "items": 0,
+            "density": null,
+            "toggle_rates": null,
+            "entropy": null,
+        });
+    }
+
+    let mut set = [0usize; 32];
+    for item in fingerprint {
+        for (bit, count) in set.iter_mut().enumerate() {
+            *count += (item >> bit & 1) as usize;
+        }
+    }
+    let mut toggles = [0usize; 32];
+    for pair in fingerprint.windows(2) {
+        let changed = pair[0] ^ pair[1];
+        for (bit, count) in toggles.iter_mut().enumerate() {
+            *count += (changed >> bit & 1) as usize;
+        }
+    }
+
+    let density = set.iter().sum::<usize>() as f64 / (32 * n) as f64;
+    let toggle_rates: Vec<f64> = toggles
+        .iter()
+        .map(|&t| t as f64 / (n - 1).max(1) as f64)
+        .collect();
+    let entropy: f64 = set
+        .iter()
+        .map(|&s| binary_entropy(s as f64 / n as f64))
+        .sum();
+
+    json!({
+        "items": n,
+        "density": density,
+        "toggle_rates": toggle_rates,
+        "entropy": entropy,
+    })
+}
+
+/// Number of bits set in an INTEGER or a BLOB, or `None` if NULL.
+pub(crate) fn bit_count(value: ValueRef<'_>) -> Result<Option<i64>> {
+    match value {
+        ValueRef::Null => Ok(None),
+        ValueRef::Integer(x) => Ok(Some(i64::from(x.count_ones()))),
+        ValueRef::Blob(bytes) => Ok(Some(
+            bytes.iter().map(|byte| i64::from(byte.count_ones())).sum(),
+        )),
+        _ => bail!("bit_count: argument must be an INTEGER or a BLOB"),
+    }
+}
+
+/// Number of bits two INTEGERs or two BLOBs of the same length differ in, or
+/// `None` if either is NULL.
+pub(crate) fn hamming_distance(a: ValueRef<'_>, b: ValueRef<'_>) -> Result<Option<i64>> {
+    match (a, b) {
+        (ValueRef::Null, _) | (_, ValueRef::Null) => Ok(None),
+        (ValueRef::Integer(a), ValueRef::Integer(b)) => Ok(Some(i64::from((a ^ b).count_ones()))),
+        (ValueRef::Blob(a), ValueRef::Blob(b)) => {
+            if a.len() != b.len() {
+                bail!(
+                    "hamming_distance: BLOBs differ in length ({} and {} bytes)",
+                    a.len(),
+                    b.len()
+                );
+            }
+            Ok(Some(
+                a.iter()
+                    .zip(b)
+                    .map(|(x, y)| i64::from((x ^ y).count_ones()))
+                    .sum(),
+            ))
+        }
+        _ => bail!("hamming_distance: arguments must be two INTEGERs or two BLOBs"),
+    }
+}
+
+/// Fraction of bits that agree between the items at the same positions of
+/// `a` and `b`, or `None` if either is empty.
+pub(crate) fn gross_similarity(a: &[u32], b: &[u32]) -> Option<f64> {
+    let n = a.len().min(b.len());
+    if n == 0 {
+        return None;
+    }
+    // Independent lanes, so that the loop vectorizes.
+    const LANES: usize = 8;
+    let mut lanes = [0u32; LANES];
+    let (a_chunks, b_chunks) = (a[..n].chunks_exact(LANES), b[..n].chunks_exact(LANES));
+    let (a_rest, b_rest) = (a_chunks.remainder(), b_chunks.remainder());
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for lane in 0..LANES {
+            lanes[lane] += (x[lane] ^ y[lane]).count_ones();
+        }
+    }
+    let differing: u64 = lanes.iter().map(|&count| u64::from(count)).sum::<u64>()
+        + a_rest
+            .iter()
+            .zip(b_rest)
+            .map(|(x, y)| u64::from((x ^ y).count_ones()))
+            .sum::<u64>();
+    Some(1.0 - differing as f64 / (32 * n) as f64)
+}
+
+/// Entropy in bits of a bit that is set with probability `p`.
+fn binary_entropy(p: f64) -> f64 {
+    if p <= 0.0 || p >= 1.0 {
+        return 0.0;
+    }
+    -(p * p.log2() + (1.0 - p) * (1.0 - p).log2())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_bits() {
+        // A steady tone: the same item over and over.
+        let stats = fingerprint_bits(&[0x0000_ffff; 100]);
+        assert_eq!(stats["density"], 0.5);
+        assert_eq!(stats["entropy"], 0.0);
+        assert_eq!(stats["toggle_rates"][0], 0.0);
+
+        // Bit 0 alternates, bit 1 is set every other pair.
+        let stats = fingerprint_bits(&[0b00, 0b01, 0b10, 0b11]);
+        assert_eq!(stats["items"], 4);
+        assert_eq!(stats["toggle_rates"][0], 1.0);
+        assert_eq!(stats["entropy"], 2.0);
+
+        assert_eq!(fingerprint_bits(&[])["entropy"], Value::Null);
+    }
+
+    #[test]
+    fn test_gross_similarity() {
+        let a: Vec<u32> = (0..100).map(|i| i * 0x0101_0101).collect();
+        assert_eq!(gross_similarity(&a, &a), Some(1.0));
+        assert_eq!(gross_similarity(&a, &[]), None);
+
+        // One bit in 32 differs, over the 50 items both have.
+        let b: Vec<u32> = a[..50].iter().map(|item| item ^ 1).collect();
+        assert_eq!(gross_similarity(&a, &b), Some(1.0 - 1.0 / 32.0));
+
+        let inverted: Vec<u32> = a.iter().map(|item| !item).collect();
+        assert_eq!(gross_similarity(&a, &inverted), Some(0.0));
+    }
+
+    #[test]
+    fn test_bit_count_and_hamming_distance() {
+        assert_eq!(bit_count(ValueRef::Integer(0b1011)).unwrap(), Some(3));
+        assert_eq!(bit_count(ValueRef::Integer(-1)).unwrap(), Some(64));
+        assert_eq!(bit_count(ValueRef::Blob(&[0xff, 0x01])).unwrap(), Some(9));
+        assert_eq!(bit_count(ValueRef::Null).unwrap(), None);
+        assert!(bit_count(ValueRef::Text(b"1")).is_err());
+
+        let distance = |a, b| hamming_distance(a, b).unwrap();
+        assert_eq!(
+            distance(ValueRef::Integer(0b1100), ValueRef::Integer(0b1010)),
+            Some(2)
+        );
+        assert_eq!(
+            distance(ValueRef::Blob(&[0xf0, 0x00]), ValueRef::Blob(&[0x0f, 0x01])),
+            Some(9)
+        );
+        assert_eq!(distance(ValueRef::Integer(1), ValueRef::Null), None);
+        assert!(hamming_distance(ValueRef::Blob(&[0]), ValueRef::Blob(&[0, 0])).is_err());
+        assert!(hamming_distance(ValueRef::Integer(0), ValueRef::Blob(&[0])).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! Streaming audio out of BLOBs.
+//!
+//! `fingerprint_blob_ref(table, column, rowid)` fingerprints audio stored in
+//! a BLOB like `fingerprint_blob(data)`, but reads it with SQLite's
+//! incremental BLOB I/O as it decodes, rather than loading the whole value
+//! into memory first:
+//!
+//! ```sql
+//! SELECT fingerprint_blob_ref('uploads', 'data', rowid, 'flac') FROM uploads;
+//! ```
+//!
+//! The table must be in the main database, and as for `sqlite3_blob_open()`
+//! the column can't be indexed or part of the primary key.
+
+use std::ffi::{CStr, CString};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::raw::{c_int, c_void};
+
+use anyhow::{bail, Context, Result};
+use rusqlite::ffi;
+use symphonia::core::io::MediaSource;
+
+/// A BLOB open for reading.
+pub(crate) struct BlobSource {
+    blob: *mut ffi::sqlite3_blob,
+    pos: u64,
+    len: u64,
+}
+
+// SAFETY: The BLOB handle is only used through `&mut self`, and connections
+// are in serialized mode or used from one thread at a time.
+unsafe impl Send for BlobSource {}
+unsafe impl Sync for BlobSource {}
+
+impl BlobSource {
+    /// Open the value of `column` in the row of `table` with `rowid`.
+    ///
+    /// # Safety
+    ///
+    /// `db` must be a valid connection handle that outlives the source.
+    pub unsafe fn open(
+        db: *mut ffi::sqlite3,
+        table: &str,
+        column: &str,
+        rowid: i64,
+    ) -> Result<Self> {
+        let table = CString::new(table).context("Table names must not contain NUL")?;
+        let column = CString::new(column).context("Column names must not contain NUL")?;
+
+        let mut blob: *mut ffi::sqlite3_blob = std::ptr::null_mut();
+        let rc = ffi::sqlite3_blob_open(
+            db,
+            c"main".as_ptr(),
+            table.as_ptr(),
+            column.as_ptr(),
+            rowid,
+            0,
+            &mut blob,
+        );
+        if rc != ffi::SQLITE_OK {
+            // The handle is set even on failure, and must be closed.
+            ffi::sqlite3_blob_close(blob);
+            let message = CStr::from_ptr(ffi::sqlite3_errmsg(db)).to_string_lossy();
+            bail!("Failed to open BLOB: {message}");
+        }
+
+        Ok(Self {
+            blob,
+            pos: 0,
+            len: ffi::sqlite3_blob_bytes(blob).max(0) as u64,
+        })
+    }
+}
+
+impl Read for BlobSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = (buf.len() as u64)
+            .min(self.len.saturating_sub(self.pos))
+            .min(c_int::MAX as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        // SAFETY: The BLOB is open, `buf` holds at least `n` bytes, and
+        // `pos + n` is within the BLOB, which is at most `c_int::MAX` long.
+        let rc = unsafe {
+            ffi::sqlite3_blob_read(
+                self.blob,
+                buf.as_mut_ptr().cast::<c_void>(),
+                n as c_int,
+                self.pos as c_int,
+            )
+        };
+        if rc != ffi::SQLITE_OK {
+            // E.g. SQLITE_ABORT when the row was changed while reading.
+            return Err(std::io::Error::other(format!(
+                "BLOB read failed (SQLite error {rc})"
+            )));
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BlobSource {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seek before the start of the BLOB",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl MediaSource for BlobSource {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
+
+impl Drop for BlobSource {
+    fn drop(&mut self) {
+        // SAFETY: The BLOB was opened and is closed only here.
+        unsafe {
+            ffi::sqlite3_blob_close(self.blob);
+        }
+    }
+}
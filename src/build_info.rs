@@ -0,0 +1,261 @@
+//! Version and build information of the loaded extension.
+//!
+//! The `chromaprint_info` table has a row per fact about the build, for
+//! checking at runtime what a deployed extension was built with:
+//!
+//! ```sql
+//! SELECT name, value FROM chromaprint_info;
+//! SELECT value FROM chromaprint_info WHERE name = 'codec';
+//! ```
+//!
+//! | `name` | `value` |
+//! |---|---|
+//! | `version` | Version of the extension |
+//! | `rusty_chromaprint` | Version of the Chromaprint implementation |
+//! | `symphonia` | Version of the decoder, without the `decode` feature NULL |
+//! | `preset` | The connection's `preset` option |
+//! | `algorithm` | Chromaprint's algorithm id of that preset |
+//! | `codec` | One row per codec Symphonia decodes, by short name |
+//! | `feature` | One row per enabled cargo feature |
+//! | `target` | Architecture and OS built for, e.g. `x86_64-linux` |
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::sync::Arc;
+
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+#[cfg(feature = "decode")]
+use symphonia::core::codecs::{self, CodecType};
+
+use crate::panic::catch_panic;
+use crate::state::State;
+
+/// Version of rusty-chromaprint the extension is built with, as locked in
+/// `Cargo.lock`.
+const RUSTY_CHROMAPRINT_VERSION: &str = "0.3.0";
+
+/// Version of Symphonia the extension is built with, as locked in
+/// `Cargo.lock`, if it decodes audio.
+const SYMPHONIA_VERSION: Option<&str> = if cfg!(feature = "decode") {
+    Some("0.5.4")
+} else {
+    None
+};
+
+/// Codecs Symphonia can be built to decode, and the PCM formats it knows.
+#[cfg(feature = "decode")]
+const CODECS: [CodecType; 17] = [
+    codecs::CODEC_TYPE_AAC,
+    codecs::CODEC_TYPE_ADPCM_IMA_WAV,
+    codecs::CODEC_TYPE_ADPCM_MS,
+    codecs::CODEC_TYPE_ALAC,
+    codecs::CODEC_TYPE_FLAC,
+    codecs::CODEC_TYPE_MP1,
+    codecs::CODEC_TYPE_MP2,
+    codecs::CODEC_TYPE_MP3,
+    codecs::CODEC_TYPE_OPUS,
+    codecs::CODEC_TYPE_PCM_ALAW,
+    codecs::CODEC_TYPE_PCM_F32LE,
+    codecs::CODEC_TYPE_PCM_MULAW,
+    codecs::CODEC_TYPE_PCM_S16LE,
+    codecs::CODEC_TYPE_PCM_S24LE,
+    codecs::CODEC_TYPE_PCM_U8,
+    codecs::CODEC_TYPE_VORBIS,
+    codecs::CODEC_TYPE_WAVPACK,
+];
+
+/// Cargo features of the build, and whether each is enabled.
+const FEATURES: [(&str, bool); 8] = [
+    ("extension", cfg!(feature = "extension")),
+    ("decode", cfg!(feature = "decode")),
+    ("sqlite-malloc", cfg!(feature = "sqlite-malloc")),
+    ("shared-cache", cfg!(feature = "shared-cache")),
+    ("trusted-files", cfg!(feature = "trusted-files")),
+    ("blob-only", cfg!(feature = "blob-only")),
+    ("acoustid", cfg!(feature = "acoustid")),
+    ("net", cfg!(feature = "net")),
+];
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "chromaprint_info",
+        eponymous_only_module::<InfoTab>(),
+        Some(state),
+    )
+}
+
+/// The rows of the table for a connection with `state`.
+fn rows(state: &State) -> Vec<(&'static str, Option<String>)> {
+    let preset = state.options().preset;
+    let mut rows = vec![
+        ("version", Some(env!("CARGO_PKG_VERSION").to_owned())),
+        (
+            "rusty_chromaprint",
+            Some(RUSTY_CHROMAPRINT_VERSION.to_owned()),
+        ),
+        ("symphonia", SYMPHONIA_VERSION.map(str::to_owned)),
+        ("preset", Some(preset.name().to_owned())),
+        ("algorithm", Some(preset.id().to_string())),
+    ];
+    #[cfg(feature = "decode")]
+    rows.extend(
+        CODECS
+            .iter()
+            .filter_map(|&codec| symphonia::default::get_codecs().get_codec(codec))
+            .map(|codec| ("codec", Some(codec.short_name.to_owned()))),
+    );
+    rows.extend(
+        FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| ("feature", Some((*feature).to_owned()))),
+    );
+    rows.push((
+        "target",
+        Some(format!(
+            "{}-{}",
+            std::env::consts::ARCH,
+            std::env::consts::OS
+        )),
+    ));
+    rows
+}
+
+#[repr(C)]
+struct InfoTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for InfoTab {
+    type Aux = Arc<State>;
+    type Cursor = InfoCursor<'vtab>;
+
+    fn connect(
+        _db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("chromaprint_info: missing state".to_owned())
+            })?;
+            Ok((
+                "CREATE TABLE x(name, value)".to_owned(),
+                InfoTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, _info: &mut IndexInfo) -> rusqlite::Result<()> {
+        Ok(())
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<InfoCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(InfoCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct InfoCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    rows: Vec<(&'static str, Option<String>)>,
+    index: usize,
+    phantom: PhantomData<&'vtab InfoTab>,
+}
+
+unsafe impl VTabCursor for InfoCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        _args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.rows = rows(&self.state);
+            self.index = 0;
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let (name, value) = &self.rows[self.index];
+            match i {
+                0 => ctx.set_result(name),
+                _ => ctx.set_result(value),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The version of `package` locked in `Cargo.lock`.
+    fn locked_version(package: &str) -> String {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let lock = std::fs::read_to_string(format!("{manifest_dir}/Cargo.lock")).unwrap();
+        let entry = lock
+            .split("[[package]]")
+            .find(|entry| entry.contains(&format!("name = \"{package}\"\n")))
+            .unwrap();
+        let version = entry.split("version = \"").nth(1).unwrap();
+        version[..version.find('"').unwrap()].to_owned()
+    }
+
+    #[test]
+    fn test_rows() {
+        assert_eq!(
+            RUSTY_CHROMAPRINT_VERSION,
+            locked_version("rusty-chromaprint")
+        );
+        #[cfg(feature = "decode")]
+        assert_eq!(SYMPHONIA_VERSION.unwrap(), locked_version("symphonia"));
+
+        let rows = rows(&State::default());
+        assert_eq!(
+            rows[0],
+            ("version", Some(env!("CARGO_PKG_VERSION").to_owned()))
+        );
+        assert!(rows.contains(&("preset", Some("test1".to_owned()))));
+        #[cfg(feature = "decode")]
+        {
+            assert!(rows.contains(&("codec", Some("mp3".to_owned()))));
+            assert!(!rows.contains(&("codec", Some("opus".to_owned()))));
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Process-wide cache of file fingerprints.
+//!
+//! With the `shared-cache` feature enabled, fingerprints of files are kept
+//! in one cache shared by every connection in the process, so a connection
+//! pool fingerprints each file once rather than once per connection. Entries
+//! are keyed by canonical path, size and modification time, so a file that
+//! changes is fingerprinted again. The least recently used entries are
+//! evicted first, keeping at most as many as the `shared_cache_size` option
+//! of the connection adding one says, 4096 by default. 0 turns the cache
+//! off.
+//!
+//! The lock is not held while a file is fingerprinted, so connections
+//! fingerprinting different files don't wait for each other. Two
+//! connections fingerprinting the same file at once both do the work.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::preset::Preset;
+
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    path: PathBuf,
+    size: u64,
+    mtime: Option<SystemTime>,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+}
+
+impl Key {
+    fn of(path: &Path, preset: Preset, max_length_secs: Option<u32>) -> Option<Self> {
+        let path = std::fs::canonicalize(path).ok()?;
+        let metadata = std::fs::metadata(&path).ok()?;
+        Some(Key {
+            path,
+            size: metadata.len(),
+            mtime: metadata.modified().ok(),
+            preset,
+            max_length_secs,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<Key, (u64, Vec<u32>)>,
+    /// Incremented on every use; entries record when they were last used.
+    clock: u64,
+}
+
+impl Cache {
+    fn get(&mut self, key: &Key) -> Option<Vec<u32>> {
+        self.clock += 1;
+        let (used, fingerprint) = self.entries.get_mut(key)?;
+        *used = self.clock;
+        Some(fingerprint.clone())
+    }
+
+    /// Add an entry, evicting the least recently used ones to keep at most
+    /// `size`.
+    fn insert(&mut self, key: Key, fingerprint: Vec<u32>, size: usize) {
+        if size == 0 {
+            return;
+        }
+        while self.entries.len() >= size && !self.entries.contains_key(&key) {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.clock += 1;
+        self.entries.insert(key, (self.clock, fingerprint));
+    }
+}
+
+fn with_cache<T>(f: impl FnOnce(&mut Cache) -> T) -> T {
+    f(CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(Cache::default))
+}
+
+/// The fingerprint of the file at `path` made with `preset` from at most its
+/// first `max_length_secs` seconds from the cache, or from `compute` and
+/// then kept in the cache, which is kept to at most `size` entries.
+pub(crate) fn fingerprint(
+    path: &Path,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    size: usize,
+    compute: impl FnOnce() -> Result<Vec<u32>>,
+) -> Result<Vec<u32>> {
+    let Some(key) = Key::of(path, preset, max_length_secs) else {
+        return compute();
+    };
+    if let Some(fingerprint) = with_cache(|cache| cache.get(&key)) {
+        return Ok(fingerprint);
+    }
+
+    let fingerprint = compute()?;
+    with_cache(|cache| cache.insert(key, fingerprint.clone(), size));
+    Ok(fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: usize) -> Key {
+        Key {
+            path: PathBuf::from(format!("/music/{i}.mp3")),
+            size: 1,
+            mtime: None,
+            preset: Preset::default(),
+            max_length_secs: None,
+        }
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = Cache::default();
+        for i in 0..10 {
+            cache.insert(key(i), vec![i as u32], 10);
+        }
+        assert_eq!(cache.get(&key(0)), Some(vec![0]));
+
+        cache.insert(key(10), vec![], 10);
+        assert_eq!(cache.entries.len(), 10);
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(1)).is_none());
+
+        // A smaller size evicts down to it.
+        cache.insert(key(11), vec![], 5);
+        assert_eq!(cache.entries.len(), 5);
+        assert!(cache.get(&key(0)).is_some());
+        cache.insert(key(12), vec![], 0);
+        assert!(cache.get(&key(12)).is_none());
+    }
+
+    #[test]
+    fn test_changed_file_is_recomputed() {
+        let path = std::env::temp_dir().join(format!("chromaprint-cache-{}", std::process::id()));
+        std::fs::write(&path, b"a").unwrap();
+        assert_eq!(
+            fingerprint(&path, Preset::default(), None, 10, || Ok(vec![1])).unwrap(),
+            [1]
+        );
+        assert_eq!(
+            fingerprint(&path, Preset::default(), None, 10, || Ok(vec![2])).unwrap(),
+            [1]
+        );
+
+        std::fs::write(&path, b"ab").unwrap();
+        assert_eq!(
+            fingerprint(&path, Preset::default(), None, 10, || Ok(vec![3])).unwrap(),
+            [3]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+//! Fingerprint cache in a table.
+//!
+//! Setting the `cache_table` option to a table name makes `fingerprint(path)`
+//! keep the fingerprints it makes in that table of the main database, and
+//! return them without decoding again while the file is unchanged:
+//!
+//! ```sql
+//! SELECT chromaprint_option('cache_table', 'chromaprint_cache');
+//! UPDATE tracks SET fingerprint = fingerprint(path);
+//! ```
+//!
+//! The table is created when first needed with the columns `path`, `preset`,
+//! `max_length_secs`, `size`, `mtime` and `fingerprint`. `path` is the
+//! file's canonical path, `size` is in bytes and `mtime` in nanoseconds since
+//! the Unix epoch, as in `chromaprint_sync`, and `fingerprint` is a BLOB. A
+//! file whose size or modification time differs from its row is
+//! fingerprinted again, and fingerprints made with another preset or length
+//! limit are kept in separate rows. Unlike the `shared-cache` feature's
+//! cache, the table lasts across connections and processes.
+//!
+//! Time ranges and files opened by a source provider aren't cached. Failing
+//! to read or write the table, e.g. because the database is read-only,
+//! doesn't fail the call; the fingerprint just isn't cached. Setting the
+//! option to NULL or `''` turns the cache off.
+
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::vtab::escape_double_quote;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::path::path_to_value;
+use crate::preset::Preset;
+use crate::sync::{stamp, Stamp};
+
+/// The fingerprint of the file at `path` made with `preset` from at most its
+/// first `max_length_secs` seconds, from `table` if it's there for the file
+/// as it is, or else from `compute` and then stored in `table`.
+pub(crate) fn fingerprint(
+    db: &Connection,
+    table: &str,
+    path: &Path,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    compute: impl FnOnce() -> Result<Vec<u32>>,
+) -> Result<Vec<u32>> {
+    let Ok((path, stamp)) = std::fs::canonicalize(path).and_then(|path| {
+        let stamp = stamp(&std::fs::metadata(&path)?);
+        Ok((path_to_value(&path), stamp))
+    }) else {
+        return compute();
+    };
+    let table = escape_double_quote(table);
+    let max_length_secs = max_length_secs.unwrap_or(0);
+
+    let cached = db
+        .query_row(
+            &format!(
+                "SELECT size, mtime, fingerprint FROM main.\"{table}\" \
+                 WHERE path = ?1 AND preset = ?2 AND max_length_secs = ?3"
+            ),
+            params![path, preset.name(), max_length_secs],
+            |row| {
+                Ok((
+                    Stamp {
+                        size: row.get(0)?,
+                        mtime: row.get(1)?,
+                    },
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            },
+        )
+        .optional();
+    // Until the table is created, the query fails: nothing is cached.
+    if let Ok(Some((cached_stamp, fingerprint))) = cached {
+        if cached_stamp == stamp {
+            if let Ok(fingerprint) = crate::decode_fingerprint_blob(&fingerprint) {
+                return Ok(fingerprint);
+            }
+        }
+    }
+
+    let fingerprint = compute()?;
+    let blob: Vec<u8> = fingerprint.iter().flat_map(|x| x.to_be_bytes()).collect();
+    let _ = db
+        .execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS main.\"{table}\"(\
+                 path NOT NULL, \
+                 preset TEXT NOT NULL, \
+                 max_length_secs INTEGER NOT NULL, \
+                 size INTEGER NOT NULL, \
+                 mtime INTEGER NOT NULL, \
+                 fingerprint BLOB NOT NULL, \
+                 PRIMARY KEY (path, preset, max_length_secs))"
+            ),
+            [],
+        )
+        .and_then(|_| {
+            db.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO main.\"{table}\" \
+                     (path, preset, max_length_secs, size, mtime, fingerprint) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+                ),
+                params![
+                    path,
+                    preset.name(),
+                    max_length_secs,
+                    stamp.size,
+                    stamp.mtime,
+                    blob
+                ],
+            )
+        });
+    Ok(fingerprint)
+}
@@ -0,0 +1,259 @@
+//! Per-channel statistics.
+//!
+//! `audio_channel_stats(path)` returns one row per channel, numbered from 0,
+//! to find imbalanced, dead or DC-offset channels:
+//!
+//! ```sql
+//! SELECT channel, rms_dbfs, peak_dbfs, dc_offset, silence_fraction
+//! FROM audio_channel_stats('recording.wav');
+//! ```
+//!
+//! `dc_offset` is the mean sample value, where full scale is 1, and
+//! `silence_fraction` the fraction of [`FRAME_SECS`] frames quieter than
+//! [`SILENCE_DBFS`]. A file with no samples has rows of NULLs.
+//!
+//! `audio_dc_offset(path)` returns just the DC offsets, as a JSON array
+//! with one number per channel:
+//!
+//! ```sql
+//! SELECT r.path FROM recordings r
+//! WHERE EXISTS (SELECT 1 FROM json_each(audio_dc_offset(r.path))
+//!               WHERE abs(value) > 0.01);
+//! ```
+//!
+//! `audio_phase_inverted(path)` is 1 if the first two channels are out of
+//! phase, with a correlation below [`INVERTED_CORRELATION`], so that they
+//! largely cancel when summed to mono, and 0 otherwise. It is NULL for mono
+//! files and files where either channel is silent.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rusqlite::functions::SubType;
+use rusqlite::vtab::Context;
+use rusqlite::Connection;
+
+use crate::analysis::to_dbfs;
+use crate::decode::AudioStream;
+use crate::json;
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::tvf::{self, PathFunction};
+
+const FRAME_SECS: f64 = 0.05;
+
+/// Frames quieter than this are silent.
+const SILENCE_DBFS: f64 = -60.0;
+
+/// Correlation between two channels below which they are out of phase.
+const INVERTED_CORRELATION: f64 = -0.5;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    tvf::register_path_function::<Channels>(db, state)
+}
+
+/// Running statistics of one channel.
+#[derive(Debug, Clone, Default)]
+struct ChannelStats {
+    samples: u64,
+    sum: f64,
+    sum_squares: f64,
+    peak: f32,
+    frames: u64,
+    silent_frames: u64,
+    /// Samples and sum of squares of the current frame.
+    frame_samples: usize,
+    frame_squares: f64,
+}
+
+/// Statistics of a channel as `(rms_dbfs, peak_dbfs, dc_offset,
+/// silence_fraction)`.
+type Row = (Option<f64>, Option<f64>, Option<f64>, Option<f64>);
+
+impl ChannelStats {
+    fn add(&mut self, s: f32, frame_len: usize) {
+        self.samples += 1;
+        self.sum += s as f64;
+        self.sum_squares += (s * s) as f64;
+        self.peak = self.peak.max(s.abs());
+
+        self.frame_samples += 1;
+        self.frame_squares += (s * s) as f64;
+        if self.frame_samples == frame_len {
+            let rms = (self.frame_squares / frame_len as f64).sqrt();
+            self.frames += 1;
+            if to_dbfs(rms as f32) < SILENCE_DBFS {
+                self.silent_frames += 1;
+            }
+            self.frame_samples = 0;
+            self.frame_squares = 0.0;
+        }
+    }
+
+    fn row(&self) -> Row {
+        if self.samples == 0 {
+            return (None, None, None, None);
+        }
+        let n = self.samples as f64;
+        let rms = (self.sum_squares / n).sqrt();
+        (
+            Some(to_dbfs(rms as f32)),
+            Some(to_dbfs(self.peak)),
+            Some(self.sum / n),
+            (self.frames > 0).then(|| self.silent_frames as f64 / self.frames as f64),
+        )
+    }
+}
+
+fn channel_stats(path: &Path, stats: &mut CallStats) -> Result<Vec<Row>> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    let channels = stream.channels.max(1);
+    let frame_len = ((FRAME_SECS * stream.sample_rate as f64) as usize).max(1);
+
+    let mut channel_stats = vec![ChannelStats::default(); channels];
+    while let Some(samples) = stream.next_samples(stats)? {
+        for frame in samples.chunks_exact(channels) {
+            for (s, channel) in frame.iter().zip(&mut channel_stats) {
+                channel.add(*s as f32 / 32768.0, frame_len);
+            }
+        }
+    }
+    Ok(channel_stats.iter().map(ChannelStats::row).collect())
+}
+
+pub(crate) fn audio_dc_offset(path: &Path, stats: &mut CallStats) -> Result<(String, SubType)> {
+    let offsets: Vec<Option<f64>> = channel_stats(path, stats)?
+        .into_iter()
+        .map(|(_, _, dc_offset, _)| dc_offset)
+        .collect();
+    Ok(json::result(serde_json::to_string(&offsets)?))
+}
+
+/// Running correlation of two channels, as a phase meter shows it: 1 for
+/// identical channels, 0 for unrelated ones and -1 for inverted ones.
+#[derive(Debug, Clone, Copy, Default)]
+struct Correlation {
+    lr: f64,
+    ll: f64,
+    rr: f64,
+}
+
+impl Correlation {
+    fn add(&mut self, l: f32, r: f32) {
+        let (l, r) = (l as f64, r as f64);
+        self.lr += l * r;
+        self.ll += l * l;
+        self.rr += r * r;
+    }
+
+    fn value(&self) -> Option<f64> {
+        let energy = (self.ll * self.rr).sqrt();
+        (energy > 0.0).then(|| self.lr / energy)
+    }
+}
+
+pub(crate) fn audio_phase_inverted(path: &Path, stats: &mut CallStats) -> Result<Option<bool>> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    let channels = stream.channels;
+    if channels < 2 {
+        return Ok(None);
+    }
+
+    let mut correlation = Correlation::default();
+    while let Some(samples) = stream.next_samples(stats)? {
+        for frame in samples.chunks_exact(channels) {
+            correlation.add(frame[0] as f32, frame[1] as f32);
+        }
+    }
+    Ok(correlation.value().map(|c| c < INVERTED_CORRELATION))
+}
+
+/// The statistics of each channel of a file, by channel, found once it is
+/// decoded.
+struct Channels(std::iter::Enumerate<std::vec::IntoIter<Row>>);
+
+impl PathFunction for Channels {
+    const NAME: &'static str = "audio_channel_stats";
+
+    type Row = (usize, Row);
+
+    fn columns() -> Vec<&'static str> {
+        vec![
+            "channel",
+            "rms_dbfs",
+            "peak_dbfs",
+            "dc_offset",
+            "silence_fraction",
+        ]
+    }
+
+    fn open(path: &Path, stats: &mut CallStats) -> Result<Self> {
+        Ok(Channels(
+            channel_stats(path, stats)?.into_iter().enumerate(),
+        ))
+    }
+
+    fn next_row(&mut self, _stats: &mut CallStats) -> Result<Option<Self::Row>> {
+        Ok(self.0.next())
+    }
+
+    fn column(row: &Self::Row, ctx: &mut Context, i: usize) -> rusqlite::Result<()> {
+        let (channel, (rms_dbfs, peak_dbfs, dc_offset, silence_fraction)) = row;
+        match i {
+            0 => ctx.set_result(&(*channel as i64)),
+            1 => ctx.set_result(rms_dbfs),
+            2 => ctx.set_result(peak_dbfs),
+            3 => ctx.set_result(dc_offset),
+            _ => ctx.set_result(silence_fraction),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_stats() {
+        // A square wave offset by 0.1 for 10 frames, then 10 silent frames.
+        let mut channel = ChannelStats::default();
+        for i in 0..200 {
+            let s = if i >= 100 {
+                0.0
+            } else if i % 2 == 0 {
+                0.6
+            } else {
+                -0.4
+            };
+            channel.add(s, 10);
+        }
+        let (rms_dbfs, peak_dbfs, dc_offset, silence_fraction) = channel.row();
+        assert!((rms_dbfs.unwrap() - to_dbfs((0.26f32 / 2.0).sqrt())).abs() < 1e-3);
+        assert!((peak_dbfs.unwrap() - to_dbfs(0.6)).abs() < 1e-9);
+        assert!((dc_offset.unwrap() - 0.05).abs() < 1e-6);
+        assert_eq!(silence_fraction, Some(0.5));
+
+        assert_eq!(ChannelStats::default().row(), (None, None, None, None));
+    }
+
+    #[test]
+    fn test_correlation() {
+        let sine = (0..1000).map(|i| (i as f32 * 0.1).sin());
+        let mut same = Correlation::default();
+        let mut inverted = Correlation::default();
+        let mut quieter = Correlation::default();
+        for s in sine {
+            same.add(s, s);
+            inverted.add(s, -s);
+            quieter.add(s, -0.1 * s);
+        }
+        assert!((same.value().unwrap() - 1.0).abs() < 1e-9);
+        assert!((inverted.value().unwrap() - -1.0).abs() < 1e-9);
+        assert!((quieter.value().unwrap() - -1.0).abs() < 1e-9);
+
+        let mut silent = Correlation::default();
+        silent.add(0.5, 0.0);
+        assert_eq!(silent.value(), None);
+    }
+}
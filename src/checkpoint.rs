@@ -0,0 +1,161 @@
+//! Checkpoints of long scans.
+//!
+//! `audio_scan` and `chromaprint_sync` fingerprint files as their rows are
+//! read, but their work only lasts once the statement reading them commits:
+//! a scan that is interrupted or killed part way starts over. Setting the
+//! `checkpoint_db` option to the path of a database file makes both save
+//! each file's result there as soon as it is computed, committed on its own,
+//! and reuse it instead of decoding the file again while it is unchanged.
+//! Running an interrupted scan again then resumes where it left off:
+//!
+//! ```sql
+//! SELECT chromaprint_option('checkpoint_db', '/var/lib/music/scan.db');
+//! INSERT INTO tracks(path, fingerprint, duration)
+//! SELECT path, fingerprint, duration FROM audio_scan('/music', 1)
+//! WHERE error IS NULL;
+//! ```
+//!
+//! The checkpoints can't be kept in the database being written, whose
+//! transaction would roll them back with everything else. The checkpoint
+//! database has one table, `chromaprint_checkpoint`, created when first
+//! needed, keyed by path, preset and length limit like `cache_table`'s, with
+//! the file's size and modification time and its fingerprint and duration,
+//! or why they couldn't be computed. Files that failed aren't tried again
+//! until they change. The file can be deleted once a scan is complete.
+//!
+//! With the `allowed_roots` option set, the file must be inside the roots.
+//! Failing to open the database fails the scan. Failing to read or write a
+//! row doesn't; the file is just fingerprinted again next time.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::path::path_to_value;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::sync::Stamp;
+
+/// How long to wait for another scan writing to the same database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A file's result: its fingerprint and, if known, duration in seconds, or
+/// why they couldn't be computed.
+pub(crate) type Outcome = std::result::Result<(Vec<u32>, Option<f64>), String>;
+
+pub(crate) struct Checkpoint {
+    db: Connection,
+}
+
+impl Checkpoint {
+    /// The database of the `checkpoint_db` option of `state`, if set.
+    pub fn open(state: &State) -> Result<Option<Self>> {
+        let path = state.options().checkpoint_db.clone();
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        state.call_stats().check_path(dir)?;
+
+        let db = Connection::open(&path)
+            .and_then(|db| {
+                db.busy_timeout(BUSY_TIMEOUT)?;
+                db.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS chromaprint_checkpoint(\
+                     path NOT NULL, \
+                     preset TEXT NOT NULL, \
+                     max_length_secs INTEGER NOT NULL, \
+                     size INTEGER NOT NULL, \
+                     mtime INTEGER NOT NULL, \
+                     fingerprint BLOB, \
+                     duration REAL, \
+                     error TEXT, \
+                     PRIMARY KEY (path, preset, max_length_secs))",
+                )?;
+                Ok(db)
+            })
+            .with_context(|| format!("Failed to open checkpoint {}", path.display()))?;
+        Ok(Some(Self { db }))
+    }
+
+    /// The result saved for the file at `path` made with `preset` from at
+    /// most its first `max_length_secs` seconds, if the file is unchanged
+    /// since.
+    pub fn get(
+        &self,
+        path: &Path,
+        stamp: Stamp,
+        preset: Preset,
+        max_length_secs: Option<u32>,
+    ) -> Option<Outcome> {
+        let saved = self
+            .db
+            .query_row(
+                "SELECT fingerprint, duration, error FROM chromaprint_checkpoint \
+                 WHERE path = ?1 AND preset = ?2 AND max_length_secs = ?3 \
+                 AND size = ?4 AND mtime = ?5",
+                params![
+                    path_to_value(path),
+                    preset.name(),
+                    max_length_secs.unwrap_or(0),
+                    stamp.size,
+                    stamp.mtime
+                ],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<Vec<u8>>>(0)?,
+                        row.get::<_, Option<f64>>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                    ))
+                },
+            )
+            .optional();
+        match saved {
+            Ok(Some((_, _, Some(error)))) => Some(Err(error)),
+            Ok(Some((Some(fingerprint), duration, None))) => {
+                let fingerprint = crate::decode_fingerprint_blob(&fingerprint).ok()?;
+                Some(Ok((fingerprint, duration)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Save the result for the file at `path`, as it is at `stamp`, made with
+    /// `preset` from at most its first `max_length_secs` seconds.
+    pub fn save(
+        &self,
+        path: &Path,
+        stamp: Stamp,
+        preset: Preset,
+        max_length_secs: Option<u32>,
+        outcome: &Outcome,
+    ) {
+        let (fingerprint, duration, error) = match outcome {
+            Ok((fingerprint, duration)) => {
+                let blob: Vec<u8> = fingerprint.iter().flat_map(|x| x.to_be_bytes()).collect();
+                (Some(blob), *duration, None)
+            }
+            Err(error) => (None, None, Some(error.as_str())),
+        };
+        let _ = self.db.execute(
+            "INSERT OR REPLACE INTO chromaprint_checkpoint \
+             (path, preset, max_length_secs, size, mtime, fingerprint, duration, error) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                path_to_value(path),
+                preset.name(),
+                max_length_secs.unwrap_or(0),
+                stamp.size,
+                stamp.mtime,
+                fingerprint,
+                duration,
+                error
+            ],
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Chroma feature vectors.
+//!
+//! `audio_chroma(path)` returns one row per frame with the energy of each of
+//! the 12 pitch classes, normalized to unit length:
+//!
+//! ```sql
+//! SELECT time_secs, a, c, e FROM audio_chroma('track.mp3');
+//! ```
+//!
+//! Frames and frequency range follow the chroma stage of Chromaprint, so
+//! there is one row per fingerprint item (before the fingerprint's filtering
+//! and quantization), with two differences: frames are analysed at the
+//! file's own sample rate rather than resampled to 11025 Hz, and each band
+//! is centred on its note, where Chromaprint's bands start at it and so
+//! split in-tune notes between two bands. Frames with almost no energy in
+//! the range are all zero.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rusqlite::vtab::Context;
+use rusqlite::Connection;
+
+use crate::analysis::{MonoFrames, Spectrum};
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::tvf::{self, PathFunction};
+
+const NUM_BANDS: usize = 12;
+
+/// Column names of the pitch classes; band 0 is A.
+const BANDS: [&str; NUM_BANDS] = [
+    "a", "a_sharp", "b", "c", "c_sharp", "d", "d_sharp", "e", "f", "f_sharp", "g", "g_sharp",
+];
+
+/// Chromaprint's frame length and hop, 4096 and 1365 samples at 11025 Hz.
+const FRAME_SECS: f64 = 4096.0 / 11025.0;
+const HOP_SECS: f64 = 1365.0 / 11025.0;
+
+const MIN_HZ: f32 = 28.0;
+const MAX_HZ: f32 = 3520.0;
+
+/// Frames whose chroma vector is shorter than this are left as zeros.
+const NORM_EPSILON: f32 = 0.01;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    tvf::register_path_function::<Chroma>(db, state)
+}
+
+/// Pitch class of each frequency bin in the analysed range.
+fn bin_notes(bins: usize, bin_hz: f32) -> Vec<Option<usize>> {
+    (0..bins)
+        .map(|k| {
+            let hz = k as f32 * bin_hz;
+            if k == 0 || !(MIN_HZ..MAX_HZ).contains(&hz) {
+                return None;
+            }
+            // Semitones above A0 (27.5 Hz).
+            let semitones = (NUM_BANDS as f32 * (hz / 27.5).log2()).round() as usize;
+            Some(semitones % NUM_BANDS)
+        })
+        .collect()
+}
+
+/// Sum the power of each bin into its pitch class and normalize.
+fn chroma(power: impl Iterator<Item = f32>, notes: &[Option<usize>]) -> [f32; NUM_BANDS] {
+    let mut features = [0.0; NUM_BANDS];
+    for (p, note) in power.zip(notes) {
+        if let Some(note) = note {
+            features[*note] += p;
+        }
+    }
+
+    let norm = features.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm < NORM_EPSILON {
+        return [0.0; NUM_BANDS];
+    }
+    features.map(|x| x / norm)
+}
+
+/// Chroma vectors of a file, with the time of each in seconds.
+struct Chroma {
+    frames: MonoFrames,
+    spectrum: Spectrum,
+    notes: Vec<Option<usize>>,
+    hop_secs: f64,
+    index: usize,
+}
+
+impl PathFunction for Chroma {
+    const NAME: &'static str = "audio_chroma";
+
+    type Row = (f64, [f32; NUM_BANDS]);
+
+    fn columns() -> Vec<&'static str> {
+        std::iter::once("time_secs").chain(BANDS).collect()
+    }
+
+    fn open(path: &Path, stats: &mut CallStats) -> Result<Self> {
+        let frames = MonoFrames::open_with_hop(path, FRAME_SECS, HOP_SECS, stats)?;
+        let spectrum = Spectrum::hamming(frames.frame_len, frames.sample_rate);
+        let notes = bin_notes(frames.frame_len / 2 + 1, spectrum.bin_hz);
+        let hop_secs = frames.hop_len as f64 / frames.sample_rate as f64;
+        Ok(Chroma {
+            frames,
+            spectrum,
+            notes,
+            hop_secs,
+            index: 0,
+        })
+    }
+
+    fn next_row(&mut self, stats: &mut CallStats) -> Result<Option<Self::Row>> {
+        let Some(frame) = self.frames.next_frame(stats)? else {
+            return Ok(None);
+        };
+        let power = self.spectrum.of(frame)?.iter().map(|c| c.norm_sqr());
+        let row = (
+            self.index as f64 * self.hop_secs,
+            chroma(power, &self.notes),
+        );
+        self.index += 1;
+        Ok(Some(row))
+    }
+
+    fn column(row: &Self::Row, ctx: &mut Context, i: usize) -> rusqlite::Result<()> {
+        let (time_secs, features) = row;
+        match i {
+            0 => ctx.set_result(time_secs),
+            band => ctx.set_result(&(features[band - 1] as f64)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chroma_of_a440() {
+        let bin_hz = 11025.0 / 4096.0;
+        let notes = bin_notes(2049, bin_hz);
+        let bin = (440.0 / bin_hz).round() as usize;
+        let power = (0..2049).map(|k| if k == bin { 1.0 } else { 0.0 });
+
+        let features = chroma(power, &notes);
+        assert_eq!(features[0], 1.0, "{features:?}");
+        assert_eq!(features.iter().sum::<f32>(), 1.0);
+
+        assert_eq!(chroma(std::iter::repeat(0.0), &notes), [0.0; NUM_BANDS]);
+    }
+}
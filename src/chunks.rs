@@ -0,0 +1,290 @@
+//! Fingerprints of consecutive windows of a file.
+//!
+//! `fingerprint_chunks(path, chunk_secs)` fingerprints each `chunk_secs`
+//! window of a file separately, in one decoding pass, so that a short
+//! excerpt can be matched against the part of a long recording it comes
+//! from:
+//!
+//! ```sql
+//! INSERT INTO chunks(recording_id, chunk_index, start_secs, fingerprint)
+//! SELECT r.id, c.chunk_index, c.start_secs, c.fingerprint
+//! FROM recordings r, fingerprint_chunks(r.path, 30) c;
+//! ```
+//!
+//! Chunks are numbered from 0 and start `chunk_index * chunk_secs` seconds
+//! into the file. Chromaprint needs about three seconds of audio before it
+//! outputs anything, so shorter chunks have empty fingerprints, and chunks
+//! of 10 seconds or more match far more reliably. The last chunk is shorter unless the file divides evenly;
+//! if it's too short to have a fingerprint at all, it is left out. Chunks
+//! are fingerprinted with the `preset` option's preset and encoded according
+//! to the `encoding` option, and `max_length_secs` limits them like it
+//! limits `fingerprint`.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{bail, Context as _, Result};
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+use rusty_chromaprint::Fingerprinter;
+
+use crate::decode::AudioStream;
+use crate::panic::catch_panic;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::{path, tvf};
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 3;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "fingerprint_chunks",
+        eponymous_only_module::<ChunksTab>(),
+        Some(state),
+    )
+}
+
+/// The fingerprints of the `chunk_secs` windows of the file at `path`, in
+/// order.
+fn fingerprint_chunks(
+    path: &Path,
+    chunk_secs: f64,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    stats: &mut CallStats,
+) -> Result<Vec<Vec<u32>>> {
+    if !(chunk_secs.is_finite() && chunk_secs > 0.0) {
+        bail!("Invalid chunk length: {chunk_secs}");
+    }
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    stream.set_max_length(max_length_secs);
+    let (sample_rate, channels) = (stream.sample_rate, stream.channels.max(1));
+    let chunk_frames = ((chunk_secs * sample_rate as f64).round() as usize).max(1);
+
+    let config = preset.config();
+    let new_printer = || -> Result<Fingerprinter> {
+        let mut printer = Fingerprinter::new(&config);
+        printer
+            .start(sample_rate, channels as u32)
+            .context("Failed to start fingerprinter")?;
+        Ok(printer)
+    };
+    let mut printer = new_printer()?;
+    let mut frames = 0;
+    let mut chunks = Vec::new();
+
+    while let Some(mut samples) = stream.next_samples(stats)? {
+        let started = Instant::now();
+        while !samples.is_empty() {
+            let take = (chunk_frames - frames).min(samples.len() / channels);
+            printer.consume(&samples[..take * channels]);
+            samples = &samples[take * channels..];
+            frames += take;
+            if frames == chunk_frames {
+                printer.finish();
+                chunks.push(printer.fingerprint().to_vec());
+                printer = new_printer()?;
+                frames = 0;
+            }
+        }
+        stats.fingerprint += started.elapsed();
+    }
+    if frames > 0 {
+        printer.finish();
+        let last = printer.fingerprint();
+        if !last.is_empty() {
+            chunks.push(last.to_vec());
+        }
+    }
+    Ok(chunks)
+}
+
+#[repr(C)]
+struct ChunksTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for ChunksTab {
+    type Aux = Arc<State>;
+    type Cursor = ChunksCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("fingerprint_chunks: missing state".to_owned())
+            })?;
+            tvf::reads_files(db)?;
+            Ok((
+                "CREATE TABLE x(chunk_index, start_secs, fingerprint, \
+                 path HIDDEN, chunk_secs HIDDEN)"
+                    .to_owned(),
+                ChunksTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_arguments(
+                info,
+                "fingerprint_chunks",
+                FIRST_ARGUMENT,
+                &["path", "chunk_secs"],
+            )
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<ChunksCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(ChunksCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                chunk_secs: 0.0,
+                chunks: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct ChunksCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    chunk_secs: f64,
+    /// Encoded fingerprints of the chunks.
+    chunks: Vec<String>,
+    index: usize,
+    phantom: PhantomData<&'vtab ChunksTab>,
+}
+
+unsafe impl VTabCursor for ChunksCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.chunks = Vec::new();
+            self.index = 0;
+
+            let Some(value) = args
+                .iter()
+                .next()
+                .filter(|v| v.data_type() != rusqlite::types::Type::Null)
+            else {
+                return Ok(());
+            };
+            let path = path::path_from_value(0, value)?;
+            self.chunk_secs = args.get::<f64>(1)?;
+
+            let (encoding, preset, max_length_secs) = {
+                let options = self.state.options();
+                (options.encoding, options.preset, options.max_length_secs)
+            };
+            let mut stats = self.state.call_stats();
+            let chunks =
+                fingerprint_chunks(&path, self.chunk_secs, preset, max_length_secs, &mut stats)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            self.state
+                .record_timing("fingerprint_chunks", &path.to_string_lossy(), stats);
+            self.chunks = chunks.iter().map(|chunk| encoding.encode(chunk)).collect();
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.chunks.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| match i {
+            0 => ctx.set_result(&(self.index as i64)),
+            1 => ctx.set_result(&(self.index as f64 * self.chunk_secs)),
+            2 => ctx.set_result(&self.chunks[self.index]),
+            _ => ctx.set_result(&rusqlite::types::Null),
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_chunks() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+        let window = |start_secs, length_secs| {
+            crate::fingerprint_file_window(
+                &path,
+                start_secs,
+                Some(length_secs),
+                Preset::default(),
+                None,
+                &mut CallStats::default(),
+            )
+            .unwrap()
+        };
+
+        let chunks = fingerprint_chunks(
+            &path,
+            3.0,
+            Preset::default(),
+            None,
+            &mut CallStats::default(),
+        )
+        .unwrap();
+        // 6.8 seconds: two whole chunks, and a last one too short to keep.
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0], window(0.0, 3.0));
+        // Seeking primes the decoder differently, which flips a few bits.
+        let seeked = window(3.0, 3.0);
+        assert_eq!(chunks[1].len(), seeked.len());
+        let differing: u32 = chunks[1]
+            .iter()
+            .zip(&seeked)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert!(differing < 16, "{differing}");
+
+        assert!(fingerprint_chunks(
+            &path,
+            0.0,
+            Preset::default(),
+            None,
+            &mut CallStats::default()
+        )
+        .is_err());
+    }
+}
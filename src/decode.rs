@@ -0,0 +1,392 @@
+//! Audio decoding shared by the fingerprinting and analysis functions.
+
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::{Error, SeekErrorKind};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, Tag, Visual};
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+use crate::source;
+use crate::timings::{CallStats, CountingSource};
+
+/// A format hint from a file extension, or a MIME type if it has a `/`.
+fn hint(format: Option<&str>) -> Hint {
+    let mut hint = Hint::new();
+    match format {
+        Some(mime_type) if mime_type.contains('/') => {
+            hint.mime_type(mime_type);
+        }
+        Some(ext) => {
+            hint.with_extension(ext.trim_start_matches('.'));
+        }
+        None => {}
+    }
+    hint
+}
+
+/// The first audio track of a media source, decoded to interleaved `i16` samples.
+pub(crate) struct AudioStream {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    sample_buffer: Option<SampleBuffer<i16>>,
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// Length of the track in frames, if the container declares it.
+    pub n_frames: Option<u64>,
+    /// Size of the media source in bytes, if known.
+    pub byte_len: Option<u64>,
+    time_base: Option<TimeBase>,
+    /// Timestamp at which the last packet ended.
+    next_ts: Option<u64>,
+    /// Frames to drop before the next samples returned, after a seek.
+    skip_frames: u64,
+    /// Frames left to return, if the stream is limited to a window.
+    remaining_frames: Option<u64>,
+    /// Metadata read while probing, from outside the container (e.g. ID3v2).
+    probed_metadata: Option<MetadataRevision>,
+}
+
+impl AudioStream {
+    /// Open the audio file at `path`, using its extension as a format hint.
+    ///
+    /// Paths that are URIs with a registered [`SourceProvider`](crate::SourceProvider)
+    /// are opened by the provider, and others through `stats.vfs` if set, if
+    /// `stats.allowed_roots` allows them.
+    ///
+    /// With `gapless`, encoder delay and padding are trimmed where the format
+    /// records them, so consecutive tracks join without silence.
+    pub fn open_file(path: &Path, gapless: bool, stats: &CallStats) -> Result<Self> {
+        Self::open_file_as(path, None, gapless, stats)
+    }
+
+    /// Like [`open_file`](Self::open_file), but with `format` as the format
+    /// hint if given: a file extension such as `"mp3"`, or a MIME type such as
+    /// `"audio/mpeg"`.
+    pub fn open_file_as(
+        path: &Path,
+        format: Option<&str>,
+        gapless: bool,
+        stats: &CallStats,
+    ) -> Result<Self> {
+        let (src, extension): (Box<dyn MediaSource>, _) = match source::provider_for(path) {
+            Some((provider, uri)) => (
+                provider
+                    .open(uri)
+                    .with_context(|| format!("Failed to open {uri}"))?,
+                source::extension(uri),
+            ),
+            None => {
+                stats.check_path(path)?;
+                let file: Box<dyn MediaSource> = match stats.vfs {
+                    Some(vfs) => Box::new(vfs.open(path).context("Failed to open file")?),
+                    None => Box::new(std::fs::File::open(path).context("Failed to open file")?),
+                };
+                (file, path.extension().and_then(|e| e.to_str()))
+            }
+        };
+
+        Self::open(src, &hint(format.or(extension)), gapless, stats)
+    }
+
+    /// Open audio from `source`, such as a BLOB, with `format` as the format
+    /// hint as for [`open_file_as`](Self::open_file_as).
+    pub fn open_source(
+        source: Box<dyn MediaSource>,
+        format: Option<&str>,
+        stats: &CallStats,
+    ) -> Result<Self> {
+        Self::open(source, &hint(format), false, stats)
+    }
+
+    pub fn open(
+        source: Box<dyn MediaSource>,
+        hint: &Hint,
+        gapless: bool,
+        stats: &CallStats,
+    ) -> Result<Self> {
+        let byte_len = source.byte_len();
+        let src = CountingSource::new(source, stats.bytes.clone());
+        let mss = MediaSourceStream::new(Box::new(src), Default::default());
+
+        let mut probed = symphonia::default::get_probe()
+            .format(
+                hint,
+                mss,
+                &FormatOptions {
+                    enable_gapless: gapless,
+                    ..Default::default()
+                },
+                &MetadataOptions::default(),
+            )
+            .context("Failed to probe format")?;
+
+        let probed_metadata = probed
+            .metadata
+            .get()
+            .as_mut()
+            .and_then(|metadata| metadata.skip_to_latest())
+            .cloned();
+
+        let format = probed.format;
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .context("No audio track found")?;
+
+        let sample_rate = track
+            .codec_params
+            .sample_rate
+            .context("Missing sample rate")?;
+        let channels = track
+            .codec_params
+            .channels
+            .context("Missing channels")?
+            .count();
+
+        let n_frames = track.codec_params.n_frames;
+        let time_base = track.codec_params.time_base;
+
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .context("Failed to create decoder")?;
+
+        Ok(Self {
+            track_id: track.id,
+            format,
+            decoder,
+            sample_buffer: None,
+            sample_rate,
+            channels,
+            n_frames,
+            byte_len,
+            time_base,
+            next_ts: None,
+            skip_frames: 0,
+            remaining_frames: None,
+            probed_metadata,
+        })
+    }
+
+    /// Limit the stream to `length_secs` seconds from `start_secs` into the
+    /// track, or to the rest of it if `length_secs` is `None`. Streams are
+    /// seeked where the format allows it and decoded up to the start
+    /// otherwise. A window starting past the end is empty.
+    pub fn set_window(&mut self, start_secs: f64, length_secs: Option<f64>) -> Result<()> {
+        if !(start_secs.is_finite() && start_secs >= 0.0) {
+            bail!("Invalid start: {start_secs}");
+        }
+        if let Some(length_secs) = length_secs {
+            if !(length_secs.is_finite() && length_secs >= 0.0) {
+                bail!("Invalid length: {length_secs}");
+            }
+        }
+        let secs_to_frames = |secs: f64| (secs * self.sample_rate as f64).round() as u64;
+        self.remaining_frames = length_secs.map(secs_to_frames);
+
+        let seeked = self.format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(start_secs),
+                track_id: Some(self.track_id),
+            },
+        );
+        match seeked {
+            Ok(seeked) => {
+                self.decoder.reset();
+                self.next_ts = None;
+                let early = self.ts_secs(seeked.required_ts) - self.ts_secs(seeked.actual_ts);
+                self.skip_frames = secs_to_frames(early.max(0.0));
+            }
+            Err(Error::SeekError(SeekErrorKind::OutOfRange)) => self.remaining_frames = Some(0),
+            // Formats that seek by scanning, like MP3, hit the end instead.
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                self.remaining_frames = Some(0)
+            }
+            Err(Error::SeekError(SeekErrorKind::Unseekable | SeekErrorKind::ForwardOnly)) => {
+                self.skip_frames = secs_to_frames(start_secs);
+            }
+            Err(e) => return Err(e).context("Failed to seek"),
+        }
+        Ok(())
+    }
+
+    /// Stop the stream after `max_length_secs` seconds, or sooner if it's
+    /// already limited to a shorter window.
+    pub fn set_max_length(&mut self, max_length_secs: Option<u32>) {
+        if let Some(secs) = max_length_secs {
+            let frames = u64::from(secs) * u64::from(self.sample_rate);
+            self.remaining_frames = Some(self.remaining_frames.map_or(frames, |r| r.min(frames)));
+        }
+    }
+
+    /// Every track of the media source, including those that aren't decoded.
+    pub fn tracks(&self) -> &[Track] {
+        self.format.tracks()
+    }
+
+    /// The file's tags: those read while probing, then the latest revision of
+    /// the container's own.
+    pub fn tags(&mut self) -> Vec<Tag> {
+        self.metadata(MetadataRevision::tags)
+    }
+
+    /// The file's embedded pictures, in the same order as [`tags`](Self::tags).
+    pub fn visuals(&mut self) -> Vec<Visual> {
+        self.metadata(MetadataRevision::visuals)
+    }
+
+    fn metadata<T: Clone>(&mut self, items: fn(&MetadataRevision) -> &[T]) -> Vec<T> {
+        let mut all = self
+            .probed_metadata
+            .as_ref()
+            .map(|revision| items(revision).to_vec())
+            .unwrap_or_default();
+        if let Some(revision) = self.format.metadata().skip_to_latest() {
+            all.extend_from_slice(items(revision));
+        }
+        all
+    }
+
+    /// Decode the next packet of the track, or return `None` at the end of the stream.
+    pub fn next_samples(&mut self, stats: &mut CallStats) -> Result<Option<&[i16]>> {
+        self.decode_next(stats, None)
+    }
+
+    /// Like [`next_samples`](Self::next_samples), but skip packets that fail
+    /// to decode and reset the decoder when the format requires it, passing
+    /// each such event to `report`.
+    pub fn next_samples_reporting(
+        &mut self,
+        stats: &mut CallStats,
+        report: &mut dyn FnMut(DecodeEvent),
+    ) -> Result<Option<&[i16]>> {
+        self.decode_next(stats, Some(report))
+    }
+
+    /// A timestamp of the track in seconds.
+    pub fn ts_secs(&self, ts: u64) -> f64 {
+        match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(ts);
+                time.seconds as f64 + time.frac
+            }
+            None => ts as f64 / self.sample_rate as f64,
+        }
+    }
+
+    fn decode_next(
+        &mut self,
+        stats: &mut CallStats,
+        mut report: Option<&mut dyn FnMut(DecodeEvent)>,
+    ) -> Result<Option<&[i16]>> {
+        let started = Instant::now();
+        loop {
+            if self.remaining_frames == Some(0) {
+                return Ok(None);
+            }
+            if let Some(interrupt) = stats.interrupt {
+                interrupt.check()?;
+            }
+            if let Some(progress) = &mut stats.progress {
+                progress.tick(self.next_ts.map_or(0.0, |ts| self.ts_secs(ts)))?;
+            }
+            let packet = match self.format.next_packet() {
+                Ok(packet) if packet.track_id() == self.track_id => packet,
+                Ok(_) => continue,
+                Err(Error::ResetRequired) if report.is_some() => {
+                    self.decoder.reset();
+                    if let Some(report) = &mut report {
+                        report(DecodeEvent::DecoderReset {
+                            ts: self.next_ts.unwrap_or(0),
+                        });
+                    }
+                    continue;
+                }
+                Err(_) => return Ok(None),
+            };
+
+            if let (Some(report), Some(expected)) = (&mut report, self.next_ts) {
+                if packet.ts() > expected + packet.dur() {
+                    report(DecodeEvent::Gap {
+                        ts: expected,
+                        dur: packet.ts() - expected,
+                    });
+                }
+            }
+            self.next_ts = Some(packet.ts() + packet.dur());
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(Error::DecodeError(error)) if report.is_some() => {
+                    if let Some(report) = &mut report {
+                        report(DecodeEvent::CorruptPacket {
+                            ts: packet.ts(),
+                            dur: packet.dur(),
+                            bytes: packet.buf().len(),
+                            error,
+                        });
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e).context("Failed to decode packet"),
+            };
+            // Trim the frames before and after the window, if any.
+            let decoded_frames = decoded.frames() as u64;
+            let skip = self.skip_frames.min(decoded_frames);
+            self.skip_frames -= skip;
+            let mut take = decoded_frames - skip;
+            if let Some(remaining) = &mut self.remaining_frames {
+                take = take.min(*remaining);
+                *remaining -= take;
+            }
+            if take == 0 {
+                continue;
+            }
+
+            let spec = *decoded.spec();
+            let frames = decoded.capacity();
+            if let Some(buf) = &self.sample_buffer {
+                if buf.capacity() < frames * spec.channels.count() {
+                    self.sample_buffer = None;
+                }
+            }
+            let sample_buffer = self
+                .sample_buffer
+                .get_or_insert_with(|| SampleBuffer::<i16>::new(frames as u64, spec));
+            sample_buffer.copy_interleaved_ref(decoded);
+            stats.decode += started.elapsed();
+
+            let channels = spec.channels.count();
+            let start = skip as usize * channels;
+            let end = start + take as usize * channels;
+            return Ok(Some(&sample_buffer.samples()[start..end]));
+        }
+    }
+}
+
+/// A problem that [`AudioStream::next_samples_reporting`] recovered from.
+/// Timestamps and durations are in the track's time base.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum DecodeEvent {
+    /// A packet that failed to decode and was dropped.
+    CorruptPacket {
+        ts: u64,
+        dur: u64,
+        bytes: usize,
+        error: &'static str,
+    },
+    /// The format required the decoder to be reset, e.g. for a chained stream.
+    DecoderReset { ts: u64 },
+    /// Packets jumped ahead of where the previous one ended, e.g. because the
+    /// format skipped data it couldn't parse.
+    Gap { ts: u64, dur: u64 },
+}
@@ -0,0 +1,385 @@
+//! Classification of duplicate pairs.
+//!
+//! `duplicate_kind(a, b)` tells how two copies of a track are related:
+//!
+//! ```sql
+//! SELECT a.path, b.path, duplicate_kind(a.path, b.path)
+//! FROM tracks a JOIN tracks b ON a.id < b.id
+//! WHERE compare_fingerprints(a.fingerprint, b.fingerprint) < 10;
+//! ```
+//!
+//! The result is one of:
+//!
+//! * `'identical'`: both decode to exactly the same samples, e.g. a copy or
+//!   a retagged file, or the same audio in two lossless containers.
+//! * `'transcode'`: the fingerprints match closely over the whole length of
+//!   both, as for the same master encoded differently.
+//! * `'remaster'`: the fingerprints match over most of the shorter one, but
+//!   less closely or not over the whole length, as for a different master
+//!   or edit of the same recording.
+//! * `'different'`: anything else.
+//!
+//! Each argument is the path of an audio file (TEXT or BLOB) or a
+//! fingerprint as returned by `fingerprint()`. TEXT naming an existing file
+//! or a URI with a registered source provider is a path; other TEXT that
+//! decodes as a fingerprint is a fingerprint. TEXT outside the
+//! `allowed_roots` option's roots is a fingerprint if it decodes as one, and
+//! otherwise an error, whether or not the file exists.
+//! Samples can only be compared when both arguments are paths, so a pair
+//! involving a fingerprint is at most a `'transcode'`. Fingerprints are
+//! made and matched with the `preset` option, and always scored with the
+//! default match mode.
+//!
+//! `is_duplicate_of(table, fp_col, fingerprint, threshold)` is 1 if any
+//! fingerprint in column `fp_col` of `table` (or view) compares with
+//! `fingerprint` at a score of at most `threshold`, as `compare_fingerprints`
+//! scores it, and 0 otherwise. It can reject duplicate imports in a trigger:
+//!
+//! ```sql
+//! CREATE TRIGGER tracks_no_duplicates BEFORE INSERT ON tracks
+//! WHEN is_duplicate_of('tracks', 'fingerprint', NEW.fingerprint, 3.0)
+//! BEGIN
+//!   SELECT RAISE(ABORT, 'duplicate track');
+//! END;
+//! ```
+//!
+//! Every row is compared until a duplicate is found, unless `table` is a
+//! [`chromaprint_index`](crate::index) table, whose index is used to compare
+//! only candidates, with the index's preset if it was given one; `fp_col` is
+//! then ignored. Rows with a NULL fingerprint are skipped, and a NULL
+//! `fingerprint` is a duplicate of nothing, so the function is 0. Since it
+//! reads tables named by its arguments, the function is not innocuous, and
+//! can only be used in triggers while `PRAGMA trusted_schema` is on.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rusqlite::types::ValueRef;
+use rusqlite::vtab::escape_double_quote;
+use rusqlite::Connection;
+use rusty_chromaprint::match_fingerprints;
+
+use crate::matching::MatchMode;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::{compare_fingerprints, decode_fingerprint, index, path, similarity_score, tvf};
+#[cfg(feature = "decode")]
+use crate::{fingerprint_path_inspecting, source};
+
+/// Similarity scores up to this are close enough for a transcode...
+const TRANSCODE_SCORE: f64 = 3.0;
+
+/// ...if the matched segments also cover this fraction of the longer
+/// fingerprint.
+const TRANSCODE_COVERAGE: f64 = 0.9;
+
+/// Matched segments covering this fraction of the shorter fingerprint are
+/// the same recording.
+const RECORDING_COVERAGE: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Identical,
+    Transcode,
+    Remaster,
+    Different,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Identical => "identical",
+            Kind::Transcode => "transcode",
+            Kind::Remaster => "remaster",
+            Kind::Different => "different",
+        }
+    }
+}
+
+/// An argument of `duplicate_kind`.
+#[derive(Debug)]
+pub(crate) enum Audio {
+    Path(PathBuf),
+    Fingerprint(Vec<u32>),
+}
+
+#[cfg(feature = "decode")]
+impl Audio {
+    /// Interpret argument `idx` as a path or a fingerprint.
+    pub fn from_value(idx: usize, value: ValueRef<'_>, state: &State) -> rusqlite::Result<Self> {
+        if let ValueRef::Text(s) = value {
+            let s = std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?;
+            // Check the roots before looking for the file, so that whether a
+            // path outside them exists isn't revealed.
+            if let Err(e) = state.call_stats().check_path(Path::new(s)) {
+                return decode_fingerprint(s)
+                    .map(Audio::Fingerprint)
+                    .map_err(|_| rusqlite::Error::UserFunctionError(e.into()));
+            }
+            if !Path::new(s).is_file() && !source::is_uri(s) {
+                if let Ok(fingerprint) = decode_fingerprint(s) {
+                    return Ok(Audio::Fingerprint(fingerprint));
+                }
+            }
+        }
+        Ok(Audio::Path(path::path_from_value(idx, value)?))
+    }
+
+    /// The fingerprint, and for files a hash of the decoded samples.
+    fn fingerprint(self, state: &State) -> Result<(Vec<u32>, Option<u64>)> {
+        match self {
+            Audio::Path(path) => {
+                let mut stats = state.call_stats();
+                let mut hasher = DefaultHasher::new();
+                let preset = state.options().preset;
+                let fingerprint = fingerprint_path_inspecting(
+                    &path,
+                    None,
+                    preset,
+                    None,
+                    &mut stats,
+                    &mut |samples| Hash::hash_slice(samples, &mut hasher),
+                )
+                .with_context(|| path.display().to_string())?;
+                state.record_timing("duplicate_kind", &path.to_string_lossy(), stats);
+                Ok((fingerprint, Some(hasher.finish())))
+            }
+            Audio::Fingerprint(fingerprint) => Ok((fingerprint, None)),
+        }
+    }
+}
+
+#[cfg(feature = "decode")]
+pub(crate) fn duplicate_kind(a: Audio, b: Audio, state: &State) -> Result<Kind> {
+    let (fingerprint_a, hash_a) = a.fingerprint(state)?;
+    let (fingerprint_b, hash_b) = b.fingerprint(state)?;
+    if hash_a.is_some() && hash_a == hash_b {
+        return Ok(Kind::Identical);
+    }
+
+    let config = state.options().preset.config();
+    let segments = match_fingerprints(&fingerprint_a, &fingerprint_b, &config)
+        .context("Failed to match fingerprints")?;
+    let Some(score) = similarity_score(
+        &segments,
+        &fingerprint_a,
+        &fingerprint_b,
+        &config,
+        MatchMode::Default,
+    ) else {
+        return Ok(Kind::Different);
+    };
+
+    let matched: usize = segments.iter().map(|s| s.items_count).sum();
+    let (shorter, longer) = (
+        fingerprint_a.len().min(fingerprint_b.len()),
+        fingerprint_a.len().max(fingerprint_b.len()),
+    );
+    Ok(classify(
+        score,
+        matched as f64 / shorter.max(1) as f64,
+        matched as f64 / longer.max(1) as f64,
+    ))
+}
+
+pub(crate) fn is_duplicate_of(
+    db: &Connection,
+    table: &str,
+    fp_col: &str,
+    fingerprint: &[u32],
+    threshold: f64,
+    preset: Preset,
+    mode: MatchMode,
+) -> Result<bool> {
+    if let Some(arguments) = index::table_arguments(db, table)? {
+        let preset = arguments.preset.unwrap_or(preset);
+        for (_, existing) in index::matching_fingerprints(db, table, fingerprint)? {
+            let score = compare_fingerprints(fingerprint, &existing, preset, mode)?;
+            if score.is_some_and(|score| score <= threshold) {
+                return Ok(true);
+            }
+        }
+        return Ok(false);
+    }
+
+    let mut stmt = db.prepare(&format!(
+        "SELECT \"{}\" FROM \"{}\"",
+        escape_double_quote(fp_col),
+        escape_double_quote(table),
+    ))?;
+
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let Some(existing) = tvf::fingerprint_column(row.get_ref(0)?)
+            .with_context(|| format!("Invalid fingerprint in {table}"))?
+        else {
+            continue;
+        };
+        let score = compare_fingerprints(fingerprint, &existing, preset, mode)?;
+        if score.is_some_and(|score| score <= threshold) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Classify a fingerprint match by its similarity score and the fraction of
+/// the shorter and the longer fingerprint that it covers.
+fn classify(score: f64, shorter_coverage: f64, longer_coverage: f64) -> Kind {
+    if score <= TRANSCODE_SCORE && longer_coverage >= TRANSCODE_COVERAGE {
+        Kind::Transcode
+    } else if shorter_coverage >= RECORDING_COVERAGE {
+        Kind::Remaster
+    } else {
+        Kind::Different
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "decode")]
+    fn testdata(name: &str) -> Audio {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        Audio::Path(Path::new(&manifest_dir).join("src/testdata").join(name))
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(1.5, 1.0, 0.98), Kind::Transcode);
+        assert_eq!(classify(6.0, 1.0, 0.98), Kind::Remaster);
+        // A radio edit: the whole edit matches part of the album version.
+        assert_eq!(classify(1.5, 0.95, 0.6), Kind::Remaster);
+        assert_eq!(classify(1.5, 0.2, 0.1), Kind::Different);
+    }
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn test_duplicate_kind() {
+        let state = State::default();
+        let kind = duplicate_kind(testdata("XC444467.ogg"), testdata("XC444467.ogg"), &state);
+        assert_eq!(kind.unwrap(), Kind::Identical);
+        let kind = duplicate_kind(testdata("XC444467.ogg"), testdata("XC444467.mp3"), &state);
+        assert_eq!(kind.unwrap(), Kind::Transcode);
+    }
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn test_argument() {
+        let state = State::default();
+        let fingerprint = crate::encode_fingerprint(&[1, 2, 3]);
+        let audio = Audio::from_value(0, ValueRef::Text(fingerprint.as_bytes()), &state).unwrap();
+        assert!(matches!(audio, Audio::Fingerprint(fp) if fp == [1, 2, 3]));
+
+        let audio = Audio::from_value(0, ValueRef::Text(b"track.mp3"), &state).unwrap();
+        assert!(matches!(audio, Audio::Path(_)));
+    }
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn test_argument_outside_allowed_roots() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let testdata = Path::new(&manifest_dir).join("src/testdata");
+        let state = State::default();
+        state
+            .options_mut()
+            .set(
+                "allowed_roots",
+                ValueRef::Text(testdata.to_str().unwrap().as_bytes()),
+            )
+            .unwrap();
+
+        // Whether a file outside the roots exists isn't revealed.
+        let existing = Audio::from_value(0, ValueRef::Text(b"/etc/passwd"), &state).unwrap_err();
+        let missing = Audio::from_value(0, ValueRef::Text(b"/etc/pxsswd"), &state).unwrap_err();
+        assert_eq!(
+            existing.to_string().replace("passwd", "pxsswd"),
+            missing.to_string()
+        );
+
+        let fingerprint = crate::encode_fingerprint(&[1, 2, 3]);
+        let audio = Audio::from_value(0, ValueRef::Text(fingerprint.as_bytes()), &state).unwrap();
+        assert!(matches!(audio, Audio::Fingerprint(fp) if fp == [1, 2, 3]));
+        let inside = testdata.join("XC444467.ogg");
+        let audio = Audio::from_value(
+            0,
+            ValueRef::Text(inside.to_str().unwrap().as_bytes()),
+            &state,
+        )
+        .unwrap();
+        assert!(matches!(audio, Audio::Path(_)));
+    }
+
+    #[cfg(not(feature = "extension"))]
+    #[test]
+    fn test_is_duplicate_of() {
+        use std::sync::Arc;
+
+        let mut x = 1u32;
+        let track: Vec<u32> = (0..1000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x
+            })
+            .collect();
+        let other: Vec<u32> = track.iter().map(|item| item.rotate_left(16)).collect();
+
+        let db = Connection::open_in_memory().unwrap();
+        index::register(&db, Arc::new(State::default())).unwrap();
+        db.execute_batch(
+            "CREATE VIRTUAL TABLE idx USING chromaprint_index();
+             CREATE TABLE tracks(fingerprint BLOB);
+             INSERT INTO tracks VALUES (NULL);
+             CREATE TABLE packed(fingerprint);",
+        )
+        .unwrap();
+        let blob = crate::functions::fingerprint_to_blob(&track);
+        db.execute("INSERT INTO idx(fp) VALUES (?1)", [&blob])
+            .unwrap();
+        db.execute("INSERT INTO tracks VALUES (?1)", [&blob])
+            .unwrap();
+        // Compressed like fp_compress, as a BLOB and as TEXT.
+        let config = Preset::default().config();
+        db.execute(
+            "INSERT INTO packed VALUES (?1), (?2)",
+            rusqlite::params![
+                crate::encoding::compress_bytes(&track, &config),
+                crate::encoding::compress(&[!track[0], !track[1]], &config)
+            ],
+        )
+        .unwrap();
+
+        for table in ["idx", "tracks", "packed"] {
+            let is_duplicate = |fingerprint: &[u32]| {
+                is_duplicate_of(
+                    &db,
+                    table,
+                    "fingerprint",
+                    fingerprint,
+                    3.0,
+                    Preset::default(),
+                    MatchMode::Default,
+                )
+                .unwrap()
+            };
+            assert!(is_duplicate(&track[100..900]), "{table}");
+            assert!(!is_duplicate(&other), "{table}");
+        }
+
+        // A new row without a fingerprint duplicates nothing.
+        crate::register(&db).unwrap();
+        let is_duplicate: bool = db
+            .query_row(
+                "SELECT is_duplicate_of('tracks', 'fingerprint', NULL, 3.0)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(!is_duplicate);
+    }
+}
@@ -0,0 +1,61 @@
+//! Duration of audio files.
+//!
+//! `audio_duration(path)` returns the length of a file's audio in seconds,
+//! e.g. for `acoustid_lookup`:
+//!
+//! ```sql
+//! SELECT acoustid_lookup(fingerprint(path), audio_duration(path), :api_key)
+//! FROM tracks;
+//! ```
+//!
+//! The length is read from the container or codec parameters where they
+//! declare it, which only probes the file. Files that don't declare it, such
+//! as MP3s without a Xing or VBRI header, are decoded to count their
+//! samples. Encoder delay and padding are left out where the format records
+//! them.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::decode::AudioStream;
+use crate::timings::CallStats;
+
+pub(crate) fn audio_duration(path: &Path, stats: &mut CallStats) -> Result<f64> {
+    let stream = AudioStream::open_file(path, true, stats)?;
+    match stream.n_frames {
+        Some(n_frames) => Ok(n_frames as f64 / stream.sample_rate as f64),
+        None => decoded_secs(stream, stats),
+    }
+}
+
+/// Seconds of audio decoded from the rest of `stream`.
+fn decoded_secs(mut stream: AudioStream, stats: &mut CallStats) -> Result<f64> {
+    let channels = stream.channels.max(1);
+    let mut frames = 0;
+    while let Some(samples) = stream.next_samples(stats)? {
+        frames += (samples.len() / channels) as u64;
+    }
+    Ok(frames as f64 / stream.sample_rate as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_duration() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let testdata = Path::new(&manifest_dir).join("src/testdata");
+        for name in ["XC444467.ogg", "XC444467.mp3"] {
+            let path = testdata.join(name);
+            let mut stats = CallStats::default();
+            let declared = audio_duration(&path, &mut stats).unwrap();
+            assert!((declared - 6.8).abs() < 0.1, "{declared}");
+
+            let stream = AudioStream::open_file(&path, true, &stats).unwrap();
+            let decoded = decoded_secs(stream, &mut stats).unwrap();
+            assert!((declared - decoded).abs() < 0.05, "{declared} {decoded}");
+        }
+    }
+}
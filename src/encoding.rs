@@ -0,0 +1,310 @@
+//! Text encodings of fingerprints.
+//!
+//! Fingerprints are big-endian 32-bit items, encoded as TEXT according to
+//! the `encoding` option:
+//!
+//! * `'base64'` (the default): standard base64 with padding, as `fpcalc`
+//!   prints raw fingerprints.
+//! * `'base64url'`: URL-safe base64 without padding, for use in URLs and
+//!   file names.
+//! * `'hex'`: lowercase hexadecimal, 8 digits per item.
+//!
+//! ```sql
+//! SELECT chromaprint_option('encoding', 'hex');
+//! ```
+//!
+//! Functions taking fingerprints accept all three whatever the option says,
+//! and JSON arrays of the items as integers, as `fingerprint_json` returns
+//! them and `fpcalc -raw -json` prints them. TEXT made only of hex digits, 8
+//! per item, is read as hex; base64 of a real fingerprint practically never
+//! is.
+//!
+//! `fp_to_base64`, `fp_to_base64url` and `fp_to_hex` re-encode a fingerprint
+//! in any encoding, and `fp_from_text` decodes one to a BLOB of its items,
+//! the bytes the encodings encode:
+//!
+//! ```sql
+//! UPDATE tracks SET fingerprint = fp_to_hex(fingerprint);
+//! ```
+//!
+//! `fp_to_acoustid(fingerprint)` and `fp_from_acoustid(text)` convert to and
+//! from Chromaprint's compressed format, which `fpcalc` prints and the
+//! AcoustID service accepts:
+//!
+//! ```sql
+//! SELECT fp_to_acoustid(fingerprint('song.mp3'));
+//! ```
+//!
+//! `fp_compress(fingerprint)` returns the same format's bytes as a BLOB, for
+//! storing fingerprints compactly, and `fp_decompress(blob)` reads them back.
+//! Functions taking fingerprints accept such BLOBs directly too.
+//!
+//! The compressed format records the algorithm that made a fingerprint.
+//! Both functions take an optional preset (see [`crate::preset`]), `'test1'`
+//! by default, while `fpcalc` and AcoustID use `'test2'` by default, so
+//! `fp_from_acoustid` only accepts output of `fpcalc -algorithm 1` unless
+//! given `'test2'`. `compare_fingerprints` accepts compressed fingerprints
+//! directly, from any algorithm, as long as both of the fingerprints it
+//! compares were made with the same one.
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use rusty_chromaprint::{Configuration, FingerprintCompressor};
+
+/// The highest algorithm id Chromaprint defines (`TEST5`).
+const MAX_ALGORITHM: u8 = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    #[default]
+    Base64,
+    Base64Url,
+    Hex,
+}
+
+impl Encoding {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "base64" => Self::Base64,
+            "base64url" => Self::Base64Url,
+            "hex" => Self::Hex,
+            _ => bail!("Unknown encoding: {s}"),
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Base64 => "base64",
+            Self::Base64Url => "base64url",
+            Self::Hex => "hex",
+        }
+    }
+
+    pub fn encode(self, fingerprint: &[u32]) -> String {
+        let bytes: Vec<u8> = fingerprint.iter().flat_map(|x| x.to_be_bytes()).collect();
+        match self {
+            Self::Base64 => BASE64_STANDARD.encode(bytes),
+            Self::Base64Url => BASE64_URL_SAFE_NO_PAD.encode(bytes),
+            Self::Hex => fingerprint.iter().map(|x| format!("{x:08x}")).collect(),
+        }
+    }
+}
+
+/// Decode a fingerprint in any of the encodings. Trailing bytes that don't
+/// make up a whole item are ignored.
+pub(crate) fn decode(fingerprint: &str) -> Result<Vec<u32>> {
+    let fingerprint = fingerprint.trim();
+    if fingerprint.starts_with('[') {
+        return serde_json::from_str(fingerprint).context("Invalid JSON array of items");
+    }
+    let bytes = if is_hex(fingerprint) {
+        (0..fingerprint.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&fingerprint[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()?
+    } else {
+        let unpadded = fingerprint.trim_end_matches('=');
+        if unpadded.contains(['-', '_']) {
+            BASE64_URL_SAFE_NO_PAD.decode(unpadded)?
+        } else {
+            BASE64_STANDARD_NO_PAD.decode(unpadded)?
+        }
+    };
+
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+/// Chromaprint's compressed fingerprint format, as `fpcalc` prints and the
+/// AcoustID service accepts, for fingerprints made with `algorithm`.
+pub(crate) fn compress(fingerprint: &[u32], algorithm: &Configuration) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(compress_bytes(fingerprint, algorithm))
+}
+
+/// Chromaprint's compressed fingerprint format as bytes, before base64.
+pub(crate) fn compress_bytes(fingerprint: &[u32], algorithm: &Configuration) -> Vec<u8> {
+    FingerprintCompressor::from(algorithm).compress(fingerprint)
+}
+
+/// Decompress a fingerprint in Chromaprint's compressed format, returning
+/// the id of the algorithm that made it and its items.
+pub(crate) fn decompress(compressed: &str) -> Result<(u8, Vec<u32>)> {
+    let compressed = compressed.trim().trim_end_matches('=');
+    let bytes = if compressed.contains(['+', '/']) {
+        BASE64_STANDARD_NO_PAD.decode(compressed)?
+    } else {
+        BASE64_URL_SAFE_NO_PAD.decode(compressed)?
+    };
+    decompress_bytes(&bytes)
+}
+
+/// Like [`decompress`], for the compressed format's bytes.
+pub(crate) fn decompress_bytes(bytes: &[u8]) -> Result<(u8, Vec<u32>)> {
+    let [algorithm, a, b, c, ..] = bytes[..] else {
+        bail!("Compressed fingerprint too short");
+    };
+    let size = u32::from_be_bytes([0, a, b, c]) as usize;
+
+    // Gaps between set bits of each item XOR the previous one, 3 bits each,
+    // with 0 ending an item and 7 meaning 7 plus the next 5-bit value.
+    let mut normal = BitReader::new(&bytes[4..]);
+    let mut gaps = Vec::new();
+    let mut items = 0;
+    while items < size {
+        let gap = normal.read(3).context("Compressed fingerprint truncated")?;
+        items += usize::from(gap == 0);
+        gaps.push(gap);
+    }
+    let mut exceptional = BitReader::new(&bytes[4 + (gaps.len() * 3).div_ceil(8)..]);
+
+    let mut fingerprint = Vec::with_capacity(size);
+    let (mut item, mut bit, mut previous) = (0u32, 0u32, 0u32);
+    for gap in gaps {
+        if gap == 0 {
+            previous ^= item;
+            fingerprint.push(previous);
+            (item, bit) = (0, 0);
+            continue;
+        }
+        bit += gap;
+        if gap == 7 {
+            bit += exceptional
+                .read(5)
+                .context("Compressed fingerprint truncated")?;
+        }
+        if bit > 32 {
+            bail!("Invalid compressed fingerprint");
+        }
+        item |= 1 << (bit - 1);
+    }
+    if exceptional.bit.div_ceil(8) != exceptional.bytes.len() {
+        bail!("Invalid compressed fingerprint: trailing data");
+    }
+    Ok((algorithm, fingerprint))
+}
+
+/// Decode a fingerprint in any of the encodings or Chromaprint's compressed
+/// format, returning the id of the algorithm that made it if it's compressed.
+///
+/// Text that decompresses exactly, with a known algorithm, is taken to be
+/// compressed; raw fingerprints practically never do.
+pub(crate) fn decode_any(fingerprint: &str) -> Result<(Option<u8>, Vec<u32>)> {
+    if !is_hex(fingerprint.trim()) {
+        if let Ok((algorithm @ ..=MAX_ALGORITHM, items)) = decompress(fingerprint) {
+            return Ok((Some(algorithm), items));
+        }
+    }
+    Ok((None, decode(fingerprint)?))
+}
+
+/// Like [`decode_any`], for a BLOB: compressed bytes, or big-endian items.
+pub(crate) fn decode_any_blob(fingerprint: &[u8]) -> Result<(Option<u8>, Vec<u32>)> {
+    if let Ok((algorithm @ ..=MAX_ALGORITHM, items)) = decompress_bytes(fingerprint) {
+        return Ok((Some(algorithm), items));
+    }
+    Ok((None, crate::decode_fingerprint_blob(fingerprint)?))
+}
+
+/// Reads values packed least significant bit first.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, bit: 0 }
+    }
+
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value = 0;
+        for i in 0..bits {
+            let byte = self.bytes.get(self.bit / 8)?;
+            value |= u32::from(byte >> (self.bit % 8) & 1) << i;
+            self.bit += 1;
+        }
+        Some(value)
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.len().is_multiple_of(8) && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let fingerprint = [0xfbff_fe00, 0x0000_0001, 0x1234_abcd];
+        for encoding in [Encoding::Base64, Encoding::Base64Url, Encoding::Hex] {
+            let text = encoding.encode(&fingerprint);
+            assert_eq!(decode(&text).unwrap(), fingerprint, "{text}");
+            assert_eq!(Encoding::parse(encoding.name()).unwrap(), encoding);
+        }
+
+        assert_eq!(decode(" [4227857920, 1, 305441741] ").unwrap(), fingerprint);
+        assert!(decode("[1, -1]").is_err());
+
+        assert_eq!(Encoding::Base64.encode(&fingerprint), "+//+AAAAAAESNKvN");
+        assert_eq!(Encoding::Base64Url.encode(&fingerprint), "-__-AAAAAAESNKvN");
+        assert_eq!(
+            Encoding::Hex.encode(&fingerprint),
+            "fbfffe00000000011234abcd"
+        );
+        assert_eq!(decode("AAAAAQ==").unwrap(), [1]);
+        assert_eq!(decode("AAAAAQ").unwrap(), [1]);
+        assert!(decode("").unwrap().is_empty());
+        assert!(decode("not a fingerprint!").is_err());
+        assert!(Encoding::parse("base32").is_err());
+    }
+
+    #[test]
+    fn test_compress() {
+        let test1 = Configuration::preset_test1();
+        let header = |size: u8| [0u8, 0, 0, size];
+        // Examples from Chromaprint's own tests.
+        for (fingerprint, packed) in [
+            (vec![1], &[0x01][..]),
+            (vec![7], &[0x49, 0x00][..]),
+            (vec![1 << 6], &[0x07, 0x00][..]),
+            (vec![1 << 8], &[0x07, 0x02][..]),
+        ] {
+            let expected = BASE64_URL_SAFE_NO_PAD.encode([&header(1)[..], packed].concat());
+            assert_eq!(compress(&fingerprint, &test1), expected, "{fingerprint:?}");
+            assert_eq!(decompress(&expected).unwrap(), (0, fingerprint));
+        }
+
+        let fingerprint = [0xfbff_fe00, 0x0000_0001, 0x1234_abcd, 0x8000_0000, 0];
+        let compressed = compress(&fingerprint, &Configuration::preset_test2());
+        assert_eq!(decompress(&compressed).unwrap(), (1, fingerprint.to_vec()));
+        assert!(decompress(&compressed[..compressed.len() - 4]).is_err());
+        assert!(decompress("AAA").is_err());
+        assert!(decompress(&format!("{compressed}AA")).is_err());
+    }
+
+    #[test]
+    fn test_decode_any() {
+        let fingerprint = [0xfbff_fe00, 0x0000_0001, 0x1234_abcd, 0x0321_0000];
+        let compressed = compress(&fingerprint, &Configuration::preset_test2());
+        assert_eq!(
+            decode_any(&compressed).unwrap(),
+            (Some(1), fingerprint.to_vec())
+        );
+        for encoding in [Encoding::Base64, Encoding::Base64Url, Encoding::Hex] {
+            let text = encoding.encode(&fingerprint);
+            assert_eq!(decode_any(&text).unwrap(), (None, fingerprint.to_vec()));
+        }
+
+        let compressed = compress_bytes(&fingerprint, &Configuration::preset_test2());
+        assert_eq!(
+            decode_any_blob(&compressed).unwrap(),
+            (Some(1), fingerprint.to_vec())
+        );
+        let raw: Vec<u8> = fingerprint.iter().flat_map(|x| x.to_be_bytes()).collect();
+        assert_eq!(decode_any_blob(&raw).unwrap(), (None, fingerprint.to_vec()));
+    }
+}
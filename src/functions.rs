@@ -0,0 +1,143 @@
+//! The SQL functions' implementations, for Rust applications that want to
+//! fingerprint and compare audio without going through SQL.
+//!
+//! Each works like the SQL function of the same name, with the default
+//! options unless it takes them as arguments: Chromaprint's `test1` preset,
+//! whole files and the default match mode. Fingerprints are their raw items,
+//! exactly as the SQL functions make them; [`encode_fingerprint`] and
+//! [`fingerprint_to_blob`] give the base64 TEXT and BLOB forms the SQL
+//! functions return and store, and [`decode_fingerprint`] and
+//! [`fingerprint_from_blob`] read them back.
+//!
+//! The functions that decode audio need the `decode` feature.
+//!
+//! ```no_run
+//! # #[cfg(feature = "decode")]
+//! # fn main() -> anyhow::Result<()> {
+//! use sqlite3_chromaprint::functions::{compare_fingerprints, fingerprint};
+//!
+//! let a = fingerprint("track1.mp3".as_ref())?;
+//! let b = fingerprint("track2.mp3".as_ref())?;
+//! println!("{}", compare_fingerprints(&a, &b)?);
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "decode"))]
+//! # fn main() {}
+//! ```
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::encoding::Encoding;
+use crate::matching::MatchMode;
+use crate::preset::Preset;
+use crate::timings::CallStats;
+
+#[cfg(feature = "decode")]
+/// Fingerprint the audio file at `path`, like `fingerprint(path)`.
+pub fn fingerprint(path: &Path) -> Result<Vec<u32>> {
+    fingerprint_file(path, None, Preset::default(), None)
+}
+
+#[cfg(feature = "decode")]
+/// Fingerprint the audio file at `path` with `preset`, from at most its first
+/// `max_length_secs` seconds if given, like `fingerprint(path, format,
+/// preset)` with the `max_length_secs` option. `format` is a format hint,
+/// an extension such as `"mp3"` or a MIME type such as `"audio/mpeg"`.
+pub fn fingerprint_file(
+    path: &Path,
+    format: Option<&str>,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+) -> Result<Vec<u32>> {
+    crate::fingerprint_file_raw(
+        path,
+        format,
+        preset,
+        max_length_secs.filter(|&secs| secs > 0),
+        &mut CallStats::default(),
+    )
+}
+
+#[cfg(feature = "decode")]
+/// Fingerprint audio in `data`, with `format` as the format hint if given,
+/// like `fingerprint_blob(data, format)`.
+pub fn fingerprint_blob(data: Vec<u8>, format: Option<&str>) -> Result<Vec<u32>> {
+    crate::fingerprint_bytes(
+        data,
+        format,
+        Preset::default(),
+        None,
+        &mut CallStats::default(),
+    )
+}
+
+/// Fingerprint interleaved signed 16-bit little-endian PCM, like
+/// `fingerprint_pcm(data, sample_rate, channels)`.
+pub fn fingerprint_pcm(data: &[u8], sample_rate: u32, channels: u32) -> Result<Vec<u32>> {
+    crate::fingerprint_pcm(data, sample_rate.into(), channels.into(), Preset::default())
+}
+
+/// Similarity score of two fingerprints, like `compare_fingerprints(a, b)`:
+/// from 0 for the most similar to 32 for the least.
+pub fn compare_fingerprints(fingerprint_a: &[u32], fingerprint_b: &[u32]) -> Result<f64> {
+    compare_fingerprints_with_preset(fingerprint_a, fingerprint_b, Preset::default())
+}
+
+/// Like [`compare_fingerprints`], for fingerprints made with `preset`.
+pub fn compare_fingerprints_with_preset(
+    fingerprint_a: &[u32],
+    fingerprint_b: &[u32],
+    preset: Preset,
+) -> Result<f64> {
+    let score =
+        crate::compare_fingerprints(fingerprint_a, fingerprint_b, preset, MatchMode::default())?;
+    Ok(score.unwrap_or(0.0))
+}
+
+/// A fingerprint as the base64 TEXT the SQL functions return.
+pub fn encode_fingerprint(fingerprint: &[u32]) -> String {
+    Encoding::Base64.encode(fingerprint)
+}
+
+/// Decode a fingerprint returned by the SQL functions in any encoding.
+pub fn decode_fingerprint(fingerprint: &str) -> Result<Vec<u32>> {
+    crate::decode_fingerprint(fingerprint)
+}
+
+/// A fingerprint as a BLOB of big-endian items, the bytes its base64 TEXT
+/// encodes, as `compare_fingerprints` accepts it.
+pub fn fingerprint_to_blob(fingerprint: &[u32]) -> Vec<u8> {
+    fingerprint.iter().flat_map(|x| x.to_be_bytes()).collect()
+}
+
+/// Decode a BLOB of big-endian fingerprint items.
+pub fn fingerprint_from_blob(blob: &[u8]) -> Result<Vec<u32>> {
+    crate::decode_fingerprint_blob(blob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn test_functions() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+        let a = fingerprint(&path).unwrap();
+        let b = fingerprint_blob(std::fs::read(&path).unwrap(), Some("ogg")).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(compare_fingerprints(&a, &b).unwrap(), 0.0);
+        assert_eq!(decode_fingerprint(&encode_fingerprint(&a)).unwrap(), a);
+        assert_eq!(fingerprint_from_blob(&fingerprint_to_blob(&a)).unwrap(), a);
+
+        let test2 = fingerprint_file(&path, None, Preset::Test2, Some(2)).unwrap();
+        assert!(test2.len() < a.len());
+        assert_eq!(
+            compare_fingerprints_with_preset(&test2, &test2, Preset::Test2).unwrap(),
+            0.0
+        );
+    }
+}
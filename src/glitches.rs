@@ -0,0 +1,352 @@
+//! Click, pop and dropout detection.
+//!
+//! `audio_glitches(path)` returns the defects typical of digitized vinyl
+//! and tape and of faulty transfers:
+//!
+//! ```sql
+//! SELECT time_secs, kind, duration_secs, severity
+//! FROM audio_glitches('side_a.flac') ORDER BY severity DESC;
+//! ```
+//!
+//! `kind` is one of:
+//!
+//! * `'click'`: an impulse shorter than [`POP_SECS`], such as a scratch.
+//! * `'pop'`: a longer impulse, such as a dust particle or a bad splice.
+//! * `'dropout'`: a run of digital silence of at least
+//!   [`MIN_DROPOUT_SECS`] in the middle of audio, such as a buffer underrun.
+//!
+//! Impulses are samples that the two before them predict badly: the
+//! prediction error is more than [`IMPULSE_DB`] above its recent level.
+//! `severity` is how far the event stands out from the audio around it, in
+//! dB: the peak prediction error over its recent level for clicks and pops,
+//! and the level of the interrupted audio over digital silence (-120 dBFS)
+//! for dropouts. Channels are mixed to mono first.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rusqlite::vtab::Context;
+use rusqlite::Connection;
+
+use crate::analysis::{to_dbfs, MonoFrames, MIN_DBFS};
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::tvf::{self, PathFunction};
+
+/// Frame length used for decoding; only affects buffering.
+const FRAME_SECS: f64 = 0.1;
+
+/// Prediction errors this far above their recent level are impulses...
+const IMPULSE_DB: f64 = 20.0;
+
+/// ...if they are also at least this large, where full scale is 1.
+const MIN_IMPULSE: f32 = 0.02;
+
+/// Time constant of the recent prediction error level.
+const ERROR_LEVEL_SECS: f64 = 0.01;
+
+/// Impulses closer together than this are one event.
+const MERGE_SECS: f64 = 0.001;
+
+/// Impulses at least this long are pops rather than clicks.
+const POP_SECS: f64 = 0.001;
+
+/// Runs of digital silence at least this long are dropouts...
+const MIN_DROPOUT_SECS: f64 = 0.002;
+
+/// ...unless they are longer than this, which is more likely a deliberate
+/// pause or a gap between tracks...
+const MAX_DROPOUT_SECS: f64 = 2.0;
+
+/// ...or the audio just before them is quieter than this, as after a fade
+/// out or a decaying note.
+const DROPOUT_LEVEL_DBFS: f64 = -50.0;
+
+/// Time constant of the level of the audio before a dropout; short, so
+/// only an abrupt cut to silence counts.
+const LEVEL_SECS: f64 = 0.005;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    tvf::register_path_function::<Glitches>(db, state)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Click,
+    Pop,
+    Dropout,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Click => "click",
+            Kind::Pop => "pop",
+            Kind::Dropout => "dropout",
+        }
+    }
+}
+
+/// A detected glitch, in samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Glitch {
+    kind: Kind,
+    start: u64,
+    len: u64,
+    severity: f64,
+}
+
+/// An impulse in progress.
+#[derive(Debug, Clone, Copy)]
+struct Impulse {
+    start: u64,
+    last: u64,
+    peak: f32,
+    level: f32,
+}
+
+/// Glitch detector over a stream of mono samples.
+struct Detector {
+    sample_rate: f64,
+    /// The two previous samples, newest first.
+    previous: [f32; 2],
+    /// Recent mean absolute prediction error.
+    error_level: f32,
+    /// Recent mean square sample value, excluding digital silence.
+    power: f32,
+    impulse: Option<Impulse>,
+    /// Start of the current run of digital silence, and the level before it.
+    silence: Option<(u64, f32)>,
+    n: u64,
+    glitches: Vec<Glitch>,
+}
+
+impl Detector {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate as f64,
+            previous: [0.0; 2],
+            error_level: 0.0,
+            power: 0.0,
+            impulse: None,
+            silence: None,
+            n: 0,
+            glitches: Vec::new(),
+        }
+    }
+
+    fn samples(&self, secs: f64) -> u64 {
+        (secs * self.sample_rate).round() as u64
+    }
+
+    /// Weight of a new value in a running mean with time constant `secs`.
+    fn alpha(&self, secs: f64) -> f32 {
+        (1.0 / (secs * self.sample_rate)).min(1.0) as f32
+    }
+
+    fn push(&mut self, x: f32) {
+        let i = self.n;
+        self.n += 1;
+
+        if x == 0.0 {
+            if self.silence.is_none() {
+                self.silence = Some((i, self.power));
+            }
+        } else {
+            if let Some((start, power)) = self.silence.take() {
+                self.end_silence(start, i - start, power);
+            }
+            self.power += self.alpha(LEVEL_SECS) * (x * x - self.power);
+        }
+
+        let error = (x - 2.0 * self.previous[0] + self.previous[1]).abs();
+        self.previous = [x, self.previous[0]];
+        let warmed_up = i >= self.samples(ERROR_LEVEL_SECS);
+        let ratio = 10f32.powf(IMPULSE_DB as f32 / 20.0);
+        if warmed_up && error >= MIN_IMPULSE && error > ratio * self.error_level {
+            let merge = self.samples(MERGE_SECS);
+            match &mut self.impulse {
+                Some(impulse) if i - impulse.last <= merge => {
+                    impulse.last = i;
+                    impulse.peak = impulse.peak.max(error);
+                }
+                _ => {
+                    self.end_impulse();
+                    self.impulse = Some(Impulse {
+                        start: i,
+                        last: i,
+                        peak: error,
+                        level: self.error_level,
+                    });
+                }
+            }
+        } else {
+            self.error_level += self.alpha(ERROR_LEVEL_SECS) * (error - self.error_level);
+        }
+    }
+
+    fn end_impulse(&mut self) {
+        let Some(impulse) = self.impulse.take() else {
+            return;
+        };
+        let len = impulse.last - impulse.start + 1;
+        let kind = if len >= self.samples(POP_SECS) {
+            Kind::Pop
+        } else {
+            Kind::Click
+        };
+        let ratio = impulse.peak as f64 / impulse.level.max(f32::MIN_POSITIVE) as f64;
+        self.glitches.push(Glitch {
+            kind,
+            start: impulse.start,
+            len,
+            severity: (20.0 * ratio.log10()).min(-MIN_DBFS),
+        });
+    }
+
+    fn end_silence(&mut self, start: u64, len: u64, power: f32) {
+        let level = to_dbfs(power.sqrt());
+        if len >= self.samples(MIN_DROPOUT_SECS)
+            && len <= self.samples(MAX_DROPOUT_SECS)
+            && level >= DROPOUT_LEVEL_DBFS
+        {
+            self.glitches.push(Glitch {
+                kind: Kind::Dropout,
+                start,
+                len,
+                severity: level - MIN_DBFS,
+            });
+        }
+    }
+
+    /// The glitches found, in order. Impulses at the edges of dropouts are
+    /// part of the dropout and not reported. A run of silence at the end of
+    /// the stream is not a dropout.
+    fn finish(mut self) -> Vec<Glitch> {
+        self.end_impulse();
+        let margin = self.samples(MERGE_SECS);
+        let dropouts: Vec<(u64, u64)> = self
+            .glitches
+            .iter()
+            .filter(|g| g.kind == Kind::Dropout)
+            .map(|g| (g.start.saturating_sub(margin), g.start + g.len + margin))
+            .collect();
+        let mut glitches: Vec<Glitch> = self
+            .glitches
+            .into_iter()
+            .filter(|g| {
+                g.kind == Kind::Dropout
+                    || !dropouts
+                        .iter()
+                        .any(|&(start, end)| g.start + g.len >= start && g.start <= end)
+            })
+            .collect();
+        glitches.sort_by_key(|g| g.start);
+        glitches
+    }
+}
+
+fn glitches(path: &Path, stats: &mut CallStats) -> Result<(u32, Vec<Glitch>)> {
+    let mut frames = MonoFrames::open(path, FRAME_SECS, stats)?;
+    let mut detector = Detector::new(frames.sample_rate);
+    while let Some(frame) = frames.next_frame(stats)? {
+        for &x in frame {
+            detector.push(x);
+        }
+    }
+    Ok((frames.sample_rate, detector.finish()))
+}
+
+/// The glitches of a file, found once it is decoded.
+struct Glitches {
+    sample_rate: f64,
+    glitches: std::vec::IntoIter<Glitch>,
+}
+
+impl PathFunction for Glitches {
+    const NAME: &'static str = "audio_glitches";
+
+    /// A glitch and the file's sample rate.
+    type Row = (Glitch, f64);
+
+    fn columns() -> Vec<&'static str> {
+        vec!["time_secs", "kind", "duration_secs", "severity"]
+    }
+
+    fn open(path: &Path, stats: &mut CallStats) -> Result<Self> {
+        let (sample_rate, glitches) = glitches(path, stats)?;
+        Ok(Glitches {
+            sample_rate: sample_rate as f64,
+            glitches: glitches.into_iter(),
+        })
+    }
+
+    fn next_row(&mut self, _stats: &mut CallStats) -> Result<Option<Self::Row>> {
+        Ok(self
+            .glitches
+            .next()
+            .map(|glitch| (glitch, self.sample_rate)))
+    }
+
+    fn column(row: &Self::Row, ctx: &mut Context, i: usize) -> rusqlite::Result<()> {
+        let (glitch, sample_rate) = row;
+        match i {
+            0 => ctx.set_result(&(glitch.start as f64 / sample_rate)),
+            1 => ctx.set_result(&glitch.kind.name()),
+            2 => ctx.set_result(&(glitch.len as f64 / sample_rate)),
+            _ => ctx.set_result(&glitch.severity),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(samples: &[f32]) -> Vec<Glitch> {
+        let mut detector = Detector::new(8000);
+        for &x in samples {
+            detector.push(x);
+        }
+        detector.finish()
+    }
+
+    fn sine(n: usize) -> Vec<f32> {
+        (0..n)
+            .map(|i| 0.3 * (2.0 * std::f32::consts::PI * 200.0 * i as f32 / 8000.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_click_and_pop() {
+        let mut samples = sine(8000);
+        samples[2000] += 0.5;
+        // A burst of 2 ms.
+        for (i, s) in samples[5000..5016].iter_mut().enumerate() {
+            *s += if i % 2 == 0 { 0.4 } else { -0.4 };
+        }
+
+        let glitches = detect(&samples);
+        let found: Vec<(Kind, u64)> = glitches.iter().map(|g| (g.kind, g.start)).collect();
+        assert_eq!(found, [(Kind::Click, 2000), (Kind::Pop, 5000)]);
+        assert!(glitches[0].severity > IMPULSE_DB, "{glitches:?}");
+
+        assert!(detect(&sine(8000)).is_empty());
+    }
+
+    #[test]
+    fn test_dropout() {
+        let mut samples = sine(8000);
+        samples[3000..3400].fill(0.0);
+        // Trailing silence is the end of the recording, not a dropout.
+        samples.extend([0.0; 800]);
+
+        let glitches = detect(&samples);
+        assert_eq!(glitches.len(), 1, "{glitches:?}");
+        assert_eq!(
+            (glitches[0].kind, glitches[0].start, glitches[0].len),
+            (Kind::Dropout, 3000, 400)
+        );
+    }
+}
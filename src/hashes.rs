@@ -0,0 +1,127 @@
+//! A fingerprint's items as rows.
+//!
+//! `fp_hashes(fp)` returns each 32-bit item of a fingerprint in any encoding
+//! the functions accept, with its position, for indexing them in plain SQL:
+//!
+//! ```sql
+//! CREATE TABLE hash_index AS
+//! SELECT h.hash, t.id AS track_id, h.idx
+//! FROM tracks t, fp_hashes(t.fingerprint) h;
+//! ```
+//!
+//! `idx` counts from 0, and `hash` is the item as an unsigned integer.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+
+use rusqlite::types::Type;
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+
+use crate::panic::catch_panic;
+use crate::{fingerprint_and_preset_from_value, tvf};
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 2;
+
+pub(crate) fn register(db: &Connection) -> rusqlite::Result<()> {
+    db.create_module("fp_hashes", eponymous_only_module::<HashesTab>(), None)
+}
+
+#[repr(C)]
+struct HashesTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for HashesTab {
+    type Aux = ();
+    type Cursor = HashesCursor<'vtab>;
+
+    fn connect(
+        _db: &mut VTabConnection,
+        _aux: Option<&()>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            Ok((
+                "CREATE TABLE x(idx, hash, fp HIDDEN)".to_owned(),
+                HashesTab {
+                    base: ffi::sqlite3_vtab::default(),
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| tvf::bind_arguments(info, "fp_hashes", FIRST_ARGUMENT, &["fp"]))
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<HashesCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(HashesCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                fingerprint: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct HashesCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    fingerprint: Vec<u32>,
+    index: usize,
+    phantom: PhantomData<&'vtab HashesTab>,
+}
+
+unsafe impl VTabCursor for HashesCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.fingerprint = Vec::new();
+            self.index = 0;
+
+            let Some(value) = args.iter().next() else {
+                return Ok(());
+            };
+            if value.data_type() == Type::Null {
+                return Ok(());
+            }
+            (_, self.fingerprint) = fingerprint_and_preset_from_value(0, "fp", value)?;
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.fingerprint.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| match i {
+            0 => ctx.set_result(&(self.index as i64)),
+            1 => ctx.set_result(&i64::from(self.fingerprint[self.index])),
+            _ => ctx.set_result(&rusqlite::types::Null),
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
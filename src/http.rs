@@ -0,0 +1,357 @@
+//! Fingerprints of files on HTTP servers.
+//!
+//! With the `net` feature, `fingerprint_url(url)` fingerprints an audio file
+//! served over HTTP or HTTPS without downloading it first:
+//!
+//! ```sql
+//! UPDATE tracks SET fingerprint = fingerprint_url('https://media.example.com/' || key);
+//! ```
+//!
+//! The file is read with range requests, so that formats which seek while
+//! probing, such as Ogg, only fetch the parts they read. Servers that don't
+//! support ranges are read from start to end. The format is taken from the
+//! response's `Content-Type`, or else the URL's extension, and probed if
+//! neither is known. The fingerprint is made like `fingerprint(path)`'s, with
+//! the `preset` option's preset, limited by `max_length_secs` and encoded
+//! according to the `encoding` option.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::Connection;
+use symphonia::core::io::MediaSource;
+
+use crate::panic::guard;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::{fingerprint_source, limits, source};
+
+/// Timeout of connecting to the server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Timeout of each read from the server.
+pub(crate) const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Forward seeks of at most this many bytes read through the response
+/// instead of making a new request.
+const MAX_SKIP: u64 = 64 * 1024;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_scalar_function(
+        "fingerprint_url",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+        guard(move |ctx| {
+            let url = ctx.get::<String>(0)?;
+
+            let (encoding, preset, max_length_secs) = {
+                let options = state.options();
+                (options.encoding, options.preset, options.max_length_secs)
+            };
+            let mut stats = state.call_stats();
+            let fingerprint = fingerprint_url(&url, preset, max_length_secs, &mut stats)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            state.record_timing("fingerprint_url", &url, stats);
+            let fingerprint = encoding.encode(&fingerprint);
+            limits::check_length(ctx, fingerprint.len())?;
+
+            Ok(fingerprint)
+        }),
+    )
+}
+
+/// Fingerprint the file at `url`.
+fn fingerprint_url(
+    url: &str,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    stats: &mut CallStats,
+) -> Result<Vec<u32>> {
+    let source = HttpSource::open(url)?;
+    let format = source
+        .content_type
+        .clone()
+        .or_else(|| source::extension(url).map(str::to_owned));
+    fingerprint_source(
+        Box::new(source),
+        format.as_deref(),
+        preset,
+        max_length_secs,
+        stats,
+    )
+}
+
+/// An agent with this module's timeouts.
+pub(crate) fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .build()
+}
+
+/// Send a GET request for `url`, which must be an HTTP or HTTPS URL, with
+/// the `Range` header `range` if given.
+pub(crate) fn get(agent: &ureq::Agent, url: &str, range: Option<&str>) -> Result<ureq::Response> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        bail!("Not an HTTP or HTTPS URL: {url}");
+    }
+    let mut request = agent.get(url);
+    if let Some(range) = range {
+        request = request.set("Range", range);
+    }
+    match request.call() {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(status, _)) => bail!("Failed to open {url}: HTTP {status}"),
+        Err(e) => bail!("Failed to open {url}: {e}"),
+    }
+}
+
+/// The media type of a response, without parameters, if it's a specific one.
+pub(crate) fn content_type(response: &ureq::Response) -> Option<&str> {
+    let content_type = response.header("Content-Type")?;
+    let media_type = content_type.split(';').next()?.trim();
+    (media_type.contains('/') && media_type != "application/octet-stream").then_some(media_type)
+}
+
+/// The total length in a `Content-Range` header, e.g. `bytes 0-99/1000`.
+fn content_range_len(content_range: &str) -> Option<u64> {
+    let (unit, range) = content_range.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    range.rsplit_once('/')?.1.parse().ok()
+}
+
+/// A file on an HTTP server, read with range requests where the server
+/// supports them.
+struct HttpSource {
+    agent: ureq::Agent,
+    url: String,
+    content_type: Option<String>,
+    /// Length of the file, if the server told it.
+    len: Option<u64>,
+    /// Whether the server accepts range requests.
+    seekable: bool,
+    /// Offset of the next byte to read.
+    pos: u64,
+    /// Body of the response being read, positioned at `pos`, or `None` if a
+    /// new request has to be made.
+    body: Option<Box<dyn Read + Send + Sync>>,
+}
+
+impl HttpSource {
+    fn open(url: &str) -> Result<Self> {
+        let agent = agent();
+        let response = get(&agent, url, Some("bytes=0-"))?;
+        let content_type = content_type(&response).map(str::to_owned);
+        let (len, seekable) = if response.status() == 206 {
+            let len = response.header("Content-Range").and_then(content_range_len);
+            (len, len.is_some())
+        } else {
+            let len = response
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok());
+            (len, false)
+        };
+        Ok(Self {
+            agent,
+            url: url.to_owned(),
+            content_type,
+            len,
+            seekable,
+            pos: 0,
+            body: Some(response.into_reader()),
+        })
+    }
+
+    /// The body of the response from `pos`, requesting it if needed.
+    fn body(&mut self) -> io::Result<&mut Box<dyn Read + Send + Sync>> {
+        if self.body.is_none() {
+            let range = format!("bytes={}-", self.pos);
+            let response = get(&self.agent, &self.url, Some(&range)).map_err(io::Error::other)?;
+            if response.status() != 206 {
+                return Err(io::Error::other(format!(
+                    "Range request for {} failed: HTTP {}",
+                    self.url,
+                    response.status()
+                )));
+            }
+            self.body = Some(response.into_reader());
+        }
+        Ok(self.body.as_mut().expect("body was just set"))
+    }
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.len.is_some_and(|len| self.pos >= len) {
+            return Ok(0);
+        }
+        let n = self.body()?.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(offset) => match self.len {
+                Some(len) => len.checked_add_signed(offset),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "length of the file is unknown",
+                    ))
+                }
+            },
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            ));
+        };
+
+        if target >= self.pos && target - self.pos <= MAX_SKIP && self.body.is_some() {
+            let skip = target - self.pos;
+            let skipped = io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+            if skipped == skip {
+                return Ok(self.pos);
+            }
+        }
+        if target != self.pos {
+            if !self.seekable {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "server doesn't support range requests",
+                ));
+            }
+            self.body = None;
+            self.pos = target;
+        }
+        Ok(self.pos)
+    }
+}
+
+impl MediaSource for HttpSource {
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+
+    use super::*;
+
+    /// Serve `body` over HTTP until the test ends, honouring `Range`
+    /// headers if `ranges`, returning the URL.
+    fn serve(body: Vec<u8>, ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/track", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut start = None;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some(range) = line.strip_prefix("Range: bytes=") {
+                        start = range.trim().trim_end_matches('-').parse::<usize>().ok();
+                    }
+                    line.clear();
+                }
+                let header = match start.filter(|_| ranges) {
+                    Some(start) => format!(
+                        "HTTP/1.0 206 Partial Content\r\nContent-Length: {}\r\n\
+                         Content-Range: bytes {start}-{}/{}\r\n\r\n",
+                        body.len() - start,
+                        body.len() - 1,
+                        body.len()
+                    ),
+                    None => format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()),
+                };
+                let start = start.filter(|_| ranges).unwrap_or(0);
+                let _ = stream.write_all(header.as_bytes());
+                let _ = stream.write_all(&body[start..]);
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_content_range_len() {
+        assert_eq!(content_range_len("bytes 0-99/1000"), Some(1000));
+        assert_eq!(content_range_len("bytes 0-99/*"), None);
+        assert_eq!(content_range_len("items 0-99/1000"), None);
+    }
+
+    #[test]
+    fn test_http_source() {
+        let data: Vec<u8> = (0..=255).cycle().take(300_000).collect();
+        let mut source = HttpSource::open(&serve(data.clone(), true)).unwrap();
+        assert!(source.is_seekable());
+        assert_eq!(source.byte_len(), Some(data.len() as u64));
+
+        let mut buf = [0; 16];
+        for pos in [
+            SeekFrom::Start(200_000),
+            SeekFrom::Current(100),
+            SeekFrom::End(-16),
+        ] {
+            let offset = source.seek(pos).unwrap() as usize;
+            source.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data[offset..offset + 16]);
+        }
+        assert_eq!(source.read(&mut buf).unwrap(), 0);
+
+        let mut source = HttpSource::open(&serve(data.clone(), false)).unwrap();
+        assert!(!source.is_seekable());
+        assert_eq!(source.seek(SeekFrom::Start(1000)).unwrap(), 1000);
+        assert!(source.seek(SeekFrom::Start(0)).is_err());
+    }
+
+    #[test]
+    fn test_fingerprint_url() {
+        for (file, ranges) in [("XC444467.ogg", true), ("XC444467.mp3", false)] {
+            let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+            let path = Path::new(&manifest_dir).join("src/testdata").join(file);
+            let expected = crate::fingerprint_path_inspecting(
+                &path,
+                None,
+                Preset::default(),
+                None,
+                &mut CallStats::default(),
+                &mut |_| {},
+            )
+            .unwrap();
+
+            let url = serve(std::fs::read(&path).unwrap(), ranges);
+            let fingerprint =
+                fingerprint_url(&url, Preset::default(), None, &mut CallStats::default()).unwrap();
+            assert_eq!(fingerprint, expected, "{file}");
+        }
+
+        assert!(fingerprint_url(
+            "file:///etc/passwd",
+            Preset::default(),
+            None,
+            &mut CallStats::default()
+        )
+        .is_err());
+    }
+}
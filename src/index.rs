@@ -0,0 +1,820 @@
+//! An inverted index for finding matching fingerprints in a large library.
+//!
+//! Comparing a fingerprint with each of a library's takes a full scan, too
+//! slow for large libraries. A `chromaprint_index` table keeps its
+//! fingerprints indexed by their items, and `MATCH` finds the ones matching
+//! a query, ranked by their `compare_fingerprints` score, using the index:
+//!
+//! ```sql
+//! CREATE VIRTUAL TABLE fp_index USING chromaprint_index();
+//! INSERT INTO fp_index(rowid, fp) SELECT id, fingerprint FROM tracks;
+//! SELECT rowid, score FROM fp_index WHERE fp MATCH fingerprint('clip.mp3');
+//! ```
+//!
+//! Fingerprints are inserted, updated and deleted like rows of any table, in
+//! any encoding the functions accept; reading `fp` returns them in the
+//! `encoding` option's. Only queries with `MATCH` set `score`, from 0 for the
+//! most similar to 32, under the `match_mode` option.
+//!
+//! Matching looks up each item of the query, with its lowest
+//! [`IGNORED_BITS`] bits ignored as AcoustID does, and then compares the query
+//! with the [`MAX_CANDIDATES`] fingerprints sharing the most of them,
+//! returning those that match. Fingerprints sharing no item, e.g. made with
+//! another preset, aren't found.
+//!
+//! The fingerprints are stored in the shadow table `<name>_data`, the index
+//! in `<name>_hashes`, and the arguments in `<name>_config`; they are renamed
+//! with the table. Fingerprints are compared with the preset given as an
+//! argument, `chromaprint_index(preset=test2)`, or else the `preset` option.
+
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::sync::{Arc, OnceLock};
+
+use rusqlite::types::{Type, Value, ValueRef};
+use rusqlite::vtab::{
+    escape_double_quote, parameter, update_module, Context, CreateVTab, IndexConstraintOp,
+    IndexInfo, Module, UpdateVTab, VTab, VTabConnection, VTabCursor, VTabKind, Values,
+};
+use rusqlite::{ffi, params, Connection, OptionalExtension};
+
+use crate::panic::catch_panic;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::{
+    compare_fingerprints, decode_fingerprint_blob, fingerprint_and_preset_from_value, functions,
+};
+
+/// Low bits of each item ignored when indexing and looking it up, so that
+/// items differing only in them still match.
+pub(crate) const IGNORED_BITS: u32 = 4;
+
+/// Most fingerprints compared with a query, those sharing the most items.
+pub(crate) const MAX_CANDIDATES: usize = 100;
+
+/// Plan of a scan of every fingerprint.
+const FULL_SCAN: c_int = 0;
+/// Plan of a scan of the fingerprints matching the argument.
+const MATCH_SCAN: c_int = 1;
+/// Plan of a lookup of the fingerprint with the argument as rowid.
+const ROWID_LOOKUP: c_int = 2;
+/// Bits of a plan's `idx_num` holding the plan itself.
+const PLAN_MASK: c_int = 0b11;
+/// Flag of a plan given the query's `LIMIT` as an argument.
+const LIMITED: c_int = 0b100;
+/// Flag of a plan given the query's `OFFSET` as an argument.
+const OFFSET: c_int = 0b1000;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module("chromaprint_index", module(), Some(state))
+}
+
+/// The module of [`IndexTab`]: rusqlite's [`update_module`], with the
+/// `xRename` method it doesn't implement.
+fn module() -> &'static Module<'static, IndexTab> {
+    static MODULE: OnceLock<ffi::sqlite3_module> = OnceLock::new();
+    let module = MODULE.get_or_init(|| {
+        let module: *const Module<'static, IndexTab> = update_module::<IndexTab>();
+        // SAFETY: `Module` is a transparent wrapper of `sqlite3_module`.
+        let mut module = unsafe { *module.cast::<ffi::sqlite3_module>() };
+        module.xRename = Some(rust_rename);
+        module
+    });
+    // SAFETY: As above.
+    unsafe { &*(module as *const ffi::sqlite3_module).cast::<Module<'static, IndexTab>>() }
+}
+
+/// `xRename`: rename the shadow tables of `vtab`, an [`IndexTab`], with it.
+unsafe extern "C" fn rust_rename(vtab: *mut ffi::sqlite3_vtab, new_name: *const c_char) -> c_int {
+    // SAFETY: SQLite passes the table created by `IndexTab::create` or
+    // `IndexTab::connect`, whose base class is first.
+    let table = &mut *vtab.cast::<IndexTab>();
+    let result = catch_panic(|| table.rename(CStr::from_ptr(new_name).to_str()?));
+    let Err(err) = result else {
+        return ffi::SQLITE_OK;
+    };
+    let message = err.to_string();
+    let copy = ffi::sqlite3_malloc(message.len() as c_int + 1).cast::<u8>();
+    if !copy.is_null() {
+        ptr::copy_nonoverlapping(message.as_ptr(), copy, message.len());
+        *copy.add(message.len()) = 0;
+        ffi::sqlite3_free((*vtab).zErrMsg.cast());
+        (*vtab).zErrMsg = copy.cast();
+    }
+    match err {
+        rusqlite::Error::SqliteFailure(e, _) => e.extended_code,
+        _ => ffi::SQLITE_ERROR,
+    }
+}
+
+/// Shadow table `suffix` of the table `name` in `schema`, quoted and
+/// qualified with the schema.
+fn shadow_table(schema: &str, name: &str, suffix: &str) -> String {
+    format!(
+        "\"{}\".\"{}_{suffix}\"",
+        escape_double_quote(schema),
+        escape_double_quote(name)
+    )
+}
+
+/// The distinct items of `fingerprint` as looked up in the index.
+pub(crate) fn hashes(fingerprint: &[u32]) -> BTreeSet<i64> {
+    fingerprint
+        .iter()
+        .map(|&item| i64::from(item >> IGNORED_BITS))
+        .collect()
+}
+
+/// The [`MAX_CANDIDATES`] ids with the most `hits`, from the most.
+fn candidates(hits: HashMap<i64, usize>) -> Vec<i64> {
+    let mut candidates: Vec<(i64, usize)> = hits.into_iter().collect();
+    candidates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    candidates.truncate(MAX_CANDIDATES);
+    candidates.into_iter().map(|(id, _)| id).collect()
+}
+
+/// Arguments of a `chromaprint_index` table.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Arguments {
+    /// Preset to compare fingerprints with, if given.
+    pub(crate) preset: Option<Preset>,
+}
+
+impl Arguments {
+    fn parse<'a>(arguments: impl IntoIterator<Item = &'a [u8]>) -> rusqlite::Result<Self> {
+        let mut parsed = Self::default();
+        for argument in arguments {
+            match parameter(argument)? {
+                ("preset", value) => {
+                    parsed.preset = Some(
+                        Preset::parse(value)
+                            .map_err(|e| rusqlite::Error::ModuleError(e.to_string()))?,
+                    );
+                }
+                (key, _) => {
+                    return Err(rusqlite::Error::ModuleError(format!(
+                        "chromaprint_index: unknown argument {key}"
+                    )))
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// The arguments `table` was created with, if it is a `chromaprint_index`
+/// table.
+pub(crate) fn table_arguments(db: &Connection, table: &str) -> rusqlite::Result<Option<Arguments>> {
+    let sql: Option<String> = db
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+    let is_index = sql.is_some_and(|sql| {
+        let sql = sql.to_ascii_lowercase();
+        sql.starts_with("create virtual table") && sql.contains("using chromaprint_index")
+    });
+    if !is_index {
+        return Ok(None);
+    }
+    let preset: Option<String> = db
+        .query_row(
+            &format!(
+                "SELECT value FROM {} WHERE key = 'preset'",
+                shadow_table("main", table, "config")
+            ),
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let preset = preset
+        .map(|preset| Preset::parse(&preset))
+        .transpose()
+        .map_err(|e| rusqlite::Error::ModuleError(e.to_string()))?;
+    Ok(Some(Arguments { preset }))
+}
+
+/// The fingerprints of the `chromaprint_index` table `index` sharing items
+/// with `query`, with their rowids.
+pub(crate) fn matching_fingerprints(
+    db: &Connection,
+    index: &str,
+    query: &[u32],
+) -> rusqlite::Result<Vec<(Value, Vec<u32>)>> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT rowid, fp FROM \"{}\" WHERE fp MATCH ?1",
+        escape_double_quote(index)
+    ))?;
+    let mut rows = stmt.query([functions::fingerprint_to_blob(query)])?;
+    let mut fingerprints = Vec::new();
+    while let Some(row) = rows.next()? {
+        let (_, fingerprint) = fingerprint_and_preset_from_value(1, "fp", row.get_ref(1)?)?;
+        fingerprints.push((row.get(0)?, fingerprint));
+    }
+    Ok(fingerprints)
+}
+
+/// Value `i` of the arguments of `xUpdate` or `xFilter`.
+fn column<'a>(args: &'a Values<'_>, i: usize) -> rusqlite::Result<ValueRef<'a>> {
+    args.iter()
+        .nth(i)
+        .ok_or(rusqlite::Error::InvalidColumnIndex(i))
+}
+
+#[repr(C)]
+struct IndexTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+    /// Schema of the table.
+    schema: String,
+    /// The shadow tables, quoted and qualified with the schema.
+    data: String,
+    hashes: String,
+    config: String,
+    /// Preset to compare fingerprints with, if given as an argument.
+    preset: Option<Preset>,
+    /// Number of stored fingerprints, counted when first needed to plan a
+    /// query and then kept up to date by this connection's writes.
+    len: Cell<Option<i64>>,
+}
+
+impl IndexTab {
+    /// The connection, for reading and writing the shadow tables.
+    fn connection(&self) -> rusqlite::Result<Connection> {
+        // SAFETY: the handle outlives the virtual table, which is
+        // disconnected before the connection is closed. The connection does
+        // not close the handle when dropped.
+        unsafe { Connection::from_handle(self.db) }
+    }
+
+    /// The stored fingerprint with rowid `id`, if there is one.
+    fn fingerprint(&self, id: i64) -> rusqlite::Result<Option<Vec<u32>>> {
+        self.connection()?
+            .query_row(
+                &format!("SELECT fp FROM {} WHERE id = ?1", self.data),
+                [id],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?
+            .map(|fp| decode_fingerprint_blob(&fp))
+            .transpose()
+            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+    }
+
+    /// Store `fingerprint` with rowid `id`, or a new one if `None`.
+    fn store(&self, id: Option<i64>, fingerprint: &[u32]) -> rusqlite::Result<i64> {
+        let db = self.connection()?;
+        db.execute(
+            &format!("INSERT INTO {}(id, fp) VALUES (?1, ?2)", self.data),
+            params![id, functions::fingerprint_to_blob(fingerprint)],
+        )?;
+        let id = id.unwrap_or_else(|| db.last_insert_rowid());
+        let mut insert = db.prepare(&format!(
+            "INSERT INTO {}(hash, id) VALUES (?1, ?2)",
+            self.hashes
+        ))?;
+        for hash in hashes(fingerprint) {
+            insert.execute([hash, id])?;
+        }
+        self.add_len(1);
+        Ok(id)
+    }
+
+    /// Number of stored fingerprints, an estimate if other connections
+    /// wrote to the table or a write was rolled back since it was counted.
+    fn len(&self) -> rusqlite::Result<i64> {
+        if let Some(len) = self.len.get() {
+            return Ok(len);
+        }
+        let len = self.connection()?.query_row(
+            &format!("SELECT count(*) FROM {}", self.data),
+            [],
+            |row| row.get(0),
+        )?;
+        self.len.set(Some(len));
+        Ok(len)
+    }
+
+    /// Count `delta` more stored fingerprints, if they were counted.
+    fn add_len(&self, delta: i64) {
+        self.len.set(self.len.get().map(|len| len + delta));
+    }
+
+    /// Rename the shadow tables for the table's new name.
+    fn rename(&mut self, new_name: &str) -> rusqlite::Result<()> {
+        let db = self.connection()?;
+        for (table, suffix) in [
+            (&self.data, "data"),
+            (&self.hashes, "hashes"),
+            (&self.config, "config"),
+        ] {
+            db.execute_batch(&format!(
+                "ALTER TABLE {table} RENAME TO \"{}_{suffix}\"",
+                escape_double_quote(new_name)
+            ))?;
+        }
+        self.data = shadow_table(&self.schema, new_name, "data");
+        self.hashes = shadow_table(&self.schema, new_name, "hashes");
+        self.config = shadow_table(&self.schema, new_name, "config");
+        Ok(())
+    }
+
+    /// Remove the fingerprint with rowid `id`, if there is one.
+    fn remove(&self, id: i64) -> rusqlite::Result<()> {
+        let Some(fingerprint) = self.fingerprint(id)? else {
+            return Ok(());
+        };
+        let db = self.connection()?;
+        let mut delete = db.prepare(&format!(
+            "DELETE FROM {} WHERE hash = ?1 AND id = ?2",
+            self.hashes
+        ))?;
+        for hash in hashes(&fingerprint) {
+            delete.execute([hash, id])?;
+        }
+        db.execute(&format!("DELETE FROM {} WHERE id = ?1", self.data), [id])?;
+        self.add_len(-1);
+        Ok(())
+    }
+
+    /// The stored fingerprints matching `query`, with their scores, from the
+    /// most similar.
+    fn search(&self, query: &[u32], preset: Preset) -> rusqlite::Result<Vec<(i64, Option<f64>)>> {
+        let db = self.connection()?;
+        let mut lookup = db.prepare(&format!("SELECT id FROM {} WHERE hash = ?1", self.hashes))?;
+        let mut hits = HashMap::new();
+        for hash in hashes(query) {
+            let mut rows = lookup.query([hash])?;
+            while let Some(row) = rows.next()? {
+                *hits.entry(row.get::<_, i64>(0)?).or_insert(0) += 1;
+            }
+        }
+
+        let mode = self.state.options().match_mode;
+        let mut matches = Vec::new();
+        for id in candidates(hits) {
+            let Some(fingerprint) = self.fingerprint(id)? else {
+                continue;
+            };
+            let score = compare_fingerprints(query, &fingerprint, preset, mode)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            if let Some(score) = score {
+                matches.push((id, Some(score)));
+            }
+        }
+        matches.sort_by(|a, b| a.1.unwrap_or(0.0).total_cmp(&b.1.unwrap_or(0.0)));
+        Ok(matches)
+    }
+}
+
+unsafe impl<'vtab> VTab<'vtab> for IndexTab {
+    type Aux = Arc<State>;
+    type Cursor = IndexCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("chromaprint_index: missing state".to_owned())
+            })?;
+            let [_, schema, name, arguments @ ..] = args else {
+                return Err(rusqlite::Error::ModuleError(
+                    "chromaprint_index: missing table name".to_owned(),
+                ));
+            };
+            let schema = std::str::from_utf8(schema)?;
+            let name = std::str::from_utf8(name)?;
+
+            let preset = Arguments::parse(arguments.iter().copied())?.preset;
+
+            Ok((
+                "CREATE TABLE x(fp, score HIDDEN)".to_owned(),
+                IndexTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    // SAFETY: see `IndexTab::connection`.
+                    db: unsafe { db.handle() },
+                    state,
+                    schema: schema.to_owned(),
+                    data: shadow_table(schema, name, "data"),
+                    hashes: shadow_table(schema, name, "hashes"),
+                    config: shadow_table(schema, name, "config"),
+                    preset,
+                    len: Cell::new(None),
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let mut plan = None;
+            let mut limit = None;
+            let mut offset = None;
+            for (i, constraint) in info.constraints().enumerate() {
+                if !constraint.is_usable() {
+                    continue;
+                }
+                match (constraint.column(), constraint.operator()) {
+                    (0, IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_MATCH) => {
+                        plan = Some((i, MATCH_SCAN));
+                    }
+                    (-1, IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ)
+                        if !matches!(plan, Some((_, MATCH_SCAN))) =>
+                    {
+                        plan = Some((i, ROWID_LOOKUP));
+                    }
+                    (_, IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LIMIT) => limit = Some(i),
+                    (_, IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_OFFSET) => offset = Some(i),
+                    _ => {}
+                }
+            }
+
+            let len = self.len()?;
+            let mut argv_index = 0;
+            let mut idx_num = match plan {
+                Some((constraint, idx_num)) => {
+                    argv_index += 1;
+                    let mut usage = info.constraint_usage(constraint);
+                    usage.set_argv_index(argv_index);
+                    usage.set_omit(true);
+                    idx_num
+                }
+                None => FULL_SCAN,
+            };
+            let mut ordered = info.num_of_order_by() == 0;
+            match idx_num {
+                MATCH_SCAN => {
+                    info.set_estimated_cost(1e3);
+                    info.set_estimated_rows(len.min(MAX_CANDIDATES as i64));
+                    // Matches are returned from the most similar.
+                    let mut order_bys = info.order_bys();
+                    if let (Some(order_by), None) = (order_bys.next(), order_bys.next()) {
+                        if order_by.column() == 1 && !order_by.is_order_by_desc() {
+                            info.set_order_by_consumed(true);
+                            ordered = true;
+                        }
+                    }
+                }
+                ROWID_LOOKUP => {
+                    info.set_estimated_cost(1.0);
+                    info.set_estimated_rows(1);
+                }
+                _ => {
+                    info.set_estimated_cost(len.max(1) as f64);
+                    info.set_estimated_rows(len);
+                }
+            }
+
+            // The rows past the limit can only be skipped if SQLite doesn't
+            // reorder them. SQLite still applies the limit and offset itself.
+            if let (Some(limit), true) = (limit, ordered && idx_num != ROWID_LOOKUP) {
+                argv_index += 1;
+                info.constraint_usage(limit).set_argv_index(argv_index);
+                idx_num |= LIMITED;
+                if let Some(offset) = offset {
+                    argv_index += 1;
+                    info.constraint_usage(offset).set_argv_index(argv_index);
+                    idx_num |= OFFSET;
+                }
+            }
+            info.set_idx_num(idx_num);
+            Ok(())
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<IndexCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(IndexCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                table: self,
+                rows: Vec::new(),
+                index: 0,
+            })
+        })
+    }
+}
+
+impl CreateVTab<'_> for IndexTab {
+    const KIND: VTabKind = VTabKind::Default;
+
+    fn create(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        let (schema, table) = Self::connect(db, aux, args)?;
+        catch_panic(|| {
+            let db = table.connection()?;
+            db.execute_batch(&format!(
+                "CREATE TABLE {}(id INTEGER PRIMARY KEY, fp BLOB NOT NULL);
+                 CREATE TABLE {}(hash INTEGER NOT NULL, id INTEGER NOT NULL,
+                                 PRIMARY KEY (hash, id)) WITHOUT ROWID;
+                 CREATE TABLE {}(key TEXT PRIMARY KEY, value) WITHOUT ROWID;",
+                table.data, table.hashes, table.config
+            ))?;
+            if let Some(preset) = table.preset {
+                db.execute(
+                    &format!(
+                        "INSERT INTO {}(key, value) VALUES ('preset', ?1)",
+                        table.config
+                    ),
+                    [preset.name()],
+                )?;
+            }
+            Ok(())
+        })?;
+        Ok((schema, table))
+    }
+
+    fn destroy(&self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.connection()?.execute_batch(&format!(
+                "DROP TABLE IF EXISTS {}; DROP TABLE IF EXISTS {}; DROP TABLE IF EXISTS {};",
+                self.data, self.hashes, self.config
+            ))
+        })
+    }
+}
+
+impl UpdateVTab<'_> for IndexTab {
+    fn delete(&mut self, arg: ValueRef<'_>) -> rusqlite::Result<()> {
+        catch_panic(|| self.remove(arg.as_i64()?))
+    }
+
+    fn insert(&mut self, args: &Values<'_>) -> rusqlite::Result<i64> {
+        catch_panic(|| {
+            let id = args.get::<Option<i64>>(1)?;
+            let (_, fingerprint) = fingerprint_and_preset_from_value(0, "fp", column(args, 2)?)?;
+            self.store(id, &fingerprint)
+        })
+    }
+
+    fn update(&mut self, args: &Values<'_>) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let old_id = args.get::<i64>(0)?;
+            let id = args.get::<i64>(1)?;
+            let (_, fingerprint) = fingerprint_and_preset_from_value(0, "fp", column(args, 2)?)?;
+            self.remove(old_id)?;
+            self.store(Some(id), &fingerprint)?;
+            Ok(())
+        })
+    }
+}
+
+#[repr(C)]
+struct IndexCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    table: &'vtab IndexTab,
+    /// Rowids of the rows and their scores, if matched.
+    rows: Vec<(i64, Option<f64>)>,
+    index: usize,
+}
+
+unsafe impl VTabCursor for IndexCursor<'_> {
+    fn filter(
+        &mut self,
+        idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index = 0;
+            // The limit and offset follow the plan's argument. A negative
+            // limit is no limit.
+            let first = if idx_num & PLAN_MASK == FULL_SCAN {
+                0
+            } else {
+                1
+            };
+            let limit = if idx_num & LIMITED != 0 {
+                let offset = if idx_num & OFFSET != 0 {
+                    args.get::<Option<i64>>(first + 1)?.unwrap_or(0).max(0)
+                } else {
+                    0
+                };
+                args.get::<Option<i64>>(first)?
+                    .and_then(|limit| usize::try_from(limit).ok())
+                    .map(|limit| limit.saturating_add(offset as usize))
+            } else {
+                None
+            };
+
+            self.rows = match idx_num & PLAN_MASK {
+                MATCH_SCAN => {
+                    let value = column(args, 0)?;
+                    if value.data_type() == Type::Null {
+                        Vec::new()
+                    } else {
+                        let (made_with, query) = fingerprint_and_preset_from_value(0, "fp", value)?;
+                        let preset = self
+                            .table
+                            .preset
+                            .or(made_with)
+                            .unwrap_or(self.table.state.options().preset);
+                        let mut matches = self.table.search(&query, preset)?;
+                        if let Some(limit) = limit {
+                            matches.truncate(limit);
+                        }
+                        matches
+                    }
+                }
+                ROWID_LOOKUP => {
+                    let id = args.get::<Option<i64>>(0)?;
+                    match id {
+                        Some(id) if self.table.fingerprint(id)?.is_some() => vec![(id, None)],
+                        _ => Vec::new(),
+                    }
+                }
+                _ => {
+                    let db = self.table.connection()?;
+                    let mut stmt = db.prepare(&format!(
+                        "SELECT id FROM {} ORDER BY id LIMIT ?1",
+                        self.table.data
+                    ))?;
+                    let limit = limit.map_or(-1, |limit| i64::try_from(limit).unwrap_or(-1));
+                    let ids = stmt.query_map([limit], |row| Ok((row.get(0)?, None)))?;
+                    ids.collect::<rusqlite::Result<_>>()?
+                }
+            };
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let (id, score) = self.rows[self.index];
+            match i {
+                0 => {
+                    let fingerprint = self.table.fingerprint(id)?.unwrap_or_default();
+                    let encoding = self.table.state.options().encoding;
+                    ctx.set_result(&encoding.encode(&fingerprint))
+                }
+                _ => ctx.set_result(&score),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.rows[self.index].0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashes_and_candidates() {
+        let fingerprint = [0x1234_5670, 0x1234_567f, 0xffff_ffff];
+        assert_eq!(
+            hashes(&fingerprint).into_iter().collect::<Vec<_>>(),
+            [0x0123_4567, 0x0fff_ffff]
+        );
+
+        let mut hits: HashMap<i64, usize> =
+            (0..MAX_CANDIDATES as i64 + 10).map(|id| (id, 1)).collect();
+        hits.insert(500, 7);
+        hits.insert(400, 3);
+        let candidates = candidates(hits);
+        assert_eq!(candidates.len(), MAX_CANDIDATES);
+        assert_eq!(candidates[..3], [500, 400, 0]);
+    }
+
+    #[cfg(not(feature = "extension"))]
+    #[test]
+    fn test_index_table() {
+        fn noise(seed: u32, len: usize) -> Vec<u32> {
+            let mut x = seed.wrapping_mul(2654435761) | 1;
+            (0..len)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    x
+                })
+                .collect()
+        }
+        let blob = |fingerprint: &[u32]| functions::fingerprint_to_blob(fingerprint);
+        let rowids = |db: &Connection, sql: &str, query: &[u32]| -> Vec<i64> {
+            let mut stmt = db.prepare(sql).unwrap();
+            let rows = stmt
+                .query_map([blob(query)], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap();
+            rows
+        };
+
+        let db = Connection::open_in_memory().unwrap();
+        register(&db, Arc::new(State::default())).unwrap();
+        db.execute_batch("CREATE VIRTUAL TABLE idx USING chromaprint_index()")
+            .unwrap();
+
+        let track = noise(1, 1000);
+        let mut edited = track.clone();
+        for item in &mut edited[..] {
+            *item ^= 0b11;
+        }
+        let query = track[200..600].to_vec();
+        for (id, fingerprint) in [(1, &track), (2, &edited), (3, &noise(2, 1000))] {
+            db.execute(
+                "INSERT INTO idx(rowid, fp) VALUES (?1, ?2)",
+                params![id, blob(fingerprint)],
+            )
+            .unwrap();
+        }
+
+        // Matches are ranked, and LIMIT and OFFSET apply to the ranking.
+        let matching = "SELECT rowid FROM idx WHERE fp MATCH ?1";
+        assert_eq!(rowids(&db, matching, &query), [1, 2]);
+        let ranked = "SELECT rowid FROM idx WHERE fp MATCH ?1 ORDER BY score";
+        assert_eq!(rowids(&db, ranked, &query), [1, 2]);
+        let limited = "SELECT rowid FROM idx WHERE fp MATCH ?1 LIMIT 1";
+        assert_eq!(rowids(&db, limited, &query), [1]);
+        let offset = "SELECT rowid FROM idx WHERE fp MATCH ?1 LIMIT 1 OFFSET 1";
+        assert_eq!(rowids(&db, offset, &query), [2]);
+        let (score_1, score_2): (f64, f64) = db
+            .query_row(
+                "SELECT min(score), max(score) FROM idx WHERE fp MATCH ?1",
+                [blob(&query)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(score_1, 0.0);
+        assert!(score_2 > 0.0);
+
+        // Rows are looked up and scanned by rowid.
+        let fp: String = db
+            .query_row("SELECT fp FROM idx WHERE rowid = 2", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(fp, crate::functions::encode_fingerprint(&edited));
+        let missing = "SELECT rowid FROM idx WHERE rowid = 9 AND ?1 IS NOT NULL";
+        assert!(rowids(&db, missing, &query).is_empty());
+        let scan = "SELECT rowid FROM idx WHERE ?1 IS NOT NULL LIMIT 2";
+        assert_eq!(rowids(&db, scan, &query), [1, 2]);
+
+        // Updated and deleted fingerprints leave the index.
+        db.execute(
+            "UPDATE idx SET fp = ?1 WHERE rowid = 1",
+            [blob(&noise(3, 1000))],
+        )
+        .unwrap();
+        assert_eq!(rowids(&db, matching, &query), [2]);
+        db.execute("DELETE FROM idx WHERE rowid = 2", []).unwrap();
+        assert!(rowids(&db, matching, &query).is_empty());
+        let (rows, indexed): (i64, i64) = db
+            .query_row(
+                "SELECT (SELECT count(*) FROM idx), (SELECT count(DISTINCT id) FROM idx_hashes)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((rows, indexed), (2, 2));
+
+        // The shadow tables are renamed with the table.
+        db.execute_batch("ALTER TABLE idx RENAME TO renamed")
+            .unwrap();
+        let mut stmt = db
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        let tables: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            tables,
+            [
+                "renamed",
+                "renamed_config",
+                "renamed_data",
+                "renamed_hashes"
+            ]
+        );
+        let renamed = "SELECT rowid FROM renamed WHERE fp MATCH ?1";
+        assert_eq!(rowids(&db, renamed, &noise(3, 1000)[100..500]), [1]);
+    }
+}
@@ -0,0 +1,239 @@
+//! Technical parameters of audio files.
+//!
+//! `audio_info(path)` returns a row per track of a file, with the parameters
+//! its container and codec declare, for quality audits:
+//!
+//! ```sql
+//! SELECT f.path FROM files f, audio_info(f.path) i
+//! WHERE i.sample_rate < 44100 OR (i.codec = 'mp3' AND i.bitrate < 128000);
+//! ```
+//!
+//! `track` is the container's track id and `codec` the short name of the
+//! codec, e.g. `'mp3'`, `'vorbis'` or `'pcm_s16le'`, or NULL if Symphonia
+//! doesn't know it. `bits_per_sample` is only declared by lossless codecs.
+//! `bitrate` is the average in bits per second over the whole file,
+//! container overhead included, and `duration` the length in seconds; they
+//! are NULL where the file doesn't declare its length, and `bitrate` is NULL
+//! for files with more than one track. Only the file's headers are read.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+use symphonia::core::formats::Track;
+
+use crate::decode::AudioStream;
+use crate::panic::catch_panic;
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::{path, tvf};
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 7;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "audio_info",
+        eponymous_only_module::<InfoTab>(),
+        Some(state),
+    )
+}
+
+/// The parameters of a track.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    track: u32,
+    codec: Option<&'static str>,
+    sample_rate: Option<u32>,
+    channels: Option<u32>,
+    bits_per_sample: Option<u32>,
+    bitrate: Option<i64>,
+    duration: Option<f64>,
+}
+
+impl Row {
+    /// The row of `track`, in a file of `byte_len` bytes if it's the only one.
+    fn of(track: &Track, byte_len: Option<u64>) -> Self {
+        let params = &track.codec_params;
+        let duration = match (params.n_frames, params.sample_rate) {
+            (Some(n_frames), Some(sample_rate)) => Some(n_frames as f64 / sample_rate as f64),
+            _ => None,
+        };
+        let bitrate = match (byte_len, duration) {
+            (Some(bytes), Some(duration)) if duration > 0.0 => {
+                Some((bytes as f64 * 8.0 / duration).round() as i64)
+            }
+            _ => None,
+        };
+        Self {
+            track: track.id,
+            codec: symphonia::default::get_codecs()
+                .get_codec(params.codec)
+                .map(|codec| codec.short_name),
+            sample_rate: params.sample_rate,
+            channels: params.channels.map(|channels| channels.count() as u32),
+            bits_per_sample: params.bits_per_sample,
+            bitrate,
+            duration,
+        }
+    }
+}
+
+fn audio_info(path: &Path, stats: &mut CallStats) -> Result<Vec<Row>> {
+    let stream = AudioStream::open_file(path, true, stats)?;
+    let tracks = stream.tracks();
+    let byte_len = stream.byte_len.filter(|_| tracks.len() == 1);
+    Ok(tracks
+        .iter()
+        .map(|track| Row::of(track, byte_len))
+        .collect())
+}
+
+#[repr(C)]
+struct InfoTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for InfoTab {
+    type Aux = Arc<State>;
+    type Cursor = InfoCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("audio_info: missing state".to_owned())
+            })?;
+            tvf::reads_files(db)?;
+            Ok((
+                "CREATE TABLE x(track, codec, sample_rate, channels, bits_per_sample, bitrate, \
+                 duration, path HIDDEN)"
+                    .to_owned(),
+                InfoTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| tvf::bind_arguments(info, "audio_info", FIRST_ARGUMENT, &["path"]))
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<InfoCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(InfoCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct InfoCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    rows: Vec<Row>,
+    index: usize,
+    phantom: PhantomData<&'vtab InfoTab>,
+}
+
+unsafe impl VTabCursor for InfoCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.rows = Vec::new();
+            self.index = 0;
+
+            let Some(value) = args
+                .iter()
+                .next()
+                .filter(|v| v.data_type() != rusqlite::types::Type::Null)
+            else {
+                return Ok(());
+            };
+            let path = path::path_from_value(0, value)?;
+
+            let mut stats = self.state.call_stats();
+            self.rows = audio_info(&path, &mut stats)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            self.state
+                .record_timing("audio_info", &path.to_string_lossy(), stats);
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let row = &self.rows[self.index];
+            match i {
+                0 => ctx.set_result(&row.track),
+                1 => ctx.set_result(&row.codec),
+                2 => ctx.set_result(&row.sample_rate),
+                3 => ctx.set_result(&row.channels),
+                4 => ctx.set_result(&row.bits_per_sample),
+                5 => ctx.set_result(&row.bitrate),
+                6 => ctx.set_result(&row.duration),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_info() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let testdata = Path::new(&manifest_dir).join("src/testdata");
+        let rows = audio_info(&testdata.join("XC444467.ogg"), &mut CallStats::default()).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].codec, Some("vorbis"));
+        assert_eq!(rows[0].channels, Some(1));
+        assert!(rows[0].sample_rate.is_some());
+        assert!((rows[0].duration.unwrap() - 6.8).abs() < 0.1);
+        assert!(rows[0].bitrate.unwrap() > 0);
+
+        let rows = audio_info(&testdata.join("XC444467.mp3"), &mut CallStats::default()).unwrap();
+        assert_eq!(rows[0].codec, Some("mp3"));
+        assert_eq!(rows[0].bits_per_sample, None);
+    }
+}
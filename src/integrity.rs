@@ -0,0 +1,202 @@
+//! Checks for damaged and incomplete files.
+//!
+//! `audio_truncated(path)` is the length in seconds of audio that the
+//! container declares but that can't be decoded, such as the missing end of
+//! an incomplete download or cut-off rip:
+//!
+//! ```sql
+//! SELECT path FROM tracks WHERE audio_truncated(path) > 1;
+//! ```
+//!
+//! It is 0 for complete files and NULL for files that don't declare their
+//! length, such as most MP3s without a Xing or VBRI header. Decoding stops
+//! at the first packet that fails to decode.
+//!
+//! `audio_decode_report(path)` decodes the whole file, skipping what it
+//! can't, and returns one row for each problem it recovered from:
+//!
+//! ```sql
+//! SELECT event, count(*), sum(bytes), sum(duration_secs)
+//! FROM audio_decode_report('track.mp3') GROUP BY event;
+//! ```
+//!
+//! `event` is one of:
+//!
+//! * `'corrupt_packet'`: a packet of `bytes` bytes and `duration_secs`
+//!   seconds that failed to decode, with the decoder's `message`.
+//! * `'decoder_reset'`: the format required the decoder to be reset, as
+//!   for chained Ogg streams.
+//! * `'gap'`: `duration_secs` of audio are missing before the next packet,
+//!   typically junk data the format skipped to find the next packet. The
+//!   number of bytes skipped is not known.
+//!
+//! `time_secs` is where the event occurred in the track, as far as packet
+//! timestamps tell.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rusqlite::vtab::Context;
+use rusqlite::Connection;
+
+use crate::decode::{AudioStream, DecodeEvent};
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::tvf::{self, PathFunction};
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    tvf::register_path_function::<DecodeReport>(db, state)
+}
+
+/// Seconds of the `declared` frames missing from the `decoded` ones.
+fn missing_secs(declared: u64, decoded: u64, sample_rate: u32) -> f64 {
+    declared.saturating_sub(decoded) as f64 / sample_rate as f64
+}
+
+pub(crate) fn audio_truncated(path: &Path, stats: &mut CallStats) -> Result<Option<f64>> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    let Some(declared) = stream.n_frames else {
+        return Ok(None);
+    };
+    let channels = stream.channels.max(1);
+
+    let mut decoded = 0;
+    while let Ok(Some(samples)) = stream.next_samples(stats) {
+        decoded += (samples.len() / channels) as u64;
+    }
+    Ok(Some(missing_secs(declared, decoded, stream.sample_rate)))
+}
+
+/// A row of `audio_decode_report`.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    time_secs: f64,
+    event: &'static str,
+    duration_secs: Option<f64>,
+    bytes: Option<i64>,
+    message: Option<&'static str>,
+}
+
+impl Row {
+    /// The row for `event`, with timestamps converted to seconds by `secs`.
+    fn of(event: DecodeEvent, secs: impl Fn(u64) -> f64) -> Self {
+        match event {
+            DecodeEvent::CorruptPacket {
+                ts,
+                dur,
+                bytes,
+                error,
+            } => Row {
+                time_secs: secs(ts),
+                event: "corrupt_packet",
+                duration_secs: Some(secs(ts + dur) - secs(ts)),
+                bytes: Some(bytes as i64),
+                message: Some(error),
+            },
+            DecodeEvent::DecoderReset { ts } => Row {
+                time_secs: secs(ts),
+                event: "decoder_reset",
+                duration_secs: None,
+                bytes: None,
+                message: None,
+            },
+            DecodeEvent::Gap { ts, dur } => Row {
+                time_secs: secs(ts),
+                event: "gap",
+                duration_secs: Some(secs(ts + dur) - secs(ts)),
+                bytes: None,
+                message: None,
+            },
+        }
+    }
+}
+
+/// The decode problems of a file, reported as it is decoded.
+struct DecodeReport {
+    stream: AudioStream,
+    /// Rows of events reported but not yet returned.
+    pending: VecDeque<Row>,
+    /// Whether the whole file was decoded.
+    done: bool,
+}
+
+impl PathFunction for DecodeReport {
+    const NAME: &'static str = "audio_decode_report";
+
+    type Row = Row;
+
+    fn columns() -> Vec<&'static str> {
+        vec!["time_secs", "event", "duration_secs", "bytes", "message"]
+    }
+
+    fn open(path: &Path, stats: &mut CallStats) -> Result<Self> {
+        Ok(DecodeReport {
+            stream: AudioStream::open_file(path, false, stats)?,
+            pending: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    fn next_row(&mut self, stats: &mut CallStats) -> Result<Option<Row>> {
+        while self.pending.is_empty() && !self.done {
+            let mut events = Vec::new();
+            self.done = self
+                .stream
+                .next_samples_reporting(stats, &mut |event| events.push(event))?
+                .is_none();
+            let stream = &self.stream;
+            self.pending.extend(
+                events
+                    .into_iter()
+                    .map(|event| Row::of(event, |ts| stream.ts_secs(ts))),
+            );
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    fn column(row: &Row, ctx: &mut Context, i: usize) -> rusqlite::Result<()> {
+        match i {
+            0 => ctx.set_result(&row.time_secs),
+            1 => ctx.set_result(&row.event),
+            2 => ctx.set_result(&row.duration_secs),
+            3 => ctx.set_result(&row.bytes),
+            _ => ctx.set_result(&row.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_secs() {
+        assert_eq!(missing_secs(441000, 220500, 44100), 5.0);
+        assert_eq!(missing_secs(441000, 441000, 44100), 0.0);
+        // Decoders may return a little more than declared, e.g. padding.
+        assert_eq!(missing_secs(441000, 442152, 44100), 0.0);
+    }
+
+    #[test]
+    fn test_report_rows() {
+        let secs = |ts: u64| ts as f64 / 1000.0;
+        let row = Row::of(
+            DecodeEvent::CorruptPacket {
+                ts: 1500,
+                dur: 26,
+                bytes: 417,
+                error: "mpa: invalid main_data_begin",
+            },
+            secs,
+        );
+        assert_eq!(row.time_secs, 1.5);
+        assert_eq!(row.event, "corrupt_packet");
+        assert_eq!(row.bytes, Some(417));
+        assert!((row.duration_secs.unwrap() - 0.026).abs() < 1e-9);
+
+        let row = Row::of(DecodeEvent::Gap { ts: 2000, dur: 500 }, secs);
+        assert_eq!((row.event, row.duration_secs), ("gap", Some(0.5)));
+    }
+}
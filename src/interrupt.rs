@@ -0,0 +1,141 @@
+//! Cancellation of long calls.
+//!
+//! Decoding a long file can take a while, so functions and tables that
+//! decode audio check between packets whether the connection has been
+//! interrupted with `sqlite3_interrupt()`, e.g. by Ctrl-C in the `sqlite3`
+//! shell or by a host application cancelling a query from another thread.
+//! If it has, the call stops and fails with `SQLITE_INTERRUPT`, like SQLite's
+//! own statements.
+//!
+//! The check needs `sqlite3_is_interrupted()`, new in SQLite 3.41.0; with
+//! older versions, and on connections set up with [`crate::register`]
+//! rather than an entry point SQLite calls, calls run to the end as before.
+
+use std::fmt::{Display, Formatter};
+use std::os::raw::c_int;
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use rusqlite::ffi;
+
+type IsInterrupted = unsafe extern "C" fn(*mut ffi::sqlite3) -> c_int;
+
+/// Index of `is_interrupted` in `sqlite3_api_routines`. The bindings rusqlite
+/// builds loadable extensions with predate it, so it's read by position.
+const IS_INTERRUPTED_INDEX: usize = 266;
+
+/// The SQLite version that added `sqlite3_is_interrupted()`.
+const IS_INTERRUPTED_VERSION: c_int = 3_041_000;
+
+static IS_INTERRUPTED: OnceLock<Option<IsInterrupted>> = OnceLock::new();
+
+/// Find `sqlite3_is_interrupted()` in the routines SQLite passes to the
+/// extension, if its version has it.
+///
+/// # Safety
+///
+/// `p_api` must be the routines passed to `sqlite3_extension_init`, or to
+/// an entry point registered with `sqlite3_auto_extension()`.
+pub(crate) unsafe fn init(p_api: *const ffi::sqlite3_api_routines) {
+    IS_INTERRUPTED.get_or_init(|| {
+        if p_api.is_null() {
+            return None;
+        }
+        // Until the extension is initialized, a loadable extension can only
+        // call SQLite through the routines.
+        #[cfg(feature = "extension")]
+        let version = (*p_api).libversion_number.map_or(0, |f| f());
+        #[cfg(not(feature = "extension"))]
+        let version = ffi::sqlite3_libversion_number();
+        if version < IS_INTERRUPTED_VERSION {
+            return None;
+        }
+        // SAFETY: the routines of this version extend past the index, and
+        // every member is a nullable function pointer.
+        *p_api
+            .cast::<Option<IsInterrupted>>()
+            .add(IS_INTERRUPTED_INDEX)
+    });
+}
+
+/// The interrupt flag of a connection.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Interrupt(*mut ffi::sqlite3);
+
+// SAFETY: `sqlite3_is_interrupted()` may be called from any thread, and the
+// handle outlives every call that can check it, see `of_connection`.
+unsafe impl Send for Interrupt {}
+unsafe impl Sync for Interrupt {}
+
+impl Interrupt {
+    /// The interrupt flag of connection `db`.
+    ///
+    /// # Safety
+    ///
+    /// `db` must be a valid connection handle, and stay valid while the flag
+    /// is checked.
+    pub unsafe fn of_connection(db: *mut ffi::sqlite3) -> Self {
+        Self(db)
+    }
+
+    /// Whether the connection has been interrupted.
+    pub fn is_set(self) -> bool {
+        let Some(is_interrupted) = IS_INTERRUPTED.get().copied().flatten() else {
+            return false;
+        };
+        // SAFETY: the handle is valid, see `of_connection`.
+        unsafe { is_interrupted(self.0) != 0 }
+    }
+
+    /// Fail with [`Interrupted`] if the connection has been interrupted.
+    pub fn check(self) -> Result<()> {
+        if self.is_set() {
+            return Err(Interrupted.into());
+        }
+        Ok(())
+    }
+}
+
+/// The error of a call stopped by `sqlite3_interrupt()`.
+#[derive(Debug)]
+pub(crate) struct Interrupted;
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Report the error of a call on an interrupted connection as
+/// `SQLITE_INTERRUPT`, so that it fails like SQLite's own statements.
+pub(crate) fn to_sqlite_error(interrupt: Interrupt, err: rusqlite::Error) -> rusqlite::Error {
+    match err {
+        rusqlite::Error::UserFunctionError(_) if interrupt.is_set() => {
+            rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_INTERRUPT), None)
+        }
+        err => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_without_is_interrupted() {
+        // Without `init`, as with SQLite before 3.41, calls are never
+        // interrupted.
+        // SAFETY: the handle is never passed to SQLite.
+        let interrupt = unsafe { Interrupt::of_connection(std::ptr::null_mut()) };
+        assert!(!interrupt.is_set());
+        assert!(interrupt.check().is_ok());
+
+        let err = rusqlite::Error::UserFunctionError(anyhow::anyhow!("failed").into());
+        assert!(matches!(
+            to_sqlite_error(interrupt, err),
+            rusqlite::Error::UserFunctionError(_)
+        ));
+    }
+}
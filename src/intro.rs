@@ -0,0 +1,333 @@
+//! Detection of intros and outros shared across podcast episodes.
+//!
+//! `detect_intro_outro` compares the start and end of every episode in a
+//! table with those of the others and returns, per episode, the time range
+//! of the longest stretch shared with at least one other episode:
+//!
+//! ```sql
+//! SELECT episode_id, kind, start_secs, end_secs, shared_with
+//! FROM detect_intro_outro('episodes', 'fingerprint', 'id');
+//! ```
+//!
+//! `kind` is `'intro'` or `'outro'`, and `shared_with` is the largest number
+//! of other episodes sharing any part of the range. Only the first and last
+//! [`SEARCH_SECS`] (at most half) of each episode are searched, with the
+//! `preset` option.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result};
+use rusqlite::types::Value;
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+use rusty_chromaprint::{match_fingerprints, Configuration};
+
+use crate::panic::catch_panic;
+use crate::state::State;
+use crate::tvf;
+
+/// Length of the start and end of each episode searched for shared segments.
+const SEARCH_SECS: f32 = 300.0;
+
+/// Shared stretches shorter than this are ignored.
+const MIN_SEGMENT_SECS: f32 = 5.0;
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 5;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "detect_intro_outro",
+        eponymous_only_module::<IntroOutroTab>(),
+        Some(state),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Intro,
+    Outro,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Intro => "intro",
+            Self::Outro => "outro",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Shared {
+    episode: usize,
+    kind: Kind,
+    start_secs: f64,
+    end_secs: f64,
+    shared_with: usize,
+}
+
+/// Find the intro and outro of each episode.
+fn detect(episodes: &[Vec<u32>], config: &Configuration) -> Result<Vec<Shared>> {
+    let window = (SEARCH_SECS / config.item_duration_in_seconds()) as usize;
+    let min_items = (MIN_SEGMENT_SECS / config.item_duration_in_seconds()) as usize;
+
+    let mut found = Vec::new();
+    for kind in [Kind::Intro, Kind::Outro] {
+        // The searched part of each episode and its offset in the episode.
+        let parts: Vec<(&[u32], usize)> = episodes
+            .iter()
+            .map(|fp| {
+                // Short episodes are split in half so the intro is not
+                // reported as the outro too.
+                let len = window.min(fp.len() / 2);
+                match kind {
+                    Kind::Intro => (&fp[..len], 0),
+                    Kind::Outro => (&fp[fp.len() - len..], fp.len() - len),
+                }
+            })
+            .collect();
+
+        // Number of other episodes sharing each item.
+        let mut votes: Vec<Vec<usize>> = parts.iter().map(|(p, _)| vec![0; p.len()]).collect();
+        for i in 0..parts.len() {
+            for j in i + 1..parts.len() {
+                let segments = match_fingerprints(parts[i].0, parts[j].0, config)
+                    .context("Failed to match fingerprints")?;
+                for s in segments {
+                    for k in 0..s.items_count {
+                        votes[i][s.offset1 + k] += 1;
+                        votes[j][s.offset2 + k] += 1;
+                    }
+                }
+            }
+        }
+
+        for (episode, (votes, (_, offset))) in votes.iter().zip(&parts).enumerate() {
+            if let Some((start, end)) = longest_run(votes).filter(|(s, e)| e - s >= min_items) {
+                let item_secs = config.item_duration_in_seconds() as f64;
+                found.push(Shared {
+                    episode,
+                    kind,
+                    start_secs: (offset + start) as f64 * item_secs,
+                    end_secs: (offset + end) as f64 * item_secs,
+                    shared_with: votes[start..end].iter().copied().max().unwrap_or(0),
+                });
+            }
+        }
+    }
+
+    found.sort_by_key(|s| (s.episode, s.kind == Kind::Outro));
+    Ok(found)
+}
+
+/// The longest range of items with at least one vote.
+fn longest_run(votes: &[usize]) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+    let mut start = None;
+    for (i, &v) in votes.iter().chain(std::iter::once(&0)).enumerate() {
+        match (v > 0, start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                if best.is_none_or(|(bs, be)| i - s > be - bs) {
+                    best = Some((s, i));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    best
+}
+
+#[repr(C)]
+struct IntroOutroTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for IntroOutroTab {
+    type Aux = Arc<State>;
+    type Cursor = IntroOutroCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("detect_intro_outro: missing state".to_owned())
+            })?;
+            Ok((
+                "CREATE TABLE x(episode_id, kind, start_secs, end_secs, shared_with, \
+                 episode_table HIDDEN, fp_col HIDDEN, id_col HIDDEN)"
+                    .to_owned(),
+                IntroOutroTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    // SAFETY: The handle outlives the virtual table, which is
+                    // disconnected before the connection is closed.
+                    db: unsafe { db.handle() },
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_arguments(
+                info,
+                "detect_intro_outro",
+                FIRST_ARGUMENT,
+                &["episode_table", "fp_col", "id_col"],
+            )
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<IntroOutroCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(IntroOutroCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                db: self.db,
+                state: self.state.clone(),
+                ids: Vec::new(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct IntroOutroCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+    ids: Vec<Value>,
+    rows: Vec<Shared>,
+    index: usize,
+    phantom: PhantomData<&'vtab IntroOutroTab>,
+}
+
+unsafe impl VTabCursor for IntroOutroCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let table = tvf::text_argument("detect_intro_outro", args, 0, "episode_table")?;
+            let fp_col = tvf::text_argument("detect_intro_outro", args, 1, "fp_col")?;
+            let id_col = tvf::text_argument("detect_intro_outro", args, 2, "id_col")?;
+
+            let mut ids = Vec::new();
+            let mut episodes = Vec::new();
+            // SAFETY: The handle outlives the virtual table, see `IntroOutroTab::connect`.
+            unsafe {
+                tvf::for_each_fingerprint(self.db, table, fp_col, id_col, |id, fingerprint| {
+                    ids.push(id);
+                    episodes.push(fingerprint);
+                    Ok(())
+                })?;
+            }
+
+            let config = self.state.options().preset.config();
+            self.rows = detect(&episodes, &config)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            self.ids = ids;
+            self.index = 0;
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let shared = &self.rows[self.index];
+            match i {
+                0 => ctx.set_result(&self.ids[shared.episode]),
+                1 => ctx.set_result(&shared.kind.name()),
+                2 => ctx.set_result(&shared.start_secs),
+                3 => ctx.set_result(&shared.end_secs),
+                4 => ctx.set_result(&(shared.shared_with as i64)),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(seed: u32, len: usize) -> Vec<u32> {
+        let mut x = seed.wrapping_mul(2654435761) | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_detect_shared_intro() {
+        let intro = noise(1, 200);
+        let episodes: Vec<Vec<u32>> = (0..3)
+            .map(|i| {
+                // A cold open of varying length before the intro.
+                let mut fp = noise(10 + i, 50 * i as usize);
+                fp.extend(&intro);
+                fp.extend(noise(20 + i, 3000));
+                fp
+            })
+            .collect();
+
+        let config = Configuration::preset_test1();
+        let found = detect(&episodes, &config).unwrap();
+        let intros: Vec<_> = found.iter().filter(|s| s.kind == Kind::Intro).collect();
+        assert_eq!(intros.len(), 3);
+
+        let item_secs = config.item_duration_in_seconds() as f64;
+        for (i, shared) in intros.iter().enumerate() {
+            assert_eq!(shared.episode, i);
+            assert_eq!(shared.shared_with, 2);
+            let start = 50.0 * i as f64 * item_secs;
+            assert!((shared.start_secs - start).abs() < 2.0, "{shared:?}");
+            assert!((shared.end_secs - (start + 200.0 * item_secs)).abs() < 2.0);
+        }
+        assert!(found.iter().all(|s| s.kind == Kind::Intro));
+    }
+
+    #[test]
+    fn test_longest_run() {
+        assert_eq!(longest_run(&[0, 1, 1, 0, 2, 2, 2]), Some((4, 7)));
+        assert_eq!(longest_run(&[0, 0]), None);
+    }
+}
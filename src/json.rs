@@ -0,0 +1,20 @@
+//! JSON function results.
+//!
+//! SQLite tags the results of its JSON functions with a subtype, so that
+//! another JSON function receiving one embeds it as JSON rather than as a
+//! string: `json_object('report', chromaprint_selftest())` nests the report
+//! instead of quoting it. Functions in this library that return JSON set the
+//! same subtype with [`result`], and are registered with
+//! `SQLITE_RESULT_SUBTYPE`.
+
+use std::os::raw::c_uint;
+
+use rusqlite::functions::SubType;
+
+/// The subtype SQLite's JSON functions use for JSON text (`'J'`).
+const JSON_SUBTYPE: c_uint = b'J' as c_uint;
+
+/// A function result of JSON `text`.
+pub(crate) fn result<T>(text: T) -> (T, SubType) {
+    (text, Some(JSON_SUBTYPE))
+}
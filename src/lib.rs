@@ -1,9 +1,88 @@
 //! SQLite3 extension for audio fingerprinting.
 //!
-//! This library provides two SQLite functions:
+//! This library provides the following SQLite functions:
 //!
-//! 1. `fingerprint(path TEXT)`: Fingerprint an audio file at the given path.
-//! 2. `compare_fingerprints(fingerprint_a TEXT, fingerprint_b TEXT)`: Compare two fingerprints.
+//! 1. `fingerprint(path TEXT|BLOB [, format TEXT [, preset TEXT]])`: Fingerprint an audio file at the given path.
+//!    `fingerprint(path TEXT|BLOB, start_secs REAL [, length_secs REAL])` fingerprints only a time range of it.
+//!    `fingerprint_raw(...)` takes the same arguments and returns the fingerprint as a BLOB of big-endian items.
+//!    `fingerprint_json(...)` returns it as a JSON array of integers.
+//! 2. `fingerprint_blob(data BLOB [, format TEXT])`: Fingerprint audio stored in a BLOB.
+//! 3. `fingerprint_blob_ref(table TEXT, column TEXT, rowid INTEGER [, format TEXT])`: Fingerprint a BLOB, streamed from the database.
+//! 4. `fingerprint_pcm(data BLOB, sample_rate INTEGER, channels INTEGER)`: Fingerprint interleaved s16le PCM.
+//! 5. `compare_fingerprints(fingerprint_a TEXT|BLOB, fingerprint_b TEXT|BLOB [, preset TEXT])`: Compare two fingerprints, raw or as `fpcalc` prints them.
+//! 6. `chromaprint_selftest()`: Verify that this build fingerprints a reference signal correctly.
+//! 7. `chromaprint_option(name TEXT [, value])`: Read or change a per-connection option.
+//! 8. `chromaprint_reload_config()`: Reset the options to their defaults from the environment.
+//! 9. `fingerprint_album_agg(path TEXT|BLOB)`: Aggregate fingerprint of several files decoded back to back.
+//! 10. `airplay_report(station, track_id, start_secs, end_secs, confidence)`: Merge window matches into play events.
+//! 11. `audio_speech_music(path TEXT|BLOB)`: Classify a file as `'speech'`, `'music'` or `'mixed'`.
+//! 12. `audio_voice_ratio(path TEXT|BLOB)`: Fraction of time with speech activity.
+//! 13. `audio_noise_floor(path TEXT|BLOB)`: Estimated noise floor in dBFS.
+//! 14. `audio_spectral_centroid(path TEXT|BLOB)`: Mean spectral centroid (brightness) in Hz.
+//! 15. `audio_zcr(path TEXT|BLOB)`: Zero crossing rate in sign changes per second.
+//! 16. `audio_mfcc(path TEXT|BLOB [, n_coeffs INTEGER, hop_secs REAL])`: MFCC frames as JSON.
+//! 17. `audio_loudness_range(path TEXT|BLOB)`: EBU R128 loudness range (LRA) in LU.
+//! 18. `audio_true_peak(path TEXT|BLOB)`: True peak level in dBTP (4x oversampled).
+//! 19. `audio_dc_offset(path TEXT|BLOB)`: DC offset of each channel as a JSON array.
+//! 20. `audio_phase_inverted(path TEXT|BLOB)`: Whether the stereo channels are out of phase.
+//! 21. `audio_truncated(path TEXT|BLOB)`: Seconds of declared audio that can't be decoded.
+//! 22. `duplicate_kind(a, b)`: Whether two files or fingerprints are identical, transcodes, remasters or different.
+//! 23. `is_duplicate_of(table, fp_col, fingerprint, threshold)`: Whether any fingerprint in a table matches a new one.
+//! 24. `audio_lead_trail_silence(path TEXT|BLOB [, threshold_db REAL])`: Lead-in and fade-out silence durations as JSON.
+//! 25. `fingerprint_bits(fingerprint TEXT|BLOB)`: Bit density, toggle rates and entropy of a fingerprint as JSON.
+//! 26. `fp_to_acoustid(fingerprint TEXT|BLOB [, preset TEXT])`: Chromaprint's compressed format, as `fpcalc` prints it.
+//! 27. `fp_from_acoustid(text TEXT [, preset TEXT])`: Decompress a fingerprint printed by `fpcalc`.
+//! 28. `acoustid_lookup(fingerprint TEXT|BLOB, duration REAL, api_key TEXT)`: Identify a fingerprint with the AcoustID web service (`acoustid` feature).
+//! 29. `audio_metadata(path TEXT|BLOB)`: Title, artist, album and other tags as JSON.
+//! 30. `cover_art(path TEXT|BLOB)`: Embedded front cover image as a BLOB.
+//! 31. `cover_art_mime(path TEXT|BLOB)`: Media type of the embedded front cover image.
+//! 32. `audio_duration(path TEXT|BLOB)`: Length in seconds, from the container where it declares it.
+//! 33. `fingerprint_stream(url TEXT, seconds INTEGER)`: Fingerprint the first seconds of a live HTTP audio stream (`net` feature).
+//! 34. `fingerprint_url(url TEXT)`: Fingerprint an audio file on an HTTP server, reading it with range requests (`net` feature).
+//! 35. `fp_to_base64(fingerprint TEXT|BLOB)`, `fp_to_base64url(...)`, `fp_to_hex(...)`: Re-encode a fingerprint in any encoding.
+//! 36. `fp_from_text(fingerprint TEXT)`: A fingerprint in any encoding as a BLOB of big-endian items.
+//! 37. `fp_compress(fingerprint TEXT|BLOB [, preset TEXT])`: Chromaprint's compressed format as a BLOB, smaller than the raw items.
+//! 38. `fp_decompress(compressed BLOB)`: Decompress a fingerprint compressed by `fp_compress`.
+//! 39. `fp_length(fingerprint TEXT|BLOB)`: Number of items in a fingerprint.
+//! 40. `fp_duration_secs(fingerprint TEXT|BLOB [, preset TEXT])`: Approximate length of the audio a fingerprint was made from.
+//! 41. `fp_minhash(fingerprint TEXT|BLOB [, bands INTEGER, rows INTEGER])`: MinHash signature of a fingerprint's items, as a BLOB.
+//! 42. `fp_simhash64(fingerprint TEXT|BLOB)`: 64-bit SimHash of a fingerprint's items, for prefiltering comparisons.
+//! 43. `fp_simhash64_distance(a INTEGER, b INTEGER)`: Number of bits two SimHash signatures differ in.
+//! 44. `bit_count(x INTEGER|BLOB)`: Number of bits set.
+//! 45. `hamming_distance(a INTEGER|BLOB, b INTEGER|BLOB)`: Number of bits two integers or equal-length BLOBs differ in.
+//! 46. `fp_gross_similarity(fp_a TEXT|BLOB, fp_b TEXT|BLOB [, items INTEGER])`: Fraction of bits that agree between aligned items, a cheap prefilter.
+//!
+//! And the following virtual tables:
+//!
+//! 1. `chromaprint_timings`: Decode and fingerprint timings of recent calls (see the `timings` option).
+//! 2. `identify_tracklist(mix, reference_table, fp_col, id_col)`: Tracks identified in a mix, with start/end times.
+//! 3. `detect_intro_outro(episode_table, fp_col, id_col)`: Intros and outros shared across episodes.
+//! 4. `infer_segments(path TEXT|BLOB)`: Candidate chapter points where the audio changes abruptly.
+//! 5. `audio_spectral_centroid_windows(path TEXT|BLOB)`: Spectral centroid of each second.
+//! 6. `audio_chroma(path TEXT|BLOB)`: Chroma vector (pitch class energies) of each frame.
+//! 7. `audio_onsets(path TEXT|BLOB)`: Note and percussive onset times with strengths.
+//! 8. `audio_beats(path TEXT|BLOB)`: Estimated beat times, with downbeats where detectable.
+//! 9. `audio_channel_stats(path TEXT|BLOB)`: RMS, peak, DC offset and silence fraction of each channel.
+//! 10. `audio_decode_report(path TEXT|BLOB)`: Corrupt packets, decoder resets and gaps found while decoding.
+//! 11. `audio_glitches(path TEXT|BLOB)`: Clicks, pops and dropouts with their severity.
+//! 12. `chromaprint_sync(dir TEXT|BLOB, table TEXT)`: Fingerprint new and changed files into a table, and report the changes.
+//! 13. `chromaprint_benchmark(path TEXT|BLOB, iterations, max_threads)`: Decode and fingerprint throughput by thread count.
+//! 14. `match_segments(fp_a TEXT|BLOB, fp_b TEXT|BLOB)`: Offsets, durations and scores of the segments two fingerprints share.
+//! 15. `audio_scan(dir TEXT|BLOB, recursive [, jobs])`: Fingerprint and duration of each audio file in a directory, or why it failed, optionally on worker threads.
+//! 16. `musicbrainz_recordings(fingerprint TEXT|BLOB, duration REAL)`: Candidate MusicBrainz recordings from AcoustID, with a response cache (`acoustid` feature).
+//! 17. `audio_tags(path TEXT|BLOB)`: Each tag of a file as a key/value row.
+//! 18. `audio_info(path TEXT|BLOB)`: Codec, sample rate, channels, bit depth, bitrate and duration of each track.
+//! 19. `fingerprint_chunks(path TEXT|BLOB, chunk_secs REAL)`: Fingerprint of each window of a file, in one decoding pass.
+//! 20. `chromaprint_info`: Versions, preset, codecs and features of this build.
+//! 21. `fp_hashes(fp TEXT|BLOB)`: Each item of a fingerprint with its index.
+//! 22. `chromaprint_index`: Fingerprints indexed by their items, for finding those matching a query with `MATCH` (`CREATE VIRTUAL TABLE ... USING chromaprint_index()`).
+//! 23. `fp_lsh_keys(fp TEXT|BLOB [, bands INTEGER, rows INTEGER])`: Locality-sensitive key of each band of a fingerprint's MinHash signature, for finding similar ones with an equi-join.
+//! 24. `audio_search(query_fp TEXT|BLOB, fp_table TEXT, fp_col TEXT, k INTEGER)`: The `k` fingerprints of a table most similar to a query, with their scores and shared duration.
+//!
+//! With the `blob-only` feature, the functions and tables that read files
+//! aren't registered, leaving those working on BLOBs, PCM and fingerprints.
+//! Without the `decode` feature, e.g. for WebAssembly, neither are those
+//! decoding BLOBs, leaving those working on PCM and fingerprints.
 //!
 //! The fingerprints are generated using Chromaprint, a library for generating audio fingerprints.
 //!     
@@ -17,207 +96,1784 @@
 //! );
 //! ```
 
+// Without decoding, much of the shared machinery has no callers.
+#![cfg_attr(not(feature = "decode"), allow(dead_code, unused_imports))]
+
 use std::os::raw::{c_char, c_int};
 use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use base64::prelude::*;
+use anyhow::{bail, Context, Result};
 use rusqlite::ffi;
-use rusqlite::functions::FunctionFlags;
+use rusqlite::functions::{FunctionFlags, SqlFnOutput};
 use rusqlite::types::{ToSqlOutput, Value, ValueRef};
-use rusqlite::Connection;
-use rusty_chromaprint::{match_fingerprints, Configuration, Fingerprinter};
-use symphonia::core::audio::SampleBuffer;
-use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
+use rusqlite::{Connection, ToSql};
+use rusty_chromaprint::{match_fingerprints, Configuration, Fingerprinter, Segment};
+
+#[cfg(feature = "acoustid")]
+mod acoustid;
+mod airplay;
+#[cfg(feature = "decode")]
+mod album;
+#[cfg(feature = "sqlite-malloc")]
+mod alloc;
+#[cfg(feature = "decode")]
+mod analysis;
+#[cfg(feature = "decode")]
+mod beats;
+#[cfg(feature = "decode")]
+mod benchmark;
+mod bits;
+#[cfg(feature = "decode")]
+mod blob;
+mod build_info;
+#[cfg(feature = "shared-cache")]
+mod cache;
+#[cfg(feature = "decode")]
+mod cache_table;
+#[cfg(feature = "decode")]
+mod channels;
+#[cfg(feature = "decode")]
+mod checkpoint;
+#[cfg(feature = "decode")]
+mod chroma;
+#[cfg(feature = "decode")]
+mod chunks;
+#[cfg(feature = "decode")]
+mod decode;
+mod duplicates;
+#[cfg(feature = "decode")]
+mod duration;
+mod encoding;
+pub mod functions;
+#[cfg(feature = "decode")]
+mod glitches;
+mod hashes;
+#[cfg(feature = "net")]
+mod http;
+mod index;
+#[cfg(feature = "decode")]
+mod info;
+#[cfg(feature = "decode")]
+mod integrity;
+mod interrupt;
+mod intro;
+mod json;
+mod limits;
+#[cfg(feature = "net")]
+mod live;
+#[cfg(feature = "decode")]
+mod loudness;
+mod matching;
+#[cfg(feature = "decode")]
+mod metadata;
+#[cfg(feature = "decode")]
+mod mfcc;
+mod minhash;
+#[cfg(feature = "acoustid")]
+mod musicbrainz;
+#[cfg(feature = "decode")]
+mod onsets;
+mod options;
+mod overlap;
+mod panic;
+mod path;
+mod preset;
+mod progress;
+mod roots;
+#[cfg(feature = "decode")]
+mod scan;
+mod search;
+#[cfg(feature = "decode")]
+mod segments;
+mod selftest;
+mod simhash;
+#[cfg(feature = "decode")]
+mod source;
+#[cfg(feature = "decode")]
+mod spectral;
+#[cfg(feature = "decode")]
+mod speech;
+mod state;
+#[cfg(feature = "decode")]
+mod sync;
+mod timings;
+#[cfg(feature = "decode")]
+mod tracklist;
+mod tvf;
+mod vfs;
+
+pub use preset::Preset;
+#[cfg(feature = "decode")]
+pub use source::{register_source_provider, SourceProvider};
+#[cfg(feature = "decode")]
+pub use symphonia::core::io::MediaSource;
+
+#[cfg(feature = "decode")]
+use decode::AudioStream;
+use encoding::Encoding;
+use matching::MatchMode;
+use panic::guard;
+use state::State;
+use timings::CallStats;
+
+/// Serves the large allocations of the whole program, see [`alloc`].
+#[cfg(feature = "sqlite-malloc")]
+#[global_allocator]
+static ALLOCATOR: alloc::SqliteAllocator = alloc::SqliteAllocator;
+
+/// Flags of functions that only compute on their arguments.
+const PURE_FUNCTION_FLAGS: FunctionFlags = FunctionFlags::SQLITE_UTF8
+    .union(FunctionFlags::SQLITE_DETERMINISTIC)
+    .union(FunctionFlags::SQLITE_INNOCUOUS);
 
+/// Flags of functions whose results also depend on the connection's options,
+/// such as `encoding` or `preset`. They are not deterministic, so that an
+/// index or generated column can't go stale when an option changes.
+const OPTION_FUNCTION_FLAGS: FunctionFlags =
+    FunctionFlags::SQLITE_UTF8.union(FunctionFlags::SQLITE_INNOCUOUS);
+
+/// Flags of functions that read the files named by their arguments.
+///
+/// Files can change between calls, so these functions are not deterministic.
+/// They are also direct-only: a schema using them in a view, trigger, index
+/// or generated column would make whoever opens the database read files of
+/// the schema author's choosing. The `trusted-files` feature registers them
+/// as deterministic and usable anywhere instead, for databases whose schema
+/// is trusted and whose files don't change.
+pub(crate) const FILE_FUNCTION_FLAGS: FunctionFlags = if cfg!(feature = "trusted-files") {
+    FunctionFlags::SQLITE_UTF8.union(FunctionFlags::SQLITE_DETERMINISTIC)
+} else {
+    FunctionFlags::SQLITE_UTF8.union(FunctionFlags::SQLITE_DIRECTONLY)
+};
+
+/// How a fingerprinting function returns fingerprints.
+#[cfg(feature = "decode")]
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    /// TEXT in the `encoding` option's encoding.
+    Text,
+    /// A BLOB of big-endian items, the bytes the TEXT encodes.
+    Blob,
+    /// A JSON array of the items as integers.
+    Json,
+}
+
+/// Packets of samples [`fingerprint_stream`] decodes ahead of the
+/// fingerprinter at most.
+#[cfg(feature = "decode")]
+const PIPELINE_DEPTH: usize = 16;
+
+/// Entry point called by SQLite when the extension is loaded.
+///
+/// # Safety
+///
+/// Must only be called by SQLite with a valid database handle and API routines.
+#[cfg(feature = "extension")]
 #[no_mangle]
 pub unsafe extern "C" fn sqlite3_extension_init(
     db: *mut ffi::sqlite3,
     pz_err_msg: *mut *mut c_char,
     p_api: *mut ffi::sqlite3_api_routines,
 ) -> c_int {
+    interrupt::init(p_api);
     Connection::extension_init2(db, pz_err_msg, p_api, extension_init)
 }
 
+/// Entry point SQLite derives from the file name when the extension is
+/// installed as `chromaprint.so`, e.g. by sqlpkg, and loaded with an
+/// explicit entry point or by a loader that doesn't fall back to
+/// [`sqlite3_extension_init`].
+///
+/// # Safety
+///
+/// Must only be called by SQLite with a valid database handle and API routines.
+#[cfg(feature = "extension")]
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_chromaprint_init(
+    db: *mut ffi::sqlite3,
+    pz_err_msg: *mut *mut c_char,
+    p_api: *mut ffi::sqlite3_api_routines,
+) -> c_int {
+    sqlite3_extension_init(db, pz_err_msg, p_api)
+}
+
+/// Entry point SQLite derives from the file name of the library as built,
+/// `libsqlite3_chromaprint.so`, keeping only its letters.
+///
+/// # Safety
+///
+/// Must only be called by SQLite with a valid database handle and API routines.
+#[cfg(feature = "extension")]
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_sqlitechromaprint_init(
+    db: *mut ffi::sqlite3,
+    pz_err_msg: *mut *mut c_char,
+    p_api: *mut ffi::sqlite3_api_routines,
+) -> c_int {
+    sqlite3_extension_init(db, pz_err_msg, p_api)
+}
+
+/// Register on the connection loading the extension, and keep the library
+/// loaded for the rest of the process (`SQLITE_OK_LOAD_PERMANENTLY`).
+///
+/// Closing the connection would otherwise unload the library while other
+/// connections, e.g. of a pool, or `audio_scan`'s worker threads may still
+/// use it, and drop the process-wide state: the `shared-cache` cache, source
+/// providers and AcoustID's rate limit. Everything else is per-connection,
+/// see [`State`].
+#[cfg(feature = "extension")]
 fn extension_init(db: Connection) -> rusqlite::Result<bool> {
+    register(&db)?;
+    Ok(true)
+}
+
+/// Entry point of statically linked builds, e.g. for iOS, where extensions
+/// can't be loaded. Built without the default `extension` feature, the
+/// crate's static library calls the SQLite it's linked with directly, and
+/// passing this to `sqlite3_auto_extension()` registers the functions and
+/// tables on every connection opened afterwards.
+///
+/// # Safety
+///
+/// Must only be called by SQLite with a valid database handle.
+#[cfg(not(feature = "extension"))]
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_chromaprint_init(
+    db: *mut ffi::sqlite3,
+    pz_err_msg: *mut *mut c_char,
+    p_api: *const ffi::sqlite3_api_routines,
+) -> c_int {
+    interrupt::init(p_api);
+    let Err(err) = Connection::from_handle(db).and_then(|db| register(&db)) else {
+        return ffi::SQLITE_OK;
+    };
+    if !pz_err_msg.is_null() {
+        let message = err.to_string();
+        let buf = ffi::sqlite3_malloc64(message.len() as u64 + 1).cast::<u8>();
+        if !buf.is_null() {
+            std::ptr::copy_nonoverlapping(message.as_ptr(), buf, message.len());
+            *buf.add(message.len()) = 0;
+            *pz_err_msg = buf.cast();
+        }
+    }
+    match err {
+        rusqlite::Error::SqliteFailure(e, _) => e.extended_code,
+        _ => ffi::SQLITE_ERROR,
+    }
+}
+
+/// Register the extension's functions and virtual tables on `db`, for Rust
+/// applications that link the crate instead of loading the extension, with
+/// the crate's default features off:
+///
+/// ```no_run
+/// let db = rusqlite::Connection::open("library.db")?;
+/// sqlite3_chromaprint::register(&db)?;
+/// # Ok::<(), rusqlite::Error>(())
+/// ```
+///
+/// Calls on connections set up this way can't be stopped with
+/// `sqlite3_interrupt()`, as finding `sqlite3_is_interrupted()` needs the
+/// routines SQLite passes to extension entry points.
+pub fn register(db: &Connection) -> rusqlite::Result<()> {
+    #[cfg(feature = "sqlite-malloc")]
+    alloc::enable();
+
+    let options =
+        options::Options::from_env().map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+    // SAFETY: the handle is valid for the duration of this call.
+    let vfs = unsafe { vfs::Vfs::of_connection(db.handle()) };
+    // SAFETY: the state, and every call checking the flag, is dropped
+    // before the connection is closed.
+    let interrupt = unsafe { interrupt::Interrupt::of_connection(db.handle()) };
+    // SAFETY: as for the interrupt flag.
+    let progress = unsafe { progress::ProgressDb::of_connection(db.handle()) };
+    let state = Arc::new(State::new(options, vfs, Some(interrupt), Some(progress)));
+
+    #[cfg(feature = "decode")]
+    {
+        for n_arg in [1, 2] {
+            let state = state.clone();
+            db.create_scalar_function(
+                "fingerprint_blob",
+                n_arg,
+                OPTION_FUNCTION_FLAGS,
+                guard(move |ctx| {
+                    let data = match ctx.get_raw(0) {
+                        ValueRef::Null => return Ok(None),
+                        ValueRef::Blob(data) => data.to_vec(),
+                        v => {
+                            return Err(rusqlite::Error::InvalidFunctionParameterType(
+                                0,
+                                v.data_type(),
+                            ))
+                        }
+                    };
+                    let format = if ctx.len() > 1 {
+                        ctx.get::<Option<String>>(1)?
+                    } else {
+                        None
+                    };
+
+                    let argument = format!("{} bytes", data.len());
+                    let (encoding, preset, max_length_secs) = {
+                        let options = state.options();
+                        (options.encoding, options.preset, options.max_length_secs)
+                    };
+                    let mut stats = state.call_stats();
+                    let fingerprint = fingerprint_bytes(
+                        data,
+                        format.as_deref(),
+                        preset,
+                        max_length_secs,
+                        &mut stats,
+                    )
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+                    state.record_timing("fingerprint_blob", &argument, stats);
+                    let fingerprint = encoding.encode(&fingerprint);
+                    limits::check_length(ctx, fingerprint.len())?;
+
+                    Ok(Some(fingerprint))
+                }),
+            )?;
+        }
+
+        for n_arg in [3, 4] {
+            let state = state.clone();
+            db.create_scalar_function(
+                "fingerprint_blob_ref",
+                n_arg,
+                FunctionFlags::SQLITE_UTF8,
+                guard(move |ctx| {
+                    let table = ctx.get::<String>(0)?;
+                    let column = ctx.get::<String>(1)?;
+                    let rowid = ctx.get::<i64>(2)?;
+                    let format = if ctx.len() > 3 {
+                        ctx.get::<Option<String>>(3)?
+                    } else {
+                        None
+                    };
+
+                    // SAFETY: the connection reference does not outlive this call.
+                    let db = unsafe { ctx.get_connection()? };
+                    // SAFETY: the source is dropped before this call returns.
+                    let source =
+                        unsafe { blob::BlobSource::open(db.handle(), &table, &column, rowid) }
+                            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+
+                    let (encoding, preset, max_length_secs) = {
+                        let options = state.options();
+                        (options.encoding, options.preset, options.max_length_secs)
+                    };
+                    let mut stats = state.call_stats();
+                    let fingerprint = fingerprint_source(
+                        Box::new(source),
+                        format.as_deref(),
+                        preset,
+                        max_length_secs,
+                        &mut stats,
+                    )
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+                    state.record_timing(
+                        "fingerprint_blob_ref",
+                        &format!("{table}.{column}[{rowid}]"),
+                        stats,
+                    );
+                    let fingerprint = encoding.encode(&fingerprint);
+                    limits::check_length(ctx, fingerprint.len())?;
+
+                    Ok(fingerprint)
+                }),
+            )?;
+        }
+    }
+
+    let pcm_state = state.clone();
     db.create_scalar_function(
-        "fingerprint",
-        1,
-        FunctionFlags::SQLITE_DETERMINISTIC,
-        |ctx| {
-            let path = match ctx.get_raw(0) {
-                ValueRef::Text(s) => Ok(std::path::Path::new(
-                    std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?,
-                )),
-                v => Err(rusqlite::Error::InvalidFunctionParameterType(
-                    0,
-                    v.data_type(),
-                )),
-            }?;
-
-            let fingerprint = fingerprint_file(Path::new(path))
+        "fingerprint_pcm",
+        3,
+        OPTION_FUNCTION_FLAGS,
+        guard(move |ctx| {
+            let data = match ctx.get_raw(0) {
+                ValueRef::Null => return Ok(None),
+                ValueRef::Blob(data) => data,
+                v => {
+                    return Err(rusqlite::Error::InvalidFunctionParameterType(
+                        0,
+                        v.data_type(),
+                    ))
+                }
+            };
+            let sample_rate = ctx.get::<i64>(1)?;
+            let channels = ctx.get::<i64>(2)?;
+
+            let (encoding, preset) = {
+                let options = pcm_state.options();
+                (options.encoding, options.preset)
+            };
+            let fingerprint = fingerprint_pcm(data, sample_rate, channels, preset)
                 .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            let fingerprint = encoding.encode(&fingerprint);
+            limits::check_length(ctx, fingerprint.len())?;
 
-            Ok(ToSqlOutput::Owned(Value::Text(fingerprint)))
-        },
+            Ok(Some(fingerprint))
+        }),
+    )?;
+
+    for n_arg in [2, 3] {
+        let state = state.clone();
+        db.create_scalar_function(
+            "compare_fingerprints",
+            n_arg,
+            OPTION_FUNCTION_FLAGS,
+            guard(move |ctx| {
+                let (preset_a, fingerprint_a) =
+                    fingerprint_and_preset_from_value(0, "fingerprint_a", ctx.get_raw(0))?;
+                let (preset_b, fingerprint_b) =
+                    fingerprint_and_preset_from_value(1, "fingerprint_b", ctx.get_raw(1))?;
+                let default = state.options().preset;
+                let preset =
+                    comparison_preset(preset_argument(ctx, 2)?, preset_a, preset_b, default)
+                        .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+
+                let mode = state.options().match_mode;
+                let similarity_score =
+                    compare_fingerprints(&fingerprint_a, &fingerprint_b, preset, mode)
+                        .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+
+                Ok(ToSqlOutput::Owned(Value::Real(
+                    similarity_score.unwrap_or(0.0),
+                )))
+            }),
+        )?;
+    }
+
+    db.create_scalar_function(
+        "chromaprint_selftest",
+        0,
+        PURE_FUNCTION_FLAGS | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+        guard(|ctx| {
+            let report = selftest::run()
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?
+                .to_string();
+            limits::check_length(ctx, report.len())?;
+
+            Ok(json::result(report))
+        }),
+    )?;
+
+    for n_arg in [1, 2] {
+        let state = state.clone();
+        db.create_scalar_function(
+            "chromaprint_option",
+            n_arg,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            guard(move |ctx| {
+                let name = ctx.get::<String>(0)?;
+                let mut options = state.options_mut();
+                if ctx.len() > 1 {
+                    options
+                        .set(&name, ctx.get_raw(1))
+                        .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+                }
+                options
+                    .get(&name)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+            }),
+        )?;
+    }
+
+    let reload_state = state.clone();
+    db.create_scalar_function(
+        "chromaprint_reload_config",
+        0,
+        FunctionFlags::SQLITE_UTF8
+            | FunctionFlags::SQLITE_DIRECTONLY
+            | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+        guard(move |_ctx| {
+            let mut options = options::Options::from_env()
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            // SQL can't lift a restriction set by the host.
+            if let Some(allowed_roots) = reload_state.options().allowed_roots.clone() {
+                options.allowed_roots = Some(allowed_roots);
+            }
+            let report = options.to_json().to_string();
+            *reload_state.options_mut() = options;
+
+            Ok(json::result(report))
+        }),
     )?;
 
+    let duplicate_of_state = state.clone();
     db.create_scalar_function(
-        "compare_fingerprints",
+        "is_duplicate_of",
+        4,
+        FunctionFlags::SQLITE_UTF8,
+        guard(move |ctx| {
+            let table = ctx.get::<String>(0)?;
+            let fp_col = ctx.get::<String>(1)?;
+            // Nothing is a duplicate of a row without a fingerprint.
+            if ctx.get_raw(2) == ValueRef::Null {
+                return Ok(false);
+            }
+            let (made_with, fingerprint) =
+                fingerprint_and_preset_from_value(2, "fingerprint", ctx.get_raw(2))?;
+            let threshold = ctx.get::<f64>(3)?;
+
+            let (preset, mode) = {
+                let options = duplicate_of_state.options();
+                (made_with.unwrap_or(options.preset), options.match_mode)
+            };
+            // SAFETY: the connection reference does not outlive this call.
+            let db = unsafe { ctx.get_connection()? };
+            duplicates::is_duplicate_of(&db, &table, &fp_col, &fingerprint, threshold, preset, mode)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+        }),
+    )?;
+
+    db.create_scalar_function(
+        "fingerprint_bits",
+        1,
+        PURE_FUNCTION_FLAGS | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+        guard(|ctx| {
+            let fingerprint = fingerprint_from_value(0, "fingerprint", ctx.get_raw(0))?;
+            let stats = bits::fingerprint_bits(&fingerprint).to_string();
+            limits::check_length(ctx, stats.len())?;
+
+            Ok(json::result(stats))
+        }),
+    )?;
+
+    db.create_scalar_function(
+        "bit_count",
+        1,
+        PURE_FUNCTION_FLAGS,
+        guard(|ctx| {
+            bits::bit_count(ctx.get_raw(0))
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+        }),
+    )?;
+
+    db.create_scalar_function(
+        "hamming_distance",
         2,
-        FunctionFlags::SQLITE_DETERMINISTIC,
-        |ctx| {
-            let fingerprint_a: &str = match ctx.get_raw(0) {
-                ValueRef::Text(s) => {
-                    Ok(std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?)
+        PURE_FUNCTION_FLAGS,
+        guard(|ctx| {
+            bits::hamming_distance(ctx.get_raw(0), ctx.get_raw(1))
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+        }),
+    )?;
+
+    for n_arg in [2, 3] {
+        db.create_scalar_function(
+            "fp_gross_similarity",
+            n_arg,
+            PURE_FUNCTION_FLAGS,
+            guard(|ctx| {
+                if ctx.get_raw(0) == ValueRef::Null || ctx.get_raw(1) == ValueRef::Null {
+                    return Ok(None);
                 }
-                v => Err(rusqlite::Error::InvalidFunctionParameterType(
-                    0,
-                    v.data_type(),
-                )),
-            }?;
-            let fingerprint_b: &str = match ctx.get_raw(1) {
-                ValueRef::Text(s) => {
-                    Ok(std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?)
+                let (_, mut a) = fingerprint_and_preset_from_value(0, "fp_a", ctx.get_raw(0))?;
+                let (_, mut b) = fingerprint_and_preset_from_value(1, "fp_b", ctx.get_raw(1))?;
+                if ctx.len() > 2 {
+                    if let Some(items) = ctx.get::<Option<i64>>(2)? {
+                        let items = usize::try_from(items).map_err(|_| {
+                            rusqlite::Error::UserFunctionError(
+                                format!("items must not be negative, got {items}").into(),
+                            )
+                        })?;
+                        a.truncate(items);
+                        b.truncate(items);
+                    }
+                }
+                Ok(bits::gross_similarity(&a, &b))
+            }),
+        )?;
+    }
+
+    db.create_scalar_function(
+        "fp_length",
+        1,
+        PURE_FUNCTION_FLAGS,
+        guard(|ctx| {
+            if ctx.get_raw(0) == ValueRef::Null {
+                return Ok(None);
+            }
+            let (_, fingerprint) =
+                fingerprint_and_preset_from_value(0, "fingerprint", ctx.get_raw(0))?;
+            Ok(Some(fingerprint.len() as i64))
+        }),
+    )?;
+
+    for n_arg in [1, 2] {
+        let state = state.clone();
+        db.create_scalar_function(
+            "fp_duration_secs",
+            n_arg,
+            OPTION_FUNCTION_FLAGS,
+            guard(move |ctx| {
+                if ctx.get_raw(0) == ValueRef::Null {
+                    return Ok(None);
                 }
-                v => Err(rusqlite::Error::InvalidFunctionParameterType(
-                    1,
-                    v.data_type(),
-                )),
-            }?;
+                let (made_with, fingerprint) =
+                    fingerprint_and_preset_from_value(0, "fingerprint", ctx.get_raw(0))?;
+                let preset = preset_argument(ctx, 1)?
+                    .or(made_with)
+                    .unwrap_or(state.options().preset);
+                Ok(Some(fingerprint_duration_secs(fingerprint.len(), preset)))
+            }),
+        )?;
+    }
 
-            let similarity_score = compare_fingerprints(fingerprint_a, fingerprint_b)
+    for n_arg in [1, 2, 3] {
+        db.create_scalar_function(
+            "fp_minhash",
+            n_arg,
+            PURE_FUNCTION_FLAGS,
+            guard(|ctx| {
+                if ctx.get_raw(0) == ValueRef::Null {
+                    return Ok(None);
+                }
+                let (_, fingerprint) =
+                    fingerprint_and_preset_from_value(0, "fingerprint", ctx.get_raw(0))?;
+                let bands = if ctx.len() > 1 {
+                    ctx.get::<Option<i64>>(1)?
+                } else {
+                    None
+                };
+                let rows = if ctx.len() > 2 {
+                    ctx.get::<Option<i64>>(2)?
+                } else {
+                    None
+                };
+                let len = minhash::signature_len(
+                    bands.unwrap_or(minhash::DEFAULT_BANDS),
+                    rows.unwrap_or(minhash::DEFAULT_ROWS),
+                )
                 .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+                Ok(minhash::signature(&fingerprint, len)
+                    .map(|signature| functions::fingerprint_to_blob(&signature)))
+            }),
+        )?;
+    }
+
+    db.create_scalar_function(
+        "fp_simhash64",
+        1,
+        PURE_FUNCTION_FLAGS,
+        guard(|ctx| {
+            if ctx.get_raw(0) == ValueRef::Null {
+                return Ok(None);
+            }
+            let (_, fingerprint) =
+                fingerprint_and_preset_from_value(0, "fingerprint", ctx.get_raw(0))?;
+            Ok(simhash::simhash64(&fingerprint))
+        }),
+    )?;
+
+    db.create_scalar_function(
+        "fp_simhash64_distance",
+        2,
+        PURE_FUNCTION_FLAGS,
+        guard(|ctx| {
+            let a = ctx.get::<Option<i64>>(0)?;
+            let b = ctx.get::<Option<i64>>(1)?;
+            Ok(a.zip(b).map(|(a, b)| simhash::distance(a, b)))
+        }),
+    )?;
+
+    for n_arg in [1, 2] {
+        let state = state.clone();
+        db.create_scalar_function(
+            "fp_to_acoustid",
+            n_arg,
+            OPTION_FUNCTION_FLAGS,
+            guard(move |ctx| {
+                if ctx.get_raw(0) == ValueRef::Null {
+                    return Ok(None);
+                }
+                let fingerprint = fingerprint_from_value(0, "fingerprint", ctx.get_raw(0))?;
+                let preset = preset_argument(ctx, 1)?.unwrap_or(state.options().preset);
+                let compressed = encoding::compress(&fingerprint, &preset.config());
+                limits::check_length(ctx, compressed.len())?;
+
+                Ok(Some(compressed))
+            }),
+        )?;
+    }
+
+    for n_arg in [1, 2] {
+        let state = state.clone();
+        db.create_scalar_function(
+            "fp_from_acoustid",
+            n_arg,
+            OPTION_FUNCTION_FLAGS,
+            guard(move |ctx| {
+                let Some(compressed) = ctx.get::<Option<String>>(0)? else {
+                    return Ok(None);
+                };
+                let preset = preset_argument(ctx, 1)?.unwrap_or(state.options().preset);
+                let fingerprint = fingerprint_from_acoustid(&compressed, preset)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+                let fingerprint = state.options().encoding.encode(&fingerprint);
+                limits::check_length(ctx, fingerprint.len())?;
+
+                Ok(Some(fingerprint))
+            }),
+        )?;
+    }
+
+    for n_arg in [1, 2] {
+        let state = state.clone();
+        db.create_scalar_function(
+            "fp_compress",
+            n_arg,
+            OPTION_FUNCTION_FLAGS,
+            guard(move |ctx| {
+                if ctx.get_raw(0) == ValueRef::Null {
+                    return Ok(None);
+                }
+                let (made_with, fingerprint) =
+                    fingerprint_and_preset_from_value(0, "fingerprint", ctx.get_raw(0))?;
+                let preset = preset_argument(ctx, 1)?
+                    .or(made_with)
+                    .unwrap_or(state.options().preset);
+                let compressed = encoding::compress_bytes(&fingerprint, &preset.config());
+                limits::check_length(ctx, compressed.len())?;
+
+                Ok(Some(compressed))
+            }),
+        )?;
+    }
+
+    let decompress_state = state.clone();
+    db.create_scalar_function(
+        "fp_decompress",
+        1,
+        OPTION_FUNCTION_FLAGS,
+        guard(move |ctx| {
+            let compressed = match ctx.get_raw(0) {
+                ValueRef::Null => return Ok(None),
+                ValueRef::Blob(b) => encoding::decompress_bytes(b),
+                ValueRef::Text(s) => encoding::decompress(
+                    std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?,
+                ),
+                v => {
+                    return Err(rusqlite::Error::InvalidFunctionParameterType(
+                        0,
+                        v.data_type(),
+                    ))
+                }
+            };
+            let (_, fingerprint) =
+                compressed.map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            let fingerprint = decompress_state.options().encoding.encode(&fingerprint);
+            limits::check_length(ctx, fingerprint.len())?;
+
+            Ok(Some(fingerprint))
+        }),
+    )?;
+
+    for (name, encoding) in [
+        ("fp_to_base64", Encoding::Base64),
+        ("fp_to_base64url", Encoding::Base64Url),
+        ("fp_to_hex", Encoding::Hex),
+    ] {
+        db.create_scalar_function(
+            name,
+            1,
+            PURE_FUNCTION_FLAGS,
+            guard(move |ctx| {
+                if ctx.get_raw(0) == ValueRef::Null {
+                    return Ok(None);
+                }
+                let (_, fingerprint) =
+                    fingerprint_and_preset_from_value(0, "fingerprint", ctx.get_raw(0))?;
+                let fingerprint = encoding.encode(&fingerprint);
+                limits::check_length(ctx, fingerprint.len())?;
+
+                Ok(Some(fingerprint))
+            }),
+        )?;
+    }
+
+    db.create_scalar_function(
+        "fp_from_text",
+        1,
+        PURE_FUNCTION_FLAGS,
+        guard(|ctx| {
+            if ctx.get_raw(0) == ValueRef::Null {
+                return Ok(None);
+            }
+            let (_, fingerprint) =
+                fingerprint_and_preset_from_value(0, "fingerprint", ctx.get_raw(0))?;
+            let fingerprint = functions::fingerprint_to_blob(&fingerprint);
+            limits::check_length(ctx, fingerprint.len())?;
+
+            Ok(Some(fingerprint))
+        }),
+    )?;
+
+    db.create_aggregate_function(
+        "airplay_report",
+        5,
+        PURE_FUNCTION_FLAGS | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+        airplay::AirplayReport,
+    )?;
+
+    #[cfg(all(feature = "decode", not(feature = "blob-only")))]
+    register_file_functions(db, &state)?;
+
+    timings::register(db, state.clone())?;
+    build_info::register(db, state.clone())?;
+    intro::register(db, state.clone())?;
+    hashes::register(db)?;
+    index::register(db, state.clone())?;
+    minhash::register(db)?;
+    search::register(db, state.clone())?;
+    overlap::register(db, state.clone())?;
+    #[cfg(feature = "acoustid")]
+    {
+        acoustid::register(db, state.clone())?;
+        musicbrainz::register(db, state.clone())?;
+    }
+    #[cfg(feature = "net")]
+    {
+        live::register(db, state.clone())?;
+        http::register(db, state)?;
+    }
+
+    Ok(())
+}
 
-            Ok(ToSqlOutput::Owned(Value::Real(
-                similarity_score.unwrap_or(0.0),
-            )))
+#[cfg(feature = "decode")]
+/// Register the functions and tables that read files, all but those of the
+/// `net` feature, which the `blob-only` feature leaves out.
+#[cfg_attr(feature = "blob-only", allow(dead_code))]
+fn register_file_functions(db: &Connection, state: &Arc<State>) -> rusqlite::Result<()> {
+    for (name, output) in [
+        ("fingerprint", Output::Text),
+        ("fingerprint_raw", Output::Blob),
+        ("fingerprint_json", Output::Json),
+    ] {
+        let flags = match output {
+            Output::Json => FILE_FUNCTION_FLAGS | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+            _ => FILE_FUNCTION_FLAGS,
+        };
+        for n_arg in [1, 2, 3] {
+            let state = state.clone();
+            db.create_scalar_function(
+                name,
+                n_arg,
+                flags,
+                guard(move |ctx| {
+                    let path = path::path_from_value(0, ctx.get_raw(0))?;
+                    let (encoding, max_length_secs) = {
+                        let options = state.options();
+                        (options.encoding, options.max_length_secs)
+                    };
+                    let mut stats = state.call_stats();
+
+                    // A number rather than a format starts a time range.
+                    let window = ctx.len() > 1
+                        && matches!(ctx.get_raw(1), ValueRef::Integer(_) | ValueRef::Real(_));
+                    let fingerprint = if window {
+                        let start_secs = ctx.get::<f64>(1)?;
+                        let length_secs = if ctx.len() > 2 {
+                            ctx.get::<Option<f64>>(2)?
+                        } else {
+                            None
+                        };
+                        let preset = state.options().preset;
+                        fingerprint_file_window(
+                            &path,
+                            start_secs,
+                            length_secs,
+                            preset,
+                            max_length_secs,
+                            &mut stats,
+                        )
+                    } else {
+                        let format = if ctx.len() > 1 {
+                            ctx.get::<Option<String>>(1)?
+                        } else {
+                            None
+                        };
+                        let preset = preset_argument(ctx, 2)?.unwrap_or(state.options().preset);
+                        let cache_table = state.options().cache_table.clone();
+                        // The caches return fingerprints without opening files.
+                        stats
+                            .check_path(&path)
+                            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+                        let mut compute = || {
+                            fingerprint_file_raw(
+                                &path,
+                                format.as_deref(),
+                                preset,
+                                max_length_secs,
+                                &mut stats,
+                            )
+                        };
+                        match cache_table {
+                            Some(table) => {
+                                // SAFETY: the connection reference does not outlive this call.
+                                let db = unsafe { ctx.get_connection()? };
+                                cache_table::fingerprint(
+                                    &db,
+                                    &table,
+                                    &path,
+                                    preset,
+                                    max_length_secs,
+                                    compute,
+                                )
+                            }
+                            None => compute(),
+                        }
+                    }
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+                    state.record_timing(name, &path.to_string_lossy(), stats);
+
+                    let (fingerprint, subtype) = match output {
+                        Output::Text => {
+                            let text = encoding.encode(&fingerprint);
+                            limits::check_length(ctx, text.len())?;
+                            (Value::Text(text), None)
+                        }
+                        Output::Blob => {
+                            let blob = functions::fingerprint_to_blob(&fingerprint);
+                            limits::check_length(ctx, blob.len())?;
+                            (Value::Blob(blob), None)
+                        }
+                        Output::Json => {
+                            let text = serde_json::json!(fingerprint).to_string();
+                            limits::check_length(ctx, text.len())?;
+                            json::result(Value::Text(text))
+                        }
+                    };
+                    Ok((ToSqlOutput::Owned(fingerprint), subtype))
+                }),
+            )?;
+        }
+    }
+
+    db.create_aggregate_function(
+        "fingerprint_album_agg",
+        1,
+        FILE_FUNCTION_FLAGS,
+        album::AlbumFingerprint {
+            state: state.clone(),
         },
     )?;
 
-    Ok(false)
+    create_path_function(db, state, "audio_speech_music", speech::speech_music)?;
+    create_path_function(db, state, "audio_voice_ratio", speech::audio_voice_ratio)?;
+    create_path_function(db, state, "audio_noise_floor", analysis::audio_noise_floor)?;
+    create_path_function(
+        db,
+        state,
+        "audio_spectral_centroid",
+        spectral::audio_spectral_centroid,
+    )?;
+    create_path_function(db, state, "audio_zcr", analysis::audio_zcr)?;
+
+    for n_arg in [1, 2] {
+        let state = state.clone();
+        db.create_scalar_function(
+            "audio_lead_trail_silence",
+            n_arg,
+            FILE_FUNCTION_FLAGS | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+            guard(move |ctx| {
+                let path = path::path_from_value(0, ctx.get_raw(0))?;
+                let threshold_db = if ctx.len() > 1 {
+                    ctx.get::<f64>(1)?
+                } else {
+                    analysis::DEFAULT_SILENCE_DBFS
+                };
+
+                let mut stats = state.call_stats();
+                let silence = analysis::audio_lead_trail_silence(&path, threshold_db, &mut stats)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?
+                    .to_string();
+                state.record_timing("audio_lead_trail_silence", &path.to_string_lossy(), stats);
+                limits::check_length(ctx, silence.len())?;
+
+                Ok(json::result(silence))
+            }),
+        )?;
+    }
+
+    for n_arg in [1, 3] {
+        let state = state.clone();
+        db.create_scalar_function(
+            "audio_mfcc",
+            n_arg,
+            FILE_FUNCTION_FLAGS | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+            guard(move |ctx| {
+                let path = path::path_from_value(0, ctx.get_raw(0))?;
+                let (n_coeffs, hop_secs) = if ctx.len() > 1 {
+                    (ctx.get::<i64>(1)?, ctx.get::<f64>(2)?)
+                } else {
+                    (mfcc::DEFAULT_COEFFS, mfcc::DEFAULT_HOP_SECS)
+                };
+
+                let mut stats = state.call_stats();
+                let mfccs = mfcc::audio_mfcc(&path, n_coeffs, hop_secs, &mut stats)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?
+                    .to_string();
+                state.record_timing("audio_mfcc", &path.to_string_lossy(), stats);
+                limits::check_length(ctx, mfccs.len())?;
+
+                Ok(json::result(mfccs))
+            }),
+        )?;
+    }
+
+    create_path_function(
+        db,
+        state,
+        "audio_loudness_range",
+        loudness::audio_loudness_range,
+    )?;
+    create_path_function(db, state, "audio_true_peak", loudness::audio_true_peak)?;
+    create_path_function_with_flags(
+        db,
+        state,
+        "audio_dc_offset",
+        FILE_FUNCTION_FLAGS | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+        channels::audio_dc_offset,
+    )?;
+    create_path_function(
+        db,
+        state,
+        "audio_phase_inverted",
+        channels::audio_phase_inverted,
+    )?;
+    create_path_function(db, state, "audio_truncated", integrity::audio_truncated)?;
+    create_path_function_with_flags(
+        db,
+        state,
+        "audio_metadata",
+        FILE_FUNCTION_FLAGS | FunctionFlags::SQLITE_RESULT_SUBTYPE,
+        metadata::audio_metadata,
+    )?;
+    create_path_function(db, state, "cover_art", metadata::cover_art)?;
+    create_path_function(db, state, "audio_duration", duration::audio_duration)?;
+    create_path_function(db, state, "cover_art_mime", metadata::cover_art_mime)?;
+
+    let duplicate_state = state.clone();
+    db.create_scalar_function(
+        "duplicate_kind",
+        2,
+        FILE_FUNCTION_FLAGS,
+        guard(move |ctx| {
+            let a = duplicates::Audio::from_value(0, ctx.get_raw(0), &duplicate_state)?;
+            let b = duplicates::Audio::from_value(1, ctx.get_raw(1), &duplicate_state)?;
+
+            let kind = duplicates::duplicate_kind(a, b, &duplicate_state)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+
+            Ok(kind.name())
+        }),
+    )?;
+
+    tracklist::register(db, state.clone())?;
+    segments::register(db, state.clone())?;
+    spectral::register(db, state.clone())?;
+    chroma::register(db, state.clone())?;
+    onsets::register(db, state.clone())?;
+    beats::register(db, state.clone())?;
+    channels::register(db, state.clone())?;
+    integrity::register(db, state.clone())?;
+    glitches::register(db, state.clone())?;
+    sync::register(db, state.clone())?;
+    benchmark::register(db, state.clone())?;
+    scan::register(db, state.clone())?;
+    metadata::register(db, state.clone())?;
+    info::register(db, state.clone())?;
+    chunks::register(db, state.clone())?;
+
+    Ok(())
+}
+
+#[cfg(feature = "decode")]
+/// Register `name(path TEXT|BLOB)`, an analysis function of the file at `path`.
+fn create_path_function<T: ToSql + 'static>(
+    db: &Connection,
+    state: &Arc<State>,
+    name: &'static str,
+    f: fn(&Path, &mut CallStats) -> Result<T>,
+) -> rusqlite::Result<()> {
+    create_path_function_with_flags(db, state, name, FILE_FUNCTION_FLAGS, f)
+}
+
+#[cfg(feature = "decode")]
+/// Like [`create_path_function`], with the given function flags.
+fn create_path_function_with_flags<T: SqlFnOutput + 'static>(
+    db: &Connection,
+    state: &Arc<State>,
+    name: &'static str,
+    flags: FunctionFlags,
+    f: fn(&Path, &mut CallStats) -> Result<T>,
+) -> rusqlite::Result<()> {
+    let state = state.clone();
+    db.create_scalar_function(
+        name,
+        1,
+        flags,
+        guard(move |ctx| {
+            let path = path::path_from_value(0, ctx.get_raw(0))?;
+
+            let mut stats = state.call_stats();
+            let result =
+                f(&path, &mut stats).map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            state.record_timing(name, &path.to_string_lossy(), stats);
+            limits::check_output(ctx, &result)?;
+
+            Ok(result)
+        }),
+    )
 }
 
-fn fingerprint_file(path: &Path) -> Result<String> {
-    let src = std::fs::File::open(path).context("Failed to open file")?;
-    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+#[cfg(feature = "decode")]
+/// Fingerprint the file at `path`, or at most its first `max_length_secs`
+/// seconds, with `format` as the format hint if given (see
+/// [`AudioStream::open_file_as`]).
+fn fingerprint_file_raw(
+    path: &Path,
+    format: Option<&str>,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    stats: &mut CallStats,
+) -> Result<Vec<u32>> {
+    let cache_size = stats.shared_cache_size;
+    cached_fingerprint(path, preset, max_length_secs, cache_size, || {
+        fingerprint_path_inspecting(path, format, preset, max_length_secs, stats, &mut |_| {})
+    })
+}
 
-    let mut hint = Hint::new();
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        hint.with_extension(ext);
+#[cfg(feature = "decode")]
+/// Fingerprint `length_secs` seconds of the file at `path` from `start_secs`
+/// on, or the rest of it up to `max_length_secs` without a length (see
+/// [`AudioStream::set_window`]). Windows aren't cached.
+fn fingerprint_file_window(
+    path: &Path,
+    start_secs: f64,
+    length_secs: Option<f64>,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    stats: &mut CallStats,
+) -> Result<Vec<u32>> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    stream.set_window(start_secs, length_secs)?;
+    if length_secs.is_none() {
+        stream.set_max_length(max_length_secs);
     }
+    fingerprint_stream(stream, preset, stats, &mut |_| {})
+}
 
-    let probed = symphonia::default::get_probe()
-        .format(
-            &hint,
-            mss,
-            &FormatOptions::default(),
-            &MetadataOptions::default(),
-        )
-        .context("Failed to probe format")?;
-
-    let mut format = probed.format;
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .context("No audio track found")?;
-
-    let sample_rate = track
-        .codec_params
-        .sample_rate
-        .context("Missing sample rate")?;
-    let channels = track
-        .codec_params
-        .channels
-        .context("Missing channels")?
-        .count();
-
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &DecoderOptions::default())
-        .context("Failed to create decoder")?;
-
-    let config = Configuration::preset_test1();
+#[cfg(feature = "decode")]
+fn fingerprint_path(path: &Path, preset: Preset, stats: &mut CallStats) -> Result<Vec<u32>> {
+    stats.check_path(path)?;
+    let cache_size = stats.shared_cache_size;
+    cached_fingerprint(path, preset, None, cache_size, || {
+        fingerprint_path_inspecting(path, None, preset, None, stats, &mut |_| {})
+    })
+}
+
+#[cfg(feature = "decode")]
+/// The fingerprint of the file at `path` made with `preset` from at most its
+/// first `max_length_secs` seconds, from the shared cache if enabled, or
+/// from `compute`. The cache keeps at most `cache_size` fingerprints, or
+/// [`options::DEFAULT_SHARED_CACHE_SIZE`].
+fn cached_fingerprint(
+    path: &Path,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    cache_size: Option<u32>,
+    compute: impl FnOnce() -> Result<Vec<u32>>,
+) -> Result<Vec<u32>> {
+    #[cfg(feature = "shared-cache")]
+    return cache::fingerprint(
+        path,
+        preset,
+        max_length_secs,
+        cache_size.unwrap_or(options::DEFAULT_SHARED_CACHE_SIZE) as usize,
+        compute,
+    );
+    #[cfg(not(feature = "shared-cache"))]
+    {
+        let _ = (path, preset, max_length_secs, cache_size);
+        compute()
+    }
+}
+
+#[cfg(feature = "decode")]
+/// Like [`fingerprint_path`], but with an optional format hint, a preset and
+/// a maximum length, and also pass each packet's samples to `inspect`.
+fn fingerprint_path_inspecting(
+    path: &Path,
+    format: Option<&str>,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    stats: &mut CallStats,
+    inspect: &mut dyn FnMut(&[i16]),
+) -> Result<Vec<u32>> {
+    let mut stream = AudioStream::open_file_as(path, format, false, stats)?;
+    stream.set_max_length(max_length_secs);
+    fingerprint_stream(stream, preset, stats, inspect)
+}
+
+#[cfg(feature = "decode")]
+/// Fingerprint audio held in memory, with an optional format hint.
+fn fingerprint_bytes(
+    data: Vec<u8>,
+    format: Option<&str>,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    stats: &mut CallStats,
+) -> Result<Vec<u32>> {
+    let source = Box::new(std::io::Cursor::new(data));
+    fingerprint_source(source, format, preset, max_length_secs, stats)
+}
+
+#[cfg(feature = "decode")]
+/// Fingerprint audio from `source`, or at most its first `max_length_secs`
+/// seconds, with an optional format hint.
+fn fingerprint_source(
+    source: Box<dyn MediaSource>,
+    format: Option<&str>,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    stats: &mut CallStats,
+) -> Result<Vec<u32>> {
+    let mut stream = AudioStream::open_source(source, format, stats)?;
+    stream.set_max_length(max_length_secs);
+    fingerprint_stream(stream, preset, stats, &mut |_| {})
+}
+
+#[cfg(feature = "decode")]
+/// Fingerprint the rest of `stream`, decoding on this thread while another
+/// thread fingerprints the packets decoded so far.
+fn fingerprint_stream(
+    mut stream: AudioStream,
+    preset: Preset,
+    stats: &mut CallStats,
+    inspect: &mut dyn FnMut(&[i16]),
+) -> Result<Vec<u32>> {
+    let (sample_rate, channels) = (stream.sample_rate, stream.channels as u32);
+    let (sender, receiver) = mpsc::sync_channel::<Vec<i16>>(PIPELINE_DEPTH);
+    std::thread::scope(|scope| {
+        // The fingerprinter isn't `Send`, so it's made on its thread.
+        let fingerprinter = scope.spawn(move || -> Result<(Vec<u32>, Duration)> {
+            let config = preset.config();
+            let mut printer = Fingerprinter::new(&config);
+            printer
+                .start(sample_rate, channels)
+                .context("Failed to start fingerprinter")?;
+            let mut elapsed = Duration::ZERO;
+            for samples in receiver {
+                let started = Instant::now();
+                printer.consume(&samples);
+                elapsed += started.elapsed();
+            }
+            let started = Instant::now();
+            printer.finish();
+            elapsed += started.elapsed();
+            Ok((printer.fingerprint().to_vec(), elapsed))
+        });
+
+        let decoded = send_samples(&mut stream, stats, inspect, sender);
+        let (fingerprint, elapsed) = fingerprinter
+            .join()
+            .unwrap_or_else(|payload| std::panic::resume_unwind(payload))?;
+        stats.fingerprint += elapsed;
+        decoded.map(|()| fingerprint)
+    })
+}
+
+#[cfg(feature = "decode")]
+/// Decode the rest of `stream`, passing each packet's samples to `inspect`
+/// and sending them to `sender` until it's disconnected.
+fn send_samples(
+    stream: &mut AudioStream,
+    stats: &mut CallStats,
+    inspect: &mut dyn FnMut(&[i16]),
+    sender: mpsc::SyncSender<Vec<i16>>,
+) -> Result<()> {
+    while let Some(samples) = stream.next_samples(stats)? {
+        inspect(samples);
+        if sender.send(samples.to_vec()).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn fingerprint_samples(
+    samples: &[i16],
+    sample_rate: u32,
+    channels: u32,
+    preset: Preset,
+) -> Result<Vec<u32>> {
+    let config = preset.config();
     let mut printer = Fingerprinter::new(&config);
     printer
-        .start(sample_rate, channels as u32)
+        .start(sample_rate, channels)
         .context("Failed to start fingerprinter")?;
+    printer.consume(samples);
+    printer.finish();
+    Ok(printer.fingerprint().to_vec())
+}
 
-    while let Ok(packet) = format.next_packet() {
-        let decoded = decoder.decode(&packet).context("Failed to decode packet")?;
-        let mut sample_buffer =
-            SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
-        sample_buffer.copy_interleaved_ref(decoded);
-        printer.consume(sample_buffer.samples());
+/// Fingerprint interleaved signed 16-bit little-endian PCM.
+fn fingerprint_pcm(
+    data: &[u8],
+    sample_rate: i64,
+    channels: i64,
+    preset: Preset,
+) -> Result<Vec<u32>> {
+    let Ok(sample_rate @ 1..) = u32::try_from(sample_rate) else {
+        bail!("Invalid sample rate: {sample_rate}");
+    };
+    let Ok(channels @ 1..) = u32::try_from(channels) else {
+        bail!("Invalid channel count: {channels}");
+    };
+    if !data.len().is_multiple_of(2 * channels as usize) {
+        bail!("PCM data must be whole frames of {channels} 16-bit samples");
     }
 
-    printer.finish();
-    let fingerprint = printer.fingerprint();
-    let fingerprint: Vec<u8> = fingerprint
-        .iter()
-        .flat_map(|&x| x.to_be_bytes().to_vec())
+    let samples: Vec<i16> = data
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
         .collect();
-    Ok(BASE64_STANDARD.encode(&fingerprint))
+    fingerprint_samples(&samples, sample_rate, channels, preset)
 }
 
-fn compare_fingerprints(fingerprint_a: &str, fingerprint_b: &str) -> Result<Option<f64>> {
-    let fingerprint_a = BASE64_STANDARD
-        .decode(fingerprint_a.trim())
-        .context("Base64 decode error for fingerprint_a")?;
-    let fingerprint_b = BASE64_STANDARD
-        .decode(fingerprint_b.trim())
-        .context("Base64 decode error for fingerprint_b")?;
+fn encode_fingerprint(fingerprint: &[u32]) -> String {
+    Encoding::Base64.encode(fingerprint)
+}
 
-    let fingerprint_a: Vec<u32> = fingerprint_a
-        .chunks_exact(4)
-        .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
-        .collect();
+fn decode_fingerprint(fingerprint: &str) -> Result<Vec<u32>> {
+    encoding::decode(fingerprint)
+}
 
-    let fingerprint_b: Vec<u32> = fingerprint_b
+/// Decode a BLOB of big-endian 32-bit fingerprint items.
+fn decode_fingerprint_blob(fingerprint: &[u8]) -> Result<Vec<u32>> {
+    if !fingerprint.len().is_multiple_of(4) {
+        bail!(
+            "Length of {} bytes is not a multiple of 4",
+            fingerprint.len()
+        );
+    }
+
+    Ok(fingerprint
         .chunks_exact(4)
         .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
-        .collect();
+        .collect())
+}
 
-    let config = Configuration::preset_test1();
-    let segments = match_fingerprints(&fingerprint_a, &fingerprint_b, &config)
+/// Interpret argument `idx`, called `name` in errors, as a fingerprint:
+/// TEXT as returned by `fingerprint()` in any encoding, or a BLOB of the
+/// same bytes without the encoding.
+fn fingerprint_from_value(
+    idx: usize,
+    name: &str,
+    value: ValueRef<'_>,
+) -> rusqlite::Result<Vec<u32>> {
+    match value {
+        ValueRef::Text(s) => {
+            let s = std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?;
+            decode_fingerprint(s).with_context(|| format!("Decode error for {name}"))
+        }
+        ValueRef::Blob(b) => {
+            decode_fingerprint_blob(b).with_context(|| format!("Invalid BLOB for {name}"))
+        }
+        v => {
+            return Err(rusqlite::Error::InvalidFunctionParameterType(
+                idx,
+                v.data_type(),
+            ))
+        }
+    }
+    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+}
+
+/// Like `fingerprint_from_value`, but also accepting fingerprints in
+/// Chromaprint's compressed format, as TEXT or as a BLOB of its bytes, and
+/// returning the preset that made the fingerprint if it records it. Raw
+/// fingerprints don't.
+fn fingerprint_and_preset_from_value(
+    idx: usize,
+    name: &str,
+    value: ValueRef<'_>,
+) -> rusqlite::Result<(Option<Preset>, Vec<u32>)> {
+    let (algorithm, fingerprint) = match value {
+        ValueRef::Text(s) => {
+            let s = std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?;
+            encoding::decode_any(s).with_context(|| format!("Decode error for {name}"))
+        }
+        ValueRef::Blob(b) => {
+            encoding::decode_any_blob(b).with_context(|| format!("Invalid BLOB for {name}"))
+        }
+        _ => return Ok((None, fingerprint_from_value(idx, name, value)?)),
+    }
+    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+    let preset = algorithm.map(|id| {
+        Preset::from_id(id).ok_or_else(|| {
+            rusqlite::Error::UserFunctionError(format!("Unknown algorithm {id} of {name}").into())
+        })
+    });
+    Ok((preset.transpose()?, fingerprint))
+}
+
+/// Approximate length in seconds of the audio a fingerprint of `items` items
+/// made with `preset` was made from: the items' duration, and the audio the
+/// fingerprinter needs before the first one.
+fn fingerprint_duration_secs(items: usize, preset: Preset) -> f64 {
+    if items == 0 {
+        return 0.0;
+    }
+    let config = preset.config();
+    items as f64 * f64::from(config.item_duration_in_seconds())
+        + config.delay() as f64 / f64::from(config.sample_rate())
+}
+
+/// The optional preset argument at `idx`; NULL or absent if not given.
+fn preset_argument(
+    ctx: &rusqlite::functions::Context<'_>,
+    idx: usize,
+) -> rusqlite::Result<Option<Preset>> {
+    if ctx.len() <= idx {
+        return Ok(None);
+    }
+    ctx.get::<Option<String>>(idx)?
+        .map(|name| Preset::parse(&name))
+        .transpose()
+        .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+}
+
+/// The preset to compare two fingerprints with: `preset` if given, or else
+/// the one that made `fingerprint_a`. Fingerprints that don't record their
+/// preset are assumed to be made with `preset`, or `default`.
+fn comparison_preset(
+    preset: Option<Preset>,
+    preset_a: Option<Preset>,
+    preset_b: Option<Preset>,
+    default: Preset,
+) -> Result<Preset> {
+    let assumed = preset.unwrap_or(default);
+    let preset_a = preset_a.unwrap_or(assumed);
+    let preset_b = preset_b.unwrap_or(assumed);
+    let preset = preset.unwrap_or(preset_a);
+    for (name, made_with) in [("fingerprint_a", preset_a), ("fingerprint_b", preset_b)] {
+        if made_with != preset {
+            bail!(
+                "{name} was made with preset {}, not {}",
+                made_with.name(),
+                preset.name()
+            );
+        }
+    }
+    Ok(preset)
+}
+
+/// Decompress a fingerprint `fpcalc` printed, which must have been made with
+/// `preset`.
+fn fingerprint_from_acoustid(compressed: &str, preset: Preset) -> Result<Vec<u32>> {
+    let (algorithm, fingerprint) = encoding::decompress(compressed)?;
+    if algorithm != preset.id() {
+        bail!(
+            "Fingerprint made with algorithm {}, not {} (use fpcalc -algorithm {})",
+            algorithm + 1,
+            preset.id() + 1,
+            preset.id() + 1
+        );
+    }
+    Ok(fingerprint)
+}
+
+fn compare_fingerprints(
+    fingerprint_a: &[u32],
+    fingerprint_b: &[u32],
+    preset: Preset,
+    mode: MatchMode,
+) -> Result<Option<f64>> {
+    let config = preset.config();
+    let segments = match_fingerprints(fingerprint_a, fingerprint_b, &config)
         .context("Failed to match fingerprints")?;
 
+    Ok(similarity_score(
+        &segments,
+        fingerprint_a,
+        fingerprint_b,
+        &config,
+        mode,
+    ))
+}
+
+/// Overall similarity score of matched segments (0 = identical, 32 =
+/// unrelated), or `None` if nothing matched.
+fn similarity_score(
+    segments: &[Segment],
+    fingerprint_a: &[u32],
+    fingerprint_b: &[u32],
+    config: &Configuration,
+    mode: MatchMode,
+) -> Option<f64> {
     if segments.is_empty() {
-        return Ok(None);
+        return None;
     }
 
-    let total_duration: f64 = segments.iter().map(|s| s.duration(&config) as f64).sum();
-    let similarity_score = 32.0
-        - (total_duration
+    let total_duration: f64 = segments.iter().map(|s| s.duration(config) as f64).sum();
+    Some(
+        32.0 - (total_duration
             / segments
                 .iter()
-                .map(|s| s.duration(&config) as f64 / (32.0 - s.score))
-                .sum::<f64>());
-
-    Ok(Some(similarity_score))
+                .map(|s| {
+                    let score =
+                        matching::segment_score(s, fingerprint_a, fingerprint_b, config, mode);
+                    s.duration(config) as f64 / (32.0 - score)
+                })
+                .sum::<f64>()),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(all(feature = "decode", not(feature = "extension")))]
+    #[test]
+    fn test_register() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+
+        let db = Connection::open_in_memory().unwrap();
+        register(&db).unwrap();
+        let fingerprint: String = db
+            .query_row("SELECT fingerprint(?1)", [path.to_str()], |row| row.get(0))
+            .unwrap();
+        let expected =
+            fingerprint_path(&path, Preset::default(), &mut CallStats::default()).unwrap();
+        assert_eq!(decode_fingerprint(&fingerprint).unwrap(), expected);
+
+        let raw: Vec<u8> = db
+            .query_row("SELECT fingerprint_raw(?1)", [path.to_str()], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(decode_fingerprint_blob(&raw).unwrap(), expected);
+    }
+
+    #[cfg(feature = "decode")]
     #[test]
     fn test_fingerprint_file() {
         let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
 
-        let fingerprint_a =
-            fingerprint_file(&Path::new(&manifest_dir).join("src/testdata/XC444467.ogg")).unwrap();
+        let fingerprint_a = fingerprint_file_raw(
+            &Path::new(&manifest_dir).join("src/testdata/XC444467.ogg"),
+            None,
+            Preset::default(),
+            None,
+            &mut CallStats::default(),
+        )
+        .unwrap();
 
-        let fingerprint_b =
-            fingerprint_file(&Path::new(&manifest_dir).join("src/testdata/XC444467.mp3")).unwrap();
+        let fingerprint_b = fingerprint_file_raw(
+            &Path::new(&manifest_dir).join("src/testdata/XC444467.mp3"),
+            None,
+            Preset::default(),
+            None,
+            &mut CallStats::default(),
+        )
+        .unwrap();
 
-        let similarity_score = compare_fingerprints(&fingerprint_a, &fingerprint_b).unwrap();
+        let similarity_score = compare_fingerprints(
+            &fingerprint_a,
+            &fingerprint_b,
+            Preset::default(),
+            MatchMode::Default,
+        )
+        .unwrap();
 
         // Less is better, range approx. 0.0 - 32.0
         assert!(similarity_score.unwrap() < 2.0);
     }
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn test_fingerprint_file_window() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        for file in ["src/testdata/XC444467.ogg", "src/testdata/XC444467.mp3"] {
+            let path = Path::new(&manifest_dir).join(file);
+            let whole =
+                fingerprint_path(&path, Preset::default(), &mut CallStats::default()).unwrap();
+            let window = |start_secs, length_secs| {
+                fingerprint_file_window(
+                    &path,
+                    start_secs,
+                    length_secs,
+                    Preset::default(),
+                    None,
+                    &mut CallStats::default(),
+                )
+                .unwrap()
+            };
+
+            assert_eq!(window(0.0, None), whole, "{file}");
+            let part = window(1.0, Some(5.0));
+            assert!(!part.is_empty() && part.len() < whole.len(), "{file}");
+            let score =
+                compare_fingerprints(&whole, &part, Preset::default(), MatchMode::Default).unwrap();
+            assert!(score.unwrap() < 2.0, "{file}");
+            assert!(window(60.0, Some(3.0)).is_empty(), "{file}");
+        }
+    }
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn test_fingerprint_max_length() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+        let fingerprint = |max_length_secs| {
+            fingerprint_file_raw(
+                &path,
+                None,
+                Preset::default(),
+                max_length_secs,
+                &mut CallStats::default(),
+            )
+            .unwrap()
+        };
+
+        let whole = fingerprint(None);
+        let start = fingerprint(Some(4));
+        assert!(!start.is_empty() && start.len() < whole.len());
+        assert_eq!(start, whole[..start.len()]);
+        assert_eq!(fingerprint(Some(60)), whole);
+    }
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn test_fingerprint_bytes() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+        let expected =
+            fingerprint_path(&path, Preset::default(), &mut CallStats::default()).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        for format in [None, Some("ogg"), Some("audio/ogg")] {
+            let fingerprint = fingerprint_bytes(
+                data.clone(),
+                format,
+                Preset::Test1,
+                None,
+                &mut CallStats::default(),
+            );
+            assert_eq!(fingerprint.unwrap(), expected, "{format:?}");
+        }
+        assert!(fingerprint_bytes(
+            b"not audio".to_vec(),
+            None,
+            Preset::Test1,
+            None,
+            &mut CallStats::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_fingerprint_pcm() {
+        let samples: Vec<i16> = (0..44100 * 2)
+            .map(|i| ((i as f64 * 0.05).sin() * 10000.0 * (1.0 + (i / 4000) as f64 % 3.0)) as i16)
+            .collect();
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+
+        let preset = Preset::Test2;
+        let expected = fingerprint_samples(&samples, 22050, 2, preset).unwrap();
+        assert_eq!(fingerprint_pcm(&data, 22050, 2, preset).unwrap(), expected);
+        assert!(fingerprint_pcm(&data[1..], 22050, 2, preset).is_err());
+        assert!(fingerprint_pcm(&data, 0, 2, preset).is_err());
+        assert!(fingerprint_pcm(&data, 22050, -1, preset).is_err());
+    }
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn test_fingerprint_file_format_hint() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let original = Path::new(&manifest_dir).join("src/testdata/XC444467.mp3");
+        let renamed = std::env::temp_dir().join(format!("XC444467-{}", std::process::id()));
+        std::fs::copy(&original, &renamed).unwrap();
+
+        let expected = fingerprint_file_raw(
+            &original,
+            None,
+            Preset::default(),
+            None,
+            &mut CallStats::default(),
+        )
+        .unwrap();
+        for format in ["mp3", "audio/mpeg"] {
+            let fingerprint = fingerprint_file_raw(
+                &renamed,
+                Some(format),
+                Preset::default(),
+                None,
+                &mut CallStats::default(),
+            );
+            assert_eq!(fingerprint.unwrap(), expected, "{format}");
+        }
+        std::fs::remove_file(renamed).unwrap();
+    }
+
+    #[test]
+    fn test_fingerprint_duration_secs() {
+        assert_eq!(fingerprint_duration_secs(0, Preset::Test1), 0.0);
+        // XC444467.ogg is 6.79 s long and fingerprints to 34 items.
+        let secs = fingerprint_duration_secs(34, Preset::Test1);
+        assert!((secs - 6.79).abs() < 0.1, "{secs}");
+        assert!(fingerprint_duration_secs(68, Preset::Test1) > secs + 4.0);
+    }
+
+    #[test]
+    fn test_comparison_preset() {
+        use Preset::*;
+        assert_eq!(comparison_preset(None, None, None, Test1).unwrap(), Test1);
+        assert_eq!(
+            comparison_preset(Some(Test2), None, None, Test1).unwrap(),
+            Test2
+        );
+        assert_eq!(
+            comparison_preset(None, Some(Test2), Some(Test2), Test1).unwrap(),
+            Test2
+        );
+        assert_eq!(
+            comparison_preset(Some(Test3), Some(Test3), None, Test1).unwrap(),
+            Test3
+        );
+        assert!(comparison_preset(None, None, Some(Test2), Test1).is_err());
+        assert_eq!(
+            comparison_preset(None, None, Some(Test2), Test2).unwrap(),
+            Test2
+        );
+        assert!(comparison_preset(Some(Test2), Some(Test2), Some(Test3), Test1).is_err());
+        let err = comparison_preset(Some(Test1), None, Some(Test2), Test1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "fingerprint_b was made with preset test2, not test1"
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_from_value() {
+        let text = fingerprint_from_value(0, "fp", ValueRef::Text(b"AAAAAQAAAAI=")).unwrap();
+        let blob = fingerprint_from_value(0, "fp", ValueRef::Blob(&[0, 0, 0, 1, 0, 0, 0, 2]));
+        assert_eq!(text, [1, 2]);
+        assert_eq!(blob.unwrap(), text);
+
+        assert!(fingerprint_from_value(0, "fp", ValueRef::Blob(&[0, 0, 1])).is_err());
+        assert!(fingerprint_from_value(0, "fp", ValueRef::Integer(1)).is_err());
+    }
 }
@@ -0,0 +1,90 @@
+//! Enforcement of the connection's `SQLITE_LIMIT_LENGTH` on returned values.
+//!
+//! SQLite rejects oversized TEXT and BLOB results with a bare `SQLITE_TOOBIG`
+//! ("string or blob too big") that does not say which function produced it.
+//! Functions that can return large values check the limit themselves and fail
+//! with a [`ResultTooLarge`] error instead.
+
+use std::fmt::{Display, Formatter};
+
+use rusqlite::functions::{Context, SqlFnOutput};
+use rusqlite::limits::Limit;
+use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+
+/// A function result exceeded the connection's `SQLITE_LIMIT_LENGTH`.
+#[derive(Debug)]
+pub(crate) struct ResultTooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl Display for ResultTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Result too large: {} bytes exceeds SQLITE_LIMIT_LENGTH of {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for ResultTooLarge {}
+
+/// Fail if a result of `size` bytes would exceed the connection's length limit.
+pub(crate) fn check_length(ctx: &Context<'_>, size: usize) -> rusqlite::Result<()> {
+    // SAFETY: the connection reference does not outlive this call.
+    let limit = unsafe { ctx.get_connection()? }.limit(Limit::SQLITE_LIMIT_LENGTH)?;
+    check_size(size, limit.max(0) as usize)
+        .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))
+}
+
+/// Like [`check_length`], for a function's `output` of any type; only TEXT
+/// and BLOB values have a length.
+pub(crate) fn check_output<T: SqlFnOutput>(ctx: &Context<'_>, output: &T) -> rusqlite::Result<()> {
+    let (output, _) = output.to_sql()?;
+    check_length(ctx, output_length(&output))
+}
+
+/// Length in bytes of a TEXT or BLOB value, or 0 for other values.
+fn output_length(output: &ToSqlOutput<'_>) -> usize {
+    match output {
+        ToSqlOutput::Borrowed(ValueRef::Text(bytes) | ValueRef::Blob(bytes)) => bytes.len(),
+        ToSqlOutput::Owned(Value::Text(text)) => text.len(),
+        ToSqlOutput::Owned(Value::Blob(bytes)) => bytes.len(),
+        _ => 0,
+    }
+}
+
+fn check_size(size: usize, limit: usize) -> Result<(), ResultTooLarge> {
+    if size > limit {
+        return Err(ResultTooLarge { size, limit });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_size() {
+        assert!(check_size(10, 10).is_ok());
+        let err = check_size(11, 10).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Result too large: 11 bytes exceeds SQLITE_LIMIT_LENGTH of 10 bytes"
+        );
+    }
+
+    #[test]
+    fn test_output_length() {
+        let cover = Some(vec![0u8; 1000]);
+        assert_eq!(output_length(&cover.to_sql().unwrap().0), 1000);
+        assert_eq!(output_length(&None::<Vec<u8>>.to_sql().unwrap().0), 0);
+        assert_eq!(output_length(&2.5f64.to_sql().unwrap().0), 0);
+
+        // JSON results, e.g. of audio_metadata, with their subtype.
+        let tags = crate::json::result("{\"title\":\"x\"}".to_owned());
+        assert_eq!(output_length(&SqlFnOutput::to_sql(&tags).unwrap().0), 13);
+    }
+}
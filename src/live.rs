@@ -0,0 +1,130 @@
+//! Fingerprints of live streams.
+//!
+//! With the `net` feature, `fingerprint_stream(url, seconds)` connects to an
+//! HTTP or HTTPS audio stream, such as an Icecast or SHOUTcast radio
+//! station, and fingerprints the first `seconds` seconds it receives:
+//!
+//! ```sql
+//! INSERT INTO captures(station_id, captured, fingerprint)
+//! SELECT id, unixepoch(), fingerprint_stream(url, 30) FROM stations;
+//! ```
+//!
+//! The format is taken from the response's `Content-Type`, or else the
+//! URL's extension, and probed if neither is known. The call returns once
+//! `seconds` seconds of audio have been decoded, which for a live stream
+//! takes about that long, or when the stream ends. A stream that stalls for
+//! [`http::READ_TIMEOUT`] fails. The fingerprint is made with the `preset` option's
+//! preset and encoded according to the `encoding` option.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::Connection;
+use symphonia::core::io::ReadOnlySource;
+
+use crate::panic::guard;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::{fingerprint_source, http, limits, source};
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_scalar_function(
+        "fingerprint_stream",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+        guard(move |ctx| {
+            let url = ctx.get::<String>(0)?;
+            let seconds = ctx.get::<i64>(1)?;
+
+            let (encoding, preset) = {
+                let options = state.options();
+                (options.encoding, options.preset)
+            };
+            let mut stats = state.call_stats();
+            let fingerprint = fingerprint_live(&url, seconds, preset, &mut stats)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            state.record_timing("fingerprint_stream", &url, stats);
+            let fingerprint = encoding.encode(&fingerprint);
+            limits::check_length(ctx, fingerprint.len())?;
+
+            Ok(fingerprint)
+        }),
+    )
+}
+
+/// Fingerprint the first `seconds` seconds of the stream at `url`.
+fn fingerprint_live(
+    url: &str,
+    seconds: i64,
+    preset: Preset,
+    stats: &mut CallStats,
+) -> Result<Vec<u32>> {
+    let Ok(seconds @ 1..) = u32::try_from(seconds) else {
+        bail!("Invalid number of seconds: {seconds}");
+    };
+    let response = http::get(&http::agent(), url, None)?;
+    let format = http::content_type(&response)
+        .or_else(|| source::extension(url))
+        .map(str::to_owned);
+
+    let source = Box::new(ReadOnlySource::new(response.into_reader()));
+    fingerprint_source(source, format.as_deref(), preset, Some(seconds), stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+
+    use super::*;
+
+    /// Serve `body` once over HTTP, returning the URL.
+    fn serve(body: Vec<u8>, content_type: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stream", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let header = format!("HTTP/1.0 200 OK\r\nContent-Type: {content_type}\r\n\r\n");
+            stream.write_all(header.as_bytes()).unwrap();
+            let _ = stream.write_all(&body);
+        });
+        url
+    }
+
+    #[test]
+    fn test_fingerprint_live() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.mp3");
+        let expected = crate::fingerprint_path_inspecting(
+            &path,
+            None,
+            Preset::default(),
+            Some(4),
+            &mut CallStats::default(),
+            &mut |_| {},
+        )
+        .unwrap();
+
+        let url = serve(std::fs::read(&path).unwrap(), "audio/mpeg; charset=utf-8");
+        let fingerprint =
+            fingerprint_live(&url, 4, Preset::default(), &mut CallStats::default()).unwrap();
+        assert_eq!(fingerprint, expected);
+
+        assert!(fingerprint_live(&url, 0, Preset::default(), &mut CallStats::default()).is_err());
+        assert!(fingerprint_live(
+            "file:///etc/passwd",
+            4,
+            Preset::default(),
+            &mut CallStats::default()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,294 @@
+//! Loudness measurements following EBU R128.
+//!
+//! `audio_loudness_range(path)` is the loudness range (LRA) in LU, as
+//! specified by EBU Tech 3342: the spread between the 10th and 95th
+//! percentiles of the short-term (3 s) loudness, ignoring silence and
+//! passages more than 20 LU below the mean. Files shorter than one
+//! short-term window give NULL.
+//!
+//! `audio_true_peak(path)` is the true peak level in dBTP: the highest
+//! absolute sample of any channel after 4x oversampling, as specified by
+//! BS.1770, which catches inter-sample peaks that clip on conversion to
+//! analogue or lossy encoding.
+//!
+//! Every channel has a weight of 1, so files with surround channels read
+//! slightly lower than the 1.41 weight of BS.1770 for them would give.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::analysis::to_dbfs;
+use crate::decode::AudioStream;
+use crate::timings::CallStats;
+
+/// Loudness is measured in blocks of this length...
+const BLOCK_SECS: f64 = 0.1;
+
+/// ...and the short-term loudness over this many consecutive blocks (3 s).
+const SHORT_TERM_BLOCKS: usize = 30;
+
+/// Short-term loudness below this is silence.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+/// Short-term loudness this far below the mean is ignored.
+const RELATIVE_GATE_LU: f64 = -20.0;
+
+const LOW_PERCENTILE: f64 = 0.10;
+const HIGH_PERCENTILE: f64 = 0.95;
+
+/// Oversampling factor for true peak detection...
+const OVERSAMPLING: usize = 4;
+
+/// ...and number of input samples each interpolated sample is computed from.
+const TAPS_PER_PHASE: usize = 12;
+
+/// A second order IIR filter.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    /// Previous inputs and outputs.
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting filter of BS.1770: a high shelf modelling the head
+/// followed by a high-pass filter, designed for any sample rate.
+#[derive(Debug, Clone, Copy)]
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (std::f64::consts::PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (std::f64::consts::PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+}
+
+/// Loudness in LUFS of a mean square K-weighted level summed over channels.
+fn to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// The K-weighted power of each block of the file at `path`, summed over
+/// channels.
+fn block_powers(path: &Path, stats: &mut CallStats) -> Result<Vec<f64>> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    let channels = stream.channels.max(1);
+    let block_len = ((BLOCK_SECS * stream.sample_rate as f64) as usize).max(1);
+    let mut filters = vec![KWeighting::new(stream.sample_rate); channels];
+
+    let mut powers = Vec::new();
+    let (mut sum, mut n) = (0.0, 0);
+    while let Some(samples) = stream.next_samples(stats)? {
+        for frame in samples.chunks_exact(channels) {
+            for (s, filter) in frame.iter().zip(&mut filters) {
+                let y = filter.process(*s as f64 / 32768.0);
+                sum += y * y;
+            }
+            n += 1;
+            if n == block_len {
+                powers.push(sum / block_len as f64);
+                (sum, n) = (0.0, 0);
+            }
+        }
+    }
+    Ok(powers)
+}
+
+/// Short-term loudness in LUFS of each run of [`SHORT_TERM_BLOCKS`].
+fn short_term_loudness(powers: &[f64]) -> Vec<f64> {
+    powers
+        .windows(SHORT_TERM_BLOCKS)
+        .map(|w| to_lufs(w.iter().sum::<f64>() / w.len() as f64))
+        .collect()
+}
+
+/// The loudness range in LU of the given short-term loudness values.
+fn loudness_range(short_term: &[f64]) -> Option<f64> {
+    let loud: Vec<f64> = short_term
+        .iter()
+        .copied()
+        .filter(|&l| l >= ABSOLUTE_GATE_LUFS)
+        .collect();
+    if loud.is_empty() {
+        return None;
+    }
+    let mean_power = loud.iter().map(|l| 10f64.powf(l / 10.0)).sum::<f64>() / loud.len() as f64;
+    let gate = 10.0 * mean_power.log10() + RELATIVE_GATE_LU;
+
+    let mut gated: Vec<f64> = loud.into_iter().filter(|&l| l >= gate).collect();
+    gated.sort_by(f64::total_cmp);
+    let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+    Some(percentile(HIGH_PERCENTILE) - percentile(LOW_PERCENTILE))
+}
+
+pub(crate) fn audio_loudness_range(path: &Path, stats: &mut CallStats) -> Result<Option<f64>> {
+    let powers = block_powers(path, stats)?;
+    Ok(loudness_range(&short_term_loudness(&powers)))
+}
+
+/// Peak detector over a signal interpolated to [`OVERSAMPLING`] times its
+/// sample rate by a Hann-windowed sinc filter.
+struct TruePeak {
+    /// Filter taps of each interpolation phase.
+    phases: [[f64; TAPS_PER_PHASE]; OVERSAMPLING],
+    /// The most recent input samples, newest first.
+    history: [f64; TAPS_PER_PHASE],
+    peak: f64,
+}
+
+impl TruePeak {
+    fn new() -> Self {
+        let len = OVERSAMPLING * TAPS_PER_PHASE;
+        let centre = (len - 1) as f64 / 2.0;
+        let mut phases = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+        for n in 0..len {
+            let t = std::f64::consts::PI * (n as f64 - centre) / OVERSAMPLING as f64;
+            let sinc = if t == 0.0 { 1.0 } else { t.sin() / t };
+            let hann =
+                0.5 - 0.5 * (2.0 * std::f64::consts::PI * (n + 1) as f64 / (len + 1) as f64).cos();
+            phases[n % OVERSAMPLING][n / OVERSAMPLING] = sinc * hann;
+        }
+        // Unity gain at 0 Hz, so a constant signal peaks at its own level.
+        for phase in &mut phases {
+            let gain: f64 = phase.iter().sum();
+            phase.iter_mut().for_each(|tap| *tap /= gain);
+        }
+
+        Self {
+            phases,
+            history: [0.0; TAPS_PER_PHASE],
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f64) {
+        self.history.rotate_right(1);
+        self.history[0] = x;
+        self.peak = self.peak.max(x.abs());
+        for phase in &self.phases {
+            let y: f64 = phase.iter().zip(&self.history).map(|(h, x)| h * x).sum();
+            self.peak = self.peak.max(y.abs());
+        }
+    }
+}
+
+pub(crate) fn audio_true_peak(path: &Path, stats: &mut CallStats) -> Result<Option<f64>> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    let channels = stream.channels.max(1);
+    let mut detectors: Vec<TruePeak> = (0..channels).map(|_| TruePeak::new()).collect();
+
+    let mut empty = true;
+    while let Some(samples) = stream.next_samples(stats)? {
+        for frame in samples.chunks_exact(channels) {
+            for (s, detector) in frame.iter().zip(&mut detectors) {
+                detector.process(*s as f64 / 32768.0);
+            }
+            empty = false;
+        }
+    }
+
+    let peak = detectors.iter().map(|d| d.peak).fold(0.0, f64::max);
+    Ok((!empty).then(|| to_dbfs(peak as f32)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_k_weighting_of_sine() {
+        // BS.1770: a full scale 997 Hz sine in one channel reads -3.01 LUFS.
+        let sample_rate = 48000;
+        let mut filter = KWeighting::new(sample_rate);
+        let n = sample_rate as usize;
+        let power = (0..2 * n)
+            .map(|i| {
+                let x = (2.0 * std::f64::consts::PI * 997.0 * i as f64 / sample_rate as f64).sin();
+                filter.process(x)
+            })
+            .skip(n)
+            .map(|y| y * y)
+            .sum::<f64>()
+            / n as f64;
+        assert!((to_lufs(power) - -3.01).abs() < 0.05, "{}", to_lufs(power));
+    }
+
+    #[test]
+    fn test_true_peak_between_samples() {
+        // A quarter of the sample rate, sampled 45 degrees off its peaks.
+        let mut detector = TruePeak::new();
+        let mut sample_peak: f64 = 0.0;
+        for i in 0..1000 {
+            let x =
+                0.5 * (std::f64::consts::FRAC_PI_2 * i as f64 + std::f64::consts::FRAC_PI_4).sin();
+            sample_peak = sample_peak.max(x.abs());
+            detector.process(x);
+        }
+        assert!((sample_peak - 0.5 * std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-9);
+        assert!((detector.peak - 0.5).abs() < 0.01, "{}", detector.peak);
+    }
+
+    #[test]
+    fn test_loudness_range() {
+        // Evenly spread from -30 to -10 LUFS, plus silence that is gated.
+        let mut short_term: Vec<f64> = (0..=200).map(|i| -30.0 + i as f64 / 10.0).collect();
+        short_term.extend([-90.0; 50]);
+        let lra = loudness_range(&short_term).unwrap();
+        assert!((lra - 17.0).abs() < 0.1, "{lra}");
+
+        assert_eq!(loudness_range(&[-20.0; 10]), Some(0.0));
+        assert_eq!(loudness_range(&[-80.0; 10]), None);
+    }
+}
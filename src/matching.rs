@@ -0,0 +1,97 @@
+//! Scoring of matched fingerprint segments.
+
+use anyhow::{bail, Result};
+use rusty_chromaprint::{Configuration, Segment};
+
+/// How matched segments are scored by `compare_fingerprints`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum MatchMode {
+    /// Use the matcher's score: the mean bit error rate over the segment.
+    #[default]
+    Default,
+    /// Down-weight the edges of each segment, where crossfades in DJ mixes
+    /// blend two tracks and depress the score of an otherwise good match.
+    Crossfade,
+}
+
+impl MatchMode {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "default" => Self::Default,
+            "crossfade" => Self::Crossfade,
+            _ => bail!("Unknown match mode: {s}"),
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Crossfade => "crossfade",
+        }
+    }
+}
+
+/// Typical crossfade length; weights ramp up linearly over this span.
+const CROSSFADE_SECS: f32 = 8.0;
+
+/// Score a matched segment (0 = identical, 32 = unrelated) under `mode`.
+pub(crate) fn segment_score(
+    segment: &Segment,
+    fp_a: &[u32],
+    fp_b: &[u32],
+    config: &Configuration,
+    mode: MatchMode,
+) -> f64 {
+    match mode {
+        MatchMode::Default => segment.score,
+        MatchMode::Crossfade => {
+            let n = segment.items_count;
+            let ramp = ((CROSSFADE_SECS / config.item_duration_in_seconds()) as usize)
+                .min(n / 4)
+                .max(1);
+
+            let (weighted, total) = (0..n)
+                .map(|k| {
+                    let bits = (fp_a[segment.offset1 + k] ^ fp_b[segment.offset2 + k]).count_ones();
+                    let weight = ((k.min(n - 1 - k) + 1) as f64 / ramp as f64).min(1.0);
+                    (weight * bits as f64, weight)
+                })
+                .fold((0.0, 0.0), |(s, w), (x, y)| (s + x, w + y));
+
+            if total > 0.0 {
+                weighted / total
+            } else {
+                segment.score
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crossfade_down_weights_edges() {
+        let config = Configuration::preset_test1();
+        let n = 400;
+        let fp_a = vec![0u32; n];
+        // Noisy edges (a crossfade on both sides) around a clean middle.
+        let fp_b: Vec<u32> = (0..n)
+            .map(|i| if i < 40 || i >= n - 40 { 0xFFFF } else { 0x1 })
+            .collect();
+
+        let bits: f64 = fp_b.iter().map(|x| x.count_ones() as f64).sum();
+        let segment = Segment {
+            offset1: 0,
+            offset2: 0,
+            items_count: n,
+            score: bits / n as f64,
+        };
+
+        let default = segment_score(&segment, &fp_a, &fp_b, &config, MatchMode::Default);
+        let crossfade = segment_score(&segment, &fp_a, &fp_b, &config, MatchMode::Crossfade);
+        assert!(crossfade < default, "{crossfade} >= {default}");
+        assert!(crossfade > 1.0);
+    }
+}
@@ -0,0 +1,458 @@
+//! Tags of audio files.
+//!
+//! `audio_metadata(path)` returns the tags Symphonia reads while opening a
+//! file as a JSON object, so that a library can be catalogued in the same
+//! pass that fingerprints it:
+//!
+//! ```sql
+//! SELECT path, audio_metadata(path) ->> '$.title', fingerprint(path) FROM files;
+//! ```
+//!
+//! Common tags have the same key whatever the format: `title`, `artist`,
+//! `album`, `album_artist`, `date`, `genre`, `track_number`, `disc_number`
+//! and so on. Others keep the key they have in the file. Track and disc
+//! numbers are integers, with a `"3/12"` number split into `track_number`
+//! and `track_total`. A tag that occurs more than once with different values,
+//! such as several artists, is an array. Embedded pictures are left out.
+//!
+//! `audio_tags(path)` returns the same tags as rows, for schemas that store
+//! them relationally:
+//!
+//! ```sql
+//! INSERT INTO tags(track_id, key, value)
+//! SELECT t.id, coalesce(standard_key, key), value
+//! FROM tracks t, audio_tags(t.path);
+//! ```
+//!
+//! `key` is the tag's key as the file has it, e.g. `TPE1` in ID3v2 or
+//! `ARTIST` in a Vorbis comment, and `standard_key` the key `audio_metadata`
+//! gives it, or NULL if it has none. `value` is TEXT, INTEGER, REAL or BLOB
+//! as stored, unsplit. There is a row for every occurrence of a tag.
+//!
+//! `cover_art(path)` returns the image data of a file's front cover, and
+//! `cover_art_mime(path)` its media type, e.g. `'image/jpeg'`:
+//!
+//! ```sql
+//! UPDATE albums SET cover = cover_art(path), cover_type = cover_art_mime(path);
+//! ```
+//!
+//! Files without a picture marked as the front cover return their first
+//! embedded picture, and files without pictures NULL.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rusqlite::functions::SubType;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+use serde_json::{Map, Value as Json};
+use symphonia::core::meta::{StandardTagKey, StandardVisualKey, Tag, Value, Visual};
+
+use crate::decode::AudioStream;
+use crate::panic::catch_panic;
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::{json, path, tvf};
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 3;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "audio_tags",
+        eponymous_only_module::<TagsTab>(),
+        Some(state),
+    )
+}
+
+pub(crate) fn audio_metadata(path: &Path, stats: &mut CallStats) -> Result<(String, SubType)> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    Ok(json::result(tags_to_json(&stream.tags()).to_string()))
+}
+
+pub(crate) fn cover_art(path: &Path, stats: &mut CallStats) -> Result<Option<Vec<u8>>> {
+    Ok(front_cover(path, stats)?.map(|visual| visual.data.into_vec()))
+}
+
+pub(crate) fn cover_art_mime(path: &Path, stats: &mut CallStats) -> Result<Option<String>> {
+    Ok(front_cover(path, stats)?.map(|visual| visual.media_type))
+}
+
+fn front_cover(path: &Path, stats: &mut CallStats) -> Result<Option<Visual>> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    Ok(choose_cover(stream.visuals()))
+}
+
+/// The picture marked as the front cover, or else the first.
+fn choose_cover(mut visuals: Vec<Visual>) -> Option<Visual> {
+    let index = visuals
+        .iter()
+        .position(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+        .unwrap_or(0);
+    (index < visuals.len()).then(|| visuals.swap_remove(index))
+}
+
+/// A tag as `(key, value, standard_key)`.
+type Row = (String, SqlValue, Option<&'static str>);
+
+fn audio_tags(path: &Path, stats: &mut CallStats) -> Result<Vec<Row>> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    Ok(stream.tags().iter().map(tag_row).collect())
+}
+
+fn tag_row(tag: &Tag) -> Row {
+    let value = match &tag.value {
+        Value::String(s) => SqlValue::Text(s.clone()),
+        Value::UnsignedInt(i) => {
+            i64::try_from(*i).map_or(SqlValue::Real(*i as f64), SqlValue::Integer)
+        }
+        Value::SignedInt(i) => SqlValue::Integer(*i),
+        Value::Float(f) => SqlValue::Real(*f),
+        Value::Boolean(b) => SqlValue::Integer(*b as i64),
+        Value::Binary(bytes) => SqlValue::Blob(bytes.to_vec()),
+        Value::Flag => SqlValue::Null,
+    };
+    (tag.key.clone(), value, tag.std_key.and_then(key_name))
+}
+
+/// The JSON object of a file's tags.
+fn tags_to_json(tags: &[Tag]) -> Json {
+    let mut object = Map::new();
+    for tag in tags {
+        let key = tag.std_key.and_then(key_name).unwrap_or(&tag.key);
+        let value = match (&tag.value, key) {
+            (Value::String(s), "track_number" | "disc_number") => {
+                let (number, total) = s.split_once('/').unwrap_or((s, ""));
+                if let Ok(total) = total.trim().parse::<u64>() {
+                    let total_key = key.replace("_number", "_total");
+                    insert(&mut object, &total_key, total.into());
+                }
+                match number.trim().parse::<u64>() {
+                    Ok(number) => number.into(),
+                    Err(_) => s.as_str().into(),
+                }
+            }
+            (Value::String(s), _) => s.as_str().into(),
+            (Value::UnsignedInt(i), _) => (*i).into(),
+            (Value::SignedInt(i), _) => (*i).into(),
+            (Value::Float(f), _) => (*f).into(),
+            (Value::Boolean(b), _) => (*b).into(),
+            (Value::Binary(_) | Value::Flag, _) => continue,
+        };
+        insert(&mut object, key, value);
+    }
+    Json::Object(object)
+}
+
+/// Add a value of `key`, turning the key's values into an array if it
+/// already has a different one.
+fn insert(object: &mut Map<String, Json>, key: &str, value: Json) {
+    let Some(existing) = object.get_mut(key) else {
+        object.insert(key.to_owned(), value);
+        return;
+    };
+    match existing {
+        Json::Array(values) if !values.contains(&value) => values.push(value),
+        Json::Array(_) => {}
+        existing if *existing != value => {
+            *existing = Json::Array(vec![existing.take(), value]);
+        }
+        _ => {}
+    }
+}
+
+/// The key of a standard tag.
+fn key_name(key: StandardTagKey) -> Option<&'static str> {
+    use StandardTagKey::*;
+    Some(match key {
+        AcoustidFingerprint => "acoustid_fingerprint",
+        AcoustidId => "acoustid_id",
+        Album => "album",
+        AlbumArtist => "album_artist",
+        Artist => "artist",
+        Bpm => "bpm",
+        Comment => "comment",
+        Compilation => "compilation",
+        Composer => "composer",
+        Conductor => "conductor",
+        Copyright => "copyright",
+        Date => "date",
+        Description => "description",
+        DiscNumber => "disc_number",
+        DiscSubtitle => "disc_subtitle",
+        DiscTotal => "disc_total",
+        EncodedBy => "encoded_by",
+        Encoder => "encoder",
+        Genre => "genre",
+        IdentBarcode => "barcode",
+        IdentCatalogNumber => "catalog_number",
+        IdentIsrc => "isrc",
+        Label => "label",
+        Language => "language",
+        Lyricist => "lyricist",
+        Lyrics => "lyrics",
+        Mood => "mood",
+        MusicBrainzAlbumArtistId => "musicbrainz_album_artist_id",
+        MusicBrainzAlbumId => "musicbrainz_album_id",
+        MusicBrainzArtistId => "musicbrainz_artist_id",
+        MusicBrainzRecordingId => "musicbrainz_recording_id",
+        MusicBrainzReleaseGroupId => "musicbrainz_release_group_id",
+        MusicBrainzReleaseTrackId => "musicbrainz_release_track_id",
+        MusicBrainzTrackId => "musicbrainz_track_id",
+        MusicBrainzWorkId => "musicbrainz_work_id",
+        OriginalDate => "original_date",
+        Performer => "performer",
+        Producer => "producer",
+        ReleaseCountry => "release_country",
+        ReleaseDate => "release_date",
+        Remixer => "remixer",
+        ReplayGainAlbumGain => "replaygain_album_gain",
+        ReplayGainAlbumPeak => "replaygain_album_peak",
+        ReplayGainTrackGain => "replaygain_track_gain",
+        ReplayGainTrackPeak => "replaygain_track_peak",
+        SortAlbum => "sort_album",
+        SortAlbumArtist => "sort_album_artist",
+        SortArtist => "sort_artist",
+        SortComposer => "sort_composer",
+        SortTrackTitle => "sort_title",
+        TrackNumber => "track_number",
+        TrackSubtitle => "subtitle",
+        TrackTitle => "title",
+        TrackTotal => "track_total",
+        Writer => "writer",
+        _ => return None,
+    })
+}
+
+#[repr(C)]
+struct TagsTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for TagsTab {
+    type Aux = Arc<State>;
+    type Cursor = TagsCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("audio_tags: missing state".to_owned())
+            })?;
+            tvf::reads_files(db)?;
+            Ok((
+                "CREATE TABLE x(key, value, standard_key, path HIDDEN)".to_owned(),
+                TagsTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| tvf::bind_arguments(info, "audio_tags", FIRST_ARGUMENT, &["path"]))
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<TagsCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(TagsCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct TagsCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    rows: Vec<Row>,
+    index: usize,
+    phantom: PhantomData<&'vtab TagsTab>,
+}
+
+unsafe impl VTabCursor for TagsCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.rows = Vec::new();
+            self.index = 0;
+
+            let Some(value) = args
+                .iter()
+                .next()
+                .filter(|v| v.data_type() != rusqlite::types::Type::Null)
+            else {
+                return Ok(());
+            };
+            let path = path::path_from_value(0, value)?;
+
+            let mut stats = self.state.call_stats();
+            self.rows = audio_tags(&path, &mut stats)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            self.state
+                .record_timing("audio_tags", &path.to_string_lossy(), stats);
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let (key, value, standard_key) = &self.rows[self.index];
+            match i {
+                0 => ctx.set_result(key),
+                1 => ctx.set_result(value),
+                2 => ctx.set_result(standard_key),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(key: Option<StandardTagKey>, raw: &str, value: Value) -> Tag {
+        Tag::new(key, raw, value)
+    }
+
+    #[test]
+    fn test_tags_to_json() {
+        let tags = [
+            tag(
+                Some(StandardTagKey::TrackTitle),
+                "TIT2",
+                Value::String("Song".into()),
+            ),
+            tag(
+                Some(StandardTagKey::Artist),
+                "ARTIST",
+                Value::String("A".into()),
+            ),
+            tag(
+                Some(StandardTagKey::Artist),
+                "ARTIST",
+                Value::String("B".into()),
+            ),
+            tag(
+                Some(StandardTagKey::TrackNumber),
+                "TRCK",
+                Value::String("3/12".into()),
+            ),
+            tag(None, "MOOD_CUSTOM", Value::UnsignedInt(7)),
+            tag(None, "APIC", Value::Binary(Box::new([1, 2]))),
+            tag(
+                Some(StandardTagKey::TrackTitle),
+                "TITLE",
+                Value::String("Song".into()),
+            ),
+        ];
+        assert_eq!(
+            tags_to_json(&tags),
+            serde_json::json!({
+                "title": "Song",
+                "artist": ["A", "B"],
+                "track_number": 3,
+                "track_total": 12,
+                "MOOD_CUSTOM": 7,
+            })
+        );
+    }
+
+    #[test]
+    fn test_audio_metadata() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+        let (metadata, _) = audio_metadata(&path, &mut CallStats::default()).unwrap();
+        let metadata: Json = serde_json::from_str(&metadata).unwrap();
+        assert_eq!(metadata["title"], "Purple Sunbird (Cinnyris asiaticus)");
+        assert_eq!(metadata["artist"], "Aladdin");
+        assert_eq!(metadata["album"], "xeno-canto");
+    }
+
+    fn visual(usage: Option<StandardVisualKey>, data: &[u8]) -> Visual {
+        Visual {
+            media_type: "image/png".to_owned(),
+            dimensions: None,
+            bits_per_pixel: None,
+            color_mode: None,
+            usage,
+            tags: Vec::new(),
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn test_choose_cover() {
+        let visuals = vec![
+            visual(Some(StandardVisualKey::BackCover), &[1]),
+            visual(Some(StandardVisualKey::FrontCover), &[2]),
+        ];
+        assert_eq!(&*choose_cover(visuals).unwrap().data, &[2]);
+        let visuals = vec![visual(None, &[1]), visual(None, &[2])];
+        assert_eq!(&*choose_cover(visuals).unwrap().data, &[1]);
+        assert!(choose_cover(Vec::new()).is_none());
+
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.mp3");
+        assert_eq!(cover_art(&path, &mut CallStats::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_tag_row() {
+        let row = tag_row(&tag(
+            Some(StandardTagKey::Artist),
+            "TPE1",
+            Value::String("A".into()),
+        ));
+        assert_eq!(
+            row,
+            (
+                "TPE1".to_owned(),
+                SqlValue::Text("A".to_owned()),
+                Some("artist")
+            )
+        );
+        let row = tag_row(&tag(None, "TRACK_GAIN", Value::Float(-6.5)));
+        assert_eq!(row, ("TRACK_GAIN".to_owned(), SqlValue::Real(-6.5), None));
+        let row = tag_row(&tag(None, "BIG", Value::UnsignedInt(u64::MAX)));
+        assert_eq!(row.1, SqlValue::Real(u64::MAX as f64));
+    }
+}
@@ -0,0 +1,149 @@
+//! Mel-frequency cepstral coefficients (MFCCs).
+//!
+//! `audio_mfcc(path [, n_coeffs, hop_secs])` returns the MFCCs of 25 ms
+//! frames starting every `hop_secs` (default 10 ms), as JSON:
+//!
+//! ```json
+//! {"sample_rate": 44100, "hop_secs": 0.01, "frames": [[-312.5, 41.2, ...], ...]}
+//! ```
+//!
+//! Each frame has `n_coeffs` (default 13, at most [`N_MELS`]) coefficients:
+//! the orthonormal DCT-II of the log energies (in dB) of [`N_MELS`]
+//! triangular filters on the HTK mel scale, up to 8 kHz or the Nyquist
+//! frequency. `hop_secs` is rounded to whole samples; the JSON gives the
+//! exact value.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+use crate::analysis::{MonoFrames, Spectrum};
+use crate::timings::CallStats;
+
+pub(crate) const DEFAULT_COEFFS: i64 = 13;
+pub(crate) const DEFAULT_HOP_SECS: f64 = 0.01;
+
+/// Number of mel filters, and so the maximum number of coefficients.
+const N_MELS: usize = 40;
+
+const FRAME_SECS: f64 = 0.025;
+
+const MAX_HZ: f32 = 8000.0;
+
+/// Added to filter energies so silent frames have a finite logarithm.
+const ENERGY_FLOOR: f32 = 1e-10;
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// Triangular mel filters over `bins` frequency bins of `bin_hz` each, as
+/// one row of weights per filter.
+fn mel_filters(bins: usize, bin_hz: f32) -> Vec<Vec<f32>> {
+    let max_hz = MAX_HZ.min(bin_hz * (bins - 1) as f32);
+    let max_mel = hz_to_mel(max_hz);
+    let edges: Vec<f32> = (0..N_MELS + 2)
+        .map(|i| mel_to_hz(max_mel * i as f32 / (N_MELS + 1) as f32))
+        .collect();
+
+    edges
+        .windows(3)
+        .map(|e| {
+            (0..bins)
+                .map(|k| {
+                    let hz = k as f32 * bin_hz;
+                    let rising = (hz - e[0]) / (e[1] - e[0]);
+                    let falling = (e[2] - hz) / (e[2] - e[1]);
+                    rising.min(falling).max(0.0)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The first `n` coefficients of the orthonormal DCT-II of `x`.
+fn dct(x: &[f32], n: usize) -> Vec<f32> {
+    let len = x.len() as f32;
+    (0..n)
+        .map(|k| {
+            let scale = if k == 0 {
+                (1.0 / len).sqrt()
+            } else {
+                (2.0 / len).sqrt()
+            };
+            scale
+                * x.iter()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        v * (std::f32::consts::PI * k as f32 * (i as f32 + 0.5) / len).cos()
+                    })
+                    .sum::<f32>()
+        })
+        .collect()
+}
+
+pub(crate) fn audio_mfcc(
+    path: &Path,
+    n_coeffs: i64,
+    hop_secs: f64,
+    stats: &mut CallStats,
+) -> Result<Value> {
+    if !(1..=N_MELS as i64).contains(&n_coeffs) {
+        bail!("n_coeffs must be between 1 and {N_MELS}, got {n_coeffs}");
+    }
+    if hop_secs.is_nan() || hop_secs <= 0.0 {
+        bail!("hop must be positive, got {hop_secs}");
+    }
+
+    let mut frames = MonoFrames::open_with_hop(path, FRAME_SECS, hop_secs, stats)?;
+    let mut spectrum = Spectrum::new(frames.frame_len, frames.sample_rate);
+    let filters = mel_filters(frames.frame_len / 2 + 1, spectrum.bin_hz);
+
+    let mut mfccs = Vec::new();
+    while let Some(frame) = frames.next_frame(stats)? {
+        let power: Vec<f32> = spectrum.of(frame)?.iter().map(|c| c.norm_sqr()).collect();
+        let log_energies: Vec<f32> = filters
+            .iter()
+            .map(|f| {
+                let energy: f32 = f.iter().zip(&power).map(|(w, p)| w * p).sum();
+                10.0 * (energy + ENERGY_FLOOR).log10()
+            })
+            .collect();
+        mfccs.push(dct(&log_energies, n_coeffs as usize));
+    }
+
+    Ok(json!({
+        "sample_rate": frames.sample_rate,
+        "hop_secs": frames.hop_len as f64 / frames.sample_rate as f64,
+        "frames": mfccs,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dct_of_constant() {
+        let c = dct(&[2.0; 40], 4);
+        assert!((c[0] - 2.0 * 40f32.sqrt()).abs() < 1e-4);
+        assert!(c[1..].iter().all(|v| v.abs() < 1e-4), "{c:?}");
+    }
+
+    #[test]
+    fn test_mel_filters() {
+        assert!((mel_to_hz(hz_to_mel(1000.0)) - 1000.0).abs() < 1e-2);
+
+        let filters = mel_filters(257, 16000.0 / 512.0);
+        assert_eq!(filters.len(), N_MELS);
+        // Filters overlap by half, so inner bins are covered with weight 1.
+        let bin = (1000.0 / (16000.0 / 512.0)) as usize;
+        let total: f32 = filters.iter().map(|f| f[bin]).sum();
+        assert!((total - 1.0).abs() < 1e-3, "{total}");
+    }
+}
@@ -0,0 +1,259 @@
+//! MinHash signatures of fingerprints, for finding candidate duplicates with
+//! an equi-join.
+//!
+//! A fingerprint's signature is `bands * rows` minima of the hashes of its
+//! items, the lowest [`index::IGNORED_BITS`](crate::index::IGNORED_BITS) bits
+//! ignored. Two fingerprints share each minimum with a probability equal to
+//! the Jaccard similarity of their items. `fp_minhash(fp [, bands, rows])`
+//! returns it as a BLOB of big-endian 32-bit minima, and
+//! `fp_lsh_keys(fp [, bands, rows])` a key per band of `rows` minima, so that
+//! fingerprints sharing a key are likely similar:
+//!
+//! ```sql
+//! CREATE TABLE lsh AS
+//! SELECT t.id, k.key FROM tracks t, fp_lsh_keys(t.fingerprint) k;
+//! CREATE INDEX lsh_key ON lsh(key);
+//! SELECT DISTINCT a.id, b.id FROM lsh a JOIN lsh b USING (key)
+//! WHERE a.id < b.id;
+//! ```
+//!
+//! More rows per band make keys more selective, and more bands find more
+//! candidates. The defaults, 16 bands of 4 rows, mostly find fingerprints
+//! sharing at least half their items. Signatures and keys only compare with
+//! those of the same shape, and empty fingerprints have none.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+
+use anyhow::{bail, Result};
+use rusqlite::types::Type;
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+
+use crate::panic::catch_panic;
+use crate::{fingerprint_and_preset_from_value, index, tvf};
+
+/// Number of bands when not given.
+pub(crate) const DEFAULT_BANDS: i64 = 16;
+
+/// Number of rows per band when not given.
+pub(crate) const DEFAULT_ROWS: i64 = 4;
+
+/// Most minima in a signature.
+const MAX_SIGNATURE_LEN: i64 = 1024;
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 2;
+
+pub(crate) fn register(db: &Connection) -> rusqlite::Result<()> {
+    db.create_module("fp_lsh_keys", eponymous_only_module::<LshKeysTab>(), None)
+}
+
+/// SplitMix64's finalizer, a fast hash of 64-bit integers.
+pub(crate) fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Check a signature's shape, returning its length.
+pub(crate) fn signature_len(bands: i64, rows: i64) -> Result<usize> {
+    if bands < 1 || rows < 1 {
+        bail!("bands and rows must be positive, got {bands} and {rows}");
+    }
+    if bands.saturating_mul(rows) > MAX_SIGNATURE_LEN {
+        bail!("bands * rows must be at most {MAX_SIGNATURE_LEN}, got {bands} * {rows}");
+    }
+    Ok((bands * rows) as usize)
+}
+
+/// The MinHash signature of `fingerprint` with `len` minima, or `None` if it
+/// is empty.
+pub(crate) fn signature(fingerprint: &[u32], len: usize) -> Option<Vec<u32>> {
+    let hashes = index::hashes(fingerprint);
+    if hashes.is_empty() {
+        return None;
+    }
+    let signature = (0..len as u64)
+        .map(|i| {
+            let seed = mix(i);
+            hashes
+                .iter()
+                .map(|&hash| (mix(hash as u64 ^ seed) >> 32) as u32)
+                .min()
+                .unwrap_or(u32::MAX)
+        })
+        .collect();
+    Some(signature)
+}
+
+/// The key of each band of `rows` minima of `signature`, distinct between
+/// bands.
+pub(crate) fn band_keys(signature: &[u32], rows: usize) -> Vec<i64> {
+    signature
+        .chunks(rows)
+        .enumerate()
+        .map(|(band, minima)| {
+            let key = minima
+                .iter()
+                .fold(mix(band as u64), |key, &min| mix(key ^ u64::from(min)));
+            key as i64
+        })
+        .collect()
+}
+
+#[repr(C)]
+struct LshKeysTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for LshKeysTab {
+    type Aux = ();
+    type Cursor = LshKeysCursor<'vtab>;
+
+    fn connect(
+        _db: &mut VTabConnection,
+        _aux: Option<&()>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            Ok((
+                "CREATE TABLE x(band, key, fp HIDDEN, bands HIDDEN, rows HIDDEN)".to_owned(),
+                LshKeysTab {
+                    base: ffi::sqlite3_vtab::default(),
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_optional_arguments(
+                info,
+                "fp_lsh_keys",
+                FIRST_ARGUMENT,
+                &["fp", "bands", "rows"],
+                2,
+            )
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<LshKeysCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(LshKeysCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                keys: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct LshKeysCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    keys: Vec<i64>,
+    index: usize,
+    phantom: PhantomData<&'vtab LshKeysTab>,
+}
+
+unsafe impl VTabCursor for LshKeysCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.keys = Vec::new();
+            self.index = 0;
+
+            let Some(value) = args.iter().next().filter(|v| v.data_type() != Type::Null) else {
+                return Ok(());
+            };
+            let (_, fingerprint) = fingerprint_and_preset_from_value(0, "fp", value)?;
+            let bands = if args.len() > 1 {
+                args.get::<Option<i64>>(1)?
+            } else {
+                None
+            };
+            let rows = if args.len() > 2 {
+                args.get::<Option<i64>>(2)?
+            } else {
+                None
+            };
+            let rows = rows.unwrap_or(DEFAULT_ROWS);
+            let len = signature_len(bands.unwrap_or(DEFAULT_BANDS), rows)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            if let Some(signature) = signature(&fingerprint, len) {
+                self.keys = band_keys(&signature, rows as usize);
+            }
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.keys.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| match i {
+            0 => ctx.set_result(&(self.index as i64)),
+            1 => ctx.set_result(&self.keys[self.index]),
+            _ => ctx.set_result(&rusqlite::types::Null),
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_and_keys() {
+        assert!(signature_len(0, 4).is_err());
+        assert!(signature_len(64, 32).is_err());
+        let len = signature_len(DEFAULT_BANDS, DEFAULT_ROWS).unwrap();
+        assert_eq!(len, 64);
+        assert_eq!(signature(&[], len), None);
+
+        // Items differing only in ignored bits hash alike.
+        let a: Vec<u32> = (0..200).map(|i| i * 0x1000).collect();
+        let b: Vec<u32> = a.iter().map(|item| item | 0xf).collect();
+        let sig_a = signature(&a, len).unwrap();
+        assert_eq!(sig_a.len(), len);
+        assert_eq!(signature(&b, len).unwrap(), sig_a);
+
+        // Fingerprints sharing most items share most minima, and keys.
+        let c: Vec<u32> = (10..210).map(|i| i * 0x1000).collect();
+        let sig_c = signature(&c, len).unwrap();
+        let shared = sig_a.iter().zip(&sig_c).filter(|(x, y)| x == y).count();
+        assert!(shared > len / 2, "{shared} of {len}");
+        let keys_a = band_keys(&sig_a, 4);
+        let keys_c = band_keys(&sig_c, 4);
+        assert_eq!(keys_a.len(), 16);
+        assert!(keys_a.iter().any(|key| keys_c.contains(key)));
+
+        // Unrelated fingerprints share none.
+        let d: Vec<u32> = (1000..1200).map(|i| i * 0x1000).collect();
+        let keys_d = band_keys(&signature(&d, len).unwrap(), 4);
+        assert!(!keys_a.iter().any(|key| keys_d.contains(key)));
+    }
+}
@@ -0,0 +1,339 @@
+//! MusicBrainz recordings of a fingerprint.
+//!
+//! With the `acoustid` feature, `musicbrainz_recordings(fingerprint,
+//! duration)` looks a fingerprint up like `acoustid_lookup` and returns a
+//! row per candidate MusicBrainz recording, best match first:
+//!
+//! ```sql
+//! SELECT chromaprint_option('acoustid_key', :api_key);
+//! SELECT mbid, title, artist, release, score
+//! FROM musicbrainz_recordings(:fpcalc_fp, 215);
+//! ```
+//!
+//! `mbid` is the recording's MusicBrainz identifier, `artist` its artist
+//! credit, `release` the title of one of the release groups it appears on,
+//! and `score` AcoustID's confidence in the match, from 0 to 1. The titles
+//! are from AcoustID's copy of the MusicBrainz database; recordings it has
+//! no metadata for have NULL titles.
+//!
+//! The API key is read from the `acoustid_key` option, which only reads
+//! back from SQL as `'***'`. Responses are cached in the
+//! `chromaprint_acoustid_cache` table of the main database, created when
+//! first needed, so looking the same fingerprint and duration up again
+//! doesn't call the service. Delete rows from the table to look them up
+//! afresh. If the database is read-only, responses aren't cached.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context as _, Result};
+use rusqlite::types::Type;
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, params, Connection, OptionalExtension};
+use serde_json::Value as Json;
+
+use crate::acoustid;
+use crate::panic::catch_panic;
+use crate::state::State;
+use crate::tvf;
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 6;
+
+/// Metadata requested with each lookup.
+const META: &str = "recordings releasegroups compress";
+
+const CREATE_CACHE: &str = "CREATE TABLE IF NOT EXISTS main.chromaprint_acoustid_cache(\
+     fingerprint TEXT NOT NULL, \
+     duration INTEGER NOT NULL, \
+     response TEXT NOT NULL, \
+     fetched INTEGER NOT NULL, \
+     PRIMARY KEY (fingerprint, duration))";
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "musicbrainz_recordings",
+        eponymous_only_module::<RecordingsTab>(),
+        Some(state),
+    )
+}
+
+/// A candidate recording.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    mbid: String,
+    title: Option<String>,
+    artist: Option<String>,
+    release: Option<String>,
+    score: f64,
+    /// The AcoustID track the recording was found through.
+    track: String,
+}
+
+/// The recordings of a lookup response, in the order of their results.
+fn recordings(response: &str) -> Result<Vec<Row>> {
+    let response: Json = serde_json::from_str(response).context("Invalid AcoustID response")?;
+    if response["status"] != "ok" {
+        bail!(
+            "AcoustID lookup failed: {}",
+            response["error"]["message"]
+                .as_str()
+                .unwrap_or("unknown error")
+        );
+    }
+
+    let mut rows = Vec::new();
+    for result in response["results"].as_array().into_iter().flatten() {
+        let (Some(track), Some(score)) = (result["id"].as_str(), result["score"].as_f64()) else {
+            continue;
+        };
+        for recording in result["recordings"].as_array().into_iter().flatten() {
+            let Some(mbid) = recording["id"].as_str() else {
+                continue;
+            };
+            let artist = recording["artists"].as_array().map(|artists| {
+                artists
+                    .iter()
+                    .map(|a| {
+                        let name = a["name"].as_str().unwrap_or_default();
+                        let join = a["joinphrase"].as_str().unwrap_or_default();
+                        format!("{name}{join}")
+                    })
+                    .collect()
+            });
+            rows.push(Row {
+                mbid: mbid.to_owned(),
+                title: recording["title"].as_str().map(str::to_owned),
+                artist,
+                release: recording["releasegroups"][0]["title"]
+                    .as_str()
+                    .map(str::to_owned),
+                score,
+                track: track.to_owned(),
+            });
+        }
+    }
+    Ok(rows)
+}
+
+#[repr(C)]
+struct RecordingsTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for RecordingsTab {
+    type Aux = Arc<State>;
+    type Cursor = RecordingsCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("musicbrainz_recordings: missing state".to_owned())
+            })?;
+            Ok((
+                "CREATE TABLE x(mbid, title, artist, release, score, acoustid, \
+                 fingerprint HIDDEN, duration HIDDEN)"
+                    .to_owned(),
+                RecordingsTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    // SAFETY: The handle outlives the virtual table, which is
+                    // disconnected before the connection is closed.
+                    db: unsafe { db.handle() },
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_arguments(
+                info,
+                "musicbrainz_recordings",
+                FIRST_ARGUMENT,
+                &["fingerprint", "duration"],
+            )
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<RecordingsCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(RecordingsCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                db: self.db,
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct RecordingsCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+    rows: Vec<Row>,
+    index: usize,
+    phantom: PhantomData<&'vtab RecordingsTab>,
+}
+
+impl RecordingsCursor<'_> {
+    fn connection(&self) -> rusqlite::Result<Connection> {
+        // SAFETY: The handle outlives the virtual table, see
+        // `RecordingsTab::connect`. The connection does not close the handle
+        // when dropped.
+        unsafe { Connection::from_handle(self.db) }
+    }
+
+    /// The lookup response for a fingerprint, from the cache if it's there.
+    fn response(&self, compressed: &str, duration: f64) -> Result<String> {
+        let db = self.connection()?;
+        let key = acoustid::duration_secs(duration);
+        let cached = db
+            .query_row(
+                "SELECT response FROM main.chromaprint_acoustid_cache \
+                 WHERE fingerprint = ?1 AND duration = ?2",
+                params![compressed, key],
+                |row| row.get::<_, String>(0),
+            )
+            .optional();
+        // Until the cache table is created, the query fails: nothing is cached.
+        if let Ok(Some(response)) = cached {
+            return Ok(response);
+        }
+
+        let api_key = self
+            .state
+            .options()
+            .acoustid_key
+            .clone()
+            .context("Set the acoustid_key option to an AcoustID API key")?;
+        let response = acoustid::lookup(compressed, duration, &api_key, META)?;
+
+        // Only successful responses are cached; failing to cache, e.g. in a
+        // read-only database, doesn't fail the lookup.
+        if recordings(&response).is_ok() {
+            let fetched = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+            let _ = db.execute(CREATE_CACHE, []).and_then(|_| {
+                db.execute(
+                    "INSERT OR REPLACE INTO main.chromaprint_acoustid_cache \
+                     (fingerprint, duration, response, fetched) VALUES (?1, ?2, ?3, ?4)",
+                    params![compressed, key, response, fetched],
+                )
+            });
+        }
+        Ok(response)
+    }
+}
+
+unsafe impl VTabCursor for RecordingsCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.rows = Vec::new();
+            self.index = 0;
+
+            let Some(value) = args.iter().next().filter(|v| v.data_type() != Type::Null) else {
+                return Ok(());
+            };
+            let preset = self.state.options().preset;
+            let compressed = acoustid::compressed_from_value(0, "fingerprint", value, preset)?;
+            let duration = args.get::<f64>(1)?;
+
+            self.rows = self
+                .response(&compressed, duration)
+                .and_then(|response| recordings(&response))
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let row = &self.rows[self.index];
+            match i {
+                0 => ctx.set_result(&row.mbid),
+                1 => ctx.set_result(&row.title),
+                2 => ctx.set_result(&row.artist),
+                3 => ctx.set_result(&row.release),
+                4 => ctx.set_result(&row.score),
+                5 => ctx.set_result(&row.track),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recordings() {
+        let response = r#"{"status": "ok", "results": [
+            {"id": "t1", "score": 0.97, "recordings": [
+                {"id": "r1", "title": "Song", "releasegroups": [{"title": "Album"}],
+                 "artists": [{"name": "A", "joinphrase": " & "}, {"name": "B"}]},
+                {"id": "r2"}
+            ]},
+            {"id": "t2", "score": 0.5}
+        ]}"#;
+        let rows = recordings(response).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[0],
+            Row {
+                mbid: "r1".to_owned(),
+                title: Some("Song".to_owned()),
+                artist: Some("A & B".to_owned()),
+                release: Some("Album".to_owned()),
+                score: 0.97,
+                track: "t1".to_owned(),
+            }
+        );
+        assert_eq!(rows[1].title, None);
+        assert_eq!(rows[1].artist, None);
+
+        let error = r#"{"status": "error", "error": {"code": 4, "message": "invalid API key"}}"#;
+        assert_eq!(
+            recordings(error).unwrap_err().to_string(),
+            "AcoustID lookup failed: invalid API key"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Onset detection.
+//!
+//! `audio_onsets(path)` returns the times where notes or percussive hits
+//! start, with the strength of each from 0 to 1:
+//!
+//! ```sql
+//! SELECT time_secs, strength FROM audio_onsets('track.mp3');
+//! ```
+//!
+//! Onsets are peaks of the spectral flux: the total increase in
+//! log-compressed magnitude of each frequency bin from one frame to the
+//! next. Strengths are relative to the largest flux in the file, so they
+//! compare onsets within a file rather than across files. Steady sounds
+//! have no onsets. Times are frame centres, so they are accurate to a few
+//! tens of milliseconds.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rusqlite::vtab::Context;
+use rusqlite::Connection;
+
+use crate::analysis::{MonoFrames, Spectrum};
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::tvf::{self, PathFunction};
+
+/// Frame length; 2048 samples at 44.1 kHz.
+const FRAME_SECS: f64 = 0.046;
+
+const HOP_SECS: f64 = 0.01;
+
+/// Weight of magnitudes before log compression, so quiet partials count.
+const COMPRESSION: f32 = 100.0;
+
+/// An onset is the largest flux within this many frames on either side...
+const PEAK_FRAMES: usize = 3;
+
+/// ...and exceeds the mean flux within this many frames on either side...
+const MEAN_FRAMES: usize = 10;
+
+/// ...by at least this much, relative to the largest flux in the file.
+const THRESHOLD: f32 = 0.05;
+
+/// Minimum flux of an onset, so steady sounds have none rather than onsets
+/// at their tiny fluctuations.
+pub(crate) const MIN_FLUX: f32 = 0.1;
+
+/// Minimum distance between two reported onsets.
+const MIN_SPACING_SECS: f64 = 0.05;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    tvf::register_path_function::<Onsets>(db, state)
+}
+
+/// Spectral flux of each frame of the file at `path`, as the mean increase
+/// over all bins, and the hop and frame length in seconds.
+pub(crate) fn spectral_flux(path: &Path, stats: &mut CallStats) -> Result<(f64, f64, Vec<f32>)> {
+    let mut frames = MonoFrames::open_with_hop(path, FRAME_SECS, HOP_SECS, stats)?;
+    let mut spectrum = Spectrum::new(frames.frame_len, frames.sample_rate);
+    let sample_rate = frames.sample_rate as f64;
+    let hop_secs = frames.hop_len as f64 / sample_rate;
+    let frame_secs = frames.frame_len as f64 / sample_rate;
+
+    let mut previous: Option<Vec<f32>> = None;
+    let mut flux = Vec::new();
+    while let Some(frame) = frames.next_frame(stats)? {
+        let magnitudes: Vec<f32> = spectrum
+            .of(frame)?
+            .iter()
+            .map(|c| (COMPRESSION * c.norm()).ln_1p())
+            .collect();
+        flux.push(previous.as_ref().map_or(0.0, |previous| {
+            magnitudes
+                .iter()
+                .zip(previous)
+                .map(|(m, p)| (m - p).max(0.0))
+                .sum::<f32>()
+                / magnitudes.len() as f32
+        }));
+        previous = Some(magnitudes);
+    }
+    Ok((hop_secs, frame_secs, flux))
+}
+
+/// Onsets as `(frame, strength)` from the flux of each frame.
+fn pick_onsets(flux: &[f32], min_spacing: usize) -> Vec<(usize, f32)> {
+    let max = flux.iter().copied().fold(0.0, f32::max);
+    if max < MIN_FLUX {
+        return Vec::new();
+    }
+    let strength: Vec<f32> = flux.iter().map(|f| f / max).collect();
+    let around =
+        |i: usize, n: usize| &strength[i.saturating_sub(n)..(i + n + 1).min(strength.len())];
+
+    let mut onsets: Vec<(usize, f32)> = Vec::new();
+    for (i, &s) in strength.iter().enumerate() {
+        let peak = around(i, PEAK_FRAMES).iter().all(|&x| x <= s);
+        let local = around(i, MEAN_FRAMES);
+        let mean = local.iter().sum::<f32>() / local.len() as f32;
+        if !peak || s < mean + THRESHOLD || flux[i] < MIN_FLUX {
+            continue;
+        }
+        if onsets
+            .last()
+            .is_some_and(|&(last, _)| i - last < min_spacing)
+        {
+            continue;
+        }
+        onsets.push((i, s));
+    }
+    onsets
+}
+
+/// The onsets of a file, found once it is decoded.
+struct Onsets(std::vec::IntoIter<(f64, f64)>);
+
+impl PathFunction for Onsets {
+    const NAME: &'static str = "audio_onsets";
+
+    /// `(time_secs, strength)`.
+    type Row = (f64, f64);
+
+    fn columns() -> Vec<&'static str> {
+        vec!["time_secs", "strength"]
+    }
+
+    fn open(path: &Path, stats: &mut CallStats) -> Result<Self> {
+        let (hop_secs, frame_secs, flux) = spectral_flux(path, stats)?;
+        let min_spacing = ((MIN_SPACING_SECS / hop_secs).round() as usize).max(1);
+        let onsets: Vec<_> = pick_onsets(&flux, min_spacing)
+            .into_iter()
+            .map(|(i, s)| (i as f64 * hop_secs + frame_secs / 2.0, s as f64))
+            .collect();
+        Ok(Onsets(onsets.into_iter()))
+    }
+
+    fn next_row(&mut self, _stats: &mut CallStats) -> Result<Option<Self::Row>> {
+        Ok(self.0.next())
+    }
+
+    fn column(row: &Self::Row, ctx: &mut Context, i: usize) -> rusqlite::Result<()> {
+        let (time_secs, strength) = row;
+        match i {
+            0 => ctx.set_result(time_secs),
+            _ => ctx.set_result(strength),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_onsets() {
+        // Low background flux with hits every 50 frames, a weaker one at 75,
+        // and a second peak too close to the hit at 100.
+        let mut flux = vec![0.1; 200];
+        for i in [0, 50, 100, 150] {
+            flux[i] = 2.0;
+        }
+        flux[75] = 1.0;
+        flux[104] = 1.5;
+
+        let onsets = pick_onsets(&flux, 5);
+        let frames: Vec<usize> = onsets.iter().map(|&(i, _)| i).collect();
+        assert_eq!(frames, [0, 50, 75, 100, 150]);
+        assert_eq!(onsets[2].1, 0.5);
+
+        assert!(pick_onsets(&[0.0; 100], 5).is_empty());
+        flux.iter_mut().for_each(|f| *f /= 100.0);
+        assert!(pick_onsets(&flux, 5).is_empty());
+    }
+}
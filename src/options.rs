@@ -0,0 +1,404 @@
+//! Per-connection options.
+//!
+//! Options are read and changed from SQL with `chromaprint_option(name)` and
+//! `chromaprint_option(name, value)`. Changes apply to the next function call,
+//! including calls from statements that were prepared earlier.
+//!
+//! Each option starts out at the value of the `SQLITE3_CHROMAPRINT_<NAME>`
+//! environment variable of the process loading the extension, e.g.
+//! `SQLITE3_CHROMAPRINT_MATCH_MODE=crossfade`. If that is unset, the same
+//! variable in the environment of `cargo build` sets the default compiled
+//! into the extension, and otherwise the built-in default applies. This lets
+//! deployments that only `.load` the extension tune it without SQL.
+//!
+//! `chromaprint_reload_config()` reads the environment again and resets
+//! every option of the connection to its default, returning the new values
+//! as JSON. Long-lived connections can pick up changed settings this way
+//! without reconnecting or loading the extension again.
+//!
+//! Neither reveals the value of `acoustid_key`, which reads as `'***'` when
+//! set and NULL otherwise, so that queries able to read options can't leak
+//! the key.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::types::{Value, ValueRef};
+
+use crate::encoding::Encoding;
+use crate::matching::MatchMode;
+use crate::preset::Preset;
+use crate::roots;
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Options {
+    /// Record decode/fingerprint timings for each call in `chromaprint_timings`.
+    pub timings: bool,
+    /// How `compare_fingerprints` scores matched segments.
+    pub match_mode: MatchMode,
+    /// Read files through the connection's VFS.
+    pub vfs: bool,
+    /// How functions returning fingerprints encode them as TEXT.
+    pub encoding: Encoding,
+    /// Chromaprint preset of functions not given one.
+    pub preset: Preset,
+    /// AcoustID application key of `musicbrainz_recordings`.
+    pub acoustid_key: Option<String>,
+    /// Seconds of each file that fingerprinting functions read, if limited.
+    pub max_length_secs: Option<u32>,
+    /// Table of the main database that `fingerprint` caches fingerprints in.
+    pub cache_table: Option<String>,
+    /// Database that `audio_scan` and `chromaprint_sync` save progress in.
+    pub checkpoint_db: Option<PathBuf>,
+    /// SQL function that decoding calls with its progress, see `progress`.
+    pub progress_function: Option<String>,
+    /// Directories that functions may read files in, if restricted.
+    pub allowed_roots: Option<Arc<[PathBuf]>>,
+    /// Worker threads of `audio_scan` when not given `jobs`, if not 1.
+    pub scan_jobs: Option<u32>,
+    /// Fingerprints kept by the `shared-cache` feature's cache, if not
+    /// [`DEFAULT_SHARED_CACHE_SIZE`].
+    pub shared_cache_size: Option<u32>,
+}
+
+/// The value read for options holding a secret, when set.
+const REDACTED: &str = "***";
+
+/// Fingerprints kept by the `shared-cache` feature's cache by default; a few
+/// KB each for typical tracks.
+pub(crate) const DEFAULT_SHARED_CACHE_SIZE: u32 = 4096;
+
+/// Every option, with its default from the build environment if set.
+const COMPILED_DEFAULTS: &[(&str, Option<&str>)] = &[
+    ("timings", option_env!("SQLITE3_CHROMAPRINT_TIMINGS")),
+    ("match_mode", option_env!("SQLITE3_CHROMAPRINT_MATCH_MODE")),
+    ("vfs", option_env!("SQLITE3_CHROMAPRINT_VFS")),
+    ("encoding", option_env!("SQLITE3_CHROMAPRINT_ENCODING")),
+    ("preset", option_env!("SQLITE3_CHROMAPRINT_PRESET")),
+    (
+        "acoustid_key",
+        option_env!("SQLITE3_CHROMAPRINT_ACOUSTID_KEY"),
+    ),
+    (
+        "max_length_secs",
+        option_env!("SQLITE3_CHROMAPRINT_MAX_LENGTH_SECS"),
+    ),
+    (
+        "cache_table",
+        option_env!("SQLITE3_CHROMAPRINT_CACHE_TABLE"),
+    ),
+    (
+        "checkpoint_db",
+        option_env!("SQLITE3_CHROMAPRINT_CHECKPOINT_DB"),
+    ),
+    (
+        "progress_function",
+        option_env!("SQLITE3_CHROMAPRINT_PROGRESS_FUNCTION"),
+    ),
+    (
+        "allowed_roots",
+        option_env!("SQLITE3_CHROMAPRINT_ALLOWED_ROOTS"),
+    ),
+    ("scan_jobs", option_env!("SQLITE3_CHROMAPRINT_SCAN_JOBS")),
+    (
+        "shared_cache_size",
+        option_env!("SQLITE3_CHROMAPRINT_SHARED_CACHE_SIZE"),
+    ),
+];
+
+impl Options {
+    /// The options a new connection starts with: from the environment, then
+    /// the build environment, then the built-in defaults.
+    pub fn from_env() -> Result<Self> {
+        Self::with_defaults(|var| std::env::var(var).ok())
+    }
+
+    fn with_defaults(env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut options = Self::default();
+        for &(name, compiled) in COMPILED_DEFAULTS {
+            let var = format!("SQLITE3_CHROMAPRINT_{}", name.to_ascii_uppercase());
+            let value = env(&var).or_else(|| compiled.map(str::to_owned));
+            if let Some(value) = value {
+                options
+                    .set(name, ValueRef::Text(value.as_bytes()))
+                    .with_context(|| format!("Invalid {var}"))?;
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Value> {
+        Ok(match name {
+            "timings" => Value::Integer(self.timings as i64),
+            "match_mode" => Value::Text(self.match_mode.name().to_owned()),
+            "vfs" => Value::Integer(self.vfs as i64),
+            "encoding" => Value::Text(self.encoding.name().to_owned()),
+            "preset" => Value::Text(self.preset.name().to_owned()),
+            // Keys are secret, so only whether one is set is revealed.
+            "acoustid_key" => self
+                .acoustid_key
+                .as_ref()
+                .map_or(Value::Null, |_| Value::Text(REDACTED.to_owned())),
+            "max_length_secs" => Value::Integer(self.max_length_secs.unwrap_or(0).into()),
+            "cache_table" => self.cache_table.clone().map_or(Value::Null, Value::Text),
+            "checkpoint_db" => self.checkpoint_db.as_ref().map_or(Value::Null, |path| {
+                Value::Text(path.to_string_lossy().into_owned())
+            }),
+            "progress_function" => self
+                .progress_function
+                .clone()
+                .map_or(Value::Null, Value::Text),
+            "allowed_roots" => self
+                .allowed_roots
+                .as_ref()
+                .map_or(Value::Null, |roots| Value::Text(roots::join(roots))),
+            "scan_jobs" => Value::Integer(self.scan_jobs.unwrap_or(1).into()),
+            "shared_cache_size" => Value::Integer(
+                self.shared_cache_size
+                    .unwrap_or(DEFAULT_SHARED_CACHE_SIZE)
+                    .into(),
+            ),
+            _ => bail!("Unknown option: {name}"),
+        })
+    }
+
+    /// Every option and its value, as a JSON object.
+    pub fn to_json(&self) -> serde_json::Value {
+        COMPILED_DEFAULTS
+            .iter()
+            .map(|&(name, _)| {
+                let value = match self.get(name) {
+                    Ok(Value::Integer(i)) => i.into(),
+                    Ok(Value::Text(s)) => s.into(),
+                    _ => serde_json::Value::Null,
+                };
+                (name.to_owned(), value)
+            })
+            .collect()
+    }
+
+    pub fn set(&mut self, name: &str, value: ValueRef<'_>) -> Result<()> {
+        match name {
+            "timings" => self.timings = parse_bool(name, value)?,
+            "match_mode" => self.match_mode = MatchMode::parse(parse_text(name, value)?)?,
+            "vfs" => self.vfs = parse_bool(name, value)?,
+            "encoding" => self.encoding = Encoding::parse(parse_text(name, value)?)?,
+            "preset" => self.preset = Preset::parse(parse_text(name, value)?)?,
+            "acoustid_key" => {
+                self.acoustid_key = match value {
+                    ValueRef::Null => None,
+                    value => Some(parse_text(name, value)?.to_owned()),
+                }
+            }
+            "max_length_secs" => {
+                self.max_length_secs =
+                    Some(parse_count(name, value, "seconds")?).filter(|&secs| secs > 0)
+            }
+            "cache_table" => {
+                self.cache_table = match value {
+                    ValueRef::Null => None,
+                    value => Some(parse_text(name, value)?.to_owned()).filter(|t| !t.is_empty()),
+                }
+            }
+            "checkpoint_db" => {
+                self.checkpoint_db = match value {
+                    ValueRef::Null => None,
+                    value => Some(parse_text(name, value)?)
+                        .filter(|p| !p.is_empty())
+                        .map(PathBuf::from),
+                }
+            }
+            "progress_function" => {
+                self.progress_function = match value {
+                    ValueRef::Null => None,
+                    value => Some(parse_text(name, value)?.to_owned()).filter(|f| !f.is_empty()),
+                }
+            }
+            "allowed_roots" => {
+                let allowed_roots = match value {
+                    ValueRef::Null => None,
+                    value => Some(roots::parse(parse_text(name, value)?)).filter(|r| !r.is_empty()),
+                };
+                if let Some(current) = &self.allowed_roots {
+                    let Some(narrower) = &allowed_roots else {
+                        bail!("allowed_roots can only be narrowed, not removed");
+                    };
+                    roots::check_narrower(current, narrower)?;
+                }
+                self.allowed_roots = allowed_roots.map(Arc::from);
+            }
+            "scan_jobs" => {
+                self.scan_jobs = Some(parse_count(name, value, "threads")?).filter(|&n| n != 1)
+            }
+            "shared_cache_size" => {
+                self.shared_cache_size = Some(parse_count(name, value, "fingerprints")?)
+            }
+            _ => bail!("Unknown option: {name}"),
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(name: &str, value: ValueRef<'_>) -> Result<bool> {
+    match value {
+        ValueRef::Integer(i) => Ok(i != 0),
+        ValueRef::Text(s) => match std::str::from_utf8(s)?.to_ascii_lowercase().as_str() {
+            "1" | "on" | "true" | "yes" => Ok(true),
+            "0" | "off" | "false" | "no" => Ok(false),
+            s => bail!("Invalid boolean value for {name}: {s}"),
+        },
+        v => bail!("Invalid value type for {name}: {}", v.data_type()),
+    }
+}
+
+/// A whole number of `things`, e.g. seconds.
+fn parse_count(name: &str, value: ValueRef<'_>, things: &str) -> Result<u32> {
+    let count = match value {
+        ValueRef::Integer(i) => i,
+        ValueRef::Text(s) => std::str::from_utf8(s)?
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid number of {things} for {name}"))?,
+        v => bail!("Invalid value type for {name}: {}", v.data_type()),
+    };
+    u32::try_from(count).with_context(|| format!("Invalid number of {things} for {name}: {count}"))
+}
+
+fn parse_text<'a>(name: &str, value: ValueRef<'a>) -> Result<&'a str> {
+    match value {
+        ValueRef::Text(s) => Ok(std::str::from_utf8(s)?),
+        v => bail!("Invalid value type for {name}: {}", v.data_type()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_get() {
+        let mut options = Options::default();
+        options.set("timings", ValueRef::Text(b"on")).unwrap();
+        assert_eq!(options.get("timings").unwrap(), Value::Integer(1));
+        options.set("timings", ValueRef::Integer(0)).unwrap();
+        assert_eq!(options.get("timings").unwrap(), Value::Integer(0));
+
+        assert!(options.set("timings", ValueRef::Text(b"maybe")).is_err());
+        assert!(options.set("match_mode", ValueRef::Text(b"fuzzy")).is_err());
+        options.set("encoding", ValueRef::Text(b"hex")).unwrap();
+        assert_eq!(options.encoding, Encoding::Hex);
+        assert!(options.set("encoding", ValueRef::Text(b"base32")).is_err());
+        options.set("preset", ValueRef::Text(b"test2")).unwrap();
+        assert_eq!(options.preset, Preset::Test2);
+        options.set("preset", ValueRef::Text(b"fpcalc")).unwrap();
+        assert_eq!(options.preset, Preset::Test2);
+        assert_eq!(
+            options.get("preset").unwrap(),
+            Value::Text("test2".to_owned())
+        );
+        assert!(options.set("preset", ValueRef::Text(b"test6")).is_err());
+        assert!(options.get("no_such_option").is_err());
+
+        assert_eq!(options.to_json()["encoding"], "hex");
+        assert_eq!(options.to_json()["timings"], 0);
+
+        assert_eq!(options.get("acoustid_key").unwrap(), Value::Null);
+        options.set("acoustid_key", ValueRef::Text(b"key")).unwrap();
+        assert_eq!(options.acoustid_key.as_deref(), Some("key"));
+        assert_eq!(options.to_json()["acoustid_key"], "***");
+        assert_eq!(
+            options.get("acoustid_key").unwrap(),
+            Value::Text("***".to_owned())
+        );
+        options.set("acoustid_key", ValueRef::Null).unwrap();
+        assert_eq!(options.acoustid_key, None);
+
+        assert_eq!(options.get("max_length_secs").unwrap(), Value::Integer(0));
+        options
+            .set("max_length_secs", ValueRef::Text(b"120"))
+            .unwrap();
+        assert_eq!(options.max_length_secs, Some(120));
+        options
+            .set("max_length_secs", ValueRef::Integer(0))
+            .unwrap();
+        assert_eq!(options.max_length_secs, None);
+        assert!(options
+            .set("max_length_secs", ValueRef::Integer(-1))
+            .is_err());
+        assert!(options.set("max_length_secs", ValueRef::Real(1.5)).is_err());
+
+        assert_eq!(options.get("cache_table").unwrap(), Value::Null);
+        options
+            .set("cache_table", ValueRef::Text(b"fp_cache"))
+            .unwrap();
+        assert_eq!(options.cache_table.as_deref(), Some("fp_cache"));
+        options.set("cache_table", ValueRef::Text(b"")).unwrap();
+        assert_eq!(options.cache_table, None);
+
+        assert_eq!(options.get("scan_jobs").unwrap(), Value::Integer(1));
+        options.set("scan_jobs", ValueRef::Text(b"0")).unwrap();
+        assert_eq!(options.scan_jobs, Some(0));
+        assert!(options.set("scan_jobs", ValueRef::Integer(-2)).is_err());
+        assert_eq!(
+            options.get("shared_cache_size").unwrap(),
+            Value::Integer(DEFAULT_SHARED_CACHE_SIZE.into())
+        );
+        options
+            .set("shared_cache_size", ValueRef::Integer(100))
+            .unwrap();
+        assert_eq!(options.shared_cache_size, Some(100));
+
+        assert_eq!(options.get("checkpoint_db").unwrap(), Value::Null);
+        options
+            .set("checkpoint_db", ValueRef::Text(b"/tmp/scan.db"))
+            .unwrap();
+        assert_eq!(
+            options.get("checkpoint_db").unwrap(),
+            Value::Text("/tmp/scan.db".to_owned())
+        );
+        options.set("checkpoint_db", ValueRef::Null).unwrap();
+        assert_eq!(options.checkpoint_db, None);
+
+        assert_eq!(options.get("progress_function").unwrap(), Value::Null);
+        options
+            .set("progress_function", ValueRef::Text(b"ui_tick"))
+            .unwrap();
+        assert_eq!(options.to_json()["progress_function"], "ui_tick");
+        options.set("progress_function", ValueRef::Null).unwrap();
+        assert_eq!(options.progress_function, None);
+        assert!(options
+            .set("progress_function", ValueRef::Integer(1))
+            .is_err());
+
+        assert_eq!(options.get("allowed_roots").unwrap(), Value::Null);
+        options.set("allowed_roots", ValueRef::Text(b"")).unwrap();
+        assert_eq!(options.allowed_roots, None);
+        let root = std::env::temp_dir();
+        let root = root.to_str().unwrap();
+        options
+            .set("allowed_roots", ValueRef::Text(root.as_bytes()))
+            .unwrap();
+        assert_eq!(options.to_json()["allowed_roots"], root);
+        assert!(options.set("allowed_roots", ValueRef::Null).is_err());
+        assert_eq!(options.to_json()["allowed_roots"], root);
+    }
+
+    #[test]
+    fn test_defaults_from_env() {
+        let options = Options::with_defaults(|var| match var {
+            "SQLITE3_CHROMAPRINT_TIMINGS" => Some("yes".to_owned()),
+            "SQLITE3_CHROMAPRINT_MATCH_MODE" => Some("crossfade".to_owned()),
+            _ => None,
+        })
+        .unwrap();
+        assert!(options.timings);
+        assert_eq!(options.match_mode, MatchMode::Crossfade);
+
+        let err = Options::with_defaults(|var| {
+            (var == "SQLITE3_CHROMAPRINT_MATCH_MODE").then(|| "fuzzy".to_owned())
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid SQLITE3_CHROMAPRINT_MATCH_MODE");
+    }
+}
@@ -0,0 +1,225 @@
+//! Where two recordings overlap.
+//!
+//! `match_segments(fp_a, fp_b)` returns the segments that
+//! `compare_fingerprints` summarizes in one score, one row each, in order of
+//! their position in `fp_a`:
+//!
+//! ```sql
+//! SELECT offset_a_secs, offset_b_secs, duration_secs, score
+//! FROM match_segments(fingerprint('live.mp3'), fingerprint('studio.mp3'));
+//! ```
+//!
+//! `score` is scored under the `match_mode` option, like
+//! `compare_fingerprints`: 0 for identical audio and 32 for unrelated audio.
+//! Fingerprints are matched with the preset they record, or else the `preset`
+//! option.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result};
+use rusqlite::types::Type;
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+use rusty_chromaprint::match_fingerprints;
+
+use crate::matching::{self, MatchMode};
+use crate::panic::catch_panic;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::{comparison_preset, fingerprint_and_preset_from_value, tvf};
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 4;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "match_segments",
+        eponymous_only_module::<MatchSegmentsTab>(),
+        Some(state),
+    )
+}
+
+/// A matched segment, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Row {
+    offset_a_secs: f64,
+    offset_b_secs: f64,
+    duration_secs: f64,
+    score: f64,
+}
+
+/// The segments `fp_a` and `fp_b` share, in order of their offset in `fp_a`.
+fn match_segments(fp_a: &[u32], fp_b: &[u32], preset: Preset, mode: MatchMode) -> Result<Vec<Row>> {
+    let config = preset.config();
+    let mut segments =
+        match_fingerprints(fp_a, fp_b, &config).context("Failed to match fingerprints")?;
+    segments.sort_by_key(|s| s.offset1);
+    Ok(segments
+        .iter()
+        .map(|s| Row {
+            offset_a_secs: s.start1(&config) as f64,
+            offset_b_secs: s.start2(&config) as f64,
+            duration_secs: s.duration(&config) as f64,
+            score: matching::segment_score(s, fp_a, fp_b, &config, mode),
+        })
+        .collect())
+}
+
+#[repr(C)]
+struct MatchSegmentsTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for MatchSegmentsTab {
+    type Aux = Arc<State>;
+    type Cursor = MatchSegmentsCursor<'vtab>;
+
+    fn connect(
+        _db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("match_segments: missing state".to_owned())
+            })?;
+            Ok((
+                "CREATE TABLE x(offset_a_secs, offset_b_secs, duration_secs, score, \
+                 fp_a HIDDEN, fp_b HIDDEN)"
+                    .to_owned(),
+                MatchSegmentsTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_arguments(info, "match_segments", FIRST_ARGUMENT, &["fp_a", "fp_b"])
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<MatchSegmentsCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(MatchSegmentsCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct MatchSegmentsCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    rows: Vec<Row>,
+    index: usize,
+    phantom: PhantomData<&'vtab MatchSegmentsTab>,
+}
+
+unsafe impl VTabCursor for MatchSegmentsCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.rows = Vec::new();
+            self.index = 0;
+
+            let mut fingerprints = Vec::new();
+            for (i, (value, name)) in args.iter().zip(["fp_a", "fp_b"]).enumerate() {
+                if value.data_type() == Type::Null {
+                    return Ok(());
+                }
+                fingerprints.push(fingerprint_and_preset_from_value(i, name, value)?);
+            }
+            let [(preset_a, fp_a), (preset_b, fp_b)] = &fingerprints[..] else {
+                return Ok(());
+            };
+
+            let (default, mode) = {
+                let options = self.state.options();
+                (options.preset, options.match_mode)
+            };
+            let preset = comparison_preset(None, *preset_a, *preset_b, default)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            self.rows = match_segments(fp_a, fp_b, preset, mode)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let row = self.rows[self.index];
+            match i {
+                0 => ctx.set_result(&row.offset_a_secs),
+                1 => ctx.set_result(&row.offset_b_secs),
+                2 => ctx.set_result(&row.duration_secs),
+                3 => ctx.set_result(&row.score),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::timings::CallStats;
+
+    #[cfg(feature = "decode")]
+    #[test]
+    fn test_match_segments() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+        let fingerprint =
+            crate::fingerprint_path(&path, Preset::default(), &mut CallStats::default()).unwrap();
+
+        // The second half of the recording is the start of the excerpt.
+        let half = fingerprint.len() / 2;
+        let rows = match_segments(
+            &fingerprint,
+            &fingerprint[half..],
+            Preset::default(),
+            MatchMode::Default,
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 1, "{rows:?}");
+        assert_eq!(rows[0].offset_b_secs, 0.0);
+        assert!(rows[0].offset_a_secs > 0.0);
+        assert_eq!(rows[0].score, 0.0);
+    }
+}
@@ -0,0 +1,80 @@
+//! Conversion of panics inside SQL callbacks into SQL errors.
+//!
+//! A panic in a decoder or the fingerprinter must not unwind into SQLite.
+//! rusqlite already guards scalar functions, but reports every panic as the
+//! same generic error and does not guard virtual table callbacks at all. Every
+//! callback in this library runs inside [`catch_panic`], which turns a panic
+//! into an ordinary error carrying the panic message.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use rusqlite::functions::Context;
+
+use crate::interrupt::{self, Interrupt};
+
+/// A panic caught inside an SQL callback.
+#[derive(Debug)]
+pub(crate) struct Panic(String);
+
+impl Display for Panic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Panic: {}", self.0)
+    }
+}
+
+impl std::error::Error for Panic {}
+
+/// Run `f`, converting a panic into a [`rusqlite::Error::UserFunctionError`].
+pub(crate) fn catch_panic<T>(f: impl FnOnce() -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        Err(rusqlite::Error::UserFunctionError(Box::new(Panic(
+            panic_message(payload.as_ref()),
+        ))))
+    })
+}
+
+/// Wrap a scalar function implementation with [`catch_panic`], and report
+/// its errors on an interrupted connection as interrupts.
+pub(crate) fn guard<F, T>(f: F) -> impl Fn(&Context<'_>) -> rusqlite::Result<T> + Send + 'static
+where
+    F: Fn(&Context<'_>) -> rusqlite::Result<T> + Send + 'static,
+{
+    move |ctx| {
+        catch_panic(|| f(ctx)).map_err(|err| {
+            // SAFETY: the connection reference does not outlive this call.
+            match unsafe { ctx.get_connection() } {
+                // SAFETY: the handle is valid for the duration of this call.
+                Ok(db) => interrupt::to_sqlite_error(
+                    unsafe { Interrupt::of_connection(db.handle()) },
+                    err,
+                ),
+                Err(_) => err,
+            }
+        })
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic(|| Ok(1)).unwrap(), 1);
+
+        let err = catch_panic::<()>(|| panic!("index {} out of range", 3)).unwrap_err();
+        assert_eq!(err.to_string(), "Panic: index 3 out of range");
+    }
+}
@@ -0,0 +1,117 @@
+//! Conversion of SQL arguments into filesystem paths.
+//!
+//! TEXT paths are UTF-8 and are handed to the standard library as-is; on
+//! Windows it converts them to UTF-16 for the wide Win32 file APIs, so
+//! non-ASCII names (CJK, emoji, ...) work like any other path.
+//!
+//! Some paths cannot be represented as UTF-8 at all (arbitrary bytes on Unix,
+//! unpaired surrogates on Windows). Those can be passed as a BLOB holding the
+//! platform's native encoding: the raw bytes on Unix, and UTF-16LE code units
+//! on Windows.
+
+use std::path::{Path, PathBuf};
+
+use rusqlite::types::{Value, ValueRef};
+
+/// Interpret argument `idx` (a TEXT or BLOB value) as a path.
+pub(crate) fn path_from_value(idx: usize, value: ValueRef<'_>) -> rusqlite::Result<PathBuf> {
+    match value {
+        ValueRef::Text(s) => Ok(PathBuf::from(
+            std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?,
+        )),
+        ValueRef::Blob(b) => path_from_native(b),
+        v => Err(rusqlite::Error::InvalidFunctionParameterType(
+            idx,
+            v.data_type(),
+        )),
+    }
+}
+
+/// The SQL value of `path`: TEXT if it is valid Unicode, and otherwise a BLOB
+/// in the platform's native encoding, as accepted by [`path_from_value`].
+pub(crate) fn path_to_value(path: &Path) -> Value {
+    match path.to_str() {
+        Some(s) => Value::Text(s.to_owned()),
+        None => Value::Blob(path_to_native(path)),
+    }
+}
+
+#[cfg(unix)]
+fn path_from_native(bytes: &[u8]) -> rusqlite::Result<PathBuf> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+#[cfg(windows)]
+fn path_from_native(bytes: &[u8]) -> rusqlite::Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+
+    if !bytes.len().is_multiple_of(2) {
+        return Err(rusqlite::Error::UserFunctionError(
+            "BLOB paths must be UTF-16LE encoded".into(),
+        ));
+    }
+    let wide: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    Ok(PathBuf::from(OsString::from_wide(&wide)))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn path_from_native(bytes: &[u8]) -> rusqlite::Result<PathBuf> {
+    Ok(PathBuf::from(
+        std::str::from_utf8(bytes).map_err(rusqlite::Error::Utf8Error)?,
+    ))
+}
+
+#[cfg(unix)]
+fn path_to_native(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(windows)]
+fn path_to_native(path: &Path) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+
+    path.as_os_str()
+        .encode_wide()
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn path_to_native(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_path() {
+        let path = path_from_value(0, ValueRef::Text("音楽/🎵.mp3".as_bytes())).unwrap();
+        assert_eq!(path, PathBuf::from("音楽/🎵.mp3"));
+        assert!(path_from_value(0, ValueRef::Integer(1)).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_blob_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = path_from_value(0, ValueRef::Blob(b"caf\xe9.mp3")).unwrap();
+        assert_eq!(path.as_os_str().as_bytes(), b"caf\xe9.mp3");
+        assert_eq!(path.extension().unwrap(), "mp3");
+
+        let value = path_to_value(&path);
+        assert_eq!(value, Value::Blob(b"caf\xe9.mp3".to_vec()));
+        assert_eq!(path_from_value(0, (&value).into()).unwrap(), path);
+    }
+}
@@ -0,0 +1,161 @@
+//! Chromaprint algorithm presets.
+//!
+//! Fingerprints are made with the preset of the `preset` option, Chromaprint's
+//! `TEST1` algorithm by default, unless a function is given another:
+//!
+//! ```sql
+//! SELECT fingerprint('song.mp3', NULL, 'test2');
+//! SELECT compare_fingerprints(a, b, 'test2') FROM pairs;
+//! ```
+//!
+//! `'test1'` to `'test5'` select the presets of the same names. `'test2'` is
+//! what `fpcalc` and AcoustID use by default, and `'fpcalc'` and
+//! `'acoustid'` are other names for it. Fingerprints made with
+//! different presets can't be compared with each other. `'test4'` is meant
+//! to skip leading silence, which rusty-chromaprint doesn't implement, so its
+//! fingerprints of audio that starts with silence differ from `fpcalc`'s.
+//!
+//! Raw fingerprints don't record their preset, so `compare_fingerprints`
+//! assumes they were made with the one it's given, or the option's.
+//! Fingerprints in Chromaprint's compressed format, e.g. from
+//! `fp_to_acoustid(fp, preset)`, do record it, and comparing one made with
+//! another preset is an error.
+//!
+//! The option's default stays `'test1'` so that fingerprints stored by
+//! earlier versions keep matching new ones. For fingerprints that match
+//! `fpcalc`'s, set it to `'test2'`, e.g. with `SQLITE3_CHROMAPRINT_PRESET`,
+//! and fingerprint stored files again, since fingerprints can't be converted
+//! from one preset to another:
+//!
+//! ```sql
+//! SELECT chromaprint_option('preset', 'test2');
+//! UPDATE tracks SET fingerprint = fingerprint(path);
+//! ```
+//!
+//! `fpcalc` only fingerprints the first 120 seconds unless run with
+//! `-length 0`, and decodes with FFmpeg rather than Symphonia, so
+//! fingerprints of lossy formats may still differ from its output in a few
+//! bits where the decoders round differently.
+
+use anyhow::{bail, Result};
+use rusty_chromaprint::Configuration;
+
+/// Chromaprint's default FFT frame size, which `TEST5` halves.
+const FRAME_SIZE: usize = 4096;
+
+/// A Chromaprint algorithm preset. Fingerprints can only be compared with
+/// fingerprints made with the same preset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Preset {
+    /// `TEST1`, the default of this extension.
+    #[default]
+    Test1,
+    /// `TEST2`, the default of `fpcalc` and AcoustID.
+    Test2,
+    Test3,
+    Test4,
+    Test5,
+}
+
+impl Preset {
+    const ALL: [Self; 5] = [
+        Self::Test1,
+        Self::Test2,
+        Self::Test3,
+        Self::Test4,
+        Self::Test5,
+    ];
+
+    /// The preset named `s`, `"test1"` to `"test5"`, or `"fpcalc"` or
+    /// `"acoustid"` for the one they use, `TEST2`.
+    pub fn parse(s: &str) -> Result<Self> {
+        if let "fpcalc" | "acoustid" = s {
+            return Ok(Self::Test2);
+        }
+        match Self::ALL.into_iter().find(|preset| preset.name() == s) {
+            Some(preset) => Ok(preset),
+            None => bail!("Unknown preset: {s}"),
+        }
+    }
+
+    /// The preset's name, as [`parse`](Self::parse) takes it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Test1 => "test1",
+            Self::Test2 => "test2",
+            Self::Test3 => "test3",
+            Self::Test4 => "test4",
+            Self::Test5 => "test5",
+        }
+    }
+
+    /// The preset with the algorithm id that compressed fingerprints record.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|preset| preset.id() == id)
+    }
+
+    pub(crate) fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn config(self) -> Configuration {
+        match self {
+            Self::Test1 => Configuration::preset_test1(),
+            Self::Test2 => Configuration::preset_test2(),
+            Self::Test3 => Configuration::preset_test3(),
+            // rusty-chromaprint's own `TEST4` and `TEST5` presets lack the
+            // classifiers, which Chromaprint takes from `TEST2`.
+            Self::Test4 => Configuration::preset_test2()
+                .with_id(self.id())
+                .with_removed_silence(50),
+            Self::Test5 => Configuration::preset_test2()
+                .with_id(self.id())
+                .with_frame_size(FRAME_SIZE / 2)
+                .with_frame_overlap(FRAME_SIZE / 2 - FRAME_SIZE / 4),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        for preset in Preset::ALL {
+            assert_eq!(Preset::parse(preset.name()).unwrap(), preset);
+            assert_eq!(preset.config().id(), preset.id());
+            assert_eq!(Preset::from_id(preset.id()), Some(preset));
+
+            let mut printer = rusty_chromaprint::Fingerprinter::new(&preset.config());
+            printer.start(11025, 1).unwrap();
+            printer.consume(&vec![0; 11025 * 3]);
+            printer.finish();
+            assert!(!printer.fingerprint().is_empty(), "{preset:?}");
+        }
+        assert_eq!(Preset::default().config().id(), 0);
+        assert!(Preset::parse("test6").is_err());
+        assert_eq!(Preset::parse("fpcalc").unwrap(), Preset::Test2);
+        assert_eq!(Preset::parse("acoustid").unwrap(), Preset::Test2);
+        assert_eq!(Preset::from_id(5), None);
+    }
+
+    #[test]
+    fn test_test2_matches_chromaprint() {
+        // Chromaprint's own reference vectors for `TEST2`, which `fpcalc`
+        // uses: 130 blocks of 1024 silent samples at 44.1 kHz, from its
+        // `Test2SilenceFp` and `Test2SilenceRawFp` tests.
+        let config = Preset::Test2.config();
+        let mut printer = rusty_chromaprint::Fingerprinter::new(&config);
+        printer.start(44100, 1).unwrap();
+        for _ in 0..130 {
+            printer.consume(&[0; 1024]);
+        }
+        printer.finish();
+        assert_eq!(printer.fingerprint(), [627964279; 3]);
+        assert_eq!(
+            crate::encoding::compress(printer.fingerprint(), &config),
+            "AQAAA0mUaEkSRZEGAA"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Progress callbacks during long decodes.
+//!
+//! SQLite only calls a `sqlite3_progress_handler()` between the instructions
+//! of a statement, and decoding a file happens within one instruction, so
+//! applications that keep their UI responsive with a progress handler stall
+//! while a long file is fingerprinted. Setting the `progress_function` option
+//! to the name of an SQL function the application defines makes the
+//! functions and tables that decode audio call it about every [`INTERVAL`]
+//! while decoding, with the position reached in the file in seconds:
+//!
+//! ```sql
+//! SELECT chromaprint_option('progress_function', 'ui_tick');
+//! SELECT fingerprint(path) FROM tracks;  -- calls ui_tick(position_secs)
+//! ```
+//!
+//! Like a progress handler returning non-zero, a function returning a true
+//! value stops the call, which fails with an `interrupted` error as when the
+//! connection is interrupted (see [`crate::interrupt`]). An error from the
+//! function fails the call. The
+//! function is only called from the thread running the statement, so not
+//! while `audio_scan` fingerprints files on worker threads.
+
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use rusqlite::vtab::escape_double_quote;
+use rusqlite::{ffi, Connection};
+
+use crate::interrupt::Interrupted;
+
+/// Least time between calls of the progress function.
+pub(crate) const INTERVAL: Duration = Duration::from_millis(100);
+
+/// A connection to call progress functions on.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ProgressDb(*mut ffi::sqlite3);
+
+// SAFETY: the handle is only used on the thread that made the `Progress`
+// using it, which runs the statement (see `Progress::tick`), and it outlives
+// every call, see `of_connection`.
+unsafe impl Send for ProgressDb {}
+unsafe impl Sync for ProgressDb {}
+
+impl ProgressDb {
+    /// Connection `db`.
+    ///
+    /// # Safety
+    ///
+    /// `db` must be a valid connection handle, and stay valid while progress
+    /// functions are called on it.
+    pub unsafe fn of_connection(db: *mut ffi::sqlite3) -> Self {
+        Self(db)
+    }
+
+    /// Progress of a call made on this thread, reported to `function`.
+    pub fn progress(self, function: String) -> Progress {
+        Progress {
+            db: self,
+            function,
+            thread: thread::current().id(),
+            last: Instant::now(),
+        }
+    }
+}
+
+/// Reports the progress of one call.
+#[derive(Debug, Clone)]
+pub(crate) struct Progress {
+    db: ProgressDb,
+    function: String,
+    /// The thread running the statement.
+    thread: ThreadId,
+    /// When the function was last called, or the call started.
+    last: Instant,
+}
+
+impl Progress {
+    /// Call the progress function with `position_secs` if [`INTERVAL`] has
+    /// passed since it was last called, and fail with [`Interrupted`] if it
+    /// returns a true value.
+    pub fn tick(&mut self, position_secs: f64) -> Result<()> {
+        if thread::current().id() != self.thread || self.last.elapsed() < INTERVAL {
+            return Ok(());
+        }
+        // SAFETY: the handle is valid, see `ProgressDb::of_connection`. The
+        // connection does not close the handle when dropped.
+        let db = unsafe { Connection::from_handle(self.db.0) }?;
+        let stop = db
+            .query_row(
+                &format!(
+                    "SELECT coalesce(\"{}\"(?1), 0) != 0",
+                    escape_double_quote(&self.function)
+                ),
+                [position_secs],
+                |row| row.get::<_, bool>(0),
+            )
+            .map_err(|e| anyhow!("Progress function {} failed: {e}", self.function))?;
+        self.last = Instant::now();
+        if stop {
+            return Err(Interrupted.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tick_waits_for_interval() {
+        // SAFETY: the handle is never used, as the interval hasn't passed.
+        let db = unsafe { ProgressDb::of_connection(std::ptr::null_mut()) };
+        let mut progress = db.progress("ui_tick".to_owned());
+        assert!(progress.tick(1.0).is_ok());
+
+        // Nor from another thread.
+        progress.last -= INTERVAL;
+        thread::scope(|scope| {
+            scope.spawn(|| assert!(progress.clone().tick(2.0).is_ok()));
+        });
+    }
+}
@@ -0,0 +1,112 @@
+//! Directories that path-taking functions may read.
+//!
+//! Any SQL can call `fingerprint('/etc/passwd')`, so services that run
+//! statements they don't fully control can restrict the functions and tables
+//! that read files to some directories with the `allowed_roots` option, a
+//! list separated like `PATH` (`:` on Unix, `;` on Windows):
+//!
+//! ```sql
+//! SELECT chromaprint_option('allowed_roots', '/srv/media:/data/audio');
+//! ```
+//!
+//! A path is allowed if, with symbolic links and `..` resolved, it is inside
+//! one of the roots. Other paths, including ones that don't exist, fail with
+//! the same error, so that their existence isn't revealed either. Paths
+//! opened by a source provider aren't filesystem paths and aren't checked.
+//!
+//! Once set, the option can only be narrowed to roots inside the current
+//! ones, and `chromaprint_reload_config()` keeps it, so SQL run after the
+//! host sets it can't lift the restriction. NULL or `''`, the default,
+//! allows every path.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+/// The roots in `list`, a list separated like `PATH`.
+pub(crate) fn parse(list: &str) -> Vec<PathBuf> {
+    std::env::split_paths(OsStr::new(list))
+        .filter(|root| !root.as_os_str().is_empty())
+        .collect()
+}
+
+/// `roots` as a list separated like `PATH`.
+pub(crate) fn join(roots: &[PathBuf]) -> String {
+    std::env::join_paths(roots)
+        .map(|list| list.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Whether `path`, resolved, is inside one of `roots`.
+fn is_within(roots: &[PathBuf], path: &Path) -> bool {
+    let Ok(path) = std::fs::canonicalize(path) else {
+        return false;
+    };
+    roots
+        .iter()
+        .filter_map(|root| std::fs::canonicalize(root).ok())
+        .any(|root| path.starts_with(root))
+}
+
+/// Fail unless `path` is inside one of `roots`.
+pub(crate) fn check(roots: &[PathBuf], path: &Path) -> Result<()> {
+    if !is_within(roots, path) {
+        bail!(
+            "{} is not inside the allowed_roots option's directories",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Fail unless every root of `narrower` is inside one of `roots`.
+pub(crate) fn check_narrower(roots: &[PathBuf], narrower: &[PathBuf]) -> Result<()> {
+    for root in narrower {
+        if !is_within(roots, root) {
+            bail!(
+                "allowed_roots can only be narrowed: {} is not inside the current roots",
+                root.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_and_join() {
+        let roots = parse("/srv/media::/data/audio");
+        assert_eq!(
+            roots,
+            [PathBuf::from("/srv/media"), PathBuf::from("/data/audio")]
+        );
+        assert_eq!(join(&roots), "/srv/media:/data/audio");
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = std::env::temp_dir().join(format!("chromaprint-roots-{}", std::process::id()));
+        let media = dir.join("media");
+        std::fs::create_dir_all(media.join("album")).unwrap();
+        std::fs::write(media.join("album/01.mp3"), b"x").unwrap();
+        std::fs::write(dir.join("secret.mp3"), b"x").unwrap();
+        let roots = [media.clone()];
+
+        let results = [
+            check(&roots, &media.join("album/01.mp3")).is_ok(),
+            check(&roots, &media.join("album/../../secret.mp3")).is_ok(),
+            check(&roots, &dir.join("secret.mp3")).is_ok(),
+            check(&roots, &media.join("missing.mp3")).is_ok(),
+            check_narrower(&roots, &[media.join("album")]).is_ok(),
+            check_narrower(&roots, std::slice::from_ref(&dir)).is_ok(),
+        ];
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(results, [true, false, false, false, true, false]);
+    }
+}
@@ -0,0 +1,562 @@
+//! Directory scanning.
+//!
+//! `audio_scan(dir, recursive)` fingerprints the audio files in a
+//! directory, and with a true `recursive` its subdirectories, returning a
+//! row per file in path order:
+//!
+//! ```sql
+//! INSERT INTO tracks(path, fingerprint, duration)
+//! SELECT path, fingerprint, duration FROM audio_scan('/music', 1)
+//! WHERE error IS NULL;
+//! ```
+//!
+//! Files are found by extension like `chromaprint_sync` finds them. A file
+//! that can't be fingerprinted gets a row with a NULL `fingerprint` and
+//! `duration` and the reason in `error`, rather than failing the query.
+//! `duration` is in seconds. Files are fingerprinted as their rows are
+//! returned, so a `LIMIT` stops the scan early.
+//!
+//! With the `max_length_secs` option set, only the start of longer files is
+//! fingerprinted, and their `duration` is the length their container
+//! declares, or the length fingerprinted if it declares none.
+//!
+//! An optional third argument, `jobs`, fingerprints that many files at once
+//! on worker threads, or one per CPU if it is 0:
+//!
+//! ```sql
+//! SELECT path, fingerprint FROM audio_scan('/music', 1, 0);
+//! ```
+//!
+//! Rows are then returned as files finish, which isn't in path order. The
+//! workers stay at most `jobs` files ahead of the rows read, and a `LIMIT`
+//! still stops the scan early, once the files being fingerprinted are done.
+//! Without `jobs`, the `scan_jobs` option gives the number of threads, 1 by
+//! default.
+//!
+//! With the `checkpoint_db` option set, each file's result is saved to that
+//! database as soon as it is made, and a later scan returns it rather than
+//! decoding the file again while the file is unchanged, so a scan that is
+//! interrupted resumes where it left off (see [`crate::checkpoint`]).
+
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+
+use anyhow::{bail, Result};
+use rusqlite::types::{Type, Value};
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+
+use crate::checkpoint::Checkpoint;
+use crate::decode::AudioStream;
+use crate::encoding::Encoding;
+use crate::panic::catch_panic;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::sync::Stamp;
+use crate::timings::CallStats;
+use crate::{fingerprint_stream, path, sync, tvf};
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 4;
+
+/// Upper limit of `jobs`, against typos.
+const MAX_JOBS: i64 = 1024;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "audio_scan",
+        eponymous_only_module::<ScanTab>(),
+        Some(state),
+    )
+}
+
+/// A row of `audio_scan`.
+#[derive(Debug, Clone)]
+struct Row {
+    path: Value,
+    /// The fingerprint and duration, or why they couldn't be computed.
+    result: Result<(String, f64), String>,
+}
+
+/// The fingerprint of the file at `path`, or of at most its first
+/// `max_length_secs` seconds, and its duration in seconds.
+fn scan_file(
+    path: &Path,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    stats: &mut CallStats,
+) -> Result<(Vec<u32>, f64)> {
+    let mut stream = AudioStream::open_file(path, false, stats)?;
+    stream.set_max_length(max_length_secs);
+    let sample_rate = stream.sample_rate as f64;
+    let frame_rate = sample_rate * stream.channels.max(1) as f64;
+    let declared = stream
+        .n_frames
+        .map(|n_frames| n_frames as f64 / sample_rate);
+    let mut samples = 0;
+    let fingerprint =
+        fingerprint_stream(stream, preset, stats, &mut |packet| samples += packet.len())?;
+
+    // Files cut short report the length they declare, if any.
+    let decoded = samples as f64 / frame_rate;
+    let duration = match (max_length_secs, declared) {
+        (Some(max), Some(declared)) if decoded >= max as f64 => declared,
+        _ => decoded,
+    };
+    Ok((fingerprint, duration))
+}
+
+/// The row of the file at `path`, from `checkpoint` if it was saved there
+/// for the file as it is at `stamp`, or else from [`scan_file`] and then
+/// saved there.
+fn scan_row(
+    path: &Path,
+    stamp: Stamp,
+    checkpoint: Option<&Mutex<Checkpoint>>,
+    preset: Preset,
+    max_length_secs: Option<u32>,
+    encoding: Encoding,
+    state: &State,
+) -> Row {
+    let saved = checkpoint.and_then(|checkpoint| {
+        checkpoint
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(path, stamp, preset, max_length_secs)
+    });
+    let result = match saved {
+        Some(Ok((fingerprint, Some(duration)))) => Ok((fingerprint, duration)),
+        Some(Err(error)) => Err(error),
+        // Fingerprints saved by `chromaprint_sync` have no duration.
+        Some(Ok((_, None))) | None => {
+            let mut stats = state.call_stats();
+            let result =
+                match catch_panic(|| Ok(scan_file(path, preset, max_length_secs, &mut stats))) {
+                    Ok(result) => result.map_err(|e| format!("{e:#}")),
+                    Err(panic) => Err(panic.to_string()),
+                };
+            state.record_timing("audio_scan", &path.to_string_lossy(), stats);
+            if let Some(checkpoint) = checkpoint {
+                let outcome = result
+                    .clone()
+                    .map(|(fingerprint, duration)| (fingerprint, Some(duration)));
+                checkpoint
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .save(path, stamp, preset, max_length_secs, &outcome);
+            }
+            result
+        }
+    };
+    Row {
+        path: path::path_to_value(path),
+        result: result.map(|(fingerprint, duration)| (encoding.encode(&fingerprint), duration)),
+    }
+}
+
+/// The number of worker threads for `jobs`, or `None` to scan on the
+/// calling thread.
+fn worker_count(jobs: Option<i64>) -> Result<Option<usize>> {
+    match jobs {
+        None | Some(1) => Ok(None),
+        Some(0) => {
+            let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+            Ok((cpus > 1).then_some(cpus))
+        }
+        Some(jobs @ 2..=MAX_JOBS) => Ok(Some(jobs as usize)),
+        Some(jobs) => bail!("Invalid number of jobs: {jobs}"),
+    }
+}
+
+/// Files being fingerprinted on worker threads.
+struct Workers {
+    /// Files no worker has taken yet.
+    queue: Arc<Mutex<VecDeque<(PathBuf, Stamp)>>>,
+    /// Rows of finished files; `None` once the workers are being stopped.
+    results: Option<mpsc::Receiver<Row>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Fingerprint `paths` on `count` threads, at most `count` files ahead
+    /// of the rows taken.
+    fn spawn(
+        paths: VecDeque<(PathBuf, Stamp)>,
+        count: usize,
+        checkpoint: Option<Arc<Mutex<Checkpoint>>>,
+        state: &Arc<State>,
+    ) -> Self {
+        let (encoding, preset, max_length_secs) = {
+            let options = state.options();
+            (options.encoding, options.preset, options.max_length_secs)
+        };
+        let count = count.min(paths.len());
+        let queue = Arc::new(Mutex::new(paths));
+        let (sender, results) = mpsc::sync_channel(count);
+        let handles = (0..count)
+            .map(|_| {
+                let queue = queue.clone();
+                let sender = sender.clone();
+                let checkpoint = checkpoint.clone();
+                let state = state.clone();
+                std::thread::spawn(move || loop {
+                    let Some((path, stamp)) = queue
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .pop_front()
+                    else {
+                        return;
+                    };
+                    let row = scan_row(
+                        &path,
+                        stamp,
+                        checkpoint.as_deref(),
+                        preset,
+                        max_length_secs,
+                        encoding,
+                        &state,
+                    );
+                    if sender.send(row).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+        Self {
+            queue,
+            results: Some(results),
+            handles,
+        }
+    }
+
+    /// The row of the next file to finish, or `None` once all have.
+    fn next(&self) -> Option<Row> {
+        self.results.as_ref()?.recv().ok()
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        // Sending fails once the receiver is dropped, which stops workers
+        // after the file they are fingerprinting.
+        self.results = None;
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[repr(C)]
+struct ScanTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for ScanTab {
+    type Aux = Arc<State>;
+    type Cursor = ScanCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("audio_scan: missing state".to_owned())
+            })?;
+            tvf::reads_files(db)?;
+            Ok((
+                "CREATE TABLE x(path, fingerprint, duration, error, \
+                 dir HIDDEN, recursive HIDDEN, jobs HIDDEN)"
+                    .to_owned(),
+                ScanTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_optional_arguments(
+                info,
+                "audio_scan",
+                FIRST_ARGUMENT,
+                &["dir", "recursive", "jobs"],
+                1,
+            )
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<ScanCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(ScanCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                pending: VecDeque::new(),
+                checkpoint: None,
+                workers: None,
+                row: None,
+                rowid: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+/// Cursor fingerprinting one file per row.
+#[repr(C)]
+struct ScanCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    pending: VecDeque<(PathBuf, Stamp)>,
+    /// Where results are saved as they are made, see [`crate::checkpoint`].
+    checkpoint: Option<Arc<Mutex<Checkpoint>>>,
+    /// Workers fingerprinting the files instead, if scanning in parallel.
+    workers: Option<Workers>,
+    row: Option<Row>,
+    rowid: i64,
+    phantom: PhantomData<&'vtab ScanTab>,
+}
+
+impl ScanCursor<'_> {
+    /// Fingerprint the next file and set the row reporting it.
+    fn advance(&mut self) {
+        if let Some(workers) = &self.workers {
+            self.row = workers.next();
+            if self.row.is_some() {
+                self.rowid += 1;
+            }
+            return;
+        }
+        self.row = self.pending.pop_front().map(|(path, stamp)| {
+            self.rowid += 1;
+            let (encoding, preset, max_length_secs) = {
+                let options = self.state.options();
+                (options.encoding, options.preset, options.max_length_secs)
+            };
+            scan_row(
+                &path,
+                stamp,
+                self.checkpoint.as_deref(),
+                preset,
+                max_length_secs,
+                encoding,
+                &self.state,
+            )
+        });
+    }
+}
+
+unsafe impl VTabCursor for ScanCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.pending = VecDeque::new();
+            self.workers = None;
+            self.checkpoint = None;
+            self.row = None;
+            self.rowid = 0;
+
+            let Some(value) = args.iter().next().filter(|v| v.data_type() != Type::Null) else {
+                return Ok(());
+            };
+            let dir = path::path_from_value(0, value)?;
+            let recursive = args.get::<Option<bool>>(1)?.unwrap_or(false);
+            let jobs = if args.len() > 2 {
+                args.get::<Option<i64>>(2)?
+            } else {
+                self.state.options().scan_jobs.map(i64::from)
+            };
+            let workers =
+                worker_count(jobs).map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+
+            let mut files = HashMap::new();
+            self.state
+                .call_stats()
+                .check_path(&dir)
+                .and_then(|()| sync::walk(&dir, recursive, &mut files))
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            let mut paths: Vec<(PathBuf, Stamp)> = files.into_iter().collect();
+            paths.sort_by(|a, b| a.0.cmp(&b.0));
+            if !paths.is_empty() {
+                self.checkpoint = Checkpoint::open(&self.state)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?
+                    .map(|checkpoint| Arc::new(Mutex::new(checkpoint)));
+            }
+            match workers {
+                Some(count) => {
+                    self.workers = Some(Workers::spawn(
+                        paths.into(),
+                        count,
+                        self.checkpoint.clone(),
+                        &self.state,
+                    ))
+                }
+                None => self.pending = paths.into(),
+            }
+            self.advance();
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.advance();
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.row.is_none()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let Some(row) = &self.row else {
+                return ctx.set_result(&rusqlite::types::Null);
+            };
+            match (i, &row.result) {
+                (0, _) => ctx.set_result(&row.path),
+                (1, Ok((fingerprint, _))) => ctx.set_result(fingerprint),
+                (2, Ok((_, duration))) => ctx.set_result(duration),
+                (3, Err(error)) => ctx.set_result(error),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.rowid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_file() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+
+        let (fingerprint, duration) =
+            scan_file(&path, Preset::Test1, None, &mut CallStats::default()).unwrap();
+        let expected =
+            crate::fingerprint_path(&path, Preset::default(), &mut CallStats::default()).unwrap();
+        assert_eq!(fingerprint, expected);
+        assert!((duration - 6.8).abs() < 0.1, "{duration}");
+
+        assert!(scan_file(
+            Path::new("/nonexistent.mp3"),
+            Preset::Test1,
+            None,
+            &mut CallStats::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_worker_count() {
+        assert_eq!(worker_count(None).unwrap(), None);
+        assert_eq!(worker_count(Some(1)).unwrap(), None);
+        assert_eq!(worker_count(Some(4)).unwrap(), Some(4));
+        assert!(worker_count(Some(-1)).is_err());
+        assert!(worker_count(Some(MAX_JOBS + 1)).is_err());
+    }
+
+    #[test]
+    fn test_workers() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let testdata = Path::new(&manifest_dir).join("src/testdata");
+        let paths = ["XC444467.ogg", "XC444467.mp3", "missing.mp3"].map(|f| testdata.join(f));
+        let queue = || {
+            paths
+                .iter()
+                .map(|path| (path.clone(), Stamp { size: 0, mtime: 0 }))
+                .collect()
+        };
+
+        let workers = Workers::spawn(queue(), 2, None, &Arc::default());
+        let rows: Vec<Row> = std::iter::from_fn(|| workers.next()).collect();
+        assert_eq!(rows.len(), 3);
+        let failed: Vec<&Row> = rows.iter().filter(|row| row.result.is_err()).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].path, path::path_to_value(&paths[2]));
+
+        // Dropping the workers early stops them.
+        drop(Workers::spawn(queue(), 2, None, &Arc::default()));
+    }
+
+    #[cfg(not(feature = "extension"))]
+    #[test]
+    fn test_scan_resumes_from_checkpoint() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let original = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+        let dir = std::env::temp_dir().join(format!("chromaprint-scan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(&original, dir.join("a.ogg")).unwrap();
+        std::fs::write(dir.join("b.mp3"), b"not audio").unwrap();
+        let checkpoint = dir.join("checkpoint.db");
+
+        let state = Arc::new(State::default());
+        state.options_mut().checkpoint_db = Some(checkpoint.clone());
+        let db = Connection::open_in_memory().unwrap();
+        register(&db, state).unwrap();
+        let scan = |db: &Connection| -> Vec<(Option<String>, Option<f64>, Option<String>)> {
+            let mut stmt = db
+                .prepare("SELECT fingerprint, duration, error FROM audio_scan(?1, 0)")
+                .unwrap();
+            let rows = stmt
+                .query_map([dir.to_str()], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap();
+            rows
+        };
+
+        let first = scan(&db);
+        assert!(first[0].0.is_some() && first[1].2.is_some(), "{first:?}");
+        // Saved rows are returned rather than fingerprinting again.
+        let saved = Connection::open(&checkpoint).unwrap();
+        let count: i64 = saved
+            .query_row("SELECT count(*) FROM chromaprint_checkpoint", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        saved
+            .execute(
+                "UPDATE chromaprint_checkpoint SET fingerprint = x'00000001', duration = 1.5 \
+                 WHERE fingerprint IS NOT NULL",
+                [],
+            )
+            .unwrap();
+        let second = scan(&db);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(second[0].0, Some(Encoding::Base64.encode(&[1])));
+        assert_eq!(second[0].1, Some(1.5));
+        assert_eq!(second[1].2, first[1].2);
+    }
+}
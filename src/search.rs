@@ -0,0 +1,405 @@
+//! Nearest-neighbour search over a table of fingerprints.
+//!
+//! `audio_search(query_fp, fp_table, fp_col, k)` returns the `k` fingerprints
+//! of a table most similar to a query, from the most similar, with their
+//! `compare_fingerprints` score and the seconds of audio they share with the
+//! query:
+//!
+//! ```sql
+//! SELECT rowid, score, matched_duration
+//! FROM audio_search(fingerprint('clip.mp3'), 'tracks', 'fingerprint', 10);
+//! ```
+//!
+//! `rowid` is the rowid of the fingerprint's row. Fingerprints that share no
+//! segment with the query aren't returned. Every fingerprint of the table is
+//! compared, unless it is a [`chromaprint_index`](crate::index) table, whose
+//! index is used to compare only candidates; `fp_col` is then ignored.
+//! Fingerprints are compared with the index's preset argument, the preset
+//! the query records, or else the `preset` option, under the `match_mode`
+//! option. Only the `k` best matches are kept while the table is scanned.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result};
+use rusqlite::types::{Type, Value};
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+use rusty_chromaprint::{match_fingerprints, Configuration};
+
+use crate::matching::MatchMode;
+use crate::panic::catch_panic;
+use crate::preset::Preset;
+use crate::state::State;
+use crate::{fingerprint_and_preset_from_value, index, similarity_score, tvf};
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 3;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "audio_search",
+        eponymous_only_module::<SearchTab>(),
+        Some(state),
+    )
+}
+
+/// A fingerprint matching the query.
+#[derive(Debug, Clone, PartialEq)]
+struct Hit {
+    rowid: Value,
+    score: f64,
+    matched_duration: f64,
+}
+
+impl Hit {
+    /// Order from the most to the least similar: by score, then by
+    /// matched duration, longest first.
+    fn rank(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then(other.matched_duration.total_cmp(&self.matched_duration))
+    }
+}
+
+/// A [`Hit`] ordered by [`Hit::rank`], so that the least similar of a
+/// [`BinaryHeap`] is on top.
+struct Ranked(Hit);
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.rank(&other.0)
+    }
+}
+
+/// The `k` fingerprints most similar to a query among those pushed.
+struct Ranking<'a> {
+    query: &'a [u32],
+    config: Configuration,
+    mode: MatchMode,
+    k: usize,
+    best: BinaryHeap<Ranked>,
+}
+
+impl<'a> Ranking<'a> {
+    fn new(query: &'a [u32], preset: Preset, mode: MatchMode, k: usize) -> Self {
+        Ranking {
+            query,
+            config: preset.config(),
+            mode,
+            k,
+            best: BinaryHeap::new(),
+        }
+    }
+
+    /// Compare `fingerprint` with the query, keeping it if it is among the
+    /// `k` most similar so far.
+    fn push(&mut self, rowid: Value, fingerprint: &[u32]) -> Result<()> {
+        if self.k == 0 {
+            return Ok(());
+        }
+        let segments = match_fingerprints(self.query, fingerprint, &self.config)
+            .context("Failed to match fingerprints")?;
+        let Some(score) =
+            similarity_score(&segments, self.query, fingerprint, &self.config, self.mode)
+        else {
+            return Ok(());
+        };
+        let matched_duration = segments
+            .iter()
+            .map(|s| s.duration(&self.config) as f64)
+            .sum();
+        self.best.push(Ranked(Hit {
+            rowid,
+            score,
+            matched_duration,
+        }));
+        if self.best.len() > self.k {
+            self.best.pop();
+        }
+        Ok(())
+    }
+
+    /// The hits kept, from the most similar.
+    fn into_hits(self) -> Vec<Hit> {
+        self.best
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Ranked(hit)| hit)
+            .collect()
+    }
+}
+
+#[repr(C)]
+struct SearchTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for SearchTab {
+    type Aux = Arc<State>;
+    type Cursor = SearchCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("audio_search: missing state".to_owned())
+            })?;
+            Ok((
+                "CREATE TABLE x(rowid, score, matched_duration, \
+                 query_fp HIDDEN, fp_table HIDDEN, fp_col HIDDEN, k HIDDEN)"
+                    .to_owned(),
+                SearchTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    // SAFETY: The handle outlives the virtual table, which is
+                    // disconnected before the connection is closed.
+                    db: unsafe { db.handle() },
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_arguments(
+                info,
+                "audio_search",
+                FIRST_ARGUMENT,
+                &["query_fp", "fp_table", "fp_col", "k"],
+            )
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<SearchCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(SearchCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                db: self.db,
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct SearchCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+    rows: Vec<Hit>,
+    index: usize,
+    phantom: PhantomData<&'vtab SearchTab>,
+}
+
+unsafe impl VTabCursor for SearchCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.rows = Vec::new();
+            self.index = 0;
+
+            let Some(value) = args.iter().next().filter(|v| v.data_type() != Type::Null) else {
+                return Ok(());
+            };
+            let (made_with, query) = fingerprint_and_preset_from_value(0, "query_fp", value)?;
+            let table = tvf::text_argument("audio_search", args, 1, "fp_table")?;
+            let fp_col = tvf::text_argument("audio_search", args, 2, "fp_col")?;
+            let k = args
+                .get::<Option<i64>>(3)?
+                .and_then(|k| usize::try_from(k).ok())
+                .filter(|&k| k > 0)
+                .ok_or_else(|| {
+                    rusqlite::Error::ModuleError("audio_search: k must be positive".to_owned())
+                })?;
+
+            // SAFETY: The handle outlives the virtual table, see
+            // `SearchTab::connect`. The connection does not close the handle
+            // when dropped.
+            let db = unsafe { Connection::from_handle(self.db)? };
+            let index_arguments = index::table_arguments(&db, table)?;
+            let (preset, mode) = {
+                let options = self.state.options();
+                let preset = index_arguments
+                    .and_then(|arguments| arguments.preset)
+                    .or(made_with)
+                    .unwrap_or(options.preset);
+                (preset, options.match_mode)
+            };
+
+            let mut ranking = Ranking::new(&query, preset, mode, k);
+            if index_arguments.is_some() {
+                for (rowid, fingerprint) in index::matching_fingerprints(&db, table, &query)? {
+                    ranking
+                        .push(rowid, &fingerprint)
+                        .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+                }
+            } else {
+                // SAFETY: As above.
+                unsafe {
+                    tvf::for_each_fingerprint(self.db, table, fp_col, "rowid", |id, fp| {
+                        ranking
+                            .push(id, &fp)
+                            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+                    })?;
+                }
+            }
+            self.rows = ranking.into_hits();
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let hit = &self.rows[self.index];
+            match i {
+                0 => ctx.set_result(&hit.rowid),
+                1 => ctx.set_result(&hit.score),
+                2 => ctx.set_result(&hit.matched_duration),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(seed: u32, len: usize) -> Vec<u32> {
+        let mut x = seed.wrapping_mul(2654435761) | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rank() {
+        let track = noise(1, 1000);
+        let query = track[200..600].to_vec();
+        let mut edited = track.clone();
+        for item in &mut edited[..] {
+            *item ^= 0x0001_0001;
+        }
+        let candidates = vec![
+            (Value::Integer(1), noise(2, 1000)),
+            (Value::Integer(2), edited),
+            (Value::Integer(3), track),
+        ];
+
+        let rank = |candidates: Vec<(Value, Vec<u32>)>, k| {
+            let mut ranking = Ranking::new(&query, Preset::default(), MatchMode::Default, k);
+            for (rowid, fingerprint) in candidates {
+                ranking.push(rowid, &fingerprint).unwrap();
+            }
+            ranking.into_hits()
+        };
+
+        let hits = rank(candidates.clone(), 5);
+        let rowids: Vec<&Value> = hits.iter().map(|hit| &hit.rowid).collect();
+        assert_eq!(rowids, [&Value::Integer(3), &Value::Integer(2)]);
+        assert_eq!(hits[0].score, 0.0);
+        assert!(hits[1].score > 0.0);
+        assert!(hits[0].matched_duration > 0.0);
+
+        let hits = rank(candidates, 1);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].rowid, Value::Integer(3));
+
+        let hits = rank(vec![(Value::Integer(3), query.clone())], 0);
+        assert!(hits.is_empty());
+    }
+
+    #[cfg(not(feature = "extension"))]
+    #[test]
+    fn test_search_index() {
+        let db = Connection::open_in_memory().unwrap();
+        let state = Arc::new(State::default());
+        index::register(&db, state.clone()).unwrap();
+        register(&db, state).unwrap();
+        db.execute_batch(
+            "CREATE VIRTUAL TABLE idx USING chromaprint_index(preset = 'test2');
+             CREATE TABLE tracks(fingerprint BLOB);",
+        )
+        .unwrap();
+        assert_eq!(
+            index::table_arguments(&db, "idx").unwrap(),
+            Some(index::Arguments {
+                preset: Some(Preset::Test2)
+            })
+        );
+        assert_eq!(index::table_arguments(&db, "tracks").unwrap(), None);
+
+        let track = noise(1, 1000);
+        let blob = crate::functions::fingerprint_to_blob(&track);
+        db.execute("INSERT INTO idx(rowid, fp) VALUES (7, ?1)", [&blob])
+            .unwrap();
+        db.execute("INSERT INTO tracks VALUES (?1)", [&blob])
+            .unwrap();
+        let query = crate::functions::fingerprint_to_blob(&track[200..600]);
+        for table in ["idx", "tracks"] {
+            let (rowid, score): (i64, f64) = db
+                .query_row(
+                    "SELECT rowid, score FROM audio_search(?1, ?2, 'fingerprint', 3)",
+                    rusqlite::params![query, table],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!(rowid, if table == "idx" { 7 } else { 1 });
+            assert_eq!(score, 0.0);
+        }
+    }
+}
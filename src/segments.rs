@@ -0,0 +1,270 @@
+//! Chapter inference from changes in the audio.
+//!
+//! `infer_segments(path)` returns candidate chapter points: times where the
+//! character of the audio changes abruptly, such as song boundaries or
+//! transitions between music and speech. Container chapter metadata is not
+//! consulted.
+//!
+//! ```sql
+//! SELECT time_secs, novelty FROM infer_segments('episode.mp3');
+//! ```
+//!
+//! Each fingerprint item encodes 32 features of the chroma spectrum. The
+//! novelty of a point is the total difference in how often each feature bit
+//! is set over the [`WINDOW_SECS`] before and after it, from 0 (no change) to
+//! 32. Points that are local maxima and stand out from the rest of the file
+//! are reported.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::sync::Arc;
+
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+use rusty_chromaprint::Configuration;
+
+use crate::panic::catch_panic;
+use crate::state::State;
+use crate::{fingerprint_path, path, tvf};
+
+/// Audio compared on either side of a candidate point.
+const WINDOW_SECS: f32 = 10.0;
+
+/// Minimum distance between two reported points.
+const MIN_SPACING_SECS: f32 = 30.0;
+
+/// Minimum novelty of a reported point, however uniform the rest of the file.
+const MIN_NOVELTY: f64 = 4.0;
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 2;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "infer_segments",
+        eponymous_only_module::<SegmentsTab>(),
+        Some(state),
+    )
+}
+
+/// Novelty of each item: the change in bit frequencies across it.
+fn novelty(fingerprint: &[u32], window: usize) -> Vec<f64> {
+    // counts[i][b]: number of items before i with bit b set.
+    let mut counts = vec![[0u32; 32]; fingerprint.len() + 1];
+    for (i, &x) in fingerprint.iter().enumerate() {
+        let mut next = counts[i];
+        for (b, count) in next.iter_mut().enumerate() {
+            *count += (x >> b) & 1;
+        }
+        counts[i + 1] = next;
+    }
+
+    (0..fingerprint.len())
+        .map(|i| {
+            if i < window || i + window > fingerprint.len() {
+                return 0.0;
+            }
+            (0..32)
+                .map(|b| {
+                    let before = counts[i][b] - counts[i - window][b];
+                    let after = counts[i + window][b] - counts[i][b];
+                    (before as f64 - after as f64).abs() / window as f64
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Candidate chapter points as `(item, novelty)`.
+fn boundaries(fingerprint: &[u32], config: &Configuration) -> Vec<(usize, f64)> {
+    let window = (WINDOW_SECS / config.item_duration_in_seconds()) as usize;
+    let spacing = (MIN_SPACING_SECS / config.item_duration_in_seconds()) as usize;
+
+    if fingerprint.len() <= 2 * window {
+        return Vec::new();
+    }
+    let novelty = novelty(fingerprint, window);
+    let scored = &novelty[window..novelty.len() - window];
+    let mean = scored.iter().sum::<f64>() / scored.len() as f64;
+    let sd = (scored.iter().map(|n| (n - mean).powi(2)).sum::<f64>() / scored.len() as f64).sqrt();
+    let threshold = (mean + 2.0 * sd).max(MIN_NOVELTY);
+
+    // Strongest points first, skipping those too close to a stronger one.
+    let mut candidates: Vec<(usize, f64)> = novelty
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|&(_, n)| n >= threshold)
+        .collect();
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut points: Vec<(usize, f64)> = Vec::new();
+    for (i, n) in candidates {
+        if points.iter().all(|&(p, _)| p.abs_diff(i) >= spacing) {
+            points.push((i, n));
+        }
+    }
+    points.sort_by_key(|&(i, _)| i);
+    points
+}
+
+#[repr(C)]
+struct SegmentsTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for SegmentsTab {
+    type Aux = Arc<State>;
+    type Cursor = SegmentsCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("infer_segments: missing state".to_owned())
+            })?;
+            tvf::reads_files(db)?;
+            Ok((
+                "CREATE TABLE x(time_secs, novelty, path HIDDEN)".to_owned(),
+                SegmentsTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| tvf::bind_arguments(info, "infer_segments", FIRST_ARGUMENT, &["path"]))
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<SegmentsCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(SegmentsCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct SegmentsCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    /// Candidate points as `(time_secs, novelty)`.
+    rows: Vec<(f64, f64)>,
+    index: usize,
+    phantom: PhantomData<&'vtab SegmentsTab>,
+}
+
+unsafe impl VTabCursor for SegmentsCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.rows = Vec::new();
+            self.index = 0;
+
+            let Some(value) = args
+                .iter()
+                .next()
+                .filter(|v| v.data_type() != rusqlite::types::Type::Null)
+            else {
+                return Ok(());
+            };
+            let path = path::path_from_value(0, value)?;
+
+            let preset = self.state.options().preset;
+            let mut stats = self.state.call_stats();
+            let fingerprint = fingerprint_path(&path, preset, &mut stats)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            self.state
+                .record_timing("infer_segments", &path.to_string_lossy(), stats);
+
+            let config = preset.config();
+            let item_secs = config.item_duration_in_seconds() as f64;
+            self.rows = boundaries(&fingerprint, &config)
+                .into_iter()
+                .map(|(i, n)| (i as f64 * item_secs, n))
+                .collect();
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let (time_secs, novelty) = self.rows[self.index];
+            match i {
+                0 => ctx.set_result(&time_secs),
+                1 => ctx.set_result(&novelty),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boundaries_at_change() {
+        let config = Configuration::preset_test1();
+        let mut x = 0x9e3779b9u32;
+        let mut noise = || {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x
+        };
+
+        // Three parts with different bits mostly set, like music, speech and
+        // music again.
+        let mut fingerprint = Vec::new();
+        for (mask, len) in [
+            (0xffff_0000u32, 1000),
+            (0x0000_ffff, 800),
+            (0xff00_ff00, 1200),
+        ] {
+            fingerprint.extend((0..len).map(|_| (noise() & noise()) | (noise() & mask)));
+        }
+
+        let points: Vec<usize> = boundaries(&fingerprint, &config)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(points.len(), 2, "{points:?}");
+        assert!(points[0].abs_diff(1000) < 5);
+        assert!(points[1].abs_diff(1800) < 5);
+    }
+}
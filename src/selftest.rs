@@ -0,0 +1,114 @@
+//! Built-in determinism check.
+//!
+//! `chromaprint_selftest()` fingerprints a small synthetic reference signal and
+//! compares the result against values recorded on a known-good build. The
+//! signal is generated with integer arithmetic only, so any difference in the
+//! output points at the fingerprinting pipeline itself (architecture, enabled
+//! features, floating point behaviour) rather than at the test input.
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::matching::MatchMode;
+use crate::preset::Preset;
+use crate::{compare_fingerprints, decode_fingerprint, encode_fingerprint, fingerprint_samples};
+
+pub(crate) const SAMPLE_RATE: u32 = 11025;
+
+/// Pitches (Hz) of the reference melody, one note per second.
+const MELODY: [u32; 12] = [262, 294, 330, 349, 392, 440, 494, 523, 440, 349, 294, 262];
+
+/// Number of sub-fingerprints produced for the reference signal.
+const EXPECTED_ITEMS: usize = 75;
+
+/// FNV-1a hash of the reference fingerprint.
+const EXPECTED_HASH: u64 = 0x81fbea26c983623b;
+
+/// Run the self test and return a JSON report.
+pub(crate) fn run() -> Result<Value> {
+    // The reference values are of the default preset, whatever the option says.
+    let fingerprint = fingerprint_samples(&reference_signal(), SAMPLE_RATE, 1, Preset::default())?;
+    let hash = fnv1a(&fingerprint);
+
+    let decoded = decode_fingerprint(&encode_fingerprint(&fingerprint))?;
+    let self_score =
+        compare_fingerprints(&decoded, &decoded, Preset::default(), MatchMode::Default)?;
+
+    let checks = [
+        json!({
+            "name": "length",
+            "pass": fingerprint.len() == EXPECTED_ITEMS,
+            "expected": EXPECTED_ITEMS,
+            "actual": fingerprint.len(),
+        }),
+        json!({
+            "name": "fingerprint",
+            "pass": hash == EXPECTED_HASH,
+            "expected": format!("{EXPECTED_HASH:016x}"),
+            "actual": format!("{hash:016x}"),
+        }),
+        json!({
+            "name": "self_match",
+            "pass": self_score == Some(0.0),
+            "expected": 0.0,
+            "actual": self_score,
+        }),
+    ];
+
+    Ok(json!({
+        "pass": checks.iter().all(|c| c["pass"] == true),
+        "version": env!("CARGO_PKG_VERSION"),
+        "arch": std::env::consts::ARCH,
+        "os": std::env::consts::OS,
+        "checks": checks,
+    }))
+}
+
+/// A mono melody of triangle waves with an octave overtone.
+pub(crate) fn reference_signal() -> Vec<i16> {
+    let mut samples = Vec::with_capacity((SAMPLE_RATE as usize) * MELODY.len());
+    let mut phase: u32 = 0;
+    let mut overtone_phase: u32 = 0;
+
+    for freq in MELODY {
+        let step = (((freq as u64) << 32) / SAMPLE_RATE as u64) as u32;
+        for _ in 0..SAMPLE_RATE {
+            phase = phase.wrapping_add(step);
+            overtone_phase = overtone_phase.wrapping_add(step.wrapping_mul(2));
+            let sample = triangle(phase) / 2 + triangle(overtone_phase) / 4;
+            samples.push(sample as i16);
+        }
+    }
+
+    samples
+}
+
+/// Triangle wave in the range [-32768, 32767] for a 32-bit phase.
+fn triangle(phase: u32) -> i32 {
+    let ramp = (phase >> 16) as i32;
+    if ramp < 0x8000 {
+        ramp * 2 - 0x8000
+    } else {
+        0x17FFF - ramp * 2
+    }
+}
+
+fn fnv1a(fingerprint: &[u32]) -> u64 {
+    fingerprint
+        .iter()
+        .flat_map(|x| x.to_be_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest_passes() {
+        let report = run().unwrap();
+        assert_eq!(report["pass"], true, "{report}");
+    }
+}
@@ -0,0 +1,105 @@
+//! Custom media sources for embedders.
+//!
+//! Applications that link this crate as a Rust library can make
+//! `fingerprint()` and the analysis functions read audio from somewhere
+//! other than the local filesystem, such as encrypted storage or HTTP with
+//! authentication, by registering a [`SourceProvider`] for a URI scheme:
+//!
+//! ```ignore
+//! sqlite3_chromaprint::register_source_provider("vault", VaultProvider::new(key));
+//! // SELECT fingerprint('vault://recordings/0042.flac');
+//! ```
+//!
+//! A path of the form `scheme://...` whose scheme has a provider is opened by
+//! that provider; every other path is opened as a file. The extension of the
+//! URI, ignoring any query or fragment, is the format hint as for files.
+//! Providers are shared by every connection in the process.
+
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use anyhow::Result;
+use symphonia::core::io::MediaSource;
+
+/// Opens the audio named by URIs of one scheme.
+pub trait SourceProvider: Send + Sync {
+    /// Open the audio at `uri`, which starts with the provider's scheme
+    /// followed by `://`.
+    fn open(&self, uri: &str) -> Result<Box<dyn MediaSource>>;
+}
+
+static PROVIDERS: RwLock<Vec<(String, Arc<dyn SourceProvider>)>> = RwLock::new(Vec::new());
+
+/// Open URIs starting with `scheme://` with `provider`, replacing any
+/// provider registered for the scheme before. Schemes are case-insensitive.
+pub fn register_source_provider(scheme: &str, provider: impl SourceProvider + 'static) {
+    let scheme = scheme.to_ascii_lowercase();
+    let mut providers = PROVIDERS.write().unwrap_or_else(PoisonError::into_inner);
+    providers.retain(|(s, _)| *s != scheme);
+    providers.push((scheme, Arc::new(provider)));
+}
+
+/// The provider for `path` and the URI it should open, if `path` is a URI
+/// with a registered scheme.
+pub(crate) fn provider_for(path: &Path) -> Option<(Arc<dyn SourceProvider>, &str)> {
+    let uri = path.to_str()?;
+    let (scheme, _) = uri.split_once("://")?;
+    let providers = PROVIDERS.read().unwrap_or_else(PoisonError::into_inner);
+    providers
+        .iter()
+        .find(|(s, _)| s.eq_ignore_ascii_case(scheme))
+        .map(|(_, provider)| (provider.clone(), uri))
+}
+
+/// Whether `s` names audio that a provider can open.
+pub(crate) fn is_uri(s: &str) -> bool {
+    provider_for(Path::new(s)).is_some()
+}
+
+/// The extension of `uri`, ignoring its query and fragment.
+pub(crate) fn extension(uri: &str) -> Option<&str> {
+    let path = uri.split(['?', '#']).next()?;
+    Path::new(path).extension()?.to_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::preset::Preset;
+
+    struct Bytes(Vec<u8>);
+
+    impl SourceProvider for Bytes {
+        fn open(&self, _uri: &str) -> Result<Box<dyn MediaSource>> {
+            Ok(Box::new(Cursor::new(self.0.clone())))
+        }
+    }
+
+    #[test]
+    fn test_provider_for() {
+        register_source_provider("Test-Source", Bytes(Vec::new()));
+        let (_, uri) = provider_for(Path::new("test-source://a/b.mp3?sig=1")).unwrap();
+        assert_eq!(uri, "test-source://a/b.mp3?sig=1");
+        assert_eq!(extension(uri), Some("mp3"));
+        assert!(provider_for(Path::new("unknown://a/b.mp3")).is_none());
+        assert!(provider_for(Path::new("/music/b.mp3")).is_none());
+    }
+
+    #[test]
+    fn test_fingerprint_from_provider() {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&manifest_dir).join("src/testdata/XC444467.ogg");
+        register_source_provider("test-bytes", Bytes(std::fs::read(&path).unwrap()));
+
+        let mut stats = crate::timings::CallStats::default();
+        let expected = crate::fingerprint_path(&path, Preset::default(), &mut stats).unwrap();
+        let fingerprint = crate::fingerprint_path(
+            Path::new("test-bytes://XC444467.ogg"),
+            Preset::default(),
+            &mut stats,
+        );
+        assert_eq!(fingerprint.unwrap(), expected);
+    }
+}
@@ -0,0 +1,164 @@
+//! Spectral centroid ("brightness").
+//!
+//! The spectral centroid of a frame is the magnitude-weighted mean frequency
+//! of its spectrum. `audio_spectral_centroid(path)` returns the mean over all
+//! non-silent frames of a file, in Hz, and `audio_spectral_centroid_windows`
+//! the mean over each second:
+//!
+//! ```sql
+//! SELECT start_secs, centroid_hz
+//! FROM audio_spectral_centroid_windows('track.mp3');
+//! ```
+//!
+//! `centroid_hz` is NULL for silent seconds.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rusqlite::vtab::Context;
+use rusqlite::Connection;
+
+use crate::analysis::{to_dbfs, FrameFeatures, MonoFrames, Spectrum};
+use crate::state::State;
+use crate::timings::CallStats;
+use crate::tvf::{self, PathFunction};
+
+/// Frame length; 2048 samples at 44.1 kHz.
+const FRAME_SECS: f64 = 0.046;
+
+/// Length of the windows returned by `audio_spectral_centroid_windows`.
+const WINDOW_SECS: f64 = 1.0;
+
+/// Frames quieter than this have no meaningful centroid.
+const SILENCE_DBFS: f64 = -60.0;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    tvf::register_path_function::<CentroidWindows>(db, state)
+}
+
+/// The centroid of a frame in Hz, or `None` if it is silent.
+fn centroid(spectrum: &mut Spectrum, frame: &[f32]) -> Result<Option<f32>> {
+    if to_dbfs(FrameFeatures::of(frame).rms) < SILENCE_DBFS {
+        return Ok(None);
+    }
+
+    let bin_hz = spectrum.bin_hz;
+    let (weighted, total) = spectrum
+        .of(frame)?
+        .iter()
+        .enumerate()
+        .map(|(k, c)| (k as f32 * bin_hz, c.norm()))
+        .fold((0.0, 0.0), |(w, t), (hz, m)| (w + hz * m, t + m));
+    Ok((total > 0.0).then(|| weighted / total))
+}
+
+/// The centroid of each frame of the file at `path`, and the frame length in
+/// seconds.
+fn frame_centroids(path: &Path, stats: &mut CallStats) -> Result<(f64, Vec<Option<f32>>)> {
+    let mut frames = MonoFrames::open(path, FRAME_SECS, stats)?;
+    let frame_secs = frames.frame_len as f64 / frames.sample_rate as f64;
+    let mut spectrum = Spectrum::new(frames.frame_len, frames.sample_rate);
+
+    let mut centroids = Vec::new();
+    while let Some(frame) = frames.next_frame(stats)? {
+        centroids.push(centroid(&mut spectrum, frame)?);
+    }
+    Ok((frame_secs, centroids))
+}
+
+fn mean(centroids: &[Option<f32>]) -> Option<f64> {
+    let (sum, n) = centroids
+        .iter()
+        .flatten()
+        .fold((0.0, 0), |(s, n), &c| (s + c as f64, n + 1));
+    (n > 0).then(|| sum / n as f64)
+}
+
+pub(crate) fn audio_spectral_centroid(path: &Path, stats: &mut CallStats) -> Result<Option<f64>> {
+    let (_, centroids) = frame_centroids(path, stats)?;
+    Ok(mean(&centroids))
+}
+
+/// The mean centroid of each window of a file, read a window at a time.
+struct CentroidWindows {
+    frames: MonoFrames,
+    spectrum: Spectrum,
+    frame_secs: f64,
+    /// Frames per window.
+    per_window: usize,
+    /// Frames read so far.
+    read: usize,
+}
+
+impl PathFunction for CentroidWindows {
+    const NAME: &'static str = "audio_spectral_centroid_windows";
+
+    /// `(start_secs, end_secs, centroid_hz)`.
+    type Row = (f64, f64, Option<f64>);
+
+    fn columns() -> Vec<&'static str> {
+        vec!["start_secs", "end_secs", "centroid_hz"]
+    }
+
+    fn open(path: &Path, stats: &mut CallStats) -> Result<Self> {
+        let frames = MonoFrames::open(path, FRAME_SECS, stats)?;
+        let frame_secs = frames.frame_len as f64 / frames.sample_rate as f64;
+        let spectrum = Spectrum::new(frames.frame_len, frames.sample_rate);
+        Ok(CentroidWindows {
+            frames,
+            spectrum,
+            frame_secs,
+            per_window: ((WINDOW_SECS / frame_secs).round() as usize).max(1),
+            read: 0,
+        })
+    }
+
+    fn next_row(&mut self, stats: &mut CallStats) -> Result<Option<Self::Row>> {
+        let mut window = Vec::with_capacity(self.per_window);
+        while window.len() < self.per_window {
+            let Some(frame) = self.frames.next_frame(stats)? else {
+                break;
+            };
+            window.push(centroid(&mut self.spectrum, frame)?);
+        }
+        if window.is_empty() {
+            return Ok(None);
+        }
+
+        let start = self.read as f64 * self.frame_secs;
+        self.read += window.len();
+        Ok(Some((
+            start,
+            self.read as f64 * self.frame_secs,
+            mean(&window),
+        )))
+    }
+
+    fn column(row: &Self::Row, ctx: &mut Context, i: usize) -> rusqlite::Result<()> {
+        let (start_secs, end_secs, centroid_hz) = row;
+        match i {
+            0 => ctx.set_result(start_secs),
+            1 => ctx.set_result(end_secs),
+            _ => ctx.set_result(centroid_hz),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_centroid_of_sine() {
+        let sample_rate = 8000;
+        let mut spectrum = Spectrum::new(1024, sample_rate);
+        let sine: Vec<f32> = (0..1024)
+            .map(|i| (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / sample_rate as f32).sin())
+            .collect();
+
+        let hz = centroid(&mut spectrum, &sine).unwrap().unwrap();
+        assert!((hz - 1000.0).abs() < 20.0, "{hz}");
+        assert_eq!(centroid(&mut spectrum, &[0.0; 1024]).unwrap(), None);
+    }
+}
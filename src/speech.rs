@@ -0,0 +1,184 @@
+//! Speech/music discrimination.
+//!
+//! `audio_speech_music(path)` classifies each second of a file from two
+//! classic features of its 20 ms frames:
+//!
+//! * The low energy ratio: the fraction of frames quieter than half the mean
+//!   level of the second. Speech alternates syllables and short pauses, so it
+//!   has many more quiet frames than music.
+//! * The high zero crossing ratio: the fraction of frames whose zero crossing
+//!   rate exceeds 1.5 times the mean. Speech alternates voiced sounds and
+//!   fricatives, so its zero crossing rate varies far more than music's.
+//!
+//! The file is `'speech'` or `'music'` when at least [`MAJORITY`] of its
+//! non-silent seconds are, and `'mixed'` otherwise. Silent files give NULL.
+//!
+//! `audio_voice_ratio(path)` is the fraction of frames with speech activity:
+//! frames in seconds classified as speech that are at least
+//! [`ACTIVITY_DB`] louder than the noise floor of the file.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::analysis::{
+    frame_features, percentile_dbfs, to_dbfs, FrameFeatures, NOISE_FLOOR_PERCENTILE,
+};
+use crate::timings::CallStats;
+
+const FRAME_SECS: f64 = 0.02;
+
+/// Frames per classified window (one second).
+const WINDOW_FRAMES: usize = 50;
+
+/// Windows quieter than this are silence and not classified.
+const SILENCE_DBFS: f64 = -50.0;
+
+/// Windows with a higher low energy ratio than this are speech...
+const SPEECH_LOW_ENERGY_RATIO: f32 = 0.2;
+
+/// ...as are windows with a higher high zero crossing ratio than this.
+const SPEECH_HIGH_ZCR_RATIO: f32 = 0.15;
+
+/// Level above the noise floor at which a frame counts as active.
+const ACTIVITY_DB: f64 = 10.0;
+
+/// Fraction of windows of one kind that makes the whole file that kind.
+const MAJORITY: f64 = 0.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Content {
+    Speech,
+    Music,
+}
+
+/// Classify a window of frames, or return `None` if it is silent.
+pub(crate) fn classify_window(frames: &[FrameFeatures]) -> Option<Content> {
+    if frames.is_empty() {
+        return None;
+    }
+    let n = frames.len() as f32;
+    let mean_rms = frames.iter().map(|f| f.rms).sum::<f32>() / n;
+    if to_dbfs(mean_rms) < SILENCE_DBFS {
+        return None;
+    }
+    let mean_zcr = frames.iter().map(|f| f.zcr).sum::<f32>() / n;
+
+    let low_energy = frames.iter().filter(|f| f.rms < 0.5 * mean_rms).count() as f32 / n;
+    let high_zcr = frames.iter().filter(|f| f.zcr > 1.5 * mean_zcr).count() as f32 / n;
+
+    if low_energy > SPEECH_LOW_ENERGY_RATIO || high_zcr > SPEECH_HIGH_ZCR_RATIO {
+        Some(Content::Speech)
+    } else {
+        Some(Content::Music)
+    }
+}
+
+/// Classification of each second of the file at `path`, with the frames it
+/// was computed from.
+pub(crate) fn classify(
+    path: &Path,
+    stats: &mut CallStats,
+) -> Result<(Vec<FrameFeatures>, Vec<Option<Content>>)> {
+    let frames = frame_features(path, FRAME_SECS, stats)?;
+    let windows = frames.chunks(WINDOW_FRAMES).map(classify_window).collect();
+    Ok((frames, windows))
+}
+
+/// Summarize per-window classifications as `'speech'`, `'music'` or `'mixed'`.
+fn summarize(windows: &[Option<Content>]) -> Option<&'static str> {
+    let classified = windows.iter().flatten().count();
+    if classified == 0 {
+        return None;
+    }
+    let speech = windows
+        .iter()
+        .filter(|w| **w == Some(Content::Speech))
+        .count() as f64
+        / classified as f64;
+
+    Some(if speech >= MAJORITY {
+        "speech"
+    } else if speech <= 1.0 - MAJORITY {
+        "music"
+    } else {
+        "mixed"
+    })
+}
+
+pub(crate) fn speech_music(path: &Path, stats: &mut CallStats) -> Result<Option<&'static str>> {
+    let (_, windows) = classify(path, stats)?;
+    Ok(summarize(&windows))
+}
+
+/// Fraction of frames with speech activity.
+fn voice_ratio(frames: &[FrameFeatures], windows: &[Option<Content>]) -> Option<f64> {
+    let floor = percentile_dbfs(frames, NOISE_FLOOR_PERCENTILE)?;
+    let threshold = (floor + ACTIVITY_DB).max(SILENCE_DBFS);
+
+    let active = frames
+        .chunks(WINDOW_FRAMES)
+        .zip(windows)
+        .filter(|(_, w)| **w == Some(Content::Speech))
+        .flat_map(|(frames, _)| frames)
+        .filter(|f| to_dbfs(f.rms) >= threshold)
+        .count();
+    Some(active as f64 / frames.len() as f64)
+}
+
+pub(crate) fn audio_voice_ratio(path: &Path, stats: &mut CallStats) -> Result<Option<f64>> {
+    let (frames, windows) = classify(path, stats)?;
+    Ok(voice_ratio(&frames, &windows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(rms: f32, zcr: f32) -> FrameFeatures {
+        FrameFeatures { rms, zcr }
+    }
+
+    #[test]
+    fn test_classify_window() {
+        // Sustained notes.
+        let music: Vec<_> = (0..50)
+            .map(|i| frame(0.3 + 0.01 * (i % 5) as f32, 0.05))
+            .collect();
+        assert_eq!(classify_window(&music), Some(Content::Music));
+
+        // Syllables separated by pauses, some with fricatives.
+        let speech: Vec<_> = (0..50)
+            .map(|i| match i % 12 {
+                0..=5 => frame(0.3, 0.03),
+                6..=7 => frame(0.1, 0.3),
+                _ => frame(0.01, 0.1),
+            })
+            .collect();
+        assert_eq!(classify_window(&speech), Some(Content::Speech));
+
+        assert_eq!(classify_window(&[frame(0.0, 0.0); 50]), None);
+    }
+
+    #[test]
+    fn test_summarize() {
+        use Content::*;
+        assert_eq!(summarize(&[Some(Speech); 10]), Some("speech"));
+        assert_eq!(summarize(&[Some(Music), None, Some(Music)]), Some("music"));
+        assert_eq!(summarize(&[Some(Music), Some(Speech)]), Some("mixed"));
+        assert_eq!(summarize(&[None, None]), None);
+    }
+
+    #[test]
+    fn test_voice_ratio() {
+        // One second of speech-like frames, half of them pauses at the noise
+        // floor, then one second classified as music.
+        let mut frames: Vec<_> = (0..50)
+            .map(|i| frame(if i % 2 == 0 { 0.3 } else { 0.001 }, 0.1))
+            .collect();
+        frames.extend([frame(0.3, 0.05); 50]);
+        let windows = [Some(Content::Speech), Some(Content::Music)];
+        assert_eq!(voice_ratio(&frames, &windows), Some(0.25));
+        assert_eq!(voice_ratio(&[], &[]), None);
+    }
+}
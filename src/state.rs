@@ -0,0 +1,82 @@
+//! State shared by all functions and virtual tables registered on a connection.
+//!
+//! Each connection the extension is registered on has its own, so connections
+//! used concurrently from several threads, e.g. a pool's, don't see each
+//! other's options or timings. The only process-wide state is the
+//! `shared-cache` cache, the registered source providers and AcoustID's rate
+//! limit, each behind its own lock.
+
+use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::interrupt::Interrupt;
+use crate::options::Options;
+use crate::progress::ProgressDb;
+use crate::timings::{CallStats, Timings};
+use crate::vfs::Vfs;
+
+#[derive(Default)]
+pub(crate) struct State {
+    pub options: RwLock<Options>,
+    pub timings: Mutex<Timings>,
+    /// VFS of the connection's main database.
+    pub vfs: Option<Vfs>,
+    /// Interrupt flag of the connection, checked while decoding.
+    pub interrupt: Option<Interrupt>,
+    /// Connection to call the `progress_function` option's function on.
+    pub progress: Option<ProgressDb>,
+}
+
+impl State {
+    pub fn new(
+        options: Options,
+        vfs: Option<Vfs>,
+        interrupt: Option<Interrupt>,
+        progress: Option<ProgressDb>,
+    ) -> Self {
+        Self {
+            options: RwLock::new(options),
+            timings: Mutex::default(),
+            vfs,
+            interrupt,
+            progress,
+        }
+    }
+
+    // A panic caught while a lock was held leaves it poisoned; the protected
+    // data is still consistent, so keep using it rather than failing every
+    // later call.
+
+    pub fn options(&self) -> RwLockReadGuard<'_, Options> {
+        self.options.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn options_mut(&self) -> RwLockWriteGuard<'_, Options> {
+        self.options.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Statistics for a new call, set up to read files as configured.
+    pub fn call_stats(&self) -> CallStats {
+        let options = self.options();
+        CallStats {
+            vfs: self.vfs.filter(|_| options.vfs),
+            interrupt: self.interrupt,
+            progress: self
+                .progress
+                .zip(options.progress_function.clone())
+                .map(|(db, function)| db.progress(function)),
+            allowed_roots: options.allowed_roots.clone(),
+            shared_cache_size: options.shared_cache_size,
+            ..CallStats::default()
+        }
+    }
+
+    /// Record the statistics of a call if timing instrumentation is enabled.
+    pub fn record_timing(&self, function: &'static str, argument: &str, stats: CallStats) {
+        if self.options().timings {
+            self.timings
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(function, argument, stats);
+        }
+    }
+}
@@ -0,0 +1,562 @@
+//! One-statement library synchronization.
+//!
+//! `chromaprint_sync(dir, table)` brings a table of fingerprints up to date
+//! with the audio files under a directory, and returns what it changed:
+//!
+//! ```sql
+//! CREATE TABLE tracks(path TEXT PRIMARY KEY, size, mtime, fingerprint);
+//! SELECT path, change, error FROM chromaprint_sync('/music', 'tracks');
+//! ```
+//!
+//! The table needs `path`, `size`, `mtime` and `fingerprint` columns, the
+//! last allowing NULL; other columns are left alone, so they need defaults.
+//! Files are found by extension ([`EXTENSIONS`]) in `dir` and its
+//! subdirectories, and stored with `dir` joined to their relative path, so
+//! `dir` should be spelled the same way on every sync. `size` is in bytes and
+//! `mtime` in nanoseconds since the Unix epoch.
+//!
+//! `change` is one of:
+//!
+//! * `'added'`: a new file was fingerprinted and inserted.
+//! * `'changed'`: a file whose size or modification time differs from its
+//!   row was fingerprinted again and its row updated.
+//! * `'deleted'`: the row of a file under `dir` that no longer exists was
+//!   deleted. Rows outside `dir` are never deleted.
+//! * `'error'`: a new or changed file couldn't be fingerprinted; `error` says
+//!   why. Its row is stored with a NULL fingerprint, so that it isn't tried
+//!   again until the file changes, or until its `size` is set to NULL.
+//!
+//! Each change is made as its row is returned, so a `LIMIT` stops the sync
+//! early, and the next sync picks up where it left off.
+//!
+//! The table itself records the progress of a sync, but only once the
+//! statement running it commits: a sync that is interrupted or killed part
+//! way loses the changes made so far. With the `checkpoint_db` option set,
+//! each file's fingerprint is also saved to that database as soon as it is
+//! made, and the next sync reuses it rather than decoding the file again
+//! (see [`crate::checkpoint`]). Long scans can instead commit as they go by
+//! syncing in batches, repeating until no changes are left:
+//!
+//! ```sql
+//! SELECT count(*) FROM (SELECT 1 FROM chromaprint_sync('/music', 'tracks') LIMIT 100);
+//! ```
+//!
+//! Each file is tried once per change, whether or not it can be
+//! fingerprinted, so repeated batches reach 0.
+
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context as _, Result};
+use rusqlite::types::Value;
+use rusqlite::vtab::{
+    eponymous_only_module, escape_double_quote, Context, IndexInfo, VTab, VTabConnection,
+    VTabCursor, Values,
+};
+use rusqlite::{ffi, params, Connection};
+
+use crate::checkpoint::Checkpoint;
+use crate::panic::catch_panic;
+use crate::state::State;
+use crate::{fingerprint_file_raw, path, tvf};
+
+/// Extensions, in lower case, of the files synchronized.
+const EXTENSIONS: &[&str] = &[
+    "aac", "aif", "aiff", "caf", "flac", "m4a", "m4b", "mka", "mp1", "mp2", "mp3", "mp4", "oga",
+    "ogg", "wav", "webm",
+];
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 3;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "chromaprint_sync",
+        eponymous_only_module::<SyncTab>(),
+        Some(state),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Added,
+    Changed,
+    Deleted,
+}
+
+impl Change {
+    fn name(self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Changed => "changed",
+            Change::Deleted => "deleted",
+        }
+    }
+}
+
+/// The size and modification time of a file, as stored in the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    pub size: i64,
+    pub mtime: i64,
+}
+
+/// A change still to be made.
+#[derive(Debug, Clone, PartialEq)]
+struct Action {
+    path: PathBuf,
+    change: Change,
+    /// The file's current stamp; unused for deletions.
+    stamp: Stamp,
+}
+
+/// A row of `chromaprint_sync`.
+#[derive(Debug, Clone)]
+struct Row {
+    path: Value,
+    change: &'static str,
+    error: Option<String>,
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+pub(crate) fn stamp(metadata: &std::fs::Metadata) -> Stamp {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as i64);
+    Stamp {
+        size: metadata.len() as i64,
+        mtime,
+    }
+}
+
+/// Add the audio files in `dir`, and with `recursive` its subdirectories, to
+/// `files`. Symbolic links to files are followed, but not those to
+/// directories, which could form cycles.
+pub(crate) fn walk(dir: &Path, recursive: bool, files: &mut HashMap<PathBuf, Stamp>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            if recursive {
+                walk(&path, recursive, files)?;
+            }
+        } else if is_audio(&path) {
+            // Skip broken links.
+            if let Ok(metadata) = std::fs::metadata(&path) {
+                if metadata.is_file() {
+                    files.insert(path, stamp(&metadata));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The changes that bring the `rows` under `dir` in line with `files`, in
+/// path order. Rows with no stamp were never synchronized and are changed.
+fn plan(
+    dir: &Path,
+    files: HashMap<PathBuf, Stamp>,
+    rows: HashMap<PathBuf, Option<Stamp>>,
+) -> VecDeque<Action> {
+    let mut actions: Vec<Action> = rows
+        .keys()
+        .filter(|path| path.starts_with(dir) && !files.contains_key(*path))
+        .map(|path| Action {
+            path: path.clone(),
+            change: Change::Deleted,
+            stamp: Stamp { size: 0, mtime: 0 },
+        })
+        .collect();
+    for (path, stamp) in files {
+        let change = match rows.get(&path) {
+            None => Change::Added,
+            Some(row) if *row != Some(stamp) => Change::Changed,
+            Some(_) => continue,
+        };
+        actions.push(Action {
+            path,
+            change,
+            stamp,
+        });
+    }
+    actions.sort_by(|a, b| a.path.cmp(&b.path));
+    actions.into()
+}
+
+#[repr(C)]
+struct SyncTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for SyncTab {
+    type Aux = Arc<State>;
+    type Cursor = SyncCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("chromaprint_sync: missing state".to_owned())
+            })?;
+            tvf::reads_files(db)?;
+            Ok((
+                "CREATE TABLE x(path, change, error, dir HIDDEN, table_name HIDDEN)".to_owned(),
+                SyncTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    // SAFETY: The handle outlives the virtual table, which is
+                    // disconnected before the connection is closed.
+                    db: unsafe { db.handle() },
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_arguments(info, "chromaprint_sync", FIRST_ARGUMENT, &["dir", "table"])
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<SyncCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(SyncCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                db: self.db,
+                state: self.state.clone(),
+                table: String::new(),
+                pending: VecDeque::new(),
+                checkpoint: None,
+                row: None,
+                rowid: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+/// Cursor making one pending change per row.
+#[repr(C)]
+struct SyncCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+    table: String,
+    pending: VecDeque<Action>,
+    /// Where results are saved as they are made, see [`crate::checkpoint`].
+    checkpoint: Option<Checkpoint>,
+    row: Option<Row>,
+    rowid: i64,
+    phantom: PhantomData<&'vtab SyncTab>,
+}
+
+impl SyncCursor<'_> {
+    fn connection(&self) -> rusqlite::Result<Connection> {
+        // SAFETY: The handle outlives the virtual table, see `SyncTab::connect`.
+        // The connection does not close the handle when dropped.
+        unsafe { Connection::from_handle(self.db) }
+    }
+
+    /// The synchronized rows of the table, by path.
+    fn rows(&self) -> rusqlite::Result<HashMap<PathBuf, Option<Stamp>>> {
+        let db = self.connection()?;
+        let mut stmt = db.prepare(&format!(
+            "SELECT path, size, mtime FROM \"{}\"",
+            escape_double_quote(&self.table)
+        ))?;
+        let mut rows = stmt.query([])?;
+        let mut stamps = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let Ok(path) = path::path_from_value(0, row.get_ref(0)?) else {
+                continue;
+            };
+            let stamp = match (row.get(1)?, row.get(2)?) {
+                (Some(size), Some(mtime)) => Some(Stamp { size, mtime }),
+                _ => None,
+            };
+            stamps.insert(path, stamp);
+        }
+        Ok(stamps)
+    }
+
+    /// Make the next pending change and set the row reporting it.
+    fn advance(&mut self) -> rusqlite::Result<()> {
+        self.row = None;
+        let Some(action) = self.pending.pop_front() else {
+            return Ok(());
+        };
+        self.rowid += 1;
+
+        let db = self.connection()?;
+        let table = escape_double_quote(&self.table);
+        let path = path::path_to_value(&action.path);
+        let mut error = None;
+        match action.change {
+            Change::Deleted => {
+                db.execute(
+                    &format!("DELETE FROM \"{table}\" WHERE path = ?1"),
+                    params![path],
+                )?;
+            }
+            Change::Added | Change::Changed => {
+                let (encoding, preset, max_length_secs) = {
+                    let options = self.state.options();
+                    (options.encoding, options.preset, options.max_length_secs)
+                };
+                let saved = self.checkpoint.as_ref().and_then(|checkpoint| {
+                    checkpoint.get(&action.path, action.stamp, preset, max_length_secs)
+                });
+                let outcome = match saved {
+                    Some(outcome) => outcome,
+                    None => {
+                        let mut stats = self.state.call_stats();
+                        let outcome = fingerprint_file_raw(
+                            &action.path,
+                            None,
+                            preset,
+                            max_length_secs,
+                            &mut stats,
+                        )
+                        .map(|fingerprint| (fingerprint, None))
+                        .map_err(|e| format!("{e:#}"));
+                        self.state.record_timing(
+                            "chromaprint_sync",
+                            &action.path.to_string_lossy(),
+                            stats,
+                        );
+                        if let Some(checkpoint) = &self.checkpoint {
+                            checkpoint.save(
+                                &action.path,
+                                action.stamp,
+                                preset,
+                                max_length_secs,
+                                &outcome,
+                            );
+                        }
+                        outcome
+                    }
+                };
+                // A file that can't be fingerprinted is stored with a NULL
+                // fingerprint, so that it isn't tried again until it changes.
+                let fingerprint = match outcome {
+                    Ok((fingerprint, _)) => Some(encoding.encode(&fingerprint)),
+                    Err(e) => {
+                        error = Some(e);
+                        None
+                    }
+                };
+                let sql = if action.change == Change::Added {
+                    format!(
+                        "INSERT INTO \"{table}\" (path, size, mtime, fingerprint) \
+                         VALUES (?1, ?2, ?3, ?4)"
+                    )
+                } else {
+                    format!(
+                        "UPDATE \"{table}\" SET size = ?2, mtime = ?3, fingerprint = ?4 \
+                         WHERE path = ?1"
+                    )
+                };
+                db.execute(
+                    &sql,
+                    params![path, action.stamp.size, action.stamp.mtime, fingerprint],
+                )?;
+            }
+        }
+
+        self.row = Some(Row {
+            path,
+            change: if error.is_some() {
+                "error"
+            } else {
+                action.change.name()
+            },
+            error,
+        });
+        Ok(())
+    }
+}
+
+unsafe impl VTabCursor for SyncCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.pending = VecDeque::new();
+            self.checkpoint = None;
+            self.row = None;
+            self.rowid = 0;
+
+            let Some(value) = args
+                .iter()
+                .next()
+                .filter(|v| v.data_type() != rusqlite::types::Type::Null)
+            else {
+                return Ok(());
+            };
+            let dir = path::path_from_value(0, value)?;
+            self.table = tvf::text_argument("chromaprint_sync", args, 1, "table")?.to_owned();
+
+            let mut files = HashMap::new();
+            self.state
+                .call_stats()
+                .check_path(&dir)
+                .and_then(|()| walk(&dir, true, &mut files))
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            self.pending = plan(&dir, files, self.rows()?);
+            if !self.pending.is_empty() {
+                self.checkpoint = Checkpoint::open(&self.state)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            }
+            self.advance()
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| self.advance())
+    }
+
+    fn eof(&self) -> bool {
+        self.row.is_none()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let Some(row) = &self.row else {
+                return ctx.set_result(&rusqlite::types::Null);
+            };
+            match i {
+                0 => ctx.set_result(&row.path),
+                1 => ctx.set_result(&row.change),
+                2 => ctx.set_result(&row.error),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.rowid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn stamp(size: i64) -> Stamp {
+        Stamp { size, mtime: 1 }
+    }
+
+    #[test]
+    fn test_plan() {
+        let dir = Path::new("/music");
+        let files = HashMap::from([
+            (dir.join("a.mp3"), stamp(1)),
+            (dir.join("b.mp3"), stamp(2)),
+            (dir.join("c.mp3"), stamp(3)),
+            (dir.join("e.mp3"), stamp(5)),
+        ]);
+        let rows = HashMap::from([
+            (dir.join("b.mp3"), Some(stamp(2))),
+            (dir.join("c.mp3"), Some(stamp(30))),
+            (dir.join("d.mp3"), Some(stamp(4))),
+            (dir.join("e.mp3"), None),
+            (PathBuf::from("/elsewhere/f.mp3"), Some(stamp(6))),
+        ]);
+
+        let actions: Vec<(PathBuf, Change)> = plan(dir, files, rows)
+            .into_iter()
+            .map(|a| (a.path, a.change))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (dir.join("a.mp3"), Change::Added),
+                (dir.join("c.mp3"), Change::Changed),
+                (dir.join("d.mp3"), Change::Deleted),
+                (dir.join("e.mp3"), Change::Changed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_walk() {
+        let dir = std::env::temp_dir().join(format!("chromaprint-sync-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("disc 2")).unwrap();
+        std::fs::write(dir.join("01.FLAC"), b"x").unwrap();
+        std::fs::write(dir.join("disc 2/01.ogg"), b"xy").unwrap();
+        std::fs::write(dir.join("cover.jpg"), b"xyz").unwrap();
+
+        let mut files = HashMap::new();
+        walk(&dir, false, &mut files).unwrap();
+        assert_eq!(files.len(), 1);
+        walk(&dir, true, &mut files).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let sizes: HashSet<(PathBuf, i64)> = files.into_iter().map(|(p, s)| (p, s.size)).collect();
+        assert_eq!(
+            sizes,
+            HashSet::from([(dir.join("01.FLAC"), 1), (dir.join("disc 2/01.ogg"), 2)])
+        );
+    }
+
+    #[cfg(not(feature = "extension"))]
+    #[test]
+    fn test_sync_skips_failed_files() {
+        let dir = std::env::temp_dir().join(format!("chromaprint-sync-bad-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("bad.mp3"), b"not audio").unwrap();
+
+        let db = Connection::open_in_memory().unwrap();
+        register(&db, Arc::new(State::default())).unwrap();
+        db.execute_batch("CREATE TABLE tracks(path TEXT PRIMARY KEY, size, mtime, fingerprint)")
+            .unwrap();
+        let sync = |db: &Connection| -> Vec<String> {
+            let mut stmt = db
+                .prepare("SELECT change FROM chromaprint_sync(?1, 'tracks')")
+                .unwrap();
+            let changes = stmt
+                .query_map([dir.to_str()], |row| row.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap();
+            changes
+        };
+        let first = sync(&db);
+        let second = sync(&db);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first, ["error"]);
+        assert!(second.is_empty(), "{second:?}");
+        let missing: bool = db
+            .query_row("SELECT fingerprint IS NULL FROM tracks", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(missing);
+    }
+}
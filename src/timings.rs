@@ -0,0 +1,288 @@
+//! Per-call timing instrumentation.
+//!
+//! When the `timings` option is enabled, analysis functions record how long
+//! they spent decoding and fingerprinting, and how many bytes they read. The
+//! most recent calls can be queried through the `chromaprint_timings` table:
+//!
+//! ```sql
+//! SELECT chromaprint_option('timings', 1);
+//! SELECT fingerprint(path) FROM tracks;
+//! SELECT argument, decode_secs + fingerprint_secs AS secs
+//! FROM chromaprint_timings ORDER BY secs DESC LIMIT 10;
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
+
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+#[cfg(feature = "decode")]
+use symphonia::core::io::MediaSource;
+
+use crate::interrupt::Interrupt;
+use crate::panic::catch_panic;
+use crate::progress::Progress;
+use crate::state::State;
+use crate::vfs::Vfs;
+#[cfg(feature = "decode")]
+use crate::{roots, source};
+
+/// Maximum number of calls kept in the log; older entries are discarded.
+const CAPACITY: usize = 1000;
+
+/// Statistics gathered during a single call.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CallStats {
+    pub decode: Duration,
+    pub fingerprint: Duration,
+    pub bytes: Arc<AtomicU64>,
+    /// VFS to read files through, if the `vfs` option is enabled.
+    pub vfs: Option<Vfs>,
+    /// Interrupt flag to stop decoding at, if called from SQL.
+    pub interrupt: Option<Interrupt>,
+    /// Progress function to call while decoding, if one is set.
+    pub progress: Option<Progress>,
+    /// Directories that files may be read in, if restricted.
+    pub allowed_roots: Option<Arc<[PathBuf]>>,
+    /// Fingerprints kept by the shared cache, if not the default.
+    pub shared_cache_size: Option<u32>,
+}
+
+#[cfg(feature = "decode")]
+impl CallStats {
+    /// Fail unless the file or directory at `path` may be read, see
+    /// [`crate::roots`].
+    pub fn check_path(&self, path: &Path) -> anyhow::Result<()> {
+        match &self.allowed_roots {
+            Some(roots) if source::provider_for(path).is_none() => roots::check(roots, path),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Timing {
+    function: &'static str,
+    argument: String,
+    decode_secs: f64,
+    fingerprint_secs: f64,
+    bytes: i64,
+}
+
+#[derive(Default)]
+pub(crate) struct Timings {
+    entries: VecDeque<(i64, Timing)>,
+    next_id: i64,
+}
+
+impl Timings {
+    pub fn push(&mut self, function: &'static str, argument: &str, stats: CallStats) {
+        if self.entries.len() == CAPACITY {
+            self.entries.pop_front();
+        }
+        self.next_id += 1;
+        self.entries.push_back((
+            self.next_id,
+            Timing {
+                function,
+                argument: argument.to_owned(),
+                decode_secs: stats.decode.as_secs_f64(),
+                fingerprint_secs: stats.fingerprint.as_secs_f64(),
+                bytes: stats.bytes.load(Ordering::Relaxed) as i64,
+            },
+        ));
+    }
+}
+
+#[cfg(feature = "decode")]
+/// A media source that counts the bytes read through it.
+pub(crate) struct CountingSource {
+    inner: Box<dyn MediaSource>,
+    bytes: Arc<AtomicU64>,
+}
+
+#[cfg(feature = "decode")]
+impl CountingSource {
+    pub fn new(inner: Box<dyn MediaSource>, bytes: Arc<AtomicU64>) -> Self {
+        Self { inner, bytes }
+    }
+}
+
+#[cfg(feature = "decode")]
+impl Read for CountingSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "decode")]
+impl Seek for CountingSource {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(feature = "decode")]
+impl MediaSource for CountingSource {
+    fn is_seekable(&self) -> bool {
+        self.inner.is_seekable()
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.inner.byte_len()
+    }
+}
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "chromaprint_timings",
+        eponymous_only_module::<TimingsTab>(),
+        Some(state),
+    )
+}
+
+#[repr(C)]
+struct TimingsTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for TimingsTab {
+    type Aux = Arc<State>;
+    type Cursor = TimingsCursor<'vtab>;
+
+    fn connect(
+        _db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("chromaprint_timings: missing state".to_owned())
+            })?;
+            Ok((
+                "CREATE TABLE x(function, argument, decode_secs, fingerprint_secs, bytes)"
+                    .to_owned(),
+                TimingsTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            info.set_estimated_cost(CAPACITY as f64);
+            Ok(())
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<TimingsCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(TimingsCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+/// Cursor over a snapshot of the log.
+///
+/// The snapshot is released as soon as the scan is exhausted; SQLite closes
+/// (and so drops) cursors of interrupted or reset statements.
+#[repr(C)]
+struct TimingsCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    rows: Vec<(i64, Timing)>,
+    index: usize,
+    phantom: PhantomData<&'vtab TimingsTab>,
+}
+
+unsafe impl VTabCursor for TimingsCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        _args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.rows = self
+                .state
+                .timings
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entries
+                .iter()
+                .cloned()
+                .collect();
+            self.index = 0;
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            if self.index >= self.rows.len() {
+                self.rows = Vec::new();
+                self.index = 0;
+            }
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let (_, timing) = &self.rows[self.index];
+            match i {
+                0 => ctx.set_result(&timing.function),
+                1 => ctx.set_result(&timing.argument),
+                2 => ctx.set_result(&timing.decode_secs),
+                3 => ctx.set_result(&timing.fingerprint_secs),
+                _ => ctx.set_result(&timing.bytes),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.rows[self.index].0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timings_capacity() {
+        let mut timings = Timings::default();
+        for i in 0..CAPACITY + 10 {
+            timings.push("fingerprint", &i.to_string(), CallStats::default());
+        }
+        assert_eq!(timings.entries.len(), CAPACITY);
+        assert_eq!(timings.entries.front().unwrap().1.argument, "10");
+        assert_eq!(timings.entries.back().unwrap().0, (CAPACITY + 10) as i64);
+    }
+}
@@ -0,0 +1,348 @@
+//! Tracklist identification for continuous mixes.
+//!
+//! `identify_tracklist` matches a mix against every fingerprint in a
+//! reference table and returns the tracks it contains, in mix order:
+//!
+//! ```sql
+//! SELECT track_id, start_secs, end_secs, confidence
+//! FROM identify_tracklist('mix.mp3', 'tracks', 'fingerprint', 'id');
+//! ```
+//!
+//! The first argument is the path of the mix or its fingerprint. The
+//! reference table, fingerprint column and id column are given as names and
+//! the table is read on each scan.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context as _, Result};
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexInfo, VTab, VTabConnection, VTabCursor, Values,
+};
+use rusqlite::{ffi, Connection};
+use rusty_chromaprint::{match_fingerprints, Configuration};
+
+use crate::matching::{self, MatchMode};
+use crate::panic::catch_panic;
+use crate::state::State;
+use crate::{decode_fingerprint, fingerprint_path, path, source, tvf};
+
+/// Tracks must match for at least this long to be listed.
+const MIN_MATCH_SECS: f64 = 10.0;
+
+/// Tracks may overlap by at most this fraction of the shorter one; more
+/// overlap means two references matched the same part of the mix.
+const MAX_OVERLAP: f64 = 0.5;
+
+/// Index of the first hidden (argument) column.
+const FIRST_ARGUMENT: c_int = 4;
+
+pub(crate) fn register(db: &Connection, state: Arc<State>) -> rusqlite::Result<()> {
+    db.create_module(
+        "identify_tracklist",
+        eponymous_only_module::<TracklistTab>(),
+        Some(state),
+    )
+}
+
+/// A track identified in a mix.
+#[derive(Debug, Clone, PartialEq)]
+struct Identified {
+    id: Value,
+    start_secs: f64,
+    end_secs: f64,
+    /// Matched duration, not counting gaps between matched segments.
+    matched_secs: f64,
+    /// 1 for a bit-exact match, 0 for a match no better than chance.
+    confidence: f64,
+}
+
+/// Match a reference track against the mix.
+fn identify(
+    mix: &[u32],
+    id: Value,
+    reference: &[u32],
+    config: &Configuration,
+    mode: MatchMode,
+) -> Result<Option<Identified>> {
+    let segments =
+        match_fingerprints(mix, reference, config).context("Failed to match fingerprints")?;
+    if segments.is_empty() {
+        return Ok(None);
+    }
+
+    let matched_secs: f64 = segments.iter().map(|s| s.duration(config) as f64).sum();
+    if matched_secs < MIN_MATCH_SECS {
+        return Ok(None);
+    }
+
+    let score = segments
+        .iter()
+        .map(|s| {
+            matching::segment_score(s, mix, reference, config, mode) * s.duration(config) as f64
+        })
+        .sum::<f64>()
+        / matched_secs;
+
+    Ok(Some(Identified {
+        id,
+        start_secs: segments
+            .iter()
+            .map(|s| s.start1(config) as f64)
+            .fold(f64::MAX, f64::min),
+        end_secs: segments
+            .iter()
+            .map(|s| s.end1(config) as f64)
+            .fold(0.0, f64::max),
+        matched_secs,
+        // Unrelated fingerprints differ in about half of their 32 bits.
+        confidence: (1.0 - score / 16.0).clamp(0.0, 1.0),
+    }))
+}
+
+/// Pick the best non-overlapping candidates and order them by start time.
+fn tracklist(mut candidates: Vec<Identified>) -> Vec<Identified> {
+    candidates.sort_by(|a, b| {
+        (b.matched_secs * b.confidence).total_cmp(&(a.matched_secs * a.confidence))
+    });
+
+    let mut tracks: Vec<Identified> = Vec::new();
+    for candidate in candidates {
+        let overlaps = tracks.iter().any(|t| {
+            let overlap =
+                t.end_secs.min(candidate.end_secs) - t.start_secs.max(candidate.start_secs);
+            let shorter =
+                (t.end_secs - t.start_secs).min(candidate.end_secs - candidate.start_secs);
+            overlap > MAX_OVERLAP * shorter
+        });
+        if !overlaps {
+            tracks.push(candidate);
+        }
+    }
+
+    tracks.sort_by(|a, b| a.start_secs.total_cmp(&b.start_secs));
+    tracks
+}
+
+#[repr(C)]
+struct TracklistTab {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for TracklistTab {
+    type Aux = Arc<State>;
+    type Cursor = TracklistCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError("identify_tracklist: missing state".to_owned())
+            })?;
+            tvf::reads_files(db)?;
+            Ok((
+                "CREATE TABLE x(track_id, start_secs, end_secs, confidence, \
+                 mix HIDDEN, reference_table HIDDEN, fp_col HIDDEN, id_col HIDDEN)"
+                    .to_owned(),
+                TracklistTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    // SAFETY: The handle outlives the virtual table, which is
+                    // disconnected before the connection is closed.
+                    db: unsafe { db.handle() },
+                    state,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            tvf::bind_arguments(
+                info,
+                "identify_tracklist",
+                FIRST_ARGUMENT,
+                &["mix", "reference_table", "fp_col", "id_col"],
+            )
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<TracklistCursor<'vtab>> {
+        catch_panic(|| {
+            Ok(TracklistCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                db: self.db,
+                state: self.state.clone(),
+                rows: Vec::new(),
+                index: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+#[repr(C)]
+struct TracklistCursor<'vtab> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    db: *mut ffi::sqlite3,
+    state: Arc<State>,
+    rows: Vec<Identified>,
+    index: usize,
+    phantom: PhantomData<&'vtab TracklistTab>,
+}
+
+impl TracklistCursor<'_> {
+    fn mix_fingerprint(&self, value: ValueRef<'_>) -> rusqlite::Result<Vec<u32>> {
+        let path = match value {
+            ValueRef::Text(s) => {
+                let s = std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error)?;
+                let path = PathBuf::from(s);
+                // Check the roots before looking for the file, so that
+                // whether a path outside them exists isn't revealed.
+                if let Err(e) = self.state.call_stats().check_path(&path) {
+                    return decode_fingerprint(s)
+                        .map_err(|_| rusqlite::Error::UserFunctionError(e.into()));
+                }
+                if !path.exists() && !source::is_uri(s) {
+                    return decode_fingerprint(s)
+                        .context("mix is neither a file nor a fingerprint")
+                        .map_err(|e| rusqlite::Error::UserFunctionError(e.into()));
+                }
+                path
+            }
+            v => path::path_from_value(0, v)?,
+        };
+
+        let preset = self.state.options().preset;
+        let mut stats = self.state.call_stats();
+        let fingerprint = fingerprint_path(&path, preset, &mut stats)
+            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+        self.state
+            .record_timing("identify_tracklist", &path.to_string_lossy(), stats);
+        Ok(fingerprint)
+    }
+
+    fn identify_all(
+        &self,
+        mix: &[u32],
+        table: &str,
+        fp_col: &str,
+        id_col: &str,
+    ) -> rusqlite::Result<Vec<Identified>> {
+        let (config, mode) = {
+            let options = self.state.options();
+            (options.preset.config(), options.match_mode)
+        };
+        let mut candidates = Vec::new();
+        // SAFETY: The handle outlives the virtual table, see `TracklistTab::connect`.
+        unsafe {
+            tvf::for_each_fingerprint(self.db, table, fp_col, id_col, |id, reference| {
+                if let Some(identified) = identify(mix, id, &reference, &config, mode)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?
+                {
+                    candidates.push(identified);
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(tracklist(candidates))
+    }
+}
+
+unsafe impl VTabCursor for TracklistCursor<'_> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let mix = match args.iter().next() {
+                Some(ValueRef::Null) | None => Vec::new(),
+                Some(v) => self.mix_fingerprint(v)?,
+            };
+            let table = tvf::text_argument("identify_tracklist", args, 1, "reference_table")?;
+            let fp_col = tvf::text_argument("identify_tracklist", args, 2, "fp_col")?;
+            let id_col = tvf::text_argument("identify_tracklist", args, 3, "id_col")?;
+
+            self.rows = if mix.is_empty() {
+                Vec::new()
+            } else {
+                self.identify_all(&mix, table, fp_col, id_col)?
+            };
+            self.index = 0;
+            Ok(())
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.index += 1;
+            Ok(())
+        })
+    }
+
+    fn eof(&self) -> bool {
+        self.index >= self.rows.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let track = &self.rows[self.index];
+            match i {
+                0 => ctx.set_result(&track.id),
+                1 => ctx.set_result(&track.start_secs),
+                2 => ctx.set_result(&track.end_secs),
+                3 => ctx.set_result(&track.confidence),
+                _ => ctx.set_result(&rusqlite::types::Null),
+            }
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.index as i64 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: i64, start_secs: f64, end_secs: f64, confidence: f64) -> Identified {
+        Identified {
+            id: Value::Integer(id),
+            start_secs,
+            end_secs,
+            matched_secs: end_secs - start_secs,
+            confidence,
+        }
+    }
+
+    #[test]
+    fn test_tracklist_drops_overlapping_matches() {
+        let tracks = tracklist(vec![
+            track(2, 290.0, 600.0, 0.9),
+            // Same part of the mix as track 2, but a worse match.
+            track(3, 300.0, 580.0, 0.5),
+            // Crossfades overlap the neighbouring tracks slightly.
+            track(1, 0.0, 300.0, 0.8),
+            track(4, 595.0, 900.0, 0.7),
+        ]);
+
+        let ids: Vec<_> = tracks.iter().map(|t| t.id.clone()).collect();
+        assert_eq!(
+            ids,
+            [Value::Integer(1), Value::Integer(2), Value::Integer(4)]
+        );
+    }
+}
@@ -0,0 +1,441 @@
+//! Helpers shared by the table-valued functions.
+//!
+//! Table-valued function arguments are hidden columns following the result
+//! columns. Functions that work on a set of fingerprints take the name of a
+//! table (or view) and of its fingerprint and id columns, and read it on each
+//! scan. Functions of one audio file, `name(path)`, implement
+//! [`PathFunction`] and share one virtual table implementation.
+
+#[cfg(feature = "decode")]
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+#[cfg(feature = "decode")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "decode")]
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use rusqlite::types::{Value, ValueRef};
+#[cfg(feature = "decode")]
+use rusqlite::vtab::{eponymous_only_module, Context, VTab, VTabCursor};
+use rusqlite::vtab::{
+    escape_double_quote, IndexConstraintOp, IndexInfo, VTabConfig, VTabConnection, Values,
+};
+use rusqlite::{ffi, Connection};
+
+use crate::encoding::{decode_any, decode_any_blob};
+#[cfg(feature = "decode")]
+use crate::panic::catch_panic;
+#[cfg(feature = "decode")]
+use crate::state::State;
+#[cfg(feature = "decode")]
+use crate::timings::CallStats;
+
+/// Pass the arguments of `function` to `filter`, in order.
+///
+/// `first` is the index of the first hidden column and `signature` lists the
+/// argument names; all arguments are required.
+pub(crate) fn bind_arguments(
+    info: &mut IndexInfo,
+    function: &str,
+    first: c_int,
+    signature: &[&str],
+) -> rusqlite::Result<()> {
+    bind_optional_arguments(info, function, first, signature, 0)
+}
+
+/// Like [`bind_arguments`], but the last `optional` arguments of `signature`
+/// may be left out. `filter` is passed the arguments given.
+pub(crate) fn bind_optional_arguments(
+    info: &mut IndexInfo,
+    function: &str,
+    first: c_int,
+    signature: &[&str],
+    optional: usize,
+) -> rusqlite::Result<()> {
+    let required = signature.len() - optional;
+    let mut arguments = vec![None; signature.len()];
+    let mut unusable = vec![false; signature.len()];
+    for (i, constraint) in info.constraints().enumerate() {
+        let Ok(argument) = usize::try_from(constraint.column() - first) else {
+            continue;
+        };
+        if argument >= arguments.len()
+            || constraint.operator() != IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ
+        {
+            continue;
+        }
+        if constraint.is_usable() {
+            arguments[argument] = Some(i);
+        } else {
+            unusable[argument] = true;
+        }
+    }
+
+    // Optional arguments can only be given after the ones before them, and
+    // not at all in plans where their values aren't available.
+    let given = arguments.iter().take_while(|a| a.is_some()).count();
+    let missing = |i: usize| arguments[i].is_none() && (i < required || unusable[i]);
+    if (0..arguments.len()).any(missing) || arguments[given..].iter().any(Option::is_some) {
+        if unusable.iter().any(|&u| u) {
+            // Arguments are available in another plan, e.g. with a
+            // different join order.
+            return Err(rusqlite::Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_CONSTRAINT),
+                None,
+            ));
+        }
+        let mut expected = signature[..required].join(", ");
+        for name in &signature[required..] {
+            expected += &format!(" [, {name}]");
+        }
+        return Err(rusqlite::Error::ModuleError(format!(
+            "{function}: expected ({expected})"
+        )));
+    }
+
+    for (n, i) in arguments.into_iter().flatten().enumerate() {
+        let mut usage = info.constraint_usage(i);
+        usage.set_argv_index(n as c_int + 1);
+        usage.set_omit(true);
+    }
+    info.set_estimated_cost(1e6);
+    Ok(())
+}
+
+/// Mark a table that reads the files named by its arguments like the file
+/// reading functions: usable only from top-level SQL, unless built with the
+/// `trusted-files` feature (see [`crate::FILE_FUNCTION_FLAGS`]).
+pub(crate) fn reads_files(db: &mut VTabConnection) -> rusqlite::Result<()> {
+    if cfg!(feature = "trusted-files") {
+        return Ok(());
+    }
+    db.config(VTabConfig::DirectOnly)
+}
+
+pub(crate) fn text_argument<'a>(
+    function: &str,
+    args: &'a Values<'_>,
+    idx: usize,
+    name: &str,
+) -> rusqlite::Result<&'a str> {
+    match args.iter().nth(idx) {
+        Some(ValueRef::Text(s)) => std::str::from_utf8(s).map_err(rusqlite::Error::Utf8Error),
+        _ => Err(rusqlite::Error::ModuleError(format!(
+            "{function}: {name} must be TEXT"
+        ))),
+    }
+}
+
+/// Call `f` with the id and decoded fingerprint of each row of `table`.
+///
+/// Fingerprints are decoded like [`fingerprint_column`] decodes them. Rows
+/// with a NULL fingerprint are skipped.
+///
+/// # Safety
+///
+/// `db` must be a valid handle of the connection the calling virtual table
+/// belongs to.
+pub(crate) unsafe fn for_each_fingerprint(
+    db: *mut ffi::sqlite3,
+    table: &str,
+    fp_col: &str,
+    id_col: &str,
+    mut f: impl FnMut(Value, Vec<u32>) -> rusqlite::Result<()>,
+) -> rusqlite::Result<()> {
+    // The connection does not close the handle when dropped.
+    let db = Connection::from_handle(db)?;
+    let mut stmt = db.prepare(&format!(
+        "SELECT \"{}\", \"{}\" FROM \"{}\"",
+        escape_double_quote(id_col),
+        escape_double_quote(fp_col),
+        escape_double_quote(table),
+    ))?;
+
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let id: Value = row.get(0)?;
+        let Some(fingerprint) = fingerprint_column(row.get_ref(1)?)
+            .with_context(|| format!("Invalid fingerprint for {id:?}"))
+            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?
+        else {
+            continue;
+        };
+
+        f(id, fingerprint)?;
+    }
+    Ok(())
+}
+
+/// Decode a stored fingerprint, or `None` for NULL.
+///
+/// Fingerprints may be TEXT in any encoding, raw BLOBs, or in Chromaprint's
+/// compressed format, as made by `fp_compress`, as TEXT or a BLOB.
+pub(crate) fn fingerprint_column(value: ValueRef<'_>) -> anyhow::Result<Option<Vec<u32>>> {
+    match value {
+        ValueRef::Null => Ok(None),
+        ValueRef::Text(s) => decode_any(std::str::from_utf8(s)?).map(|(_, fp)| Some(fp)),
+        ValueRef::Blob(b) => decode_any_blob(b).map(|(_, fp)| Some(fp)),
+        v => Err(anyhow::anyhow!(
+            "expected TEXT or BLOB, got {}",
+            v.data_type()
+        )),
+    }
+}
+
+/// A table-valued function of one audio file, `name(path)`.
+///
+/// The implementing type reads the rows of a file one at a time, so that
+/// functions that can produce rows as they decode don't hold them all, and a
+/// `LIMIT` stops them early. A NULL path has no rows. The decode and
+/// fingerprint timings are recorded once the rows end or the scan stops.
+#[cfg(feature = "decode")]
+pub(crate) trait PathFunction: Sized + 'static {
+    /// The function's name.
+    const NAME: &'static str;
+
+    type Row;
+
+    /// The names of the result columns, which the hidden `path` column
+    /// follows.
+    fn columns() -> Vec<&'static str>;
+
+    /// Start reading the rows of the file at `path`.
+    fn open(path: &Path, stats: &mut CallStats) -> anyhow::Result<Self>;
+
+    /// The next row, or `None` after the last.
+    fn next_row(&mut self, stats: &mut CallStats) -> anyhow::Result<Option<Self::Row>>;
+
+    /// Set the result to result column `i` of `row`.
+    fn column(row: &Self::Row, ctx: &mut Context, i: usize) -> rusqlite::Result<()>;
+}
+
+#[cfg(feature = "decode")]
+pub(crate) fn register_path_function<F: PathFunction>(
+    db: &Connection,
+    state: Arc<State>,
+) -> rusqlite::Result<()> {
+    db.create_module(F::NAME, eponymous_only_module::<PathTab<F>>(), Some(state))
+}
+
+#[cfg(feature = "decode")]
+#[repr(C)]
+struct PathTab<F> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab,
+    state: Arc<State>,
+    phantom: PhantomData<F>,
+}
+
+#[cfg(feature = "decode")]
+unsafe impl<'vtab, F: PathFunction> VTab<'vtab> for PathTab<F> {
+    type Aux = Arc<State>;
+    type Cursor = PathCursor<'vtab, F>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        aux: Option<&Arc<State>>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        catch_panic(|| {
+            let state = aux.cloned().ok_or_else(|| {
+                rusqlite::Error::ModuleError(format!("{}: missing state", F::NAME))
+            })?;
+            reads_files(db)?;
+            Ok((
+                format!("CREATE TABLE x({}, path HIDDEN)", F::columns().join(", ")),
+                PathTab {
+                    base: ffi::sqlite3_vtab::default(),
+                    state,
+                    phantom: PhantomData,
+                },
+            ))
+        })
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            let first = F::columns().len() as c_int;
+            bind_arguments(info, F::NAME, first, &["path"])
+        })
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<PathCursor<'vtab, F>> {
+        catch_panic(|| {
+            Ok(PathCursor {
+                base: ffi::sqlite3_vtab_cursor::default(),
+                state: self.state.clone(),
+                columns: F::columns().len(),
+                rows: None,
+                row: None,
+                rowid: 0,
+                phantom: PhantomData,
+            })
+        })
+    }
+}
+
+/// Cursor reading one row of a [`PathFunction`] at a time.
+#[cfg(feature = "decode")]
+#[repr(C)]
+struct PathCursor<'vtab, F: PathFunction> {
+    /// Base class. Must be first
+    base: ffi::sqlite3_vtab_cursor,
+    state: Arc<State>,
+    /// The number of result columns.
+    columns: usize,
+    /// The rows being read, with the file's path and the timings so far,
+    /// until they end.
+    rows: Option<(F, PathBuf, CallStats)>,
+    row: Option<F::Row>,
+    rowid: i64,
+    phantom: PhantomData<&'vtab PathTab<F>>,
+}
+
+#[cfg(feature = "decode")]
+impl<F: PathFunction> PathCursor<'_, F> {
+    /// Read the next row.
+    fn advance(&mut self) -> rusqlite::Result<()> {
+        self.row = None;
+        let Some((rows, _, stats)) = &mut self.rows else {
+            return Ok(());
+        };
+        match rows.next_row(stats) {
+            Ok(Some(row)) => {
+                self.row = Some(row);
+                self.rowid += 1;
+                Ok(())
+            }
+            Ok(None) => {
+                self.finish();
+                Ok(())
+            }
+            Err(e) => {
+                self.finish();
+                Err(rusqlite::Error::UserFunctionError(e.into()))
+            }
+        }
+    }
+
+    /// Stop reading rows and record the timings.
+    fn finish(&mut self) {
+        if let Some((_, path, stats)) = self.rows.take() {
+            self.state
+                .record_timing(F::NAME, &path.to_string_lossy(), stats);
+        }
+    }
+}
+
+#[cfg(feature = "decode")]
+impl<F: PathFunction> Drop for PathCursor<'_, F> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(feature = "decode")]
+unsafe impl<F: PathFunction> VTabCursor for PathCursor<'_, F> {
+    fn filter(
+        &mut self,
+        _idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        catch_panic(|| {
+            self.finish();
+            self.row = None;
+            self.rowid = 0;
+
+            let Some(value) = args
+                .iter()
+                .next()
+                .filter(|v| v.data_type() != rusqlite::types::Type::Null)
+            else {
+                return Ok(());
+            };
+            let path = crate::path::path_from_value(0, value)?;
+
+            let mut stats = self.state.call_stats();
+            let rows = F::open(&path, &mut stats)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+            self.rows = Some((rows, path, stats));
+            self.advance()
+        })
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        catch_panic(|| self.advance())
+    }
+
+    fn eof(&self) -> bool {
+        self.row.is_none()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        catch_panic(|| match (&self.row, usize::try_from(i)) {
+            (Some(row), Ok(i)) if i < self.columns => F::column(row, ctx, i),
+            _ => ctx.set_result(&rusqlite::types::Null),
+        })
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        catch_panic(|| Ok(self.rowid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(feature = "decode", not(feature = "extension")))]
+    #[test]
+    fn test_path_function_streams() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use super::*;
+
+        static READ: AtomicUsize = AtomicUsize::new(0);
+
+        /// Rows without end, counting the rows read.
+        struct Counter(i64);
+
+        impl PathFunction for Counter {
+            const NAME: &'static str = "counter";
+
+            type Row = i64;
+
+            fn columns() -> Vec<&'static str> {
+                vec!["n"]
+            }
+
+            fn open(_path: &Path, _stats: &mut CallStats) -> anyhow::Result<Self> {
+                Ok(Counter(0))
+            }
+
+            fn next_row(&mut self, _stats: &mut CallStats) -> anyhow::Result<Option<i64>> {
+                READ.fetch_add(1, Ordering::Relaxed);
+                self.0 += 1;
+                Ok(Some(self.0))
+            }
+
+            fn column(row: &i64, ctx: &mut Context, _i: usize) -> rusqlite::Result<()> {
+                ctx.set_result(row)
+            }
+        }
+
+        let db = Connection::open_in_memory().unwrap();
+        register_path_function::<Counter>(&db, Arc::default()).unwrap();
+        let sum: i64 = db
+            .query_row(
+                "SELECT sum(n) FROM (SELECT n FROM counter('x') LIMIT 3)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(sum, 6);
+        assert!(READ.load(Ordering::Relaxed) <= 4);
+
+        let count: i64 = db
+            .query_row("SELECT count(*) FROM counter(NULL)", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! Reading audio files through the connection's VFS.
+//!
+//! With the `vfs` option enabled, files are opened and read with the VFS of
+//! the connection's main database rather than the standard library, so VFS
+//! based sandboxes, overlays and instrumentation see the extension's file
+//! access too:
+//!
+//! ```sql
+//! SELECT chromaprint_option('vfs', 1);
+//! ```
+//!
+//! Files are opened read-only as `SQLITE_OPEN_MAIN_DB` files, since that is
+//! the kind every VFS supports. Paths must be valid UTF-8, as SQLite file
+//! names are. URIs with a registered source provider are still opened by the
+//! provider.
+
+use std::ffi::{CStr, CString};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::ffi;
+#[cfg(feature = "decode")]
+use symphonia::core::io::MediaSource;
+
+/// A registered VFS.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Vfs(*mut ffi::sqlite3_vfs);
+
+// SAFETY: VFS objects live until they are unregistered, which SQLite
+// requires not to happen while they are in use, and their methods must be
+// callable from any thread.
+unsafe impl Send for Vfs {}
+unsafe impl Sync for Vfs {}
+
+impl Vfs {
+    /// The VFS of the main database of connection `db`.
+    ///
+    /// # Safety
+    ///
+    /// `db` must be a valid connection handle.
+    pub unsafe fn of_connection(db: *mut ffi::sqlite3) -> Option<Self> {
+        let mut vfs: *mut ffi::sqlite3_vfs = std::ptr::null_mut();
+        let rc = ffi::sqlite3_file_control(
+            db,
+            c"main".as_ptr(),
+            ffi::SQLITE_FCNTL_VFS_POINTER,
+            (&mut vfs as *mut *mut ffi::sqlite3_vfs).cast(),
+        );
+        (rc == ffi::SQLITE_OK && !vfs.is_null()).then_some(Vfs(vfs))
+    }
+
+    /// Open the file at `path` for reading.
+    pub fn open(self, path: &Path) -> Result<VfsFile> {
+        let name = path.to_str().context("VFS paths must be valid UTF-8")?;
+        let name = CString::new(name).context("Paths must not contain NUL")?;
+
+        // SAFETY: `self.0` is a registered VFS, see above. The buffers passed
+        // have the sizes the VFS asks for, and the full path name outlives
+        // the file, which SQLite requires.
+        unsafe {
+            let vfs = &*self.0;
+            let mut full_name = vec![0 as c_char; vfs.mxPathname.max(0) as usize + 1];
+            let full_pathname = vfs.xFullPathname.context("VFS has no xFullPathname")?;
+            let rc = full_pathname(
+                self.0,
+                name.as_ptr(),
+                full_name.len() as c_int,
+                full_name.as_mut_ptr(),
+            );
+            if rc != ffi::SQLITE_OK {
+                bail!("Failed to resolve path (SQLite error {rc})");
+            }
+            let full_name = CStr::from_ptr(full_name.as_ptr()).to_owned();
+
+            let mut file = VfsFile {
+                buf: vec![0u64; (vfs.szOsFile.max(0) as usize).div_ceil(8).max(1)],
+                name: full_name,
+                pos: 0,
+                len: 0,
+            };
+            let open = vfs.xOpen.context("VFS has no xOpen")?;
+            let rc = open(
+                self.0,
+                file.name.as_ptr(),
+                file.file(),
+                ffi::SQLITE_OPEN_READONLY | ffi::SQLITE_OPEN_MAIN_DB,
+                std::ptr::null_mut(),
+            );
+            if rc != ffi::SQLITE_OK {
+                bail!("Failed to open file (SQLite error {rc})");
+            }
+
+            let mut len: ffi::sqlite3_int64 = 0;
+            let rc = match file.methods().and_then(|m| m.xFileSize) {
+                Some(file_size) => file_size(file.file(), &mut len),
+                None => ffi::SQLITE_MISUSE,
+            };
+            if rc != ffi::SQLITE_OK {
+                bail!("Failed to get file size (SQLite error {rc})");
+            }
+            file.len = len.max(0) as u64;
+            Ok(file)
+        }
+    }
+}
+
+/// A file open for reading through a VFS.
+pub(crate) struct VfsFile {
+    /// Storage for the VFS's `sqlite3_file` subclass, 8 byte aligned.
+    buf: Vec<u64>,
+    name: CString,
+    pos: u64,
+    len: u64,
+}
+
+// SAFETY: The file is only used through `&mut self`, and VFS files may be
+// used from any thread, one at a time.
+unsafe impl Send for VfsFile {}
+unsafe impl Sync for VfsFile {}
+
+impl VfsFile {
+    fn file(&mut self) -> *mut ffi::sqlite3_file {
+        self.buf.as_mut_ptr().cast()
+    }
+
+    /// The file's methods, or `None` if it was never opened.
+    unsafe fn methods(&mut self) -> Option<&ffi::sqlite3_io_methods> {
+        (*self.file()).pMethods.as_ref()
+    }
+}
+
+impl Read for VfsFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = (buf.len() as u64)
+            .min(self.len.saturating_sub(self.pos))
+            .min(c_int::MAX as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        // SAFETY: The file is open, and `buf` holds at least `n` bytes.
+        let rc = unsafe {
+            let read = self.methods().and_then(|m| m.xRead);
+            match read {
+                Some(read) => read(
+                    self.file(),
+                    buf.as_mut_ptr().cast::<c_void>(),
+                    n as c_int,
+                    self.pos as ffi::sqlite3_int64,
+                ),
+                None => ffi::SQLITE_MISUSE,
+            }
+        };
+        if rc != ffi::SQLITE_OK {
+            return Err(std::io::Error::other(format!(
+                "VFS read failed (SQLite error {rc})"
+            )));
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for VfsFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seek before the start of the file",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(feature = "decode")]
+impl MediaSource for VfsFile {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
+
+impl Drop for VfsFile {
+    fn drop(&mut self) {
+        // SAFETY: SQLite requires xClose to be called on a file whose methods
+        // were set, even if xOpen failed, and never otherwise.
+        unsafe {
+            if let Some(close) = self.methods().and_then(|m| m.xClose) {
+                close(self.file());
+            }
+        }
+    }
+}